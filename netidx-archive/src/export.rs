@@ -0,0 +1,478 @@
+use crate::{ArchiveReader, BatchItem, Cursor, Id};
+use anyhow::Result;
+use chrono::prelude::*;
+use fxhash::FxBuildHasher;
+use netidx::{
+    path::Path,
+    protocol::{glob::GlobSet, value::Typ},
+    subscriber::{Event, Value},
+};
+use std::{borrow::Cow, collections::HashMap, io::Write, ops::Bound, str::FromStr};
+
+/// The output format of `export`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Long format csv, one row per value with the columns
+    /// timestamp, path, type, value.
+    Csv,
+    /// A wide parquet table with a timestamp column and one column per
+    /// path. Each row holds the state of every path as of that
+    /// timestamp, paths with no value yet are null.
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(Format::Parquet),
+            #[cfg(not(feature = "parquet"))]
+            "parquet" => bail!("parquet support is not enabled"),
+            s => bail!("unknown export format {}", s),
+        }
+    }
+}
+
+fn selected(
+    reader: &ArchiveReader,
+    filter: &GlobSet,
+) -> HashMap<Id, Path, FxBuildHasher> {
+    reader.get_index().drain(..).filter(|(_, path)| filter.is_match(path)).collect()
+}
+
+// calls f for each batch between the cursor start and end, the first
+// batch is the image at the cursor start, with the start timestamp.
+fn for_each_batch(
+    reader: &ArchiveReader,
+    cursor: &Cursor,
+    mut f: impl FnMut(DateTime<Utc>, &mut dyn Iterator<Item = (Id, Event)>) -> Result<()>,
+) -> Result<()> {
+    let mut cursor = *cursor;
+    cursor.reset();
+    if let Bound::Included(ts) | Bound::Excluded(ts) = cursor.start() {
+        let mut image = reader.build_image(&cursor)?;
+        f(ts, &mut image.drain())?;
+    }
+    loop {
+        let mut batches = reader.read_deltas(&mut cursor, 1000)?;
+        if batches.is_empty() {
+            break Ok(());
+        }
+        for (ts, mut batch) in batches.drain(..) {
+            f(ts, &mut batch.drain(..).map(|BatchItem(id, ev)| (id, ev)))?;
+        }
+    }
+}
+
+fn value_to_string(v: &Value) -> String {
+    match v {
+        Value::Bytes(b) => base64::encode(&**b),
+        Value::DateTime(ts) => ts.to_rfc3339(),
+        v => v.to_string(),
+    }
+}
+
+fn csv_field(s: &str) -> Cow<'_, str> {
+    if s.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+fn export_csv(
+    reader: &ArchiveReader,
+    cursor: &Cursor,
+    paths: &HashMap<Id, Path, FxBuildHasher>,
+    mut out: impl Write,
+) -> Result<()> {
+    writeln!(out, "timestamp,path,type,value")?;
+    for_each_batch(reader, cursor, |ts, batch| {
+        let ts = ts.to_rfc3339();
+        for (id, ev) in batch {
+            if let Some(path) = paths.get(&id) {
+                let (typ, v) = match ev {
+                    Event::Unsubscribed => ("unsubscribed", String::new()),
+                    Event::Update(v) => (
+                        Typ::get(&v).map(|t| t.name()).unwrap_or("null"),
+                        value_to_string(&v),
+                    ),
+                };
+                writeln!(out, "{},{},{},{}", ts, csv_field(path), typ, csv_field(&v))?;
+            }
+        }
+        Ok(())
+    })?;
+    Ok(out.flush()?)
+}
+
+#[cfg(feature = "parquet")]
+mod wide {
+    use super::*;
+    use parquet::{
+        basic::{LogicalType, Repetition, TimeUnit, Type as PhysicalType},
+        data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int64Type},
+        file::{
+            properties::WriterProperties,
+            writer::{SerializedColumnWriter, SerializedFileWriter},
+        },
+        format::MicroSeconds,
+        schema::types::Type,
+    };
+    use std::sync::Arc;
+
+    const ROW_GROUP: usize = 8192;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Kind {
+        Bool,
+        Int,
+        Float,
+        Timestamp,
+        String,
+    }
+
+    impl Kind {
+        fn of(v: &Value) -> Option<Kind> {
+            match v {
+                Value::Null => None,
+                Value::True | Value::False => Some(Kind::Bool),
+                Value::U32(_)
+                | Value::V32(_)
+                | Value::I32(_)
+                | Value::Z32(_)
+                | Value::I64(_)
+                | Value::Z64(_) => Some(Kind::Int),
+                Value::U64(v) | Value::V64(v) if *v <= i64::MAX as u64 => Some(Kind::Int),
+                Value::F32(_) | Value::F64(_) => Some(Kind::Float),
+                Value::DateTime(_) => Some(Kind::Timestamp),
+                _ => Some(Kind::String),
+            }
+        }
+
+        fn merge(self, other: Kind) -> Kind {
+            match (self, other) {
+                (k0, k1) if k0 == k1 => k0,
+                (Kind::Int, Kind::Float) | (Kind::Float, Kind::Int) => Kind::Float,
+                (_, _) => Kind::String,
+            }
+        }
+
+        fn column(self, name: &str) -> Result<Arc<Type>> {
+            let micros = LogicalType::Timestamp {
+                is_adjusted_to_u_t_c: true,
+                unit: TimeUnit::MICROS(MicroSeconds {}),
+            };
+            let (typ, logical) = match self {
+                Kind::Bool => (PhysicalType::BOOLEAN, None),
+                Kind::Int => (PhysicalType::INT64, None),
+                Kind::Float => (PhysicalType::DOUBLE, None),
+                Kind::Timestamp => (PhysicalType::INT64, Some(micros)),
+                Kind::String => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+            };
+            Ok(Arc::new(
+                Type::primitive_type_builder(name, typ)
+                    .with_repetition(Repetition::OPTIONAL)
+                    .with_logical_type(logical)
+                    .build()?,
+            ))
+        }
+
+        fn write(
+            self,
+            col: &mut SerializedColumnWriter,
+            rows: &[Vec<Option<Value>>],
+            i: usize,
+        ) -> Result<()> {
+            fn collect<T>(
+                rows: &[Vec<Option<Value>>],
+                i: usize,
+                f: impl Fn(&Value) -> Option<T>,
+            ) -> (Vec<T>, Vec<i16>) {
+                let mut vals = Vec::new();
+                let mut defs = Vec::new();
+                for row in rows {
+                    match row[i].as_ref().and_then(|v| f(v)) {
+                        None => defs.push(0),
+                        Some(v) => {
+                            defs.push(1);
+                            vals.push(v)
+                        }
+                    }
+                }
+                (vals, defs)
+            }
+            match self {
+                Kind::Bool => {
+                    let (vals, defs) = collect(rows, i, |v| v.clone().get_as::<bool>());
+                    col.typed::<BoolType>().write_batch(&vals, Some(&defs), None)?;
+                }
+                Kind::Int => {
+                    let (vals, defs) =
+                        collect(rows, i, |v| v.clone().cast_to::<i64>().ok());
+                    col.typed::<Int64Type>().write_batch(&vals, Some(&defs), None)?;
+                }
+                Kind::Float => {
+                    let (vals, defs) =
+                        collect(rows, i, |v| v.clone().cast_to::<f64>().ok());
+                    col.typed::<DoubleType>().write_batch(&vals, Some(&defs), None)?;
+                }
+                Kind::Timestamp => {
+                    let (vals, defs) = collect(rows, i, |v| match v {
+                        Value::DateTime(ts) => Some(ts.timestamp_micros()),
+                        _ => None,
+                    });
+                    col.typed::<Int64Type>().write_batch(&vals, Some(&defs), None)?;
+                }
+                Kind::String => {
+                    let (vals, defs) = collect(rows, i, |v| match v {
+                        Value::Null => None,
+                        v => Some(ByteArray::from(value_to_string(v).into_bytes())),
+                    });
+                    col.typed::<ByteArrayType>().write_batch(&vals, Some(&defs), None)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn write_row_group<W: Write + Send>(
+        writer: &mut SerializedFileWriter<W>,
+        kinds: &[Kind],
+        timestamps: &mut Vec<i64>,
+        rows: &mut Vec<Vec<Option<Value>>>,
+    ) -> Result<()> {
+        if timestamps.is_empty() {
+            return Ok(());
+        }
+        let mut rg = writer.next_row_group()?;
+        let mut i = 0;
+        while let Some(mut col) = rg.next_column()? {
+            if i == 0 {
+                col.typed::<Int64Type>().write_batch(&timestamps, None, None)?;
+            } else {
+                kinds[i - 1].write(&mut col, &rows, i - 1)?;
+            }
+            col.close()?;
+            i += 1;
+        }
+        rg.close()?;
+        timestamps.clear();
+        rows.clear();
+        Ok(())
+    }
+
+    pub(super) fn export_parquet<W: Write + Send>(
+        reader: &ArchiveReader,
+        cursor: &Cursor,
+        paths: &HashMap<Id, Path, FxBuildHasher>,
+        out: W,
+    ) -> Result<()> {
+        // first pass, find the type of each column
+        let mut kinds: HashMap<Id, Kind, FxBuildHasher> = HashMap::default();
+        for_each_batch(reader, cursor, |_, batch| {
+            for (id, ev) in batch {
+                if let Event::Update(v) = ev {
+                    if let (true, Some(k)) = (paths.contains_key(&id), Kind::of(&v)) {
+                        let k = kinds.get(&id).map(|k0| k0.merge(k)).unwrap_or(k);
+                        kinds.insert(id, k);
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        let mut columns = paths.iter().collect::<Vec<_>>();
+        columns.sort_by(|(_, p0), (_, p1)| p0.cmp(p1));
+        let column_of = columns
+            .iter()
+            .enumerate()
+            .map(|(i, (id, _))| (**id, i))
+            .collect::<HashMap<Id, usize, FxBuildHasher>>();
+        let kinds = columns
+            .iter()
+            .map(|(id, _)| kinds.get(id).copied().unwrap_or(Kind::String))
+            .collect::<Vec<_>>();
+        let mut fields = vec![Arc::new(
+            Type::primitive_type_builder("timestamp", PhysicalType::INT64)
+                .with_repetition(Repetition::REQUIRED)
+                .with_logical_type(Some(LogicalType::Timestamp {
+                    is_adjusted_to_u_t_c: true,
+                    unit: TimeUnit::MICROS(MicroSeconds {}),
+                }))
+                .build()?,
+        )];
+        for ((_, path), kind) in columns.iter().zip(kinds.iter()) {
+            fields.push(kind.column(&**path)?);
+        }
+        let schema =
+            Arc::new(Type::group_type_builder("archive").with_fields(fields).build()?);
+        let props = Arc::new(WriterProperties::builder().build());
+        let mut writer = SerializedFileWriter::new(out, schema, props)?;
+        // second pass, write the rows
+        let mut state: Vec<Option<Value>> = vec![None; columns.len()];
+        let mut timestamps = Vec::new();
+        let mut rows = Vec::new();
+        for_each_batch(reader, cursor, |ts, batch| {
+            let mut changed = false;
+            for (id, ev) in batch {
+                if let Some(i) = column_of.get(&id) {
+                    changed = true;
+                    state[*i] = match ev {
+                        Event::Unsubscribed => None,
+                        Event::Update(v) => Some(v),
+                    };
+                }
+            }
+            if changed {
+                timestamps.push(ts.timestamp_micros());
+                rows.push(state.clone());
+                if rows.len() >= ROW_GROUP {
+                    write_row_group(&mut writer, &kinds, &mut timestamps, &mut rows)?;
+                }
+            }
+            Ok(())
+        })?;
+        write_row_group(&mut writer, &kinds, &mut timestamps, &mut rows)?;
+        writer.close()?;
+        Ok(())
+    }
+}
+
+/// Export the part of the archive between the start and end of
+/// `cursor` for the paths matching `filter` to `out` in the
+/// specified format. The state of the matching paths at the start of
+/// the cursor, if it is bounded, is written first, followed by every
+/// delta batch up to the end.
+pub fn export<W: Write + Send>(
+    reader: &ArchiveReader,
+    cursor: &Cursor,
+    filter: &GlobSet,
+    format: Format,
+    out: W,
+) -> Result<()> {
+    reader.check_remap_rescan()?;
+    let paths = selected(reader, filter);
+    match format {
+        Format::Csv => export_csv(reader, cursor, &paths, out),
+        #[cfg(feature = "parquet")]
+        Format::Parquet => wide::export_parquet(reader, cursor, &paths, out),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ArchiveWriter, MonotonicTimestamper, BATCH_POOL};
+    use netidx::{chars::Chars, protocol::glob::Glob};
+    use std::{fs, path::Path as FilePath};
+
+    #[test]
+    fn csv() {
+        let file = FilePath::new("test-data-export");
+        if FilePath::is_file(&file) {
+            fs::remove_file(file).unwrap();
+        }
+        let paths = [Path::from("/foo/bar"), Path::from("/foo/baz"), Path::from("/qux")];
+        let mut timestamper = MonotonicTimestamper::new();
+        let mut t = ArchiveWriter::open(&file).unwrap();
+        t.add_paths(&paths).unwrap();
+        let mut batch = BATCH_POOL.take();
+        batch.push(BatchItem(
+            t.id_for_path(&paths[0]).unwrap(),
+            Event::Update(Value::String(Chars::from("a,\"b\""))),
+        ));
+        batch.push(BatchItem(
+            t.id_for_path(&paths[1]).unwrap(),
+            Event::Update(Value::U64(42)),
+        ));
+        batch.push(BatchItem(
+            t.id_for_path(&paths[2]).unwrap(),
+            Event::Update(Value::U64(1)),
+        ));
+        t.add_batch(false, timestamper.timestamp(), &batch).unwrap();
+        t.flush().unwrap();
+        let filter =
+            GlobSet::new(true, vec![Glob::new(Chars::from("/foo/*")).unwrap()]).unwrap();
+        let mut out = Vec::new();
+        export(&t.reader().unwrap(), &Cursor::new(), &filter, Format::Csv, &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("timestamp,path,type,value"));
+        let mut rows =
+            lines.map(|l| l.split_once(',').unwrap().1.to_string()).collect::<Vec<_>>();
+        rows.sort();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1], "/foo/baz,u64,42");
+        assert!(rows[0].starts_with("/foo/bar,string,\""));
+        drop(t);
+        fs::remove_file(file).unwrap();
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet() {
+        use bytes::Bytes;
+        use parquet::{
+            file::reader::{FileReader, SerializedFileReader},
+            record::Field,
+        };
+        let file = FilePath::new("test-data-export-parquet");
+        if FilePath::is_file(&file) {
+            fs::remove_file(file).unwrap();
+        }
+        let paths = [Path::from("/a"), Path::from("/b"), Path::from("/c")];
+        let mut timestamper = MonotonicTimestamper::new();
+        let mut t = ArchiveWriter::open(&file).unwrap();
+        t.add_paths(&paths).unwrap();
+        let ids = paths.iter().map(|p| t.id_for_path(p).unwrap()).collect::<Vec<_>>();
+        let id = |i: usize| ids[i];
+        let mut batch = BATCH_POOL.take();
+        batch.push(BatchItem(id(0), Event::Update(Value::U64(1))));
+        batch.push(BatchItem(id(1), Event::Update(Value::I32(2))));
+        t.add_batch(false, timestamper.timestamp(), &batch).unwrap();
+        batch.clear();
+        batch.push(BatchItem(id(1), Event::Update(Value::F64(2.5))));
+        batch.push(BatchItem(id(2), Event::Update(Value::String(Chars::from("x")))));
+        t.add_batch(false, timestamper.timestamp(), &batch).unwrap();
+        t.flush().unwrap();
+        let filter =
+            GlobSet::new(true, vec![Glob::new(Chars::from("/*")).unwrap()]).unwrap();
+        let mut out = Vec::new();
+        export(&t.reader().unwrap(), &Cursor::new(), &filter, Format::Parquet, &mut out)
+            .unwrap();
+        let reader = SerializedFileReader::new(Bytes::from(out)).unwrap();
+        let rows = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| {
+                row.unwrap()
+                    .get_column_iter()
+                    .map(|(name, f)| (name.clone(), f.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        let names = rows[0].iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["timestamp", "/a", "/b", "/c"]);
+        assert!(matches!(rows[0][0].1, Field::TimestampMicros(_)));
+        // the int and float values of /b are merged into a float column
+        let fields = |row: &Vec<(String, Field)>| {
+            row[1..].iter().map(|(_, f)| f.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            fields(&rows[0]),
+            vec![Field::Long(1), Field::Double(2.), Field::Null]
+        );
+        assert_eq!(
+            fields(&rows[1]),
+            vec![Field::Long(1), Field::Double(2.5), Field::Str("x".into())]
+        );
+        drop(t);
+        fs::remove_file(file).unwrap();
+    }
+}
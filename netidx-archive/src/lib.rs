@@ -15,7 +15,7 @@ use log::warn;
 use mapr::{Mmap, MmapMut};
 use netidx::{
     chars::Chars,
-    pack::{decode_varint, encode_varint, varint_len, Pack, PackError},
+    pack::{check_len, decode_varint, encode_varint, varint_len, Pack, PackError},
     path::Path,
     pool::{Pool, Pooled},
     subscriber::{Event, FromValue, Value},
@@ -42,6 +42,9 @@ use std::{
     },
 };
 
+pub mod export;
+pub mod repair;
+
 #[derive(Debug, Clone)]
 pub struct FileHeader {
     version: u32,
@@ -69,6 +72,7 @@ impl Pack for FileHeader {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        check_len(buf, <FileHeader as Pack>::const_encoded_len().unwrap())?;
         for byte in FILE_MAGIC {
             if buf.get_u8() != *byte {
                 return Err(PackError::InvalidFormat);
@@ -126,6 +130,7 @@ impl Pack for RecordHeader {
 
     fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
         let mut v = [0u8; 8];
+        check_len(buf, v.len())?;
         buf.copy_to_slice(&mut v);
         RecordHeader::unpack(&v).map_err(|_| PackError::InvalidFormat)
     }
@@ -369,8 +374,15 @@ impl MonotonicTimestamper {
     }
 
     pub fn timestamp(&mut self) -> Timestamp {
+        self.timestamp_at(Utc::now())
+    }
+
+    /// Generate a timestamp for `now` instead of the current time,
+    /// e.g. when copying batches from another archive. The same
+    /// monotonicity guarantees apply, so if `now` is not after the
+    /// previous timestamp the result will be moved forward.
+    pub fn timestamp_at(&mut self, now: DateTime<Utc>) -> Timestamp {
         use chrono::Duration;
-        let now = Utc::now();
         let ts = match self.basis {
             None => Timestamp::NewBasis(self.update_basis(now)),
             Some(basis) => match (now - self.prev).num_microseconds() {
@@ -1150,12 +1162,12 @@ impl ArchiveReader {
         'main: loop {
             let inner = self.index.read();
             for _ in 0..1000 {
-                let (id, path) = inner.path_by_id.get_index(i).unwrap();
-                idx.push((*id, path.clone()));
-                i += 1;
                 if i >= inner.path_by_id.len() {
                     break 'main;
                 }
+                let (id, path) = inner.path_by_id.get_index(i).unwrap();
+                idx.push((*id, path.clone()));
+                i += 1;
             }
         }
         idx
@@ -1236,6 +1248,26 @@ impl ArchiveReader {
         &self,
         cursor: &mut Cursor,
         n: usize,
+    ) -> Result<Pooled<VecDeque<(DateTime<Utc>, Pooled<Vec<BatchItem>>)>>> {
+        self.read_batches(false, cursor, n)
+    }
+
+    /// read at most `n` image batches from the specified cursor, and
+    /// advance it by the number of batches read. This is the same as
+    /// `read_deltas` except it reads image batches.
+    pub fn read_images(
+        &self,
+        cursor: &mut Cursor,
+        n: usize,
+    ) -> Result<Pooled<VecDeque<(DateTime<Utc>, Pooled<Vec<BatchItem>>)>>> {
+        self.read_batches(true, cursor, n)
+    }
+
+    fn read_batches(
+        &self,
+        image: bool,
+        cursor: &mut Cursor,
+        n: usize,
     ) -> Result<Pooled<VecDeque<(DateTime<Utc>, Pooled<Vec<BatchItem>>)>>> {
         self.check_remap_rescan()?;
         let mut idxs = POS_POOL.take();
@@ -1246,10 +1278,9 @@ impl ArchiveReader {
         };
         let end = {
             let index = self.index.read();
+            let map = if image { &index.imagemap } else { &index.deltamap };
             idxs.extend(
-                index
-                    .deltamap
-                    .range((start, cursor.end))
+                map.range((start, cursor.end))
                     .map(|(ts, pos)| (*ts, *pos))
                     .take(n),
             );
@@ -0,0 +1,415 @@
+use crate::{
+    ArchiveReader, ArchiveWriter, BatchItem, Cursor, FileHeader, Id,
+    MonotonicTimestamper, PathMapping, RecordHeader, RecordTyp, BATCH_POOL,
+    COMMITTED_OFFSET, FILE_VERSION,
+};
+use anyhow::{Context, Result};
+use bytes::{Buf, BufMut};
+use chrono::prelude::*;
+use fs3::FileExt;
+use fxhash::FxBuildHasher;
+use mapr::MmapMut;
+use netidx::{pack::Pack, path::Path, subscriber::Event};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::OpenOptions,
+    path::Path as FilePath,
+};
+
+/// Something wrong with an archive found by `check`
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// the position in the file of the problem
+    pub pos: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at {}: {}", self.pos, self.msg)
+    }
+}
+
+/// The result of checking an archive
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// the length of the file
+    pub len: usize,
+    /// the committed end of the archive according to the file header
+    pub committed: usize,
+    /// the end of the last valid record
+    pub end: usize,
+    pub records: usize,
+    pub paths: usize,
+    /// paths that have a mapping but never appear in a batch
+    pub unused_paths: usize,
+    pub image_batches: usize,
+    pub delta_batches: usize,
+    pub first: Option<DateTime<Utc>>,
+    pub last: Option<DateTime<Utc>>,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    /// true if no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "file length: {}", self.len)?;
+        writeln!(f, "committed: {}", self.committed)?;
+        writeln!(f, "valid end: {}", self.end)?;
+        writeln!(f, "records: {}", self.records)?;
+        writeln!(f, "paths: {} ({} unused)", self.paths, self.unused_paths)?;
+        writeln!(f, "image batches: {}", self.image_batches)?;
+        writeln!(f, "delta batches: {}", self.delta_batches)?;
+        if let (Some(first), Some(last)) = (self.first, self.last) {
+            writeln!(f, "time range: {} - {}", first, last)?;
+        }
+        if self.problems.is_empty() {
+            write!(f, "ok")
+        } else {
+            for p in &self.problems {
+                writeln!(f, "problem {}", p)?;
+            }
+            write!(f, "{} problems", self.problems.len())
+        }
+    }
+}
+
+// decode a vector that must fill the whole record
+fn decode_vec<T: Pack>(rec: &[u8]) -> Result<Vec<T>> {
+    let mut buf = rec;
+    let v = <Vec<T> as Pack>::decode(&mut buf)?;
+    if buf.has_remaining() {
+        bail!("{} trailing bytes in record", buf.remaining())
+    }
+    Ok(v)
+}
+
+fn scan(buf: &[u8]) -> Result<Report> {
+    let fh_len = <FileHeader as Pack>::const_encoded_len().unwrap();
+    let rh_len = <RecordHeader as Pack>::const_encoded_len().unwrap();
+    if buf.len() < fh_len {
+        bail!("invalid file header: too short")
+    }
+    let header = <FileHeader as Pack>::decode(&mut &buf[..])
+        .map_err(anyhow::Error::from)
+        .context("invalid file header")?;
+    if header.version != FILE_VERSION {
+        bail!("file version is too new, can't read it")
+    }
+    let mut report = Report {
+        len: buf.len(),
+        committed: header.committed as usize,
+        end: fh_len,
+        records: 0,
+        paths: 0,
+        unused_paths: 0,
+        image_batches: 0,
+        delta_batches: 0,
+        first: None,
+        last: None,
+        problems: Vec::new(),
+    };
+    let mut path_by_id: HashMap<Id, Path, FxBuildHasher> = HashMap::default();
+    let mut id_by_path: HashMap<Path, Id> = HashMap::new();
+    let mut used: HashSet<Id, FxBuildHasher> = HashSet::default();
+    let mut time_basis: Option<DateTime<Utc>> = None;
+    let mut pos = fh_len;
+    loop {
+        if buf.len() - pos < rh_len {
+            break;
+        }
+        let rh = match <RecordHeader as Pack>::decode(&mut &buf[pos..]) {
+            Ok(rh) => rh,
+            Err(_) => {
+                report
+                    .problems
+                    .push(Problem { pos, msg: "invalid record header".into() });
+                break;
+            }
+        };
+        // every real record has a non empty body, a zero length
+        // header is the unwritten space at the end of the file
+        if rh.record_length == 0 {
+            break;
+        }
+        let len = rh.record_length as usize;
+        if buf.len() - pos - rh_len < len {
+            report.problems.push(Problem { pos, msg: "truncated record".into() });
+            break;
+        }
+        let rec = &buf[pos + rh_len..pos + rh_len + len];
+        let mut check_record = || match rh.record_type {
+            RecordTyp::Timestamp => {
+                let mut b = rec;
+                let ts = <DateTime<Utc> as Pack>::decode(&mut b)?;
+                if b.has_remaining() {
+                    bail!("{} trailing bytes in timestamp record", b.remaining())
+                }
+                time_basis = Some(ts);
+                Ok(())
+            }
+            RecordTyp::PathMappings => {
+                for PathMapping(path, id) in decode_vec::<PathMapping>(rec)? {
+                    if path_by_id.contains_key(&id) || id_by_path.contains_key(&path) {
+                        bail!("duplicate path mapping for {}, {:?}", path, id)
+                    }
+                    path_by_id.insert(id, path.clone());
+                    id_by_path.insert(path, id);
+                }
+                Ok(())
+            }
+            RecordTyp::DeltaBatch | RecordTyp::ImageBatch => {
+                let basis = match time_basis {
+                    Some(ts) => ts,
+                    None => bail!("batch before any timestamp record"),
+                };
+                for BatchItem(id, _) in decode_vec::<BatchItem>(rec)? {
+                    if !path_by_id.contains_key(&id) {
+                        bail!("unknown id {:?} in batch", id)
+                    }
+                    used.insert(id);
+                }
+                let ts = basis + chrono::Duration::microseconds(rh.timestamp as i64);
+                if report.first.is_none() {
+                    report.first = Some(ts);
+                }
+                report.last = Some(ts);
+                match rh.record_type {
+                    RecordTyp::ImageBatch => report.image_batches += 1,
+                    _ => report.delta_batches += 1,
+                }
+                Ok(())
+            }
+        };
+        if let Err(e) = check_record() {
+            report
+                .problems
+                .push(Problem { pos, msg: format!("{:?} {}", rh.record_type, e) });
+            break;
+        }
+        report.records += 1;
+        pos += rh_len + len;
+    }
+    report.end = pos;
+    report.paths = path_by_id.len();
+    report.unused_paths = path_by_id.len() - used.len();
+    if report.committed > report.end {
+        report.problems.push(Problem {
+            pos: report.end,
+            msg: format!(
+                "committed end {} is past the last valid record",
+                report.committed
+            ),
+        });
+    } else if report.committed < report.end {
+        report.problems.push(Problem {
+            pos: report.committed,
+            msg: format!("valid records up to {} are not committed", report.end),
+        });
+    }
+    Ok(report)
+}
+
+/// Check the integrity of the archive at `path`. Every record is
+/// decoded and checked, and the scan stops at the first invalid
+/// record. Fails only if the file is not an archive at all, all other
+/// problems are listed in the report.
+pub fn check(path: impl AsRef<FilePath>) -> Result<Report> {
+    let file = OpenOptions::new().read(true).open(path.as_ref())?;
+    file.try_lock_shared()?;
+    if file.metadata()?.len() == 0 {
+        bail!("invalid file header: too short")
+    }
+    let mmap = unsafe { mapr::Mmap::map(&file)? };
+    scan(&*mmap)
+}
+
+/// Repair the archive at `path` in place. Everything after the last
+/// valid record is zeroed, and the committed end of the archive is
+/// moved to the end of the last valid record, the path mappings and
+/// image index are rebuilt from the remaining records when the
+/// archive is next opened. Returns the report from before the
+/// repair. Any data after the first invalid record is lost.
+pub fn repair(path: impl AsRef<FilePath>) -> Result<Report> {
+    let file = OpenOptions::new().read(true).write(true).open(path.as_ref())?;
+    file.try_lock_exclusive()?;
+    if file.metadata()?.len() == 0 {
+        bail!("invalid file header: too short")
+    }
+    let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+    let report = scan(&*mmap)?;
+    if !report.is_ok() {
+        for b in &mut mmap[report.end..] {
+            *b = 0
+        }
+        mmap.flush()?;
+        let mut buf = &mut mmap[COMMITTED_OFFSET..];
+        buf.put_u64(report.end as u64);
+        mmap.flush()?;
+    }
+    Ok(report)
+}
+
+/// Rewrite the archive at `src` to a new archive at `dst`. Path
+/// mappings that are never used are dropped, existing images are
+/// dropped, and if `image_frequency` is specified a new image is
+/// written every `image_frequency` bytes. Timestamps are preserved.
+pub fn compact(
+    src: impl AsRef<FilePath>,
+    dst: impl AsRef<FilePath>,
+    image_frequency: Option<usize>,
+) -> Result<()> {
+    if dst.as_ref().exists() {
+        bail!("{} already exists", dst.as_ref().display())
+    }
+    let reader = ArchiveReader::open(src)?;
+    let mut used: HashSet<Id, FxBuildHasher> = HashSet::default();
+    let mut cursor = Cursor::new();
+    loop {
+        let batches = reader.read_deltas(&mut cursor, 1000)?;
+        if batches.is_empty() {
+            break;
+        }
+        for (_, batch) in batches.iter() {
+            used.extend(batch.iter().map(|BatchItem(id, _)| *id));
+        }
+    }
+    let mut writer = ArchiveWriter::open(dst)?;
+    let paths = reader
+        .get_index()
+        .drain(..)
+        .filter(|(id, _)| used.contains(id))
+        .collect::<Vec<_>>();
+    writer.add_paths(paths.iter().map(|(_, path)| path))?;
+    let ids = paths
+        .iter()
+        .map(|(id, path)| (*id, writer.id_for_path(path).unwrap()))
+        .collect::<HashMap<Id, Id, FxBuildHasher>>();
+    let mut timest = MonotonicTimestamper::new();
+    let mut image: HashMap<Id, Event, FxBuildHasher> = HashMap::default();
+    let mut last_image = writer.len();
+    cursor.reset();
+    loop {
+        let mut batches = reader.read_deltas(&mut cursor, 1000)?;
+        if batches.is_empty() {
+            break;
+        }
+        for (ts, mut batch) in batches.drain(..) {
+            let mut b = BATCH_POOL.take();
+            for BatchItem(id, ev) in batch.drain(..) {
+                let id = ids[&id];
+                if image_frequency.is_some() {
+                    image.insert(id, ev.clone());
+                }
+                b.push(BatchItem(id, ev));
+            }
+            let ts = timest.timestamp_at(ts);
+            writer.add_batch(false, ts, &b)?;
+            match image_frequency {
+                None => (),
+                Some(freq) if writer.len() - last_image < freq => (),
+                Some(_) => {
+                    let mut b = BATCH_POOL.take();
+                    for (id, ev) in image.iter() {
+                        b.push(BatchItem(*id, ev.clone()));
+                    }
+                    writer.add_batch(true, ts, &b)?;
+                    last_image = writer.len();
+                }
+            }
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use netidx::subscriber::Value;
+    use std::fs;
+
+    fn write_archive(file: &FilePath, paths: &[Path], n: usize) -> Vec<usize> {
+        if FilePath::is_file(file) {
+            fs::remove_file(file).unwrap();
+        }
+        let mut timestamper = MonotonicTimestamper::new();
+        let mut t = ArchiveWriter::open(file).unwrap();
+        t.add_paths(paths).unwrap();
+        let mut ends = Vec::new();
+        for i in 0..n {
+            let mut batch = BATCH_POOL.take();
+            batch.push(BatchItem(
+                t.id_for_path(&paths[0]).unwrap(),
+                Event::Update(Value::U64(i as u64)),
+            ));
+            t.add_batch(false, timestamper.timestamp(), &batch).unwrap();
+            t.flush().unwrap();
+            ends.push(t.len());
+        }
+        ends
+    }
+
+    #[test]
+    fn check_repair() {
+        let file = FilePath::new("test-data-repair");
+        let ends = write_archive(file, &[Path::from("/foo")], 2);
+        let report = check(file).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.delta_batches, 2);
+        // simulate a crash, the committed header is behind the data,
+        // and a partially written record follows the last batch
+        let mut data = fs::read(file).unwrap();
+        (&mut data[COMMITTED_OFFSET..]).put_u64(ends[0] as u64);
+        for b in &mut data[ends[1]..ends[1] + 8] {
+            *b = 0xff
+        }
+        fs::write(file, &data).unwrap();
+        let report = check(file).unwrap();
+        assert_eq!(report.committed, ends[0]);
+        assert_eq!(report.end, ends[1]);
+        assert_eq!(report.problems.len(), 2);
+        repair(file).unwrap();
+        let report = check(file).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(ArchiveReader::open(file).unwrap().delta_batches(), 2);
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn compact_archive() {
+        let src = FilePath::new("test-data-compact-src");
+        let dst = FilePath::new("test-data-compact-dst");
+        if FilePath::is_file(dst) {
+            fs::remove_file(dst).unwrap();
+        }
+        write_archive(src, &[Path::from("/foo"), Path::from("/unused")], 10);
+        assert_eq!(check(src).unwrap().unused_paths, 1);
+        compact(src, dst, Some(1)).unwrap();
+        let report = check(dst).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.paths, 1);
+        assert_eq!(report.unused_paths, 0);
+        assert_eq!(report.delta_batches, 10);
+        assert_eq!(report.image_batches, 10);
+        let (r0, r1) =
+            (ArchiveReader::open(src).unwrap(), ArchiveReader::open(dst).unwrap());
+        let (mut c0, mut c1) = (Cursor::new(), Cursor::new());
+        let (b0, b1) = (
+            r0.read_deltas(&mut c0, 100).unwrap(),
+            r1.read_deltas(&mut c1, 100).unwrap(),
+        );
+        assert_eq!(
+            b0.iter().map(|(ts, _)| *ts).collect::<Vec<_>>(),
+            b1.iter().map(|(ts, _)| *ts).collect::<Vec<_>>()
+        );
+        fs::remove_file(src).unwrap();
+        fs::remove_file(dst).unwrap();
+    }
+}
@@ -18,7 +18,10 @@ use netidx::{
     resolver::{Auth, ResolverRead},
     subscriber::{Dval, Event, SubId, Subscriber, UpdatesFlags, Value},
 };
-use netidx_bscript::vm::RpcCallId;
+use netidx_bscript::{
+    expr::ExprId,
+    vm::{RpcCallId, TimerId},
+};
 use netidx_protocols::{rpc::client as rpc, view};
 use std::{
     collections::HashMap,
@@ -91,6 +94,16 @@ impl Ctx {
             self.from_gui.unbounded_send(FromGui::CallRpc(name, args, id));
     }
 
+    pub(crate) fn set_timer(&self, id: TimerId, timeout: Duration, ref_id: ExprId) {
+        let _: result::Result<_, _> =
+            self.from_gui.unbounded_send(FromGui::SetTimer(id, timeout, ref_id));
+    }
+
+    pub(crate) fn clear_timers(&self, ref_ids: Vec<ExprId>) {
+        let _: result::Result<_, _> =
+            self.from_gui.unbounded_send(FromGui::ClearTimers(ref_ids));
+    }
+
     pub(crate) fn highlight(&self, paths: Vec<WidgetPath>) {
         let _: result::Result<_, _> = self.to_gui.send(ToGui::Highlight(paths));
     }
@@ -111,6 +124,7 @@ struct CtxInner {
         HashMap<Path, (Instant, mpsc::UnboundedSender<(Vec<(Chars, Value)>, RpcCallId)>)>,
     changed: Pooled<Vec<(SubId, Value)>>,
     refreshing: bool,
+    timers: HashMap<ExprId, Vec<task::JoinHandle<()>>>,
 }
 
 impl CtxInner {
@@ -133,6 +147,7 @@ impl CtxInner {
             dv_view: None,
             rpcs: HashMap::new(),
             changed: UPDATES.take(),
+            timers: HashMap::new(),
             refreshing: false,
         };
         task::spawn(inner.run());
@@ -375,6 +390,26 @@ impl CtxInner {
         Ok(())
     }
 
+    fn set_timer(&mut self, id: TimerId, timeout: Duration, ref_id: ExprId) {
+        let to_gui = self.to_gui.clone();
+        let timers = self.timers.entry(ref_id).or_insert_with(Vec::new);
+        timers.retain(|t| !t.is_finished());
+        timers.push(task::spawn(async move {
+            time::sleep(timeout).await;
+            let _: result::Result<_, _> = to_gui.send(ToGui::UpdateTimer(id));
+        }));
+    }
+
+    fn clear_timers(&mut self, ref_ids: Vec<ExprId>) {
+        for ref_id in ref_ids {
+            if let Some(timers) = self.timers.remove(&ref_id) {
+                for t in timers {
+                    t.abort()
+                }
+            }
+        }
+    }
+
     fn gc_rpcs(&mut self) {
         static MAX_RPC_AGE: Duration = Duration::from_secs(120);
         let now = Instant::now();
@@ -425,6 +460,9 @@ impl CtxInner {
                         break_err!(self.navigate_file(file).await),
                     Some(FromGui::CallRpc(path, args, id)) =>
                         break_err!(self.call_rpc(path, args, id)),
+                    Some(FromGui::SetTimer(id, timeout, ref_id)) =>
+                        self.set_timer(id, timeout, ref_id),
+                    Some(FromGui::ClearTimers(ref_ids)) => self.clear_timers(ref_ids),
                 },
                 b = self.updates.next() => if let Some(batch) = b {
                     break_err!(self.process_updates(batch))
@@ -1,6 +1,9 @@
 use super::{util::ask_modal, ToGui, ViewLoc, WidgetCtx};
 use netidx::{chars::Chars, path::Path, resolver, subscriber::Value};
-use netidx_bscript::vm::{self, Apply, ExecCtx, InitFn, Node, Register};
+use netidx_bscript::{
+    typ::{Arg, Ret, Signature},
+    vm::{self, Apply, ExecCtx, InitFn, Node, Register},
+};
 use std::{cell::RefCell, mem, result::Result, sync::Arc};
 
 pub(crate) enum LocalEvent {
@@ -18,6 +21,7 @@ impl Register<WidgetCtx, LocalEvent> for Event {
         let f: InitFn<WidgetCtx, LocalEvent> =
             Arc::new(|_, from, _| Box::new(Event { cur: None, invalid: from.len() > 0 }));
         ctx.functions.insert("event".into(), f);
+        ctx.signatures.insert("event".into(), Signature::fixed(vec![], Ret::Unknown));
     }
 }
 
@@ -41,6 +45,7 @@ impl Apply<WidgetCtx, LocalEvent> for Event {
             vm::Event::Variable(_, _)
             | vm::Event::Netidx(_, _)
             | vm::Event::Rpc(_, _)
+            | vm::Event::Timer(_)
             | vm::Event::User(LocalEvent::TableResolved(_, _)) => None,
             vm::Event::User(LocalEvent::Event(value)) => {
                 self.cur = Some(value.clone());
@@ -92,6 +97,13 @@ impl Register<WidgetCtx, LocalEvent> for Confirm {
             })
         });
         ctx.functions.insert("confirm".into(), f);
+        let sig = Signature {
+            args: vec![Arg::Any],
+            optional: vec![Arg::Any],
+            variadic: None,
+            ret: Ret::Last,
+        };
+        ctx.signatures.insert("confirm".into(), sig);
     }
 }
 
@@ -170,6 +182,13 @@ impl Register<WidgetCtx, LocalEvent> for Navigate {
             Box::new(t)
         });
         ctx.functions.insert("navigate".into(), f);
+        let sig = Signature {
+            args: vec![Arg::Any],
+            optional: vec![Arg::Any],
+            variadic: None,
+            ret: Ret::Unknown,
+        };
+        ctx.signatures.insert("navigate".into(), sig);
     }
 }
 
@@ -6,7 +6,7 @@ use netidx::{
     chars::Chars,
     subscriber::{Typ, Value},
 };
-use netidx_bscript::expr;
+use netidx_bscript::{expr, typ};
 use std::{
     cell::{Cell, RefCell},
     rc::Rc,
@@ -89,7 +89,16 @@ impl Constant {
                 match typ.parse(&*valent.get_text()) {
                     Ok(value) => {
                         errlbl.set_markup("");
-                        *spec.borrow_mut() = expr::ExprKind::Constant(value).to_expr();
+                        let lambda = match &value {
+                            Value::String(s) => s.parse::<expr::Expr>().ok().filter(|e| {
+                                matches!(e.kind, expr::ExprKind::Lambda { .. })
+                            }),
+                            _ => None,
+                        };
+                        *spec.borrow_mut() = match lambda {
+                            Some(e) => e,
+                            None => expr::ExprKind::Constant(value).to_expr(),
+                        };
                         on_change()
                     },
                     Err(e) => {
@@ -109,6 +118,9 @@ impl Constant {
     fn get_val(&self) -> Value {
         match self.spec.borrow().clone() {
             expr::Expr { kind: expr::ExprKind::Constant(v), .. } => v,
+            e @ expr::Expr { kind: expr::ExprKind::Lambda { .. }, .. } => {
+                Value::String(Chars::from(e.to_string()))
+            }
             expr::Expr { kind: expr::ExprKind::Apply { .. }, .. } => unreachable!(),
         }
     }
@@ -164,7 +176,8 @@ impl Apply {
 
     fn get_fn(&self) -> String {
         match &*self.spec.borrow() {
-            expr::Expr { kind: expr::ExprKind::Constant(_), .. } => unreachable!(),
+            expr::Expr { kind: expr::ExprKind::Constant(_), .. }
+            | expr::Expr { kind: expr::ExprKind::Lambda { .. }, .. } => unreachable!(),
             expr::Expr { kind: expr::ExprKind::Apply { function, .. }, .. } => {
                 function.clone()
             }
@@ -202,6 +215,10 @@ impl Properties {
             expr::ExprKind::Apply { .. } => {
                 Apply::insert(ctx, on_change, store, iter, spec)
             }
+            // lambdas are edited as their source
+            expr::ExprKind::Lambda { .. } => {
+                Constant::insert(ctx, on_change, store, iter, spec)
+            }
         }
     }
 
@@ -247,7 +264,8 @@ fn build_tree(
     let iter = store.insert_before(parent, None);
     Properties::insert(ctx, on_change.clone(), store, &iter, s.clone());
     match s {
-        expr::Expr { kind: expr::ExprKind::Constant(_), .. } => (),
+        expr::Expr { kind: expr::ExprKind::Constant(_), .. }
+        | expr::Expr { kind: expr::ExprKind::Lambda { .. }, .. } => (),
         expr::Expr { kind: expr::ExprKind::Apply { args, function: _ }, .. } => {
             for s in args {
                 build_tree(ctx, on_change, store, Some(&iter), s)
@@ -270,7 +288,8 @@ fn build_expr(ctx: &BSCtx, store: &gtk::TreeStore, root: &gtk::TreeIter) -> expr
             set_dbg_expr(ctx, store, root, e)
         }
         Ok(Some(p)) => match p.spec() {
-            v @ expr::Expr { kind: expr::ExprKind::Constant(_), .. } => {
+            v @ expr::Expr { kind: expr::ExprKind::Constant(_), .. }
+            | v @ expr::Expr { kind: expr::ExprKind::Lambda { .. }, .. } => {
                 set_dbg_expr(ctx, store, root, v)
             }
             expr::Expr { kind: expr::ExprKind::Apply { mut args, function }, id } => {
@@ -306,6 +325,16 @@ fn build_expr(ctx: &BSCtx, store: &gtk::TreeStore, root: &gtk::TreeIter) -> expr
     }
 }
 
+fn show_type_errors(ctx: &BSCtx, errlbl: &gtk::Label, spec: &expr::Expr) {
+    let types = typ::check(&*ctx.borrow(), spec);
+    if types.is_ok() {
+        errlbl.set_markup("");
+    } else {
+        let msg = glib::markup_escape_text(&format!("type error: {}", types));
+        errlbl.set_markup(&format!(r#"<span foreground="red">{}</span>"#, msg));
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(super) struct ExprInspector {
     root: gtk::Box,
@@ -370,6 +399,10 @@ impl ExprInspector {
             });
         }
         root.pack_start(&treewin, true, true, 5);
+        let errlbl = gtk::Label::new(None);
+        errlbl.set_use_markup(true);
+        errlbl.set_line_wrap(true);
+        root.pack_start(&errlbl, false, false, 5);
         root.pack_end(&reveal_properties, false, false, 5);
         reveal_properties.add(&properties);
         properties.pack_start(&kind, false, false, 5);
@@ -390,6 +423,7 @@ impl ExprInspector {
         let on_change: Rc<dyn Fn()> = Rc::new({
             let ctx = ctx.clone();
             let store = store.clone();
+            let errlbl = errlbl.clone();
             let scheduled = Rc::new(Cell::new(false));
             let on_change = Rc::new(on_change);
             move || {
@@ -398,10 +432,12 @@ impl ExprInspector {
                     idle_add_local(clone!(
                         @strong ctx,
                         @strong store,
+                        @strong errlbl,
                         @strong scheduled,
                         @strong on_change => move || {
                             if let Some(root) = store.get_iter_first() {
                                 let expr = build_expr(&ctx, &store, &root);
+                                show_type_errors(&ctx, &errlbl, &expr);
                                 on_change(expr)
                             }
                             scheduled.set(false);
@@ -412,6 +448,7 @@ impl ExprInspector {
             }
         });
         build_tree(&ctx, &on_change, &store, None, &init);
+        show_type_errors(&ctx, &errlbl, &init);
         kind.connect_changed(clone!(
         @strong ctx,
         @strong on_change,
@@ -31,13 +31,13 @@ use netidx::{
 };
 use netidx_bscript::{
     expr::{ExprId, ExprKind},
-    vm::{self, ExecCtx, Node, RpcCallId},
+    vm::{self, ExecCtx, Node, RpcCallId, TimerId},
 };
 use netidx_protocols::view;
 use std::{
     cell::{Cell, RefCell},
-    collections::HashMap,
-    fmt, mem,
+    collections::{HashMap, HashSet},
+    env, fmt, mem,
     path::PathBuf,
     rc::Rc,
     result, str,
@@ -45,6 +45,7 @@ use std::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::Duration,
 };
 use structopt::StructOpt;
 use util::{ask_modal, err_modal};
@@ -140,6 +141,7 @@ enum ToGui {
     Update(Batch),
     UpdateVar(Chars, Value),
     UpdateRpc(RpcCallId, Value),
+    UpdateTimer(TimerId),
     TableResolved(Path, resolver::Table),
     ShowError(String),
     SaveError(String),
@@ -153,6 +155,8 @@ enum FromGui {
     ResolveTable(Path),
     Save(ViewLoc, view::View, oneshot::Sender<Result<()>>),
     CallRpc(Path, Vec<(Chars, Value)>, RpcCallId),
+    SetTimer(TimerId, Duration, ExprId),
+    ClearTimers(Vec<ExprId>),
     Updated,
     Terminate,
 }
@@ -163,10 +167,18 @@ struct WidgetCtx {
     window: gtk::ApplicationWindow,
     new_window_loc: Rc<RefCell<ViewLoc>>,
     view_saved: Cell<bool>,
+    // the expressions that have set timers
+    timers: HashSet<ExprId>,
 }
 
 impl vm::Ctx for WidgetCtx {
-    fn clear(&mut self) {}
+    // expressions are only dropped along with the whole view, which
+    // then clears the ctx, so this is where their timers are cancelled
+    fn clear(&mut self) {
+        if !self.timers.is_empty() {
+            self.backend.clear_timers(self.timers.drain().collect())
+        }
+    }
 
     fn durable_subscribe(
         &mut self,
@@ -200,6 +212,11 @@ impl vm::Ctx for WidgetCtx {
     ) {
         self.backend.call_rpc(name, args, id)
     }
+
+    fn set_timer(&mut self, id: TimerId, timeout: Duration, ref_id: ExprId) {
+        self.timers.insert(ref_id);
+        self.backend.set_timer(id, timeout, ref_id)
+    }
 }
 
 fn val_to_bool(v: &Value) -> bool {
@@ -860,6 +877,10 @@ fn run_gui(ctx: BSCtx, app: Application, to_gui: glib::Receiver<ToGui>) {
             update_single(&current, &mut ctx.borrow_mut(), &vm::Event::Rpc(id, value));
             Continue(true)
         }
+        ToGui::UpdateTimer(id) => {
+            update_single(&current, &mut ctx.borrow_mut(), &vm::Event::Timer(id));
+            Continue(true)
+        }
         ToGui::Update(mut batch) => {
             if let Some(root) = &mut *current.borrow_mut() {
                 let mut waits = WAITS.take();
@@ -1001,6 +1022,10 @@ fn main() {
         match cfg.auth {
             config::Auth::Anonymous => Auth::Anonymous,
             config::Auth::Krb5(_) => Auth::Krb5 { upn: opt.upn.clone(), spn: None },
+            config::Auth::Token { .. } => match env::var("NETIDX_TOKEN") {
+                Ok(token) => Auth::Token(token),
+                Err(_) => panic!("NETIDX_TOKEN is required by the resolver"),
+            },
         }
     };
     let application = Application::new(
@@ -1035,6 +1060,7 @@ fn main() {
                 window: window.clone(),
                 new_window_loc: new_window_loc.clone(),
                 view_saved: Cell::new(true),
+                timers: HashSet::new(),
             })));
             run_gui(ctx, app, rx_to_gui);
         }
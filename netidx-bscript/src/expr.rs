@@ -1,5 +1,5 @@
 use crate::parser;
-use netidx::{subscriber::Value, utils};
+use netidx::{chars::Chars, subscriber::Value, utils};
 use regex::Regex;
 use serde::{
     de::{self, Visitor},
@@ -21,6 +21,9 @@ atomic_id!(ExprId);
 pub enum ExprKind {
     Constant(Value),
     Apply { args: Vec<Expr>, function: String },
+    /// An anonymous function, `|a, b| body`. The arguments are bound
+    /// as local variables in the body.
+    Lambda { args: Vec<Chars>, body: Box<Expr> },
 }
 
 impl ExprKind {
@@ -117,6 +120,16 @@ impl fmt::Display for ExprKind {
                     write!(f, ")")
                 }
             }
+            ExprKind::Lambda { args, body } => {
+                write!(f, "|")?;
+                for i in 0..args.len() {
+                    write!(f, "{}", &args[i])?;
+                    if i < args.len() - 1 {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "| {}", body)
+            }
         }
     }
 }
@@ -197,7 +210,9 @@ impl Expr {
     pub fn is_fn(&self) -> bool {
         match &self.kind {
             ExprKind::Constant(Value::String(c)) => VNAME.is_match(&*c),
-            ExprKind::Constant(_) | ExprKind::Apply { .. } => false,
+            ExprKind::Constant(_) | ExprKind::Apply { .. } | ExprKind::Lambda { .. } => {
+                false
+            }
         }
     }
 }
@@ -7,3 +7,6 @@ mod parser;
 pub mod expr;
 pub mod vm;
 pub mod stdfn;
+pub mod stdlib;
+pub mod typ;
+pub mod rt;
@@ -192,6 +192,9 @@ where
                             args.push(s.to_expr());
                             Some(ExprKind::Apply { args, function }.to_expr())
                         }
+                        (Some(Expr { kind: ExprKind::Lambda { .. }, .. }), _) => {
+                            unreachable!()
+                        }
                     }
                 })
                 .unwrap_or_else(|| ExprKind::Constant(Value::from("")).to_expr())
@@ -223,6 +226,20 @@ where
 {
     spaces().with(choice((
         attempt(interpolated()),
+        attempt(
+            (
+                between(
+                    token('|'),
+                    spaces().with(token('|')),
+                    sep_by(spaces().with(fname()), spaces().with(token(','))),
+                ),
+                expr(),
+            )
+                .map(|(args, body): (Vec<String>, Expr)| {
+                    let args = args.into_iter().map(Chars::from).collect();
+                    ExprKind::Lambda { args, body: Box::new(body) }.to_expr()
+                }),
+        ),
         attempt(from_str(flt()).map(|v| ExprKind::Constant(Value::F64(v)).to_expr())),
         attempt(from_str(int()).map(|v| ExprKind::Constant(Value::I64(v)).to_expr())),
         attempt(
@@ -583,6 +600,45 @@ mod tests {
         assert_eq!(p, parse_expr(s).unwrap());
     }
 
+    #[test]
+    fn lambda_parse() {
+        let s = r#"apply(|x, y| sum(x, y), 1, 2)"#;
+        let var = |n: &str| {
+            ExprKind::Apply {
+                function: "load_var".into(),
+                args: vec![ExprKind::Constant(Value::from(String::from(n))).to_expr()],
+            }
+            .to_expr()
+        };
+        let p = ExprKind::Apply {
+            function: "apply".into(),
+            args: vec![
+                ExprKind::Lambda {
+                    args: vec![Chars::from("x"), Chars::from("y")],
+                    body: Box::new(
+                        ExprKind::Apply {
+                            function: "sum".into(),
+                            args: vec![var("x"), var("y")],
+                        }
+                        .to_expr(),
+                    ),
+                }
+                .to_expr(),
+                ExprKind::Constant(Value::I64(1)).to_expr(),
+                ExprKind::Constant(Value::I64(2)).to_expr(),
+            ],
+        }
+        .to_expr();
+        assert_eq!(p, parse_expr(s).unwrap());
+        let p = ExprKind::Lambda {
+            args: vec![],
+            body: Box::new(ExprKind::Constant(Value::I64(42)).to_expr()),
+        }
+        .to_expr();
+        assert_eq!(p, parse_expr("|| 42").unwrap());
+        assert_eq!(p, parse_expr(&p.to_string()).unwrap());
+    }
+
     #[test]
     fn expr_parse() {
         let s = r#"load(concat_path("foo", "bar", baz)))"#;
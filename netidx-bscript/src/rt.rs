@@ -0,0 +1,513 @@
+//! A ready made runtime for evaluating bscript expressions against
+//! netidx. `Runtime` owns a `Subscriber`, a variable store, and the
+//! rpc clients needed by `call`, so any program can evaluate live
+//! expressions without implementing `vm::Ctx` itself.
+use crate::{
+    expr::{Expr, ExprId},
+    stdfn::{RpcCallId, TimerId},
+    typ,
+    vm::{self, Ctx, ExecCtx, Node},
+};
+use anyhow::{anyhow, bail, Result};
+use futures::{channel::mpsc, prelude::*, select_biased};
+use fxhash::{FxBuildHasher, FxHashMap, FxHashSet};
+use netidx::{
+    chars::Chars,
+    path::Path,
+    pool::Pooled,
+    protocol::glob::{Glob, GlobSet},
+    subscriber::{Dval, Event, SubId, Subscriber, UpdatesFlags, Value},
+};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    hash::Hash,
+    iter, mem, thread,
+    time::Duration,
+};
+use tokio::{runtime, task, time};
+
+type RpcArgs = (Vec<(Chars, Value)>, RpcCallId);
+
+enum RtEvent {
+    RpcReply { name: Path, id: RpcCallId, result: Value },
+    Timer { id: TimerId, ref_by: ExprId },
+}
+
+enum ToRt {
+    Eval(Expr, mpsc::UnboundedSender<Value>),
+    SetVar(Chars, Value),
+}
+
+/// The rpcs, subscriptions, and variables a top level expression
+/// refers to, so a `Ctx` can forget them when the expression is
+/// dropped.
+#[derive(Default)]
+pub struct Refs {
+    pub rpcs: FxHashSet<Path>,
+    pub subs: FxHashSet<SubId>,
+    pub vars: FxHashSet<Chars>,
+}
+
+impl Refs {
+    pub fn new() -> Self {
+        Refs::default()
+    }
+}
+
+/// Remove `expr_id` from the set of expressions referring to `key`,
+/// and remove `key` if that was the last one.
+pub fn remove_eid_from_set<K: Hash + Eq>(
+    tbl: &mut FxHashMap<K, FxHashSet<ExprId>>,
+    key: K,
+    expr_id: &ExprId,
+) {
+    if let Entry::Occupied(mut e) = tbl.entry(key) {
+        let set = e.get_mut();
+        set.remove(expr_id);
+        if set.is_empty() {
+            e.remove();
+        }
+    }
+}
+
+/// Add `expr_id` to the set of expressions referring to `key`
+pub fn add_eid_to_set<K: Hash + Eq>(
+    tbl: &mut FxHashMap<K, FxHashSet<ExprId>>,
+    key: K,
+    expr_id: ExprId,
+) {
+    tbl.entry(key)
+        .or_insert_with(|| HashSet::with_hasher(FxBuildHasher::default()))
+        .insert(expr_id);
+}
+
+/// The `vm::Ctx` used by `Runtime`. Subscriptions, variables, rpc
+/// calls, and timers are tracked by the top level expression that
+/// made them, so they can be routed back to it.
+pub struct NetCtx {
+    subscriber: Subscriber,
+    var: FxHashMap<Chars, FxHashSet<ExprId>>,
+    sub: FxHashMap<SubId, FxHashSet<ExprId>>,
+    rpc: FxHashMap<Path, FxHashSet<ExprId>>,
+    forward_refs: FxHashMap<ExprId, Refs>,
+    rpcs: FxHashMap<Path, mpsc::UnboundedSender<RpcArgs>>,
+    sub_updates: mpsc::Sender<Pooled<Vec<(SubId, Event)>>>,
+    var_updates: Vec<(Chars, Value)>,
+    events: mpsc::UnboundedSender<RtEvent>,
+    timers: FxHashMap<ExprId, Vec<task::JoinHandle<()>>>,
+}
+
+impl NetCtx {
+    fn new(
+        subscriber: Subscriber,
+        sub_updates: mpsc::Sender<Pooled<Vec<(SubId, Event)>>>,
+        events: mpsc::UnboundedSender<RtEvent>,
+    ) -> Self {
+        NetCtx {
+            subscriber,
+            var: HashMap::with_hasher(FxBuildHasher::default()),
+            sub: HashMap::with_hasher(FxBuildHasher::default()),
+            rpc: HashMap::with_hasher(FxBuildHasher::default()),
+            forward_refs: HashMap::with_hasher(FxBuildHasher::default()),
+            rpcs: HashMap::with_hasher(FxBuildHasher::default()),
+            sub_updates,
+            var_updates: Vec::new(),
+            events,
+            timers: HashMap::with_hasher(FxBuildHasher::default()),
+        }
+    }
+
+    /// The subscriber used to resolve `load` and `call`
+    pub fn subscriber(&self) -> &Subscriber {
+        &self.subscriber
+    }
+
+    fn unref(&mut self, expr_id: ExprId) {
+        if let Some(refs) = self.forward_refs.remove(&expr_id) {
+            for path in refs.rpcs {
+                remove_eid_from_set(&mut self.rpc, path, &expr_id);
+            }
+            for id in refs.subs {
+                remove_eid_from_set(&mut self.sub, id, &expr_id);
+            }
+            for name in refs.vars {
+                remove_eid_from_set(&mut self.var, name, &expr_id);
+            }
+        }
+        if let Some(timers) = self.timers.remove(&expr_id) {
+            for t in timers {
+                t.abort()
+            }
+        }
+    }
+
+    fn get_rpc_proc(&mut self, name: &Path) -> mpsc::UnboundedSender<RpcArgs> {
+        match self.rpcs.get(name) {
+            Some(proc) => proc.clone(),
+            None => {
+                let (tx, rx) = mpsc::unbounded();
+                task::spawn(rpc_task(
+                    self.events.clone(),
+                    self.subscriber.clone(),
+                    name.clone(),
+                    rx,
+                ));
+                self.rpcs.insert(name.clone(), tx.clone());
+                tx
+            }
+        }
+    }
+}
+
+impl Ctx for NetCtx {
+    fn clear(&mut self) {}
+
+    fn durable_subscribe(
+        &mut self,
+        flags: UpdatesFlags,
+        path: Path,
+        ref_by: ExprId,
+    ) -> Dval {
+        let dv = self.subscriber.durable_subscribe(path);
+        dv.updates(flags, self.sub_updates.clone());
+        add_eid_to_set(&mut self.sub, dv.id(), ref_by);
+        self.forward_refs.entry(ref_by).or_insert_with(Refs::new).subs.insert(dv.id());
+        dv
+    }
+
+    fn ref_var(&mut self, name: Chars, ref_by: ExprId) {
+        add_eid_to_set(&mut self.var, name.clone(), ref_by);
+        self.forward_refs.entry(ref_by).or_insert_with(Refs::new).vars.insert(name);
+    }
+
+    fn set_var(
+        &mut self,
+        variables: &mut HashMap<Chars, Value>,
+        name: Chars,
+        value: Value,
+    ) {
+        variables.insert(name.clone(), value.clone());
+        self.var_updates.push((name, value));
+    }
+
+    fn call_rpc(
+        &mut self,
+        name: Path,
+        args: Vec<(Chars, Value)>,
+        ref_by: ExprId,
+        id: RpcCallId,
+    ) {
+        add_eid_to_set(&mut self.rpc, name.clone(), ref_by);
+        self.forward_refs
+            .entry(ref_by)
+            .or_insert_with(Refs::new)
+            .rpcs
+            .insert(name.clone());
+        let mut args = (args, id);
+        for _ in 1..3 {
+            match self.get_rpc_proc(&name).unbounded_send(args) {
+                Ok(()) => return,
+                Err(e) => {
+                    self.rpcs.remove(&name);
+                    args = e.into_inner();
+                }
+            }
+        }
+        let result = Value::Error(Chars::from("failed to call rpc"));
+        let _: Result<_, _> =
+            self.events.unbounded_send(RtEvent::RpcReply { name, id, result });
+    }
+
+    fn set_timer(&mut self, id: TimerId, timeout: Duration, ref_by: ExprId) {
+        let events = self.events.clone();
+        let timers = self.timers.entry(ref_by).or_default();
+        timers.retain(|t| !t.is_finished());
+        timers.push(task::spawn(async move {
+            time::sleep(timeout).await;
+            let _: Result<_, _> = events.unbounded_send(RtEvent::Timer { id, ref_by });
+        }));
+    }
+}
+
+// the procedure called by `call`, netidx-protocols has the full rpc
+// client, but it depends on this crate.
+struct RpcClient {
+    call: Dval,
+    args: HashMap<String, Dval>,
+}
+
+impl RpcClient {
+    async fn new(subscriber: &Subscriber, name: &Path) -> Result<Self> {
+        let call = subscriber.durable_subscribe(name.clone());
+        let pat = GlobSet::new(
+            true,
+            iter::once(Glob::new(Chars::from(format!("{}/*/val", name)))?),
+        )?;
+        let mut args = HashMap::new();
+        for mut batch in subscriber.resolver().list_matching(&pat).await?.drain(..) {
+            for path in batch.drain(..) {
+                let arg = Path::dirname(&path).and_then(Path::basename);
+                if let Some(arg) = arg {
+                    let arg = String::from(arg);
+                    args.insert(arg, subscriber.durable_subscribe(path));
+                }
+            }
+        }
+        Ok(RpcClient { call, args })
+    }
+
+    async fn call(&self, args: Vec<(Chars, Value)>) -> Result<Value> {
+        for (name, val) in args {
+            match self.args.get(&*name) {
+                None => bail!("no such argument {}", name),
+                Some(dv) => {
+                    dv.wait_subscribed().await?;
+                    dv.write(val);
+                }
+            }
+        }
+        self.call
+            .write_with_recipt(Value::Null)
+            .await
+            .map_err(|_| anyhow!("call cancelled before a reply was received"))
+    }
+}
+
+// calls to the same procedure are made one at a time, in order, by
+// this task, as required by `Ctx::call_rpc`.
+async fn rpc_task(
+    reply: mpsc::UnboundedSender<RtEvent>,
+    subscriber: Subscriber,
+    name: Path,
+    mut rx: mpsc::UnboundedReceiver<RpcArgs>,
+) {
+    let proc = RpcClient::new(&subscriber, &name).await;
+    while let Some((args, id)) = rx.next().await {
+        let result = match &proc {
+            Err(e) => Value::Error(Chars::from(format!("{}", e))),
+            Ok(proc) => match proc.call(args).await {
+                Ok(v) => v,
+                Err(e) => Value::Error(Chars::from(format!("{}", e))),
+            },
+        };
+        let name = name.clone();
+        if reply.unbounded_send(RtEvent::RpcReply { name, id, result }).is_err() {
+            break;
+        }
+    }
+}
+
+struct Compiled {
+    node: Node<NetCtx, ()>,
+    out: mpsc::UnboundedSender<Value>,
+}
+
+struct Rt {
+    ctx: ExecCtx<NetCtx, ()>,
+    compiled: FxHashMap<ExprId, Compiled>,
+}
+
+impl Rt {
+    fn eval(&mut self, expr: Expr, out: mpsc::UnboundedSender<Value>) {
+        let closed = self
+            .compiled
+            .iter()
+            .filter_map(|(id, c)| if c.out.is_closed() { Some(*id) } else { None })
+            .collect::<Vec<_>>();
+        for id in closed {
+            self.compiled.remove(&id);
+            self.ctx.user.unref(id);
+        }
+        let types = typ::check(&self.ctx, &expr);
+        if !types.is_ok() {
+            let e = format!("type error: {}", types);
+            let _: Result<_, _> = out.unbounded_send(Value::Error(Chars::from(e)));
+            return;
+        }
+        let id = expr.id;
+        let node = Node::compile(&mut self.ctx, expr);
+        if let Some(v) = node.current() {
+            let _: Result<_, _> = out.unbounded_send(v);
+        }
+        self.compiled.insert(id, Compiled { node, out });
+        self.update_vars();
+    }
+
+    fn update_expr_ids(
+        &mut self,
+        ids: impl IntoIterator<Item = ExprId>,
+        ev: &vm::Event<()>,
+    ) {
+        for id in ids {
+            if let Some(c) = self.compiled.get_mut(&id) {
+                if let Some(v) = c.node.update(&mut self.ctx, ev) {
+                    if c.out.unbounded_send(v).is_err() {
+                        self.compiled.remove(&id);
+                        self.ctx.user.unref(id);
+                    }
+                }
+            }
+        }
+    }
+
+    fn ids<K: Hash + Eq>(tbl: &FxHashMap<K, FxHashSet<ExprId>>, k: &K) -> Vec<ExprId> {
+        tbl.get(k).map(|s| s.iter().copied().collect()).unwrap_or_default()
+    }
+
+    // setting a variable may cause other variables to be set, stop
+    // after 10 rounds in case of a cycle.
+    fn update_vars(&mut self) {
+        let mut n = 0;
+        while n < 10 && !self.ctx.user.var_updates.is_empty() {
+            for (name, value) in mem::take(&mut self.ctx.user.var_updates) {
+                let ids = Self::ids(&self.ctx.user.var, &name);
+                self.update_expr_ids(ids, &vm::Event::Variable(name, value));
+            }
+            n += 1;
+        }
+        self.ctx.user.var_updates.clear();
+    }
+
+    fn process_subscriptions(&mut self, mut updates: Pooled<Vec<(SubId, Event)>>) {
+        for (id, event) in updates.drain(..) {
+            if let Event::Update(value) = event {
+                let ids = Self::ids(&self.ctx.user.sub, &id);
+                self.update_expr_ids(ids, &vm::Event::Netidx(id, value));
+            }
+        }
+        self.update_vars();
+    }
+
+    fn process_event(&mut self, event: RtEvent) {
+        match event {
+            RtEvent::RpcReply { name, id, result } => {
+                let ids = Self::ids(&self.ctx.user.rpc, &name);
+                self.update_expr_ids(ids, &vm::Event::Rpc(id, result));
+            }
+            RtEvent::Timer { id, ref_by } => {
+                self.update_expr_ids(iter::once(ref_by), &vm::Event::Timer(id));
+            }
+        }
+        self.update_vars();
+    }
+
+    fn process_to_rt(&mut self, m: ToRt) {
+        match m {
+            ToRt::Eval(expr, out) => self.eval(expr, out),
+            ToRt::SetVar(name, value) => {
+                let ctx = &mut self.ctx;
+                ctx.user.set_var(&mut ctx.variables, name, value);
+                self.update_vars();
+            }
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut sub_updates: mpsc::Receiver<Pooled<Vec<(SubId, Event)>>>,
+        mut events: mpsc::UnboundedReceiver<RtEvent>,
+        mut to_rt: mpsc::UnboundedReceiver<ToRt>,
+    ) {
+        loop {
+            select_biased! {
+                m = to_rt.next() => match m {
+                    None => break,
+                    Some(m) => self.process_to_rt(m),
+                },
+                e = events.select_next_some() => self.process_event(e),
+                u = sub_updates.select_next_some() => self.process_subscriptions(u),
+            }
+        }
+    }
+}
+
+/// An embeddable bscript runtime backed by netidx. The runtime runs
+/// on it's own thread, and shuts down when the last clone of the
+/// `Runtime` is dropped.
+#[derive(Clone)]
+pub struct Runtime(mpsc::UnboundedSender<ToRt>);
+
+impl Runtime {
+    /// Start a runtime that uses `subscriber` for `load` and `call`,
+    /// with all the standard builtins. `register` may be used to add
+    /// more functions to the execution context.
+    pub fn new<F>(subscriber: Subscriber, register: F) -> Result<Self>
+    where
+        F: FnOnce(&mut ExecCtx<NetCtx, ()>) + Send + 'static,
+    {
+        let rt = runtime::Builder::new_current_thread().enable_all().build()?;
+        let (to_rt_tx, to_rt) = mpsc::unbounded();
+        thread::Builder::new().name("bscript-runtime".into()).spawn(move || {
+            rt.block_on(async move {
+                let (sub_updates_tx, sub_updates) = mpsc::channel(3);
+                let (events_tx, events) = mpsc::unbounded();
+                let user = NetCtx::new(subscriber, sub_updates_tx, events_tx);
+                let mut ctx = ExecCtx::new(user);
+                register(&mut ctx);
+                let rt =
+                    Rt { ctx, compiled: HashMap::with_hasher(FxBuildHasher::default()) };
+                rt.run(sub_updates, events, to_rt).await
+            })
+        })?;
+        Ok(Runtime(to_rt_tx))
+    }
+
+    /// Evaluate `expr`, returning a stream of it's values. The
+    /// expression is type checked first, if it fails the stream will
+    /// yield a single error. Dropping the stream stops the
+    /// evaluation and releases any resources it was using.
+    pub fn eval(&self, expr: Expr) -> impl Stream<Item = Value> {
+        let (tx, rx) = mpsc::unbounded();
+        let _: Result<_, _> = self.0.unbounded_send(ToRt::Eval(expr, tx));
+        rx
+    }
+
+    /// Set the variable `name` to `value`, as if by `store_var`.
+    pub fn set_var(&self, name: Chars, value: Value) {
+        let _: Result<_, _> = self.0.unbounded_send(ToRt::SetVar(name, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netidx::{config, publisher::Publisher, resolver::Auth, resolver_server::Server};
+
+    async fn wait_for(s: &mut (impl Stream<Item = Value> + Unpin), v: Value) {
+        let timeout = Duration::from_secs(10);
+        while time::timeout(timeout, s.next()).await.unwrap() != Some(v.clone()) {}
+    }
+
+    #[test]
+    fn runtime() {
+        runtime::Runtime::new().unwrap().block_on(async move {
+            let mut cfg =
+                config::Config::load("../cfg/simple.json").expect("load simple config");
+            let server = Server::new(cfg.clone(), config::PMap::default(), false, 0)
+                .await
+                .expect("start resolver server");
+            cfg.addrs[0] = *server.local_addr();
+            let publisher = Publisher::new(
+                cfg.clone(),
+                Auth::Anonymous,
+                "127.0.0.1/32".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+            let val = publisher.publish(Path::from("/test/a"), Value::I64(1)).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, Auth::Anonymous).unwrap();
+            let rt = Runtime::new(subscriber, |_| ()).unwrap();
+            let expr = r#"sum(load("/test/a"), load_var("offset"))"#;
+            let mut s = rt.eval(expr.parse::<Expr>().unwrap());
+            rt.set_var(Chars::from("offset"), Value::I64(10));
+            wait_for(&mut s, Value::I64(11)).await;
+            let mut batch = publisher.start_batch();
+            val.update(&mut batch, Value::I64(2));
+            batch.commit(None).await;
+            wait_for(&mut s, Value::I64(12)).await;
+            let mut s = rt.eval(r#"sum("x", 1)"#.parse::<Expr>().unwrap());
+            assert!(matches!(s.next().await, Some(Value::Error(_))));
+        })
+    }
+}
@@ -1,14 +1,25 @@
 use crate::{
     expr::{Expr, ExprId, VNAME},
+    typ::{Arg, Ret, Signature, ARITH},
     vm::{Apply, Ctx, Event, ExecCtx, InitFn, Node, Register},
 };
+use chrono::prelude::*;
 use fxhash::{FxBuildHasher, FxHashSet};
 use netidx::{
     chars::Chars,
     path::Path,
     subscriber::{self, Dval, Typ, UpdatesFlags, Value},
 };
-use std::{collections::HashSet, marker::PhantomData, sync::Arc};
+use std::{
+    collections::{HashSet, VecDeque},
+    marker::PhantomData,
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 pub struct CachedVals(pub Vec<Option<Value>>);
 
@@ -43,6 +54,8 @@ impl<C: Ctx, E> Register<C, E> for Any {
             Box::new(Any(from.iter().find_map(|s| s.current())))
         });
         ctx.functions.insert("any".into(), f);
+        let sig = Signature::variadic(vec![], Arg::Any, Ret::Args(0));
+        ctx.signatures.insert("any".into(), sig);
     }
 }
 
@@ -77,6 +90,8 @@ impl<C: Ctx, E> Register<C, E> for Do {
             Box::new(Do(from.iter().fold(None, |_, s| s.current())))
         });
         ctx.functions.insert("do".into(), f);
+        let sig = Signature::variadic(vec![], Arg::Any, Ret::Last);
+        ctx.signatures.insert("do".into(), sig);
     }
 }
 
@@ -99,6 +114,7 @@ impl<C: Ctx, E> Apply<C, E> for Do {
 pub trait CachedCurEval {
     fn eval(from: &CachedVals) -> Option<Value>;
     fn name() -> &'static str;
+    fn signature() -> Signature;
 }
 
 pub struct CachedCur<T: CachedCurEval> {
@@ -115,6 +131,7 @@ impl<C: Ctx, E, T: CachedCurEval + 'static> Register<C, E> for CachedCur<T> {
             Box::new(CachedCur::<T> { cached, current, t: PhantomData })
         });
         ctx.functions.insert(T::name().into(), f);
+        ctx.signatures.insert(T::name().into(), T::signature());
     }
 }
 
@@ -161,6 +178,10 @@ impl CachedCurEval for AllEv {
     fn name() -> &'static str {
         "all"
     }
+
+    fn signature() -> Signature {
+        Signature::variadic(vec![], Arg::Any, Ret::Args(0))
+    }
 }
 
 pub type All = CachedCur<AllEv>;
@@ -187,6 +208,10 @@ impl CachedCurEval for SumEv {
     fn name() -> &'static str {
         "sum"
     }
+
+    fn signature() -> Signature {
+        Signature::variadic(vec![], Arg::OneOf(&ARITH), Ret::Args(0))
+    }
 }
 
 pub type Sum = CachedCur<SumEv>;
@@ -213,6 +238,10 @@ impl CachedCurEval for ProductEv {
     fn name() -> &'static str {
         "product"
     }
+
+    fn signature() -> Signature {
+        Signature::variadic(vec![], Arg::OneOf(&ARITH), Ret::Args(0))
+    }
 }
 
 pub type Product = CachedCur<ProductEv>;
@@ -239,6 +268,10 @@ impl CachedCurEval for DivideEv {
     fn name() -> &'static str {
         "divide"
     }
+
+    fn signature() -> Signature {
+        Signature::variadic(vec![], Arg::OneOf(&ARITH), Ret::Args(0))
+    }
 }
 
 pub type Divide = CachedCur<DivideEv>;
@@ -262,6 +295,10 @@ impl CachedCurEval for MinEv {
     fn name() -> &'static str {
         "min"
     }
+
+    fn signature() -> Signature {
+        Signature::variadic(vec![], Arg::Any, Ret::Args(0))
+    }
 }
 
 pub type Min = CachedCur<MinEv>;
@@ -285,6 +322,10 @@ impl CachedCurEval for MaxEv {
     fn name() -> &'static str {
         "max"
     }
+
+    fn signature() -> Signature {
+        Signature::variadic(vec![], Arg::Any, Ret::Args(0))
+    }
 }
 
 pub type Max = CachedCur<MaxEv>;
@@ -307,6 +348,10 @@ impl CachedCurEval for AndEv {
     fn name() -> &'static str {
         "and"
     }
+
+    fn signature() -> Signature {
+        Signature::variadic(vec![], Arg::Is(Typ::Bool), Ret::Is(Typ::Bool))
+    }
 }
 
 pub type And = CachedCur<AndEv>;
@@ -329,6 +374,10 @@ impl CachedCurEval for OrEv {
     fn name() -> &'static str {
         "or"
     }
+
+    fn signature() -> Signature {
+        Signature::variadic(vec![], Arg::Is(Typ::Bool), Ret::Is(Typ::Bool))
+    }
 }
 
 pub type Or = CachedCur<OrEv>;
@@ -346,6 +395,10 @@ impl CachedCurEval for NotEv {
     fn name() -> &'static str {
         "not"
     }
+
+    fn signature() -> Signature {
+        Signature::fixed(vec![Arg::Is(Typ::Bool)], Ret::Is(Typ::Bool))
+    }
 }
 
 pub type Not = CachedCur<NotEv>;
@@ -367,6 +420,10 @@ impl CachedCurEval for IsErrEv {
     fn name() -> &'static str {
         "is_error"
     }
+
+    fn signature() -> Signature {
+        Signature::fixed(vec![Arg::Any], Ret::Is(Typ::Bool))
+    }
 }
 
 pub type IsErr = CachedCur<IsErrEv>;
@@ -391,6 +448,11 @@ impl CachedCurEval for StartsWithEv {
     fn name() -> &'static str {
         "starts_with"
     }
+
+    fn signature() -> Signature {
+        let args = vec![Arg::Is(Typ::String), Arg::Is(Typ::String)];
+        Signature::fixed(args, Ret::Is(Typ::Bool))
+    }
 }
 
 pub type StartsWith = CachedCur<StartsWithEv>;
@@ -415,6 +477,11 @@ impl CachedCurEval for EndsWithEv {
     fn name() -> &'static str {
         "ends_with"
     }
+
+    fn signature() -> Signature {
+        let args = vec![Arg::Is(Typ::String), Arg::Is(Typ::String)];
+        Signature::fixed(args, Ret::Is(Typ::Bool))
+    }
 }
 
 pub type EndsWith = CachedCur<EndsWithEv>;
@@ -439,6 +506,11 @@ impl CachedCurEval for ContainsEv {
     fn name() -> &'static str {
         "contains"
     }
+
+    fn signature() -> Signature {
+        let args = vec![Arg::Is(Typ::String), Arg::Is(Typ::String)];
+        Signature::fixed(args, Ret::Is(Typ::Bool))
+    }
 }
 
 pub type Contains = CachedCur<ContainsEv>;
@@ -458,6 +530,11 @@ impl CachedCurEval for StripPrefixEv {
     fn name() -> &'static str {
         "strip_prefix"
     }
+
+    fn signature() -> Signature {
+        let args = vec![Arg::Is(Typ::String), Arg::Is(Typ::String)];
+        Signature::fixed(args, Ret::Is(Typ::String))
+    }
 }
 
 pub type StripPrefix = CachedCur<StripPrefixEv>;
@@ -477,6 +554,11 @@ impl CachedCurEval for StripSuffixEv {
     fn name() -> &'static str {
         "strip_suffix"
     }
+
+    fn signature() -> Signature {
+        let args = vec![Arg::Is(Typ::String), Arg::Is(Typ::String)];
+        Signature::fixed(args, Ret::Is(Typ::String))
+    }
 }
 
 pub type StripSuffix = CachedCur<StripSuffixEv>;
@@ -496,6 +578,10 @@ impl CachedCurEval for TrimEv {
     fn name() -> &'static str {
         "trim"
     }
+
+    fn signature() -> Signature {
+        Signature::fixed(vec![Arg::Is(Typ::String)], Ret::Is(Typ::String))
+    }
 }
 
 pub type Trim = CachedCur<TrimEv>;
@@ -515,6 +601,10 @@ impl CachedCurEval for TrimStartEv {
     fn name() -> &'static str {
         "trim_start"
     }
+
+    fn signature() -> Signature {
+        Signature::fixed(vec![Arg::Is(Typ::String)], Ret::Is(Typ::String))
+    }
 }
 
 pub type TrimStart = CachedCur<TrimStartEv>;
@@ -534,6 +624,10 @@ impl CachedCurEval for TrimEndEv {
     fn name() -> &'static str {
         "trim_end"
     }
+
+    fn signature() -> Signature {
+        Signature::fixed(vec![Arg::Is(Typ::String)], Ret::Is(Typ::String))
+    }
 }
 
 pub type TrimEnd = CachedCur<TrimEndEv>;
@@ -553,6 +647,11 @@ impl CachedCurEval for ReplaceEv {
     fn name() -> &'static str {
         "replace"
     }
+
+    fn signature() -> Signature {
+        let args = vec![Arg::Is(Typ::String), Arg::Is(Typ::String), Arg::Is(Typ::String)];
+        Signature::fixed(args, Ret::Is(Typ::String))
+    }
 }
 
 pub type Replace = CachedCur<ReplaceEv>;
@@ -608,6 +707,11 @@ impl CachedCurEval for CmpEv {
         "cmp"
     }
 
+    fn signature() -> Signature {
+        let args = vec![Arg::Is(Typ::String), Arg::Any, Arg::Any];
+        Signature::fixed(args, Ret::Is(Typ::Bool))
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         match &*from.0 {
             [op, v0, v1] => match op {
@@ -671,6 +775,15 @@ impl CachedCurEval for IfEv {
         "if"
     }
 
+    fn signature() -> Signature {
+        Signature {
+            args: vec![Arg::Is(Typ::Bool), Arg::Any],
+            optional: vec![Arg::Any],
+            variadic: None,
+            ret: Ret::Args(1),
+        }
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         match &*from.0 {
             [cond, b1] => match cond {
@@ -705,6 +818,10 @@ impl CachedCurEval for FilterEv {
         "filter"
     }
 
+    fn signature() -> Signature {
+        Signature::fixed(vec![Arg::Is(Typ::Bool), Arg::Any], Ret::Args(1))
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         match &*from.0 {
             [pred, s] => match pred {
@@ -755,6 +872,10 @@ impl CachedCurEval for CastEv {
         "cast"
     }
 
+    fn signature() -> Signature {
+        Signature::fixed(vec![Arg::Is(Typ::String), Arg::Any], Ret::Named(0))
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         with_typ_prefix(from, "cast(typ, src)", |typ, v| match v {
             None => None,
@@ -772,6 +893,10 @@ impl CachedCurEval for IsaEv {
         "isa"
     }
 
+    fn signature() -> Signature {
+        Signature::fixed(vec![Arg::Is(Typ::String), Arg::Any], Ret::Is(Typ::Bool))
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         with_typ_prefix(from, "isa(typ, src)", |typ, v| match (typ, v) {
             (_, None) => None,
@@ -805,6 +930,10 @@ impl CachedCurEval for StringJoinEv {
         "string_join"
     }
 
+    fn signature() -> Signature {
+        Signature::variadic(vec![Arg::Any], Arg::Any, Ret::Is(Typ::String))
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         use bytes::BytesMut;
         let mut parts = from
@@ -838,6 +967,10 @@ impl CachedCurEval for StringConcatEv {
         "string_concat"
     }
 
+    fn signature() -> Signature {
+        Signature::variadic(vec![], Arg::Any, Ret::Is(Typ::String))
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         use bytes::BytesMut;
         let parts = from
@@ -889,6 +1022,8 @@ impl<C: Ctx, E> Register<C, E> for Eval<C, E> {
             Box::new(t)
         });
         ctx.functions.insert("eval".into(), f);
+        let sig = Signature::fixed(vec![Arg::Is(Typ::String)], Ret::Unknown);
+        ctx.signatures.insert("eval".into(), sig);
     }
 }
 
@@ -916,6 +1051,603 @@ impl<C: Ctx, E> Apply<C, E> for Eval<C, E> {
     }
 }
 
+static NEXT_LOCAL: AtomicU64 = AtomicU64::new(0);
+static NEXT_LAMBDA: AtomicU64 = AtomicU64::new(0);
+
+/// A reference to a lambda argument. Argument values are delivered
+/// to the body of the lambda as variable events using a name that
+/// can't be a valid variable name.
+pub(crate) struct Local {
+    name: Chars,
+    current: Option<Value>,
+    closure: Option<Arc<Closure>>,
+}
+
+impl Local {
+    pub(crate) fn new(name: Chars) -> Self {
+        Local { name, current: None, closure: None }
+    }
+}
+
+impl<C: Ctx, E> Apply<C, E> for Local {
+    fn current(&self) -> Option<Value> {
+        self.current.clone()
+    }
+
+    fn closure(&self) -> Option<Arc<Closure>> {
+        self.closure.clone()
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        _from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        match event {
+            Event::Variable(name, v) if name == &self.name => {
+                self.current = Some(v.clone());
+                self.closure = bound(ctx, name, v);
+                Some(v.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+// remember that the variable or lambda argument `name` holds the
+// function `f` while it's value is `v`
+fn bind<C: Ctx, E>(
+    ctx: &mut ExecCtx<C, E>,
+    name: &Chars,
+    v: &Value,
+    f: Option<&Arc<Closure>>,
+) {
+    match f {
+        None => {
+            ctx.closures.remove(name);
+        }
+        Some(f) => {
+            if ctx.closures.len().is_power_of_two() {
+                ctx.closures.retain(|_, (_, c)| c.strong_count() > 0);
+            }
+            ctx.closures.insert(name.clone(), (v.clone(), Arc::downgrade(f)));
+        }
+    }
+}
+
+// the function held by the variable or lambda argument `name`, if
+// it still has the value `v`
+fn bound<C: Ctx, E>(
+    ctx: &ExecCtx<C, E>,
+    name: &Chars,
+    v: &Value,
+) -> Option<Arc<Closure>> {
+    match ctx.closures.get(name) {
+        Some((cv, c)) if cv == v => c.upgrade(),
+        Some(_) | None => None,
+    }
+}
+
+// a lambda expression along with the arguments of the lambdas
+// enclosing it, (name, local name)
+struct Code {
+    args: Vec<Chars>,
+    body: Expr,
+    scope: Vec<(Chars, Chars)>,
+}
+
+// the value of an argument captured by a closure, and the function
+// it holds, if any
+#[derive(Clone)]
+struct Captured {
+    value: Value,
+    function: Option<Arc<Closure>>,
+}
+
+impl PartialEq for Captured {
+    fn eq(&self, other: &Captured) -> bool {
+        self.value == other.value
+            && match (&self.function, &other.function) {
+                (None, None) => true,
+                (Some(f0), Some(f1)) => Arc::ptr_eq(f0, f1),
+                (Some(_), None) | (None, Some(_)) => false,
+            }
+    }
+}
+
+/// The function a lambda expression evaluates to, it's code and the
+/// values it captured from the arguments of enclosing lambdas.
+/// Functions are not values, the value of a lambda is just a name for
+/// display, and a string can never be called. Closures are passed
+/// from the lambda expression that made them directly, through
+/// variables, and through the arguments of other lambdas. They live
+/// as long as the lambda expression that made them, or forever if
+/// they were given a name with `define`.
+pub struct Closure {
+    code: Arc<Code>,
+    env: Vec<Option<Captured>>,
+}
+
+fn closure<C: Ctx, E>(f: &Node<C, E>) -> Result<Arc<Closure>, Value> {
+    match (f.closure(), f.current()) {
+        (Some(c), _) => Ok(c),
+        (None, None) => Err(Value::Null),
+        (None, Some(v)) => {
+            Err(Value::Error(Chars::from(format!("{} is not a function", v))))
+        }
+    }
+}
+
+/// `|args| body` evaluates to a new closure when it's compiled, and
+/// again each time the value of a captured argument changes.
+pub(crate) struct Lambda {
+    code: Arc<Code>,
+    env: Vec<Option<Captured>>,
+    closure: Arc<Closure>,
+    name: Chars,
+}
+
+impl Lambda {
+    pub(crate) fn new<C: Ctx, E>(
+        ctx: &mut ExecCtx<C, E>,
+        args: Vec<Chars>,
+        body: Expr,
+    ) -> Self {
+        let scope = ctx.scope.clone();
+        let env = vec![None; scope.len()];
+        let code = Arc::new(Code { args, body, scope });
+        let (closure, name) = Lambda::make(&code, &env);
+        Lambda { code, env, closure, name }
+    }
+
+    fn make(code: &Arc<Code>, env: &[Option<Captured>]) -> (Arc<Closure>, Chars) {
+        let i = NEXT_LAMBDA.fetch_add(1, Ordering::Relaxed);
+        let name = Chars::from(format!("<lambda#{}>", i));
+        let closure = Arc::new(Closure { code: Arc::clone(code), env: env.to_vec() });
+        (closure, name)
+    }
+}
+
+impl<C: Ctx, E> Apply<C, E> for Lambda {
+    fn current(&self) -> Option<Value> {
+        Some(Value::String(self.name.clone()))
+    }
+
+    fn closure(&self) -> Option<Arc<Closure>> {
+        Some(Arc::clone(&self.closure))
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        _from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        match event {
+            Event::Variable(name, v) => {
+                let i = self.code.scope.iter().position(|(_, l)| l == name)?;
+                let function = bound(ctx, name, v);
+                self.env[i] = Some(Captured { value: v.clone(), function });
+                let (closure, name) = Lambda::make(&self.code, &self.env);
+                self.closure = closure;
+                self.name = name;
+                Apply::<C, E>::current(self)
+            }
+            _ => None,
+        }
+    }
+}
+
+// the compiled body of a closure
+struct Instance<C: Ctx + 'static, E: 'static> {
+    closure: Arc<Closure>,
+    locals: Vec<Chars>,
+    body: Node<C, E>,
+}
+
+impl<C: Ctx, E> Instance<C, E> {
+    fn new(
+        ctx: &mut ExecCtx<C, E>,
+        top_id: ExprId,
+        closure: Arc<Closure>,
+        nargs: usize,
+    ) -> Result<Self, Value> {
+        let code = Arc::clone(&closure.code);
+        if code.args.len() != nargs {
+            let e = format!("expected {} arguments not {}", code.args.len(), nargs);
+            return Err(Value::Error(Chars::from(e)));
+        }
+        let locals = code
+            .args
+            .iter()
+            .map(|a| {
+                let i = NEXT_LOCAL.fetch_add(1, Ordering::Relaxed);
+                Chars::from(format!("{}#{}", a, i))
+            })
+            .collect::<Vec<_>>();
+        let saved = mem::replace(&mut ctx.scope, code.scope.clone());
+        ctx.scope.extend(code.args.iter().cloned().zip(locals.iter().cloned()));
+        let body = Node::compile_int(ctx, code.body.clone(), top_id);
+        ctx.scope = saved;
+        let mut t = Instance { closure, locals, body };
+        let env = code.scope.iter().zip(t.closure.env.clone()).collect::<Vec<_>>();
+        for ((_, local), c) in env {
+            if let Some(c) = c {
+                bind(ctx, local, &c.value, c.function.as_ref());
+                t.body.update(ctx, &Event::Variable(local.clone(), c.value));
+            }
+        }
+        Ok(t)
+    }
+
+    // switch to another closure of the same lambda without
+    // recompiling, returns false if the code is different. Captured
+    // values that changed are delivered to the body, except `skip`,
+    // which the caller will deliver.
+    fn rebind(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        closure: &Arc<Closure>,
+        skip: Option<&Chars>,
+        res: &mut Option<Value>,
+    ) -> bool {
+        if !Arc::ptr_eq(&self.closure.code, &closure.code) {
+            return false;
+        }
+        let old = mem::replace(&mut self.closure, Arc::clone(closure));
+        let code = Arc::clone(&closure.code);
+        for (i, (_, local)) in code.scope.iter().enumerate() {
+            match &closure.env[i] {
+                Some(c) if old.env[i].as_ref() != Some(c) && skip != Some(local) => {
+                    bind(ctx, local, &c.value, c.function.as_ref());
+                    let event = Event::Variable(local.clone(), c.value.clone());
+                    if let Some(v) = self.body.update(ctx, &event) {
+                        *res = Some(v);
+                    }
+                }
+                Some(_) | None => (),
+            }
+        }
+        true
+    }
+
+    fn set(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        i: usize,
+        v: Value,
+        f: Option<Arc<Closure>>,
+    ) -> Option<Value> {
+        let local = self.locals[i].clone();
+        bind(ctx, &local, &v, f.as_ref());
+        self.body.update(ctx, &Event::Variable(local, v))
+    }
+}
+
+/// `apply(f, args..)` calls the function `f` with `args`. Functions
+/// given a name with `define`, and lambda arguments, can also be
+/// called by name, e.g. `f(1)`.
+pub struct ApplyFn<C: Ctx + 'static, E: 'static> {
+    top_id: ExprId,
+    // calling a defined function, there is no f argument
+    defined: bool,
+    args: CachedVals,
+    instance: Option<Instance<C, E>>,
+    current: Option<Value>,
+}
+
+impl<C: Ctx, E> ApplyFn<C, E> {
+    // returns true if the existing instance was reused
+    fn compile(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        f: &Node<C, E>,
+        args: &[Node<C, E>],
+        skip: Option<&Chars>,
+        res: &mut Option<Value>,
+    ) -> bool {
+        match closure(f) {
+            Err(e) => {
+                self.instance = None;
+                self.current = Some(e);
+                false
+            }
+            Ok(c) => {
+                if let Some(inst) = &mut self.instance {
+                    if inst.rebind(ctx, &c, skip, res) {
+                        return true;
+                    }
+                }
+                self.call(ctx, c, args);
+                false
+            }
+        }
+    }
+
+    fn call(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        closure: Arc<Closure>,
+        args: &[Node<C, E>],
+    ) {
+        self.instance = None;
+        match Instance::new(ctx, self.top_id, closure, self.args.0.len()) {
+            Err(e) => self.current = Some(e),
+            Ok(mut inst) => {
+                self.current = inst.body.current();
+                for (i, v) in self.args.0.iter().enumerate() {
+                    if let Some(v) = v {
+                        let f = args[i].closure();
+                        if let Some(v) = inst.set(ctx, i, v.clone(), f) {
+                            self.current = Some(v);
+                        }
+                    }
+                }
+                self.instance = Some(inst);
+            }
+        }
+    }
+}
+
+impl<C: Ctx, E> Register<C, E> for ApplyFn<C, E> {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, top_id| {
+            let mut t = ApplyFn {
+                top_id,
+                defined: false,
+                args: CachedVals::new(from.get(1..).unwrap_or(&[])),
+                instance: None,
+                current: None,
+            };
+            match from {
+                [] => {
+                    let e = "apply(f, args..): expected at least 1 argument";
+                    t.current = Some(Value::Error(Chars::from(e)))
+                }
+                [f, args @ ..] => {
+                    if f.current().is_some() {
+                        t.compile(ctx, f, args, None, &mut None);
+                    }
+                }
+            }
+            Box::new(t)
+        });
+        ctx.functions.insert("apply".into(), f);
+        let sig = Signature::variadic(vec![Arg::Any], Arg::Any, Ret::Unknown);
+        ctx.signatures.insert("apply".into(), sig);
+    }
+}
+
+impl<C: Ctx, E> Apply<C, E> for ApplyFn<C, E> {
+    fn current(&self) -> Option<Value> {
+        self.current.clone()
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        let (f, args) = match from {
+            args if self.defined => (None, args),
+            [] => return None,
+            [f, args @ ..] => {
+                let up = f.update(ctx, event).is_some();
+                (if up { Some(&*f) } else { None }, args)
+            }
+        };
+        let mut changed = Vec::new();
+        for (i, arg) in args.iter_mut().enumerate() {
+            if let Some(v) = arg.update(ctx, event) {
+                self.args.0[i] = Some(v.clone());
+                changed.push((i, v, arg.closure()));
+            }
+        }
+        let mut res = None;
+        if let Some(f) = f {
+            let skip = match event {
+                Event::Variable(name, _) => Some(name),
+                _ => None,
+            };
+            if !self.compile(ctx, f, args, skip, &mut res) {
+                return self.current.clone();
+            }
+        }
+        match &mut self.instance {
+            None => None,
+            Some(inst) => {
+                if let Some(v) = inst.body.update(ctx, event) {
+                    res = Some(v);
+                }
+                for (i, v, f) in changed {
+                    if let Some(v) = inst.set(ctx, i, v, f) {
+                        res = Some(v);
+                    }
+                }
+                if res.is_some() {
+                    self.current = res.clone();
+                }
+                res
+            }
+        }
+    }
+}
+
+/// `define(name, f)` makes the function `f` callable as `name(..)`
+/// by expressions compiled after it. `f` must be known when the
+/// define is compiled, and define is only allowed outside of a
+/// lambda.
+pub struct Define(Value);
+
+impl<C: Ctx, E> Register<C, E> for Define {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, _| {
+            let err = |e: &str| Box::new(Define(Value::Error(Chars::from(e.to_owned()))));
+            if !ctx.scope.is_empty() {
+                return err("define: not allowed inside a lambda");
+            }
+            let (name, f) = match from {
+                [name, f] => match (name.current(), f.current()) {
+                    (Some(Value::String(name)), Some(_)) if VNAME.is_match(&name) => {
+                        (name, f)
+                    }
+                    _ => return err("define(name, f): expected a name and a function"),
+                },
+                _ => return err("define(name, f): expected 2 arguments"),
+            };
+            let c = match closure(f) {
+                Ok(c) => c,
+                Err(e) => return Box::new(Define(e)),
+            };
+            let nargs = c.code.args.len();
+            let init: InitFn<C, E> = Arc::new(move |ctx, from, top_id| {
+                let mut t = ApplyFn {
+                    top_id,
+                    defined: true,
+                    args: CachedVals::new(from),
+                    instance: None,
+                    current: None,
+                };
+                t.call(ctx, Arc::clone(&c), from);
+                Box::new(t)
+            });
+            let sig = Signature::fixed(vec![Arg::Any; nargs], Ret::Unknown);
+            ctx.functions.insert(name.to_string(), init);
+            ctx.signatures.insert(name.to_string(), sig);
+            Box::new(Define(f.current().unwrap_or(Value::Null)))
+        });
+        ctx.functions.insert("define".into(), f);
+        let sig = Signature::fixed(vec![Arg::Is(Typ::String), Arg::Any], Ret::Args(1));
+        ctx.signatures.insert("define".into(), sig);
+    }
+}
+
+impl<C: Ctx, E> Apply<C, E> for Define {
+    fn current(&self) -> Option<Value> {
+        Some(self.0.clone())
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        for n in from {
+            n.update(ctx, event);
+        }
+        None
+    }
+}
+
+/// `fold(f, init, src)` calls `f(acc, v)` each time `src` updates,
+/// where acc is the result of the previous call, or `init` the first
+/// time. `f` should be a pure function of it's arguments. An update
+/// to `init` resets the accumulator.
+pub struct Fold<C: Ctx + 'static, E: 'static> {
+    top_id: ExprId,
+    instance: Result<Instance<C, E>, Value>,
+    acc: Option<Value>,
+}
+
+impl<C: Ctx, E> Fold<C, E> {
+    fn compile(&mut self, ctx: &mut ExecCtx<C, E>, f: &Node<C, E>) {
+        let c = match closure(f) {
+            Err(e) => {
+                self.instance = Err(e);
+                return;
+            }
+            Ok(c) => c,
+        };
+        if let Ok(inst) = &mut self.instance {
+            if inst.rebind(ctx, &c, None, &mut None) {
+                return;
+            }
+        }
+        self.instance = Instance::new(ctx, self.top_id, c, 2);
+    }
+
+    fn err() -> Value {
+        Value::Error(Chars::from("fold(f, init, src): expected 3 arguments"))
+    }
+}
+
+impl<C: Ctx, E> Register<C, E> for Fold<C, E> {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, top_id| {
+            let mut t = Fold { top_id, instance: Err(Fold::<C, E>::err()), acc: None };
+            if let [f, init, _] = from {
+                t.compile(ctx, f);
+                t.acc = init.current();
+            }
+            Box::new(t)
+        });
+        ctx.functions.insert("fold".into(), f);
+        let sig = Signature::fixed(vec![Arg::Any, Arg::Any, Arg::Any], Ret::Unknown);
+        ctx.signatures.insert("fold".into(), sig);
+    }
+}
+
+impl<C: Ctx, E> Apply<C, E> for Fold<C, E> {
+    fn current(&self) -> Option<Value> {
+        match &self.instance {
+            Err(Value::Null) => None,
+            Err(e) => Some(e.clone()),
+            Ok(_) => self.acc.clone(),
+        }
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        match from {
+            [f, init, src] => {
+                if f.update(ctx, event).is_some() {
+                    self.compile(ctx, f);
+                }
+                let mut up = false;
+                if let Some(init) = init.update(ctx, event) {
+                    self.acc = Some(init);
+                    up = true;
+                }
+                if let Some(v) = src.update(ctx, event) {
+                    if let (Ok(inst), Some(acc)) = (&mut self.instance, &self.acc) {
+                        inst.body.update(ctx, event);
+                        let r0 = inst.set(ctx, 0, acc.clone(), None);
+                        let r1 = inst.set(ctx, 1, v, src.closure());
+                        if let Some(acc) = r1.or(r0) {
+                            self.acc = Some(acc);
+                            up = true;
+                        }
+                    }
+                }
+                if up {
+                    Apply::<C, E>::current(self)
+                } else {
+                    None
+                }
+            }
+            exprs => {
+                let mut up = false;
+                for e in exprs {
+                    up = e.update(ctx, event).is_some() || up;
+                }
+                self.instance = Err(Fold::<C, E>::err());
+                if up {
+                    Apply::<C, E>::current(self)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
 pub struct Count {
     from: CachedVals,
     count: u64,
@@ -927,6 +1659,8 @@ impl<C: Ctx, E> Register<C, E> for Count {
             Box::new(Count { from: CachedVals::new(from), count: 0 })
         });
         ctx.functions.insert("count".into(), f);
+        let sig = Signature::fixed(vec![Arg::Any], Ret::Is(Typ::U64));
+        ctx.signatures.insert("count".into(), sig);
     }
 }
 
@@ -973,6 +1707,8 @@ impl<C: Ctx, E> Register<C, E> for Sample {
             Box::new(Sample { current })
         });
         ctx.functions.insert("sample".into(), f);
+        let sig = Signature::fixed(vec![Arg::Any, Arg::Any], Ret::Args(1));
+        ctx.signatures.insert("sample".into(), sig);
     }
 }
 
@@ -1021,6 +1757,8 @@ impl<C: Ctx, E> Register<C, E> for Mean {
             Box::new(Mean { from: CachedVals::new(from), total: 0., samples: 0 })
         });
         ctx.functions.insert("mean".into(), f);
+        let sig = Signature::fixed(vec![Arg::Any], Ret::Is(Typ::F64));
+        ctx.signatures.insert("mean".into(), sig);
     }
 }
 
@@ -1062,6 +1800,390 @@ impl<C: Ctx, E> Apply<C, E> for Mean {
     }
 }
 
+atomic_id!(TimerId);
+
+fn duration_arg(name: &str, v: &Option<Value>) -> Result<Option<Duration>, Value> {
+    match v {
+        None => Ok(None),
+        Some(v) => match v.clone().cast_to::<Duration>() {
+            Ok(d) if d > Duration::from_secs(0) => Ok(Some(d)),
+            Ok(_) | Err(_) => {
+                let e = format!("{}: expected a positive duration not {}", name, v);
+                Err(Value::Error(Chars::from(e)))
+            }
+        },
+    }
+}
+
+/// `timer(duration, repeat)` updates with the current time once
+/// `duration` has elapsed. If `repeat` is true it fires forever, if
+/// it's a number it fires that many times.
+pub struct Timer {
+    args: CachedVals,
+    top_id: ExprId,
+    id: Option<TimerId>,
+    timeout: Duration,
+    // None means forever
+    remaining: Option<u64>,
+    current: Option<Value>,
+}
+
+impl Timer {
+    fn schedule<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>) {
+        let id = TimerId::new();
+        self.id = Some(id);
+        ctx.user.set_timer(id, self.timeout, self.top_id);
+    }
+
+    fn start<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>) {
+        self.id = None;
+        let usage = "timer(duration, repeat): expected 2 arguments";
+        let (timeout, repeat) = match &*self.args.0 {
+            [timeout, repeat] => (timeout, repeat),
+            _ => {
+                self.current = Some(Value::Error(Chars::from(usage)));
+                return;
+            }
+        };
+        let timeout = match duration_arg("timer", timeout) {
+            Ok(Some(timeout)) => timeout,
+            Ok(None) => return,
+            Err(e) => {
+                self.current = Some(e);
+                return;
+            }
+        };
+        self.remaining = match repeat {
+            None => return,
+            Some(Value::True) => None,
+            Some(Value::False) => Some(1),
+            Some(v) => match v.clone().cast_to::<u64>() {
+                Ok(0) => return,
+                Ok(n) => Some(n),
+                Err(_) => {
+                    let e = "timer: expected repeat to be a bool or a number";
+                    self.current = Some(Value::Error(Chars::from(e)));
+                    return;
+                }
+            },
+        };
+        self.timeout = timeout;
+        self.schedule(ctx)
+    }
+}
+
+impl<C: Ctx, E> Register<C, E> for Timer {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, top_id| {
+            let mut t = Timer {
+                args: CachedVals::new(from),
+                top_id,
+                id: None,
+                timeout: Duration::from_secs(1),
+                remaining: None,
+                current: None,
+            };
+            t.start(ctx);
+            Box::new(t)
+        });
+        ctx.functions.insert("timer".into(), f);
+        let sig = Signature::fixed(vec![Arg::Any, Arg::Any], Ret::Is(Typ::DateTime));
+        ctx.signatures.insert("timer".into(), sig);
+    }
+}
+
+impl<C: Ctx, E> Apply<C, E> for Timer {
+    fn current(&self) -> Option<Value> {
+        self.current.clone()
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        if self.args.update(ctx, from, event) {
+            self.current = None;
+            self.start(ctx);
+            return self.current.clone();
+        }
+        match event {
+            Event::Timer(id) if self.id == Some(*id) => {
+                self.id = None;
+                self.current = Some(Value::DateTime(Utc::now()));
+                if let Some(n) = &mut self.remaining {
+                    *n -= 1;
+                }
+                if self.remaining != Some(0) {
+                    self.schedule(ctx)
+                }
+                self.current.clone()
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct NowEv;
+
+impl CachedCurEval for NowEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [] | [Some(_)] => Some(Value::DateTime(Utc::now())),
+            [None] => None,
+            _ => Some(Value::Error(Chars::from(
+                "now([trigger]): expected 0 or 1 arguments",
+            ))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "now"
+    }
+
+    fn signature() -> Signature {
+        Signature {
+            args: vec![],
+            optional: vec![Arg::Any],
+            variadic: None,
+            ret: Ret::Is(Typ::DateTime),
+        }
+    }
+}
+
+/// `now([trigger])` is the current time, updated whenever `trigger`
+/// updates.
+pub type Now = CachedCur<NowEv>;
+
+/// `delay(src, duration)` updates with each value of `src` after
+/// `duration` has elapsed.
+pub struct Delay {
+    top_id: ExprId,
+    timeout: Result<Option<Duration>, Value>,
+    queue: VecDeque<(TimerId, Value)>,
+    current: Option<Value>,
+}
+
+impl Delay {
+    fn push<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>, v: Value) {
+        if let Ok(Some(timeout)) = &self.timeout {
+            let id = TimerId::new();
+            ctx.user.set_timer(id, *timeout, self.top_id);
+            self.queue.push_back((id, v));
+        }
+    }
+
+    fn err() -> Value {
+        Value::Error(Chars::from("delay(src, duration): expected 2 arguments"))
+    }
+}
+
+impl<C: Ctx, E> Register<C, E> for Delay {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, top_id| {
+            let mut t = Delay {
+                top_id,
+                timeout: Err(Delay::err()),
+                queue: VecDeque::new(),
+                current: None,
+            };
+            if let [src, timeout] = from {
+                t.timeout = duration_arg("delay", &timeout.current());
+                if let Some(v) = src.current() {
+                    t.push(ctx, v)
+                }
+            }
+            Box::new(t)
+        });
+        ctx.functions.insert("delay".into(), f);
+        let sig = Signature::fixed(vec![Arg::Any, Arg::Any], Ret::Args(0));
+        ctx.signatures.insert("delay".into(), sig);
+    }
+}
+
+impl<C: Ctx, E> Apply<C, E> for Delay {
+    fn current(&self) -> Option<Value> {
+        match &self.timeout {
+            Err(e) => Some(e.clone()),
+            Ok(_) => self.current.clone(),
+        }
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        match from {
+            [src, timeout] => {
+                if let Some(timeout) = timeout.update(ctx, event) {
+                    self.timeout = duration_arg("delay", &Some(timeout));
+                    if let Err(e) = &self.timeout {
+                        return Some(e.clone());
+                    }
+                }
+                if let Some(v) = src.update(ctx, event) {
+                    self.push(ctx, v);
+                }
+                match event {
+                    Event::Timer(id) => {
+                        let i = self.queue.iter().position(|(i, _)| i == id)?;
+                        let (_, v) = self.queue.remove(i)?;
+                        self.current = Some(v.clone());
+                        Some(v)
+                    }
+                    _ => None,
+                }
+            }
+            exprs => {
+                let mut up = false;
+                for e in exprs {
+                    up = e.update(ctx, event).is_some() || up;
+                }
+                if up {
+                    Some(Delay::err())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// `mean_over(src, duration)` is the mean of the values of `src` in
+/// the last `duration`.
+pub struct MeanOver {
+    top_id: ExprId,
+    window: Result<Option<Duration>, Value>,
+    samples: VecDeque<(Instant, f64)>,
+    total: f64,
+    timer: Option<TimerId>,
+}
+
+impl MeanOver {
+    fn err() -> Value {
+        Value::Error(Chars::from("mean_over(src, duration): expected 2 arguments"))
+    }
+
+    // drop expired samples, and arrange to be woken when the next
+    // one expires.
+    fn expire<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>) {
+        let window = match &self.window {
+            Ok(Some(window)) => *window,
+            Ok(None) | Err(_) => return,
+        };
+        let now = Instant::now();
+        while let Some((ts, v)) = self.samples.front() {
+            if now - *ts < window {
+                break;
+            }
+            self.total -= v;
+            self.samples.pop_front();
+        }
+        if self.samples.is_empty() {
+            self.total = 0.;
+        }
+        self.timer = None;
+        if let Some((ts, _)) = self.samples.front() {
+            let id = TimerId::new();
+            self.timer = Some(id);
+            ctx.user.set_timer(id, window - (now - *ts), self.top_id);
+        }
+    }
+
+    fn add<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>, v: Value) {
+        if let Ok(v) = v.cast_to::<f64>() {
+            self.samples.push_back((Instant::now(), v));
+            self.total += v;
+            if self.timer.is_none() {
+                self.expire(ctx)
+            }
+        }
+    }
+}
+
+impl<C: Ctx, E> Register<C, E> for MeanOver {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, top_id| {
+            let mut t = MeanOver {
+                top_id,
+                window: Err(MeanOver::err()),
+                samples: VecDeque::new(),
+                total: 0.,
+                timer: None,
+            };
+            if let [src, window] = from {
+                t.window = duration_arg("mean_over", &window.current());
+                if let Some(v) = src.current() {
+                    t.add(ctx, v)
+                }
+            }
+            Box::new(t)
+        });
+        ctx.functions.insert("mean_over".into(), f);
+        let sig = Signature::fixed(vec![Arg::Any, Arg::Any], Ret::Is(Typ::F64));
+        ctx.signatures.insert("mean_over".into(), sig);
+    }
+}
+
+impl<C: Ctx, E> Apply<C, E> for MeanOver {
+    fn current(&self) -> Option<Value> {
+        match &self.window {
+            Err(e) => Some(e.clone()),
+            Ok(_) if self.samples.is_empty() => None,
+            Ok(_) => Some(Value::F64(self.total / self.samples.len() as f64)),
+        }
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        match from {
+            [src, window] => {
+                let mut up = false;
+                if let Some(window) = window.update(ctx, event) {
+                    self.window = duration_arg("mean_over", &Some(window));
+                    self.expire(ctx);
+                    up = true;
+                }
+                if let Some(v) = src.update(ctx, event) {
+                    self.add(ctx, v);
+                    up = true;
+                }
+                match event {
+                    Event::Timer(id) if self.timer == Some(*id) => {
+                        self.expire(ctx);
+                        up = true;
+                    }
+                    _ => (),
+                }
+                if up {
+                    Apply::<C, E>::current(self)
+                } else {
+                    None
+                }
+            }
+            exprs => {
+                let mut up = false;
+                for e in exprs {
+                    up = e.update(ctx, event).is_some() || up;
+                }
+                self.window = Err(MeanOver::err());
+                if up {
+                    Apply::<C, E>::current(self)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
 pub(crate) struct Uniq(Option<Value>);
 
 impl<C: Ctx, E> Register<C, E> for Uniq {
@@ -1075,6 +2197,8 @@ impl<C: Ctx, E> Register<C, E> for Uniq {
             Box::new(t)
         });
         ctx.functions.insert("uniq".into(), f);
+        let sig = Signature::fixed(vec![Arg::Any], Ret::Args(0));
+        ctx.signatures.insert("uniq".into(), sig);
     }
 }
 
@@ -1157,6 +2281,8 @@ impl<C: Ctx, E> Register<C, E> for Store {
             Box::new(t)
         });
         ctx.functions.insert("store".into(), f);
+        let sig = Signature::fixed(vec![Arg::Is(Typ::String), Arg::Any], Ret::Unknown);
+        ctx.signatures.insert("store".into(), sig);
     }
 }
 
@@ -1225,9 +2351,7 @@ impl Store {
             (None, None) => (),
             (None, Some(v)) => match self.dv.as_ref() {
                 None => self.queue(v),
-                Some((_, dv)) => {
-                    dv.write(v);
-                }
+                Some((path, dv)) => ctx.user.write(path, dv, v),
             },
             (Some(p), val) => {
                 let path = Path::from(p);
@@ -1240,7 +2364,7 @@ impl Store {
                     self.queue(v)
                 }
                 for v in self.queued.drain(..) {
-                    dv.write(v);
+                    ctx.user.write(&path, &dv, v);
                 }
                 self.dv = Some((path, dv));
             }
@@ -1275,7 +2399,7 @@ fn varname(invalid: &mut bool, name: Option<Value>) -> Option<Chars> {
 }
 
 pub struct StoreVar {
-    queued: Vec<Value>,
+    queued: Vec<(Value, Option<Arc<Closure>>)>,
     name: Option<Chars>,
     invalid: bool,
 }
@@ -1285,12 +2409,16 @@ impl<C: Ctx, E> Register<C, E> for StoreVar {
         let f: InitFn<C, E> = Arc::new(|ctx, from, _| {
             let mut t = StoreVar { queued: Vec::new(), name: None, invalid: false };
             match from {
-                [name, value] => t.set(ctx, name.current(), value.current()),
+                [name, value] => {
+                    t.set(ctx, name.current(), value.current(), value.closure())
+                }
                 _ => t.invalid = true,
             }
             Box::new(t)
         });
         ctx.functions.insert("store_var".into(), f);
+        let sig = Signature::fixed(vec![Arg::Is(Typ::String), Arg::Any], Ret::Unknown);
+        ctx.signatures.insert("store_var".into(), sig);
     }
 }
 
@@ -1321,7 +2449,7 @@ impl<C: Ctx, E> Apply<C, E> for StoreVar {
                     value
                 };
                 let up = value.is_some();
-                self.set(ctx, name, value);
+                self.set(ctx, name, value, val.closure());
                 if up {
                     Apply::<C, E>::current(self)
                 } else {
@@ -1345,8 +2473,18 @@ impl<C: Ctx, E> Apply<C, E> for StoreVar {
 }
 
 impl StoreVar {
-    fn queue_set(&mut self, v: Value) {
-        self.queued.push(v)
+    fn queue_set(&mut self, v: Value, f: Option<Arc<Closure>>) {
+        self.queued.push((v, f))
+    }
+
+    fn set_var<C: Ctx, E>(
+        ctx: &mut ExecCtx<C, E>,
+        name: &Chars,
+        v: Value,
+        f: Option<Arc<Closure>>,
+    ) {
+        bind(ctx, name, &v, f.as_ref());
+        ctx.user.set_var(&mut ctx.variables, name.clone(), v)
     }
 
     fn set<C: Ctx, E>(
@@ -1354,17 +2492,18 @@ impl StoreVar {
         ctx: &mut ExecCtx<C, E>,
         name: Option<Value>,
         value: Option<Value>,
+        f: Option<Arc<Closure>>,
     ) {
         if let Some(name) = varname(&mut self.invalid, name) {
-            for v in self.queued.drain(..) {
-                ctx.user.set_var(&mut ctx.variables, name.clone(), v)
+            for (v, f) in self.queued.drain(..) {
+                StoreVar::set_var(ctx, &name, v, f)
             }
             self.name = Some(name);
         }
         if let Some(value) = value {
             match self.name.as_ref() {
-                None => self.queue_set(value),
-                Some(name) => ctx.user.set_var(&mut ctx.variables, name.clone(), value),
+                None => self.queue_set(value, f),
+                Some(name) => StoreVar::set_var(ctx, name, value, f),
             }
         }
     }
@@ -1394,6 +2533,8 @@ impl<C: Ctx, E> Register<C, E> for Load {
             Box::new(t)
         });
         ctx.functions.insert("load".into(), f);
+        let sig = Signature::fixed(vec![Arg::Is(Typ::String)], Ret::Unknown);
+        ctx.signatures.insert("load".into(), sig);
     }
 }
 
@@ -1428,7 +2569,10 @@ impl<C: Ctx, E> Apply<C, E> for Load {
                     }
                 } else {
                     self.cur.as_ref().and_then(|dv| match event {
-                        Event::Variable(_, _) | Event::Rpc(_, _) | Event::User(_) => None,
+                        Event::Variable(_, _)
+                        | Event::Rpc(_, _)
+                        | Event::Timer(_)
+                        | Event::User(_) => None,
                         Event::Netidx(id, value) if dv.id() == *id => Some(value.clone()),
                         Event::Netidx(_, _) => None,
                     })
@@ -1471,6 +2615,7 @@ impl Load {
 pub struct LoadVar {
     name: Option<Chars>,
     cur: Option<Value>,
+    closure: Option<Arc<Closure>>,
     top_id: ExprId,
     invalid: bool,
 }
@@ -1478,7 +2623,8 @@ pub struct LoadVar {
 impl<C: Ctx, E> Register<C, E> for LoadVar {
     fn register(ctx: &mut ExecCtx<C, E>) {
         let f: InitFn<C, E> = Arc::new(|ctx, from, top_id| {
-            let mut t = LoadVar { name: None, cur: None, invalid: false, top_id };
+            let mut t =
+                LoadVar { name: None, cur: None, closure: None, invalid: false, top_id };
             match from {
                 [name] => t.subscribe(ctx, name.current()),
                 _ => t.invalid = true,
@@ -1486,6 +2632,8 @@ impl<C: Ctx, E> Register<C, E> for LoadVar {
             Box::new(t)
         });
         ctx.functions.insert("load_var".into(), f);
+        let sig = Signature::fixed(vec![Arg::Is(Typ::String)], Ret::Unknown);
+        ctx.signatures.insert("load_var".into(), sig);
     }
 }
 
@@ -1498,6 +2646,10 @@ impl<C: Ctx, E> Apply<C, E> for LoadVar {
         }
     }
 
+    fn closure(&self) -> Option<Arc<Closure>> {
+        self.closure.clone()
+    }
+
     fn update(
         &mut self,
         ctx: &mut ExecCtx<C, E>,
@@ -1521,9 +2673,11 @@ impl<C: Ctx, E> Apply<C, E> for LoadVar {
                         (None, _)
                         | (Some(_), Event::Netidx(_, _))
                         | (Some(_), Event::User(_))
-                        | (Some(_), Event::Rpc(_, _)) => None,
+                        | (Some(_), Event::Rpc(_, _))
+                        | (Some(_), Event::Timer(_)) => None,
                         (Some(vn), Event::Variable(tn, v)) if vn == tn => {
                             self.cur = Some(v.clone());
+                            self.closure = bound(ctx, vn, v);
                             Some(v.clone())
                         }
                         (Some(_), Event::Variable(_, _)) => None,
@@ -1556,6 +2710,7 @@ impl LoadVar {
     fn subscribe<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>, name: Option<Value>) {
         if let Some(name) = varname(&mut self.invalid, name) {
             self.cur = ctx.variables.get(&name).cloned();
+            self.closure = self.cur.as_ref().and_then(|v| bound(ctx, &name, v));
             ctx.user.ref_var(name.clone(), self.top_id);
             self.name = Some(name);
         }
@@ -1590,6 +2745,9 @@ impl<C: Ctx, E> Register<C, E> for RpcCall {
             Box::new(t)
         });
         ctx.functions.insert("call".into(), f);
+        let args = vec![Arg::Any, Arg::Is(Typ::String)];
+        let sig = Signature::variadic(args, Arg::Any, Ret::Unknown);
+        ctx.signatures.insert("call".into(), sig);
     }
 }
 
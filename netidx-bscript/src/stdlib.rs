@@ -0,0 +1,646 @@
+//! Math, formatting, and regex builtins
+use crate::{
+    stdfn::{CachedCur, CachedCurEval, CachedVals},
+    typ::{Arg, Ret, Signature, NUMBER},
+    vm::{Apply, Ctx, Event, ExecCtx, InitFn, Node, Register},
+};
+use chrono::{
+    format::{Item, StrftimeItems},
+    prelude::*,
+};
+use netidx::{
+    chars::Chars,
+    subscriber::{Typ, Value},
+};
+use regex::Regex;
+use std::{cmp::min, marker::PhantomData, sync::Arc};
+
+fn err(e: impl Into<String>) -> Option<Value> {
+    Some(Value::Error(Chars::from(e.into())))
+}
+
+// apply f to a float, leaving integers alone
+fn map_float(name: &str, v: &Value, f: impl Fn(f64) -> f64) -> Option<Value> {
+    match v {
+        Value::F32(v) => Some(Value::F32(f(*v as f64) as f32)),
+        Value::F64(v) => Some(Value::F64(f(*v))),
+        Value::U32(_)
+        | Value::V32(_)
+        | Value::I32(_)
+        | Value::Z32(_)
+        | Value::U64(_)
+        | Value::V64(_)
+        | Value::I64(_)
+        | Value::Z64(_) => Some(v.clone()),
+        v => err(format!("{}: expected a number not {}", name, v)),
+    }
+}
+
+fn float_arg(name: &str, v: &Value) -> Result<f64, Value> {
+    v.clone().cast_to::<f64>().map_err(|_| {
+        Value::Error(Chars::from(format!("{}: expected a number not {}", name, v)))
+    })
+}
+
+pub struct RoundEv;
+
+impl CachedCurEval for RoundEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [None] | [None, _] | [_, None] => None,
+            [Some(v)] => map_float("round", v, f64::round),
+            [Some(v), Some(digits)] => match digits.clone().cast_to::<i32>() {
+                Err(_) => err("round(n, [digits]): expected digits to be an integer"),
+                Ok(digits) => {
+                    let m = 10f64.powi(digits);
+                    map_float("round", v, |v| (v * m).round() / m)
+                }
+            },
+            _ => err("round(n, [digits]): expected 1 or 2 arguments"),
+        }
+    }
+
+    fn name() -> &'static str {
+        "round"
+    }
+
+    fn signature() -> Signature {
+        Signature {
+            args: vec![Arg::OneOf(&NUMBER)],
+            optional: vec![Arg::OneOf(&NUMBER)],
+            variadic: None,
+            ret: Ret::Args(0),
+        }
+    }
+}
+
+pub type Round = CachedCur<RoundEv>;
+
+pub struct FloorEv;
+
+impl CachedCurEval for FloorEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [None] => None,
+            [Some(v)] => map_float("floor", v, f64::floor),
+            _ => err("floor(n): expected 1 argument"),
+        }
+    }
+
+    fn name() -> &'static str {
+        "floor"
+    }
+
+    fn signature() -> Signature {
+        Signature::fixed(vec![Arg::OneOf(&NUMBER)], Ret::Args(0))
+    }
+}
+
+pub type Floor = CachedCur<FloorEv>;
+
+pub struct CeilEv;
+
+impl CachedCurEval for CeilEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [None] => None,
+            [Some(v)] => map_float("ceil", v, f64::ceil),
+            _ => err("ceil(n): expected 1 argument"),
+        }
+    }
+
+    fn name() -> &'static str {
+        "ceil"
+    }
+
+    fn signature() -> Signature {
+        Signature::fixed(vec![Arg::OneOf(&NUMBER)], Ret::Args(0))
+    }
+}
+
+pub type Ceil = CachedCur<CeilEv>;
+
+pub struct AbsEv;
+
+impl CachedCurEval for AbsEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [None] => None,
+            [Some(v)] => {
+                // the minimum signed integer has no absolute value
+                let abs = match v {
+                    Value::I32(i) => i.checked_abs().map(Value::I32),
+                    Value::Z32(i) => i.checked_abs().map(Value::Z32),
+                    Value::I64(i) => i.checked_abs().map(Value::I64),
+                    Value::Z64(i) => i.checked_abs().map(Value::Z64),
+                    v => return map_float("abs", v, f64::abs),
+                };
+                abs.or_else(|| err(format!("abs: {} overflows", v)))
+            }
+            _ => err("abs(n): expected 1 argument"),
+        }
+    }
+
+    fn name() -> &'static str {
+        "abs"
+    }
+
+    fn signature() -> Signature {
+        Signature::fixed(vec![Arg::OneOf(&NUMBER)], Ret::Args(0))
+    }
+}
+
+pub type Abs = CachedCur<AbsEv>;
+
+pub struct PowEv;
+
+impl CachedCurEval for PowEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [Some(base), Some(exp)] => {
+                match (float_arg("pow", base), float_arg("pow", exp)) {
+                    (Ok(base), Ok(exp)) => Some(Value::F64(base.powf(exp))),
+                    (Err(e), _) | (_, Err(e)) => Some(e),
+                }
+            }
+            [_, _] => None,
+            _ => err("pow(base, exp): expected 2 arguments"),
+        }
+    }
+
+    fn name() -> &'static str {
+        "pow"
+    }
+
+    fn signature() -> Signature {
+        let args = vec![Arg::OneOf(&NUMBER), Arg::OneOf(&NUMBER)];
+        Signature::fixed(args, Ret::Is(Typ::F64))
+    }
+}
+
+pub type Pow = CachedCur<PowEv>;
+
+pub struct SqrtEv;
+
+impl CachedCurEval for SqrtEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [None] => None,
+            [Some(v)] => match float_arg("sqrt", v) {
+                Ok(v) => Some(Value::F64(v.sqrt())),
+                Err(e) => Some(e),
+            },
+            _ => err("sqrt(n): expected 1 argument"),
+        }
+    }
+
+    fn name() -> &'static str {
+        "sqrt"
+    }
+
+    fn signature() -> Signature {
+        Signature::fixed(vec![Arg::OneOf(&NUMBER)], Ret::Is(Typ::F64))
+    }
+}
+
+pub type Sqrt = CachedCur<SqrtEv>;
+
+pub struct LogEv;
+
+impl CachedCurEval for LogEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [None] | [None, _] | [_, None] => None,
+            [Some(v)] => match float_arg("log", v) {
+                Ok(v) => Some(Value::F64(v.ln())),
+                Err(e) => Some(e),
+            },
+            [Some(v), Some(base)] => {
+                match (float_arg("log", v), float_arg("log", base)) {
+                    (Ok(v), Ok(base)) => Some(Value::F64(v.log(base))),
+                    (Err(e), _) | (_, Err(e)) => Some(e),
+                }
+            }
+            _ => err("log(n, [base]): expected 1 or 2 arguments"),
+        }
+    }
+
+    fn name() -> &'static str {
+        "log"
+    }
+
+    fn signature() -> Signature {
+        Signature {
+            args: vec![Arg::OneOf(&NUMBER)],
+            optional: vec![Arg::OneOf(&NUMBER)],
+            variadic: None,
+            ret: Ret::Is(Typ::F64),
+        }
+    }
+}
+
+/// `log(n, [base])`, the natural log if base isn't specified
+pub type Log = CachedCur<LogEv>;
+
+// more decimal places than an f64 can represent are just zeros
+const MAX_PRECISION: u32 = 32;
+
+pub struct FormatNumberEv;
+
+impl CachedCurEval for FormatNumberEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [Some(v), Some(precision)] => {
+                match (float_arg("format_number", v), precision.clone().cast_to::<u32>())
+                {
+                    (Err(e), _) => Some(e),
+                    (_, Err(_)) => err("format_number: expected a positive precision"),
+                    (Ok(v), Ok(p)) => {
+                        let p = min(p, MAX_PRECISION) as usize;
+                        let s = format!("{:.*}", p, v);
+                        Some(Value::String(Chars::from(s)))
+                    }
+                }
+            }
+            [_, _] => None,
+            _ => err("format_number(n, precision): expected 2 arguments"),
+        }
+    }
+
+    fn name() -> &'static str {
+        "format_number"
+    }
+
+    fn signature() -> Signature {
+        let args = vec![Arg::OneOf(&NUMBER), Arg::OneOf(&NUMBER)];
+        Signature::fixed(args, Ret::Is(Typ::String))
+    }
+}
+
+/// `format_number(n, precision)` formats n as a string with the
+/// specified number of decimal places, at most 32.
+pub type FormatNumber = CachedCur<FormatNumberEv>;
+
+pub struct FormatDateTimeEv;
+
+impl CachedCurEval for FormatDateTimeEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [Some(dt), Some(Value::String(fmt))] => {
+                match dt.clone().cast_to::<DateTime<Utc>>() {
+                    Err(_) => {
+                        err(format!("format_datetime: expected a datetime not {}", dt))
+                    }
+                    Ok(dt) => {
+                        let items = StrftimeItems::new(fmt).collect::<Vec<_>>();
+                        if items.iter().any(|i| matches!(i, Item::Error)) {
+                            err(format!("format_datetime: invalid format {}", fmt))
+                        } else {
+                            let s = dt.format_with_items(items.into_iter()).to_string();
+                            Some(Value::String(Chars::from(s)))
+                        }
+                    }
+                }
+            }
+            [Some(_), Some(_)] => err("format_datetime: expected format to be a string"),
+            [_, _] => None,
+            _ => err("format_datetime(dt, format): expected 2 arguments"),
+        }
+    }
+
+    fn name() -> &'static str {
+        "format_datetime"
+    }
+
+    fn signature() -> Signature {
+        let args = vec![Arg::Is(Typ::DateTime), Arg::Is(Typ::String)];
+        Signature::fixed(args, Ret::Is(Typ::String))
+    }
+}
+
+/// `format_datetime(dt, format)` formats dt using strftime style
+/// format specifiers, e.g. "%Y-%m-%d %H:%M:%S".
+pub type FormatDateTime = CachedCur<FormatDateTimeEv>;
+
+pub struct SubstringEv;
+
+impl CachedCurEval for SubstringEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        let (s, start, len) = match &*from.0 {
+            [Some(s), Some(start)] => (s, start, None),
+            [Some(s), Some(start), Some(len)] => (s, start, Some(len)),
+            [_, _] | [_, _, _] => return None,
+            _ => return err("substring(s, start, [len]): expected 2 or 3 arguments"),
+        };
+        let s = match s {
+            Value::String(s) => s,
+            _ => return err("substring: expected a string"),
+        };
+        let start = match start.clone().cast_to::<usize>() {
+            Ok(start) => start,
+            Err(_) => return err("substring: expected start to be a positive integer"),
+        };
+        let len = match len.map(|l| l.clone().cast_to::<usize>()) {
+            None => usize::MAX,
+            Some(Ok(len)) => len,
+            Some(Err(_)) => {
+                return err("substring: expected len to be a positive integer")
+            }
+        };
+        let s = s.chars().skip(start).take(len).collect::<String>();
+        Some(Value::String(Chars::from(s)))
+    }
+
+    fn name() -> &'static str {
+        "substring"
+    }
+
+    fn signature() -> Signature {
+        Signature {
+            args: vec![Arg::Is(Typ::String), Arg::OneOf(&NUMBER)],
+            optional: vec![Arg::OneOf(&NUMBER)],
+            variadic: None,
+            ret: Ret::Is(Typ::String),
+        }
+    }
+}
+
+/// `substring(s, start, [len])`, where start and len are in
+/// characters, not bytes.
+pub type Substring = CachedCur<SubstringEv>;
+
+pub struct SplitEv;
+
+impl CachedCurEval for SplitEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [Some(Value::String(s)), Some(Value::String(sep)), Some(i)] => {
+                match i.clone().cast_to::<usize>() {
+                    Err(_) => err("split: expected index to be a positive integer"),
+                    Ok(i) => match s.split(&**sep).nth(i) {
+                        None => Some(Value::Null),
+                        Some(s) => Some(Value::String(Chars::from(String::from(s)))),
+                    },
+                }
+            }
+            [Some(_), Some(_), Some(_)] => err("split: expected s and sep to be strings"),
+            [_, _, _] => None,
+            _ => err("split(s, sep, index): expected 3 arguments"),
+        }
+    }
+
+    fn name() -> &'static str {
+        "split"
+    }
+
+    fn signature() -> Signature {
+        let args = vec![Arg::Is(Typ::String), Arg::Is(Typ::String), Arg::OneOf(&NUMBER)];
+        Signature::fixed(args, Ret::Unknown)
+    }
+}
+
+/// `split(s, sep, index)` is the index'th field of s split by sep,
+/// or null if there aren't enough fields.
+pub type Split = CachedCur<SplitEv>;
+
+pub trait RegexEval {
+    /// args does not include the regex
+    fn eval(re: &Regex, args: &[Option<Value>]) -> Option<Value>;
+    fn name() -> &'static str;
+    fn signature() -> Signature;
+}
+
+/// A function whose first argument is a regex, which is only
+/// compiled when it changes.
+pub struct RegexFn<T: RegexEval> {
+    args: CachedVals,
+    re: Option<Result<Regex, Value>>,
+    current: Option<Value>,
+    t: PhantomData<T>,
+}
+
+impl<T: RegexEval> RegexFn<T> {
+    fn compile(&mut self) {
+        self.re = match self.args.0.first() {
+            None | Some(None) => None,
+            Some(Some(Value::String(s))) => Some(Regex::new(s).map_err(|e| {
+                let e = format!("{}: invalid regex {}", T::name(), e);
+                Value::Error(Chars::from(e))
+            })),
+            Some(Some(_)) => {
+                let e = format!("{}: expected regex to be a string", T::name());
+                Some(Err(Value::Error(Chars::from(e))))
+            }
+        }
+    }
+
+    fn eval(&mut self) -> Option<Value> {
+        self.current = match &self.re {
+            None => None,
+            Some(Err(e)) => Some(e.clone()),
+            Some(Ok(re)) => T::eval(re, &self.args.0[1..]),
+        };
+        self.current.clone()
+    }
+}
+
+impl<C: Ctx, E, T: RegexEval + 'static> Register<C, E> for RegexFn<T> {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|_, from, _| {
+            let mut t = RegexFn::<T> {
+                args: CachedVals::new(from),
+                re: None,
+                current: None,
+                t: PhantomData,
+            };
+            t.compile();
+            t.eval();
+            Box::new(t)
+        });
+        ctx.functions.insert(T::name().into(), f);
+        ctx.signatures.insert(T::name().into(), T::signature());
+    }
+}
+
+impl<C: Ctx, E, T: RegexEval + 'static> Apply<C, E> for RegexFn<T> {
+    fn current(&self) -> Option<Value> {
+        self.current.clone()
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        let re = self.args.0.first().cloned();
+        if !self.args.update(ctx, from, event) {
+            None
+        } else {
+            if self.args.0.first() != re.as_ref() {
+                self.compile();
+            }
+            self.eval()
+        }
+    }
+}
+
+pub struct RegexMatchEv;
+
+impl RegexEval for RegexMatchEv {
+    fn eval(re: &Regex, args: &[Option<Value>]) -> Option<Value> {
+        match args {
+            [None] => None,
+            [Some(Value::String(s))] => Some(Value::from(re.is_match(s))),
+            [Some(_)] => err("regex_match: expected a string"),
+            _ => err("regex_match(re, s): expected 2 arguments"),
+        }
+    }
+
+    fn name() -> &'static str {
+        "regex_match"
+    }
+
+    fn signature() -> Signature {
+        let args = vec![Arg::Is(Typ::String), Arg::Is(Typ::String)];
+        Signature::fixed(args, Ret::Is(Typ::Bool))
+    }
+}
+
+/// `regex_match(re, s)` is true if re matches anywhere in s.
+pub type RegexMatch = RegexFn<RegexMatchEv>;
+
+pub struct RegexCaptureEv;
+
+impl RegexEval for RegexCaptureEv {
+    fn eval(re: &Regex, args: &[Option<Value>]) -> Option<Value> {
+        let (s, group) = match args {
+            [Some(s)] => (s, 0),
+            [Some(s), Some(group)] => match group.clone().cast_to::<usize>() {
+                Ok(group) => (s, group),
+                Err(_) => return err("regex_capture: expected group to be an integer"),
+            },
+            [_] | [_, _] => return None,
+            _ => return err("regex_capture(re, s, [group]): expected 2 or 3 arguments"),
+        };
+        match s {
+            Value::String(s) => match re.captures(s).and_then(|c| c.get(group)) {
+                None => Some(Value::Null),
+                Some(m) => Some(Value::String(Chars::from(String::from(m.as_str())))),
+            },
+            _ => err("regex_capture: expected a string"),
+        }
+    }
+
+    fn name() -> &'static str {
+        "regex_capture"
+    }
+
+    fn signature() -> Signature {
+        Signature {
+            args: vec![Arg::Is(Typ::String), Arg::Is(Typ::String)],
+            optional: vec![Arg::OneOf(&NUMBER)],
+            variadic: None,
+            ret: Ret::Unknown,
+        }
+    }
+}
+
+/// `regex_capture(re, s, [group])` is the text matched by the
+/// capture group of the first match of re in s, or null if it didn't
+/// match. Group 0, the default, is the entire match.
+pub type RegexCapture = RegexFn<RegexCaptureEv>;
+
+pub struct RegexReplaceEv;
+
+impl RegexEval for RegexReplaceEv {
+    fn eval(re: &Regex, args: &[Option<Value>]) -> Option<Value> {
+        match args {
+            [Some(Value::String(s)), Some(Value::String(rep))] => {
+                let s = re.replace_all(s, &**rep).into_owned();
+                Some(Value::String(Chars::from(s)))
+            }
+            [Some(_), Some(_)] => err("regex_replace: expected s and rep to be strings"),
+            [_, _] => None,
+            _ => err("regex_replace(re, s, rep): expected 3 arguments"),
+        }
+    }
+
+    fn name() -> &'static str {
+        "regex_replace"
+    }
+
+    fn signature() -> Signature {
+        let args = vec![Arg::Is(Typ::String), Arg::Is(Typ::String), Arg::Is(Typ::String)];
+        Signature::fixed(args, Ret::Is(Typ::String))
+    }
+}
+
+/// `regex_replace(re, s, rep)` replaces every match of re in s with
+/// rep, which may refer to capture groups, e.g. "$1".
+pub type RegexReplace = RegexFn<RegexReplaceEv>;
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        expr::Expr,
+        vm::{tests::TestCtx, ExecCtx, Node},
+    };
+    use netidx::{chars::Chars, subscriber::Value};
+
+    fn eval(s: &str) -> Option<Value> {
+        let mut ctx = ExecCtx::<TestCtx, ()>::new(TestCtx::default());
+        Node::compile(&mut ctx, s.parse::<Expr>().unwrap()).current()
+    }
+
+    fn string(s: &str) -> Option<Value> {
+        Some(Value::String(Chars::from(String::from(s))))
+    }
+
+    #[test]
+    fn math() {
+        assert_eq!(eval("round(2.5)"), Some(Value::F64(3.)));
+        assert_eq!(eval("round(3.14159, 2)"), Some(Value::F64(3.14)));
+        assert_eq!(eval("round(42)"), Some(Value::I64(42)));
+        assert_eq!(eval("floor(f32:1.7)"), Some(Value::F32(1.)));
+        assert_eq!(eval("ceil(1.2)"), Some(Value::F64(2.)));
+        assert_eq!(eval("abs(-3)"), Some(Value::I64(3)));
+        assert_eq!(eval("abs(-3.5)"), Some(Value::F64(3.5)));
+        assert_eq!(eval("pow(2, 10)"), Some(Value::F64(1024.)));
+        assert_eq!(eval("sqrt(16)"), Some(Value::F64(4.)));
+        assert_eq!(eval("log(100, 10)"), Some(Value::F64(2.)));
+        assert_eq!(eval("log(1)"), Some(Value::F64(0.)));
+        assert!(matches!(eval(r#"abs("foo")"#), Some(Value::Error(_))));
+        assert!(matches!(eval("abs(i32:-2147483648)"), Some(Value::Error(_))));
+        assert!(matches!(eval("abs(i64:-9223372036854775808)"), Some(Value::Error(_))));
+    }
+
+    #[test]
+    fn formatting() {
+        assert_eq!(eval("format_number(3.14159, 2)"), string("3.14"));
+        assert_eq!(eval("format_number(42, 1)"), string("42.0"));
+        let s = format!("1.{}", "0".repeat(32));
+        assert_eq!(eval("format_number(1, u32:4294967295)"), string(&s));
+        let s = r#"format_datetime(datetime:"2021-03-04T05:06:07Z", "%Y/%m/%d %H:%M")"#;
+        assert_eq!(eval(s), string("2021/03/04 05:06"));
+        let s = r#"format_datetime(datetime:"2021-03-04T05:06:07Z", "%Q")"#;
+        assert!(matches!(eval(s), Some(Value::Error(_))));
+        assert_eq!(eval(r#"substring("hello world", 6)"#), string("world"));
+        assert_eq!(eval(r#"substring("hello world", 1, 3)"#), string("ell"));
+        assert_eq!(eval(r#"split("a,b,c", ",", 1)"#), string("b"));
+        assert_eq!(eval(r#"split("a,b,c", ",", 3)"#), Some(Value::Null));
+    }
+
+    #[test]
+    fn regex() {
+        assert_eq!(eval(r#"regex_match("^a+b$", "aaab")"#), Some(Value::True));
+        assert_eq!(eval(r#"regex_match("^a+b$", "aaac")"#), Some(Value::False));
+        let s = r#"regex_capture("(\\w+)@(\\w+)", "mail bob@example now", 2)"#;
+        assert_eq!(eval(s), string("example"));
+        let s = r#"regex_capture("\\d+", "abc")"#;
+        assert_eq!(eval(s), Some(Value::Null));
+        let s = r#"regex_replace("(\\d+)", "a1b22", "<$1>")"#;
+        assert_eq!(eval(s), string("a<1>b<22>"));
+        assert!(matches!(eval(r#"regex_match("(", "a")"#), Some(Value::Error(_))));
+    }
+}
@@ -0,0 +1,327 @@
+use crate::{
+    expr::{Expr, ExprId, ExprKind},
+    vm::{Ctx, ExecCtx},
+};
+use netidx::{
+    chars::Chars,
+    subscriber::{Typ, Value},
+};
+use std::{collections::HashMap, fmt};
+
+/// All the numeric types
+pub static NUMBER: [Typ; 10] = [
+    Typ::U32,
+    Typ::V32,
+    Typ::I32,
+    Typ::Z32,
+    Typ::U64,
+    Typ::V64,
+    Typ::I64,
+    Typ::Z64,
+    Typ::F32,
+    Typ::F64,
+];
+
+/// Everything that supports arithmetic
+pub static ARITH: [Typ; 12] = [
+    Typ::U32,
+    Typ::V32,
+    Typ::I32,
+    Typ::Z32,
+    Typ::U64,
+    Typ::V64,
+    Typ::I64,
+    Typ::Z64,
+    Typ::F32,
+    Typ::F64,
+    Typ::DateTime,
+    Typ::Duration,
+];
+
+/// The type of a function argument
+#[derive(Debug, Clone, Copy)]
+pub enum Arg {
+    Any,
+    OneOf(&'static [Typ]),
+    Is(Typ),
+}
+
+impl Arg {
+    fn accepts(&self, typ: Typ) -> bool {
+        match self {
+            Arg::Any => true,
+            Arg::OneOf(set) => set.contains(&typ),
+            Arg::Is(t) => *t == typ,
+        }
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arg::Any => write!(f, "any"),
+            Arg::Is(t) => write!(f, "{}", t.name()),
+            Arg::OneOf(set) => {
+                for (i, t) in set.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " | ")?
+                    }
+                    write!(f, "{}", t.name())?
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The return type of a function
+#[derive(Debug, Clone, Copy)]
+pub enum Ret {
+    /// the return type can't be known statically
+    Unknown,
+    Is(Typ),
+    /// the type of the arguments starting at the specified index,
+    /// if they all agree.
+    Args(usize),
+    /// the type of the last argument
+    Last,
+    /// the type named by the constant string argument at the
+    /// specified index
+    Named(usize),
+}
+
+/// The signature of a builtin function, registered along with the
+/// function in `ExecCtx::signatures`.
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub args: Vec<Arg>,
+    pub optional: Vec<Arg>,
+    pub variadic: Option<Arg>,
+    pub ret: Ret,
+}
+
+impl Signature {
+    /// A function taking exactly `args`
+    pub fn fixed(args: Vec<Arg>, ret: Ret) -> Self {
+        Signature { args, optional: vec![], variadic: None, ret }
+    }
+
+    /// A function taking `args` followed by any number of `rest`
+    pub fn variadic(args: Vec<Arg>, rest: Arg, ret: Ret) -> Self {
+        Signature { args, optional: vec![], variadic: Some(rest), ret }
+    }
+
+    fn arity(&self) -> String {
+        let min = self.args.len();
+        let max = min + self.optional.len();
+        match self.variadic {
+            Some(_) => format!("at least {}", min),
+            None if min == max => format!("{}", min),
+            None => format!("{} to {}", min, max),
+        }
+    }
+
+    fn arg(&self, i: usize) -> Option<Arg> {
+        if i < self.args.len() {
+            Some(self.args[i])
+        } else if i - self.args.len() < self.optional.len() {
+            Some(self.optional[i - self.args.len()])
+        } else {
+            self.variadic
+        }
+    }
+
+    fn check(
+        &self,
+        spec: &Expr,
+        function: &str,
+        args: &[Expr],
+        typs: &[Option<Typ>],
+        errors: &mut Vec<TypeError>,
+    ) -> Option<Typ> {
+        let max = self.args.len() + self.optional.len();
+        if args.len() < self.args.len() || (self.variadic.is_none() && args.len() > max) {
+            let msg = format!(
+                "{} expected {} arguments not {}",
+                function,
+                self.arity(),
+                args.len()
+            );
+            errors.push(TypeError { id: spec.id, msg });
+            return None;
+        }
+        for (i, (arg, typ)) in args.iter().zip(typs.iter()).enumerate() {
+            match (self.arg(i), typ) {
+                (Some(expected), Some(typ)) if !expected.accepts(*typ) => {
+                    let msg = format!(
+                        "{} argument {} expected {} not {}",
+                        function,
+                        i,
+                        expected,
+                        typ.name()
+                    );
+                    errors.push(TypeError { id: arg.id, msg });
+                }
+                (_, _) => (),
+            }
+        }
+        match self.ret {
+            Ret::Unknown => None,
+            Ret::Is(typ) => Some(typ),
+            Ret::Last => typs.last().copied().flatten(),
+            Ret::Args(i) => {
+                let mut typs = typs.iter().skip(i);
+                match typs.next() {
+                    None | Some(None) => None,
+                    Some(Some(typ)) => {
+                        if typs.all(|t| t == &Some(*typ)) {
+                            Some(*typ)
+                        } else {
+                            None
+                        }
+                    }
+                }
+            }
+            Ret::Named(i) => match args.get(i) {
+                Some(Expr { kind: ExprKind::Constant(Value::String(s)), .. }) => {
+                    s.parse::<Typ>().ok()
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TypeError {
+    /// the expression where the error was found
+    pub id: ExprId,
+    pub msg: String,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+/// The result of type checking an expression
+#[derive(Debug, Clone, Default)]
+pub struct Types {
+    types: HashMap<ExprId, Typ>,
+    pub errors: Vec<TypeError>,
+}
+
+impl Types {
+    /// The inferred type of the specified expression, None if it
+    /// isn't known
+    pub fn get(&self, id: ExprId) -> Option<Typ> {
+        self.types.get(&id).copied()
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl fmt::Display for Types {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, e) in self.errors.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?
+            }
+            write!(f, "{}", e)?
+        }
+        Ok(())
+    }
+}
+
+fn infer<C: Ctx, E>(
+    ctx: &ExecCtx<C, E>,
+    scope: &mut Vec<Chars>,
+    spec: &Expr,
+    t: &mut Types,
+) -> Option<Typ> {
+    let typ = match &spec.kind {
+        ExprKind::Constant(v) => Typ::get(v),
+        // the value of a lambda is the display name of it's closure
+        ExprKind::Lambda { args, body } => {
+            let len = scope.len();
+            scope.extend(args.iter().cloned());
+            infer(ctx, scope, body, t);
+            scope.truncate(len);
+            Some(Typ::String)
+        }
+        ExprKind::Apply { args, function } => {
+            let typs = args.iter().map(|a| infer(ctx, scope, a, t)).collect::<Vec<_>>();
+            match ctx.signatures.get(function) {
+                Some(sig) => sig.check(spec, function, args, &typs, &mut t.errors),
+                None => {
+                    if !ctx.functions.contains_key(function)
+                        && !scope.iter().any(|n| &**n == function)
+                    {
+                        let msg = format!("unknown function {}", function);
+                        t.errors.push(TypeError { id: spec.id, msg });
+                    }
+                    None
+                }
+            }
+        }
+    };
+    if let Some(typ) = typ {
+        t.types.insert(spec.id, typ);
+    }
+    typ
+}
+
+/// Infer the type of every node in `spec` from the signatures of
+/// the builtin functions, and report any mismatches. Types that
+/// can't be known statically, e.g. the result of `load`, are never
+/// an error.
+pub fn check<C: Ctx, E>(ctx: &ExecCtx<C, E>, spec: &Expr) -> Types {
+    let mut t = Types::default();
+    infer(ctx, &mut vec![], spec, &mut t);
+    t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::TestCtx;
+
+    fn check_str(s: &str) -> (Expr, Types) {
+        let ctx: ExecCtx<TestCtx, ()> = ExecCtx::new(TestCtx::default());
+        let e = s.parse::<Expr>().unwrap();
+        let t = check(&ctx, &e);
+        (e, t)
+    }
+
+    #[test]
+    fn typecheck() {
+        let (e, t) = check_str(r#"sum(load("/a"), "x")"#);
+        assert_eq!(t.errors.len(), 1);
+        match &e.kind {
+            ExprKind::Apply { args, .. } => assert_eq!(t.errors[0].id, args[1].id),
+            _ => unreachable!(),
+        }
+        let (e, t) = check_str(r#"if(starts_with("foo", load("/a")), 1, 2)"#);
+        assert!(t.is_ok());
+        assert_eq!(t.get(e.id), Some(Typ::I64));
+        let (e, t) = check_str(r#"string_join(", ", cast("f64", load("/a")))"#);
+        assert!(t.is_ok());
+        assert_eq!(t.get(e.id), Some(Typ::String));
+        let (e, t) = check_str(r#"if(trim("a"), 1)"#);
+        assert_eq!(t.errors.len(), 1);
+        assert_eq!(t.get(e.id), Some(Typ::I64));
+        let (e, t) = check_str(r#"not(true, false)"#);
+        assert_eq!(t.errors[0].id, e.id);
+        let (_, t) = check_str(r#"apply(|x| sum(x, 1), "y")"#);
+        assert_eq!(t.errors.len(), 0);
+        let (_, t) = check_str(r#"apply(|x| sum(1, "y"), 1)"#);
+        assert_eq!(t.errors.len(), 1);
+        let (_, t) = check_str(r#"add1(load("/a"))"#);
+        assert_eq!(t.errors.len(), 1);
+        let (_, t) = check_str(r#"apply(|f| f(load("/a")), |x| x)"#);
+        assert!(t.is_ok());
+    }
+}
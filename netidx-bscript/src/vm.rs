@@ -1,7 +1,8 @@
-pub use crate::stdfn::RpcCallId;
+pub use crate::stdfn::{RpcCallId, TimerId};
 use crate::{
     expr::{Expr, ExprId, ExprKind},
-    stdfn,
+    stdfn, stdlib,
+    typ::Signature,
 };
 use fxhash::FxBuildHasher;
 use netidx::{
@@ -11,8 +12,9 @@ use netidx::{
 };
 use std::{
     collections::{HashMap, VecDeque},
-    fmt,
+    fmt, iter,
     sync::{Arc, Weak},
+    time::Duration,
 };
 
 pub struct DbgCtx {
@@ -79,6 +81,7 @@ pub enum Event<E> {
     Variable(Chars, Value),
     Netidx(SubId, Value),
     Rpc(RpcCallId, Value),
+    Timer(TimerId),
     User(E),
 }
 
@@ -91,6 +94,14 @@ pub trait Register<C: Ctx, E> {
 
 pub trait Apply<C: Ctx, E> {
     fn current(&self) -> Option<Value>;
+
+    /// The function this expression evaluates to, if any. Functions
+    /// aren't values, they are only passed along by the expressions
+    /// that implement this.
+    fn closure(&self) -> Option<Arc<stdfn::Closure>> {
+        None
+    }
+
     fn update(
         &mut self,
         ctx: &mut ExecCtx<C, E>,
@@ -125,18 +136,41 @@ pub trait Ctx {
         ref_by: ExprId,
         id: RpcCallId,
     );
+
+    /// Deliver `Event::Timer(id)` to the expression `ref_by` once
+    /// `timeout` has elapsed. Implementations should cancel the
+    /// timers of an expression when it is dropped, but expressions
+    /// must still ignore timer events they aren't expecting. The
+    /// default implementation doesn't support timers, they never
+    /// fire.
+    fn set_timer(&mut self, _id: TimerId, _timeout: Duration, _ref_by: ExprId) {}
+
+    /// Write `value` to `dv`, which was returned by
+    /// `durable_subscribe` for `path`. Implementations may use this
+    /// to check or defer writes, by default the value is just
+    /// written.
+    fn write(&mut self, _path: &Path, dv: &Dval, value: Value) {
+        dv.write(value);
+    }
 }
 
 pub struct ExecCtx<C: Ctx + 'static, E: 'static> {
     pub functions: HashMap<String, InitFn<C, E>>,
+    pub signatures: HashMap<String, Signature>,
     pub variables: HashMap<Chars, Value>,
     pub dbg_ctx: DbgCtx,
     pub user: C,
+    // lambda arguments in scope while compiling, (name, local name)
+    pub(crate) scope: Vec<(Chars, Chars)>,
+    // the functions held by variables and lambda arguments, and the
+    // value they had when they were set
+    pub(crate) closures: HashMap<Chars, (Value, Weak<stdfn::Closure>)>,
 }
 
 impl<C: Ctx, E> ExecCtx<C, E> {
     pub fn clear(&mut self) {
         self.variables.clear();
+        self.closures.clear();
         self.dbg_ctx.clear();
         self.user.clear();
     }
@@ -144,9 +178,12 @@ impl<C: Ctx, E> ExecCtx<C, E> {
     pub fn no_std(user: C) -> Self {
         ExecCtx {
             functions: HashMap::new(),
+            signatures: HashMap::new(),
             variables: HashMap::new(),
             dbg_ctx: DbgCtx::new(),
             user,
+            scope: Vec::new(),
+            closures: HashMap::new(),
         }
     }
 
@@ -155,16 +192,20 @@ impl<C: Ctx, E> ExecCtx<C, E> {
         stdfn::All::register(&mut t);
         stdfn::And::register(&mut t);
         stdfn::Any::register(&mut t);
+        stdfn::ApplyFn::register(&mut t);
         stdfn::RpcCall::register(&mut t);
         stdfn::Cast::register(&mut t);
         stdfn::Cmp::register(&mut t);
+        stdfn::Define::register(&mut t);
         stdfn::Contains::register(&mut t);
         stdfn::Count::register(&mut t);
+        stdfn::Delay::register(&mut t);
         stdfn::Divide::register(&mut t);
         stdfn::Do::register(&mut t);
         stdfn::EndsWith::register(&mut t);
         stdfn::Eval::register(&mut t);
         stdfn::Filter::register(&mut t);
+        stdfn::Fold::register(&mut t);
         stdfn::If::register(&mut t);
         stdfn::Isa::register(&mut t);
         stdfn::IsErr::register(&mut t);
@@ -172,8 +213,10 @@ impl<C: Ctx, E> ExecCtx<C, E> {
         stdfn::LoadVar::register(&mut t);
         stdfn::Max::register(&mut t);
         stdfn::Mean::register(&mut t);
+        stdfn::MeanOver::register(&mut t);
         stdfn::Min::register(&mut t);
         stdfn::Not::register(&mut t);
+        stdfn::Now::register(&mut t);
         stdfn::Or::register(&mut t);
         stdfn::Product::register(&mut t);
         stdfn::Replace::register(&mut t);
@@ -186,10 +229,25 @@ impl<C: Ctx, E> ExecCtx<C, E> {
         stdfn::StripPrefix::register(&mut t);
         stdfn::StripSuffix::register(&mut t);
         stdfn::Sum::register(&mut t);
+        stdfn::Timer::register(&mut t);
         stdfn::TrimEnd::register(&mut t);
         stdfn::Trim::register(&mut t);
         stdfn::TrimStart::register(&mut t);
         stdfn::Uniq::register(&mut t);
+        stdlib::Abs::register(&mut t);
+        stdlib::Ceil::register(&mut t);
+        stdlib::Floor::register(&mut t);
+        stdlib::FormatDateTime::register(&mut t);
+        stdlib::FormatNumber::register(&mut t);
+        stdlib::Log::register(&mut t);
+        stdlib::Pow::register(&mut t);
+        stdlib::RegexCapture::register(&mut t);
+        stdlib::RegexMatch::register(&mut t);
+        stdlib::RegexReplace::register(&mut t);
+        stdlib::Round::register(&mut t);
+        stdlib::Split::register(&mut t);
+        stdlib::Sqrt::register(&mut t);
+        stdlib::Substring::register(&mut t);
         t
     }
 }
@@ -217,27 +275,85 @@ impl<C: Ctx, E> Node<C, E> {
                 ctx.dbg_ctx.add_event(*id, v.clone());
                 Node::Constant(spec.clone(), v.clone())
             }
+            Expr { kind: ExprKind::Lambda { args, body }, id } => {
+                let function = stdfn::Lambda::new(ctx, args.clone(), (**body).clone());
+                if let Some(v) = Apply::<C, E>::current(&function) {
+                    ctx.dbg_ctx.add_event(*id, v)
+                }
+                Node::Apply { spec, args: vec![], function: Box::new(function) }
+            }
+            Expr { kind: ExprKind::Apply { args, function }, .. }
+                if function == "load_var" =>
+            {
+                let local = match &args[..] {
+                    [Expr { kind: ExprKind::Constant(Value::String(name)), .. }] => {
+                        ctx.scope.iter().rev().find(|(n, _)| n == name)
+                    }
+                    _ => None,
+                };
+                match local {
+                    None => Node::compile_apply(ctx, spec.clone(), top_id),
+                    Some((_, local)) => {
+                        let function = Box::new(stdfn::Local::new(local.clone()));
+                        Node::Apply { spec, args: vec![], function }
+                    }
+                }
+            }
+            Expr { kind: ExprKind::Apply { args, function }, .. }
+                if !ctx.functions.contains_key(function)
+                    && ctx.scope.iter().any(|(n, _)| &**n == function) =>
+            {
+                // call a lambda argument
+                let local = ctx.scope.iter().rev().find(|(n, _)| &**n == function);
+                let local = local.map(|(_, l)| l.clone()).unwrap();
+                let f = Node::Apply {
+                    spec: ExprKind::Constant(Value::from(function.clone())).to_expr(),
+                    args: vec![],
+                    function: Box::new(stdfn::Local::new(local)),
+                };
+                let args = iter::once(f)
+                    .chain(args.iter().map(|s| Node::compile_int(ctx, s.clone(), top_id)))
+                    .collect();
+                Node::apply(ctx, spec.clone(), "apply", args, top_id)
+            }
+            Expr { kind: ExprKind::Apply { .. }, .. } => {
+                Node::compile_apply(ctx, spec.clone(), top_id)
+            }
+        }
+    }
+
+    fn compile_apply(ctx: &mut ExecCtx<C, E>, spec: Expr, top_id: ExprId) -> Self {
+        match &spec {
+            Expr { kind: ExprKind::Constant(_), .. }
+            | Expr { kind: ExprKind::Lambda { .. }, .. } => unreachable!(),
             Expr { kind: ExprKind::Apply { args, function }, .. } => {
                 let args: Vec<Node<C, E>> = args
                     .iter()
                     .map(|spec| Node::compile_int(ctx, spec.clone(), top_id))
                     .collect();
-                match ctx.functions.get(function).map(Arc::clone) {
-                    None => Node::Error(
-                        spec.clone(),
-                        Value::Error(Chars::from(format!(
-                            "unknown function {}",
-                            function
-                        ))),
-                    ),
-                    Some(init) => {
-                        let function = init(ctx, &args, top_id);
-                        if let Some(v) = function.current() {
-                            ctx.dbg_ctx.add_event(spec.id, v)
-                        }
-                        Node::Apply { spec, args, function }
-                    }
+                Node::apply(ctx, spec.clone(), function, args, top_id)
+            }
+        }
+    }
+
+    fn apply(
+        ctx: &mut ExecCtx<C, E>,
+        spec: Expr,
+        function: &str,
+        args: Vec<Node<C, E>>,
+        top_id: ExprId,
+    ) -> Self {
+        match ctx.functions.get(function).map(Arc::clone) {
+            None => Node::Error(
+                spec,
+                Value::Error(Chars::from(format!("unknown function {}", function))),
+            ),
+            Some(init) => {
+                let function = init(ctx, &args, top_id);
+                if let Some(v) = function.current() {
+                    ctx.dbg_ctx.add_event(spec.id, v)
                 }
+                Node::Apply { spec, args, function }
             }
         }
     }
@@ -255,6 +371,13 @@ impl<C: Ctx, E> Node<C, E> {
         }
     }
 
+    pub fn closure(&self) -> Option<Arc<stdfn::Closure>> {
+        match self {
+            Node::Error(_, _) | Node::Constant(_, _) => None,
+            Node::Apply { function, .. } => function.closure(),
+        }
+    }
+
     pub fn update(&mut self, ctx: &mut ExecCtx<C, E>, event: &Event<E>) -> Option<Value> {
         match self {
             Node::Error(_, v) => Some(v.clone()),
@@ -269,3 +392,213 @@ impl<C: Ctx, E> Node<C, E> {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use netidx::{config::Config, resolver::Auth, subscriber::Subscriber};
+    use tokio::runtime::Runtime;
+
+    type RpcCall = (Path, Vec<(Chars, Value)>, RpcCallId);
+
+    pub(crate) struct TestCtx {
+        pub(crate) timers: Vec<(TimerId, Duration)>,
+        pub(crate) rpcs: Vec<RpcCall>,
+        pub(crate) writes: Vec<(Path, Value)>,
+        subscriber: Subscriber,
+        _rt: Runtime,
+    }
+
+    impl Default for TestCtx {
+        // the subscriber needs a runtime for it's background task,
+        // there is no resolver, so subscriptions never succeed
+        fn default() -> Self {
+            let rt = Runtime::new().unwrap();
+            let _guard = rt.enter();
+            let cfg = Config::load("../cfg/simple.json").unwrap();
+            let subscriber = Subscriber::new(cfg, Auth::Anonymous).unwrap();
+            TestCtx { timers: vec![], rpcs: vec![], writes: vec![], subscriber, _rt: rt }
+        }
+    }
+
+    impl Ctx for TestCtx {
+        fn clear(&mut self) {}
+
+        fn durable_subscribe(
+            &mut self,
+            _flags: UpdatesFlags,
+            path: Path,
+            _ref_by: ExprId,
+        ) -> Dval {
+            self.subscriber.durable_subscribe(path)
+        }
+
+        fn ref_var(&mut self, _name: Chars, _ref_by: ExprId) {}
+
+        fn set_var(
+            &mut self,
+            variables: &mut HashMap<Chars, Value>,
+            name: Chars,
+            value: Value,
+        ) {
+            variables.insert(name, value);
+        }
+
+        fn call_rpc(
+            &mut self,
+            name: Path,
+            args: Vec<(Chars, Value)>,
+            _ref_by: ExprId,
+            id: RpcCallId,
+        ) {
+            self.rpcs.push((name, args, id))
+        }
+
+        fn set_timer(&mut self, id: TimerId, timeout: Duration, _ref_by: ExprId) {
+            self.timers.push((id, timeout))
+        }
+
+        fn write(&mut self, path: &Path, _dv: &Dval, value: Value) {
+            self.writes.push((path.clone(), value))
+        }
+    }
+
+    fn compile(ctx: &mut ExecCtx<TestCtx, ()>, s: &str) -> Node<TestCtx, ()> {
+        Node::compile(ctx, s.parse::<Expr>().unwrap())
+    }
+
+    fn var(
+        ctx: &mut ExecCtx<TestCtx, ()>,
+        node: &mut Node<TestCtx, ()>,
+        n: &str,
+        v: Value,
+    ) {
+        let name = Chars::from(String::from(n));
+        ctx.variables.insert(name.clone(), v.clone());
+        node.update(ctx, &Event::Variable(name, v));
+    }
+
+    #[test]
+    fn lambda() {
+        let mut ctx = ExecCtx::new(TestCtx::default());
+        let n = compile(&mut ctx, "apply(|x, y| sum(x, y), 1, 2)");
+        assert_eq!(n.current(), Some(Value::I64(3)));
+        let mut n = compile(&mut ctx, "apply(|x| product(x, 2), load_var(\"val\"))");
+        var(&mut ctx, &mut n, "val", Value::I64(21));
+        assert_eq!(n.current(), Some(Value::I64(42)));
+        let n = compile(&mut ctx, "add(1, load_var(\"val\"))");
+        assert!(matches!(n, Node::Error(_, _)));
+        let _def = compile(&mut ctx, "define(\"add\", |a, b| sum(a, b))");
+        let mut n = compile(&mut ctx, "add(1, load_var(\"val\"))");
+        assert_eq!(n.current(), Some(Value::I64(22)));
+        var(&mut ctx, &mut n, "val", Value::I64(2));
+        assert_eq!(n.current(), Some(Value::I64(3)));
+        let n = compile(&mut ctx, "apply(|x| x, 1, 2)");
+        assert!(matches!(n.current(), Some(Value::Error(_))));
+        let n = compile(&mut ctx, "apply(|f, x| f(x), |x| product(x, 3), 2)");
+        assert_eq!(n.current(), Some(Value::I64(6)));
+    }
+
+    #[test]
+    fn closure() {
+        let mut ctx = ExecCtx::new(TestCtx::default());
+        // the lambda captures x where it's written, not where it's called
+        let mut n = compile(
+            &mut ctx,
+            "apply(|x| store_var(\"fx\", |y| sum(x, y)), load_var(\"xv\"))",
+        );
+        var(&mut ctx, &mut n, "xv", Value::I64(10));
+        let f = ctx.variables["fx"].clone();
+        let mut m = compile(&mut ctx, "apply(load_var(\"fx\"), load_var(\"yv\"))");
+        var(&mut ctx, &mut m, "yv", Value::I64(1));
+        assert_eq!(m.current(), Some(Value::I64(11)));
+        // a new value of x is a new closure, calling it reuses the body
+        var(&mut ctx, &mut n, "xv", Value::I64(20));
+        assert_ne!(ctx.variables["fx"], f);
+        let f = ctx.variables["fx"].clone();
+        var(&mut ctx, &mut m, "fx", f);
+        assert_eq!(m.current(), Some(Value::I64(21)));
+        // nested lambdas see the current value of captured arguments
+        let mut n =
+            compile(&mut ctx, "apply(|x| apply(|y| sum(x, y), 1), load_var(\"xv\"))");
+        var(&mut ctx, &mut n, "xv", Value::I64(1));
+        assert_eq!(n.current(), Some(Value::I64(2)));
+        var(&mut ctx, &mut n, "xv", Value::I64(5));
+        assert_eq!(n.current(), Some(Value::I64(6)));
+        let n = compile(&mut ctx, "apply(\"|x| x\", 1)");
+        assert!(matches!(n.current(), Some(Value::Error(_))));
+        // the name of a closure is just a string, it can't be called
+        let f = match ctx.variables["fx"].clone() {
+            Value::String(f) => f,
+            v => panic!("unexpected {}", v),
+        };
+        let n = compile(&mut ctx, &format!("apply(\"{}\", 1)", f));
+        assert!(matches!(n.current(), Some(Value::Error(_))));
+        let mut n = compile(&mut ctx, "apply(load_var(\"gv\"), 1)");
+        var(&mut ctx, &mut n, "gv", Value::String(f.clone()));
+        assert!(matches!(n.current(), Some(Value::Error(_))));
+        // a variable that held a function and was then set from
+        // outside doesn't keep the function
+        var(&mut ctx, &mut m, "fx", Value::from("fx"));
+        assert!(matches!(m.current(), Some(Value::Error(_))));
+    }
+
+    #[test]
+    fn fold() {
+        let mut ctx = ExecCtx::new(TestCtx::default());
+        let mut n = compile(&mut ctx, "fold(|acc, v| sum(acc, v), 0, load_var(\"val\"))");
+        assert_eq!(n.current(), Some(Value::I64(0)));
+        for i in 1..=4 {
+            var(&mut ctx, &mut n, "val", Value::I64(i));
+        }
+        assert_eq!(n.current(), Some(Value::I64(10)));
+    }
+
+    fn fire(ctx: &mut ExecCtx<TestCtx, ()>, node: &mut Node<TestCtx, ()>) {
+        let (id, _) = ctx.user.timers.remove(0);
+        node.update(ctx, &Event::Timer(id));
+    }
+
+    #[test]
+    fn timers() {
+        let mut ctx = ExecCtx::new(TestCtx::default());
+        let mut n = compile(&mut ctx, "timer(duration:1.s, 2)");
+        assert_eq!(n.current(), None);
+        assert_eq!(ctx.user.timers.len(), 1);
+        assert_eq!(ctx.user.timers[0].1, Duration::from_secs(1));
+        fire(&mut ctx, &mut n);
+        assert!(matches!(n.current(), Some(Value::DateTime(_))));
+        fire(&mut ctx, &mut n);
+        assert!(ctx.user.timers.is_empty());
+        let mut n = compile(&mut ctx, "delay(load_var(\"val\"), 5)");
+        var(&mut ctx, &mut n, "val", Value::I64(1));
+        var(&mut ctx, &mut n, "val", Value::I64(2));
+        assert_eq!(n.current(), None);
+        assert_eq!(ctx.user.timers[0].1, Duration::from_secs(5));
+        fire(&mut ctx, &mut n);
+        assert_eq!(n.current(), Some(Value::I64(1)));
+        fire(&mut ctx, &mut n);
+        assert_eq!(n.current(), Some(Value::I64(2)));
+        let mut n = compile(&mut ctx, "mean_over(load_var(\"val\"), 3600)");
+        for i in 1..=3 {
+            var(&mut ctx, &mut n, "val", Value::I64(i));
+        }
+        assert_eq!(n.current(), Some(Value::F64(2.)));
+        assert_eq!(ctx.user.timers.len(), 1);
+    }
+    #[test]
+    fn ctx() {
+        let mut ctx = ExecCtx::new(TestCtx::default());
+        let mut n = compile(&mut ctx, r#"call(true, "/rpc", "arg", 1)"#);
+        assert_eq!(ctx.user.rpcs.len(), 1);
+        let (name, args, id) = ctx.user.rpcs.remove(0);
+        assert_eq!(name, Path::from("/rpc"));
+        assert_eq!(args, vec![(Chars::from("arg"), Value::I64(1))]);
+        assert_eq!(n.update(&mut ctx, &Event::Rpc(id, Value::Ok)), Some(Value::Ok));
+        let n = compile(&mut ctx, r#"load("/a")"#);
+        assert!(matches!(n.current(), Some(Value::Error(_))));
+        let mut n = compile(&mut ctx, r#"store("/b", load_var("vv"))"#);
+        var(&mut ctx, &mut n, "vv", Value::I64(42));
+        assert_eq!(ctx.user.writes, vec![(Path::from("/b"), Value::I64(42))]);
+    }
+}
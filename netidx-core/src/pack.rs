@@ -23,6 +23,7 @@ pub enum PackError {
     UnknownTag,
     TooBig,
     InvalidFormat,
+    BufferShort,
 }
 
 impl fmt::Display for PackError {
@@ -33,6 +34,17 @@ impl fmt::Display for PackError {
 
 impl error::Error for PackError {}
 
+/// Fail with `BufferShort` if `buf` holds fewer than `len`
+/// bytes. Decoders must check before reading fixed size values,
+/// reading past the end of a `Buf` panics.
+pub fn check_len(buf: &impl Buf, len: usize) -> Result<(), PackError> {
+    if buf.remaining() < len {
+        Err(PackError::BufferShort)
+    } else {
+        Ok(())
+    }
+}
+
 pub trait Pack {
     fn const_encoded_len() -> Option<usize> {
         None
@@ -113,13 +125,15 @@ impl Pack for net::SocketAddr {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => {
+                check_len(buf, 6)?;
                 let ip = net::Ipv4Addr::from(u32::to_be_bytes(buf.get_u32()));
                 let port = buf.get_u16();
                 Ok(net::SocketAddr::V4(net::SocketAddrV4::new(ip, port)))
             }
             1 => {
+                check_len(buf, 26)?;
                 let mut segments = [0u16; 8];
                 for i in 0..8 {
                     segments[i] = buf.get_u16();
@@ -275,7 +289,7 @@ pub fn decode_varint(buf: &mut impl Buf) -> Result<u64, PackError> {
     let mut value = 0;
     let mut i = 0;
     while i < 10 {
-        let byte = buf.get_u8();
+        let byte = <u8 as Pack>::decode(buf)?;
         value |= u64::from(byte & 0x7F) << (i * 7);
         if byte <= 0x7F {
             return Ok(value);
@@ -299,6 +313,7 @@ impl Pack for u128 {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        check_len(buf, mem::size_of::<u128>())?;
         Ok(buf.get_u128())
     }
 }
@@ -317,6 +332,7 @@ impl Pack for u64 {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        check_len(buf, mem::size_of::<u64>())?;
         Ok(buf.get_u64())
     }
 }
@@ -366,10 +382,30 @@ impl Pack for u32 {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        check_len(buf, mem::size_of::<u32>())?;
         Ok(buf.get_u32())
     }
 }
 
+impl Pack for u8 {
+    fn const_encoded_len() -> Option<usize> {
+        Some(mem::size_of::<u8>())
+    }
+
+    fn encoded_len(&self) -> usize {
+        mem::size_of::<u8>()
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) -> Result<(), PackError> {
+        Ok(buf.put_u8(*self))
+    }
+
+    fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        check_len(buf, mem::size_of::<u8>())?;
+        Ok(buf.get_u8())
+    }
+}
+
 impl Pack for u16 {
     fn const_encoded_len() -> Option<usize> {
         Some(mem::size_of::<u16>())
@@ -384,6 +420,7 @@ impl Pack for u16 {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        check_len(buf, mem::size_of::<u16>())?;
         Ok(buf.get_u16())
     }
 }
@@ -405,7 +442,8 @@ impl<T: Pack> Pack for Vec<T> {
 
     fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
         let elts = decode_varint(buf)? as usize;
-        let mut data = Vec::with_capacity(elts);
+        // don't trust elts enough to allocate it all up front
+        let mut data = Vec::with_capacity(elts.min(buf.remaining()));
         for _ in 0..elts {
             data.push(<T as Pack>::decode(buf)?);
         }
@@ -444,7 +482,8 @@ where
 
     fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
         let elts = decode_varint(buf)? as usize;
-        let mut data = HashMap::with_capacity_and_hasher(elts, R::default());
+        let mut data =
+            HashMap::with_capacity_and_hasher(elts.min(buf.remaining()), R::default());
         for _ in 0..elts {
             let k = <K as Pack>::decode(buf)?;
             let v = <V as Pack>::decode(buf)?;
@@ -483,7 +522,7 @@ impl<T: Pack> Pack for Option<T> {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(None),
             1 => Ok(Some(<T as Pack>::decode(buf)?)),
             _ => return Err(PackError::UnknownTag),
@@ -522,7 +561,7 @@ impl Pack for bool {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(false),
             1 => Ok(true),
             _ => Err(PackError::UnknownTag),
@@ -545,6 +584,7 @@ impl Pack for DateTime<Utc> {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        check_len(buf, <DateTime<Utc> as Pack>::const_encoded_len().unwrap())?;
         let ts = buf.get_i64();
         let ns = buf.get_u32();
         let ndt = NaiveDateTime::from_timestamp_opt(ts, ns)
@@ -568,6 +608,7 @@ impl Pack for Duration {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        check_len(buf, <Duration as Pack>::const_encoded_len().unwrap())?;
         let secs = buf.get_u64();
         let ns = buf.get_u32();
         Ok(Duration::new(secs, ns))
@@ -51,6 +51,12 @@ pub enum Hello {
     /// context will be thrown away and the old one will continue
     /// to be associated with the write address.
     ResolverAuthenticate(SocketAddr, Bytes),
+    /// A Noise protocol handshake message. The subscriber sends the
+    /// first message, and if the publisher is willing it replies
+    /// with the second. After that all traffic is encrypted, and the
+    /// subscriber is anonymous until it presents a signed
+    /// subscription token.
+    Noise(Bytes),
 }
 
 impl Pack for Hello {
@@ -62,6 +68,7 @@ impl Pack for Hello {
                 <SocketAddr as Pack>::encoded_len(addr)
                     + <Bytes as Pack>::encoded_len(tok)
             }
+            Hello::Noise(msg) => <Bytes as Pack>::encoded_len(msg),
         }
     }
 
@@ -77,11 +84,15 @@ impl Pack for Hello {
                 <SocketAddr as Pack>::encode(id, buf)?;
                 <Bytes as Pack>::encode(tok, buf)
             }
+            Hello::Noise(msg) => {
+                buf.put_u8(3);
+                <Bytes as Pack>::encode(msg, buf)
+            }
         }
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(Hello::Anonymous),
             1 => Ok(Hello::Token(<Bytes as Pack>::decode(buf)?)),
             2 => {
@@ -89,6 +100,7 @@ impl Pack for Hello {
                 let tok = <Bytes as Pack>::decode(buf)?;
                 Ok(Hello::ResolverAuthenticate(addr, tok))
             }
+            3 => Ok(Hello::Noise(<Bytes as Pack>::decode(buf)?)),
             _ => Err(PackError::UnknownTag),
         }
     }
@@ -159,7 +171,7 @@ impl Pack for To {
     }
 
     fn decode(buf: &mut impl Buf) -> anyhow::Result<Self, PackError> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => {
                 let path = <Path as Pack>::decode(buf)?;
                 let resolver = <SocketAddr as Pack>::decode(buf)?;
@@ -258,7 +270,7 @@ impl Pack for From {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(From::NoSuchValue(<Path as Pack>::decode(buf)?)),
             1 => Ok(From::Denied(<Path as Pack>::decode(buf)?)),
             2 => Ok(From::Unsubscribed(Id::decode(buf)?)),
@@ -18,6 +18,13 @@ use std::{
 type Error = PackError;
 pub type Result<T> = result::Result<T, Error>;
 
+/// The resolver protocol version. Both sides send their version when
+/// they connect, and use the lower of the two.
+///
+/// - 1: the original protocol
+/// - 2: publisher tokens carry the subscriber's principal
+pub const PROTOCOL_VERSION: u64 = 2;
+
 atomic_id!(CtxId);
 
 impl Pack for CtxId {
@@ -39,6 +46,10 @@ pub enum ClientAuthRead {
     Anonymous,
     Reuse(CtxId),
     Initiate(Bytes),
+    /// Start a Noise handshake, the server replies with the
+    /// handshake's answer, and the client then sends it's bearer
+    /// token over the encrypted connection.
+    Token(Bytes),
 }
 
 impl Pack for ClientAuthRead {
@@ -47,6 +58,7 @@ impl Pack for ClientAuthRead {
             ClientAuthRead::Anonymous => 0,
             ClientAuthRead::Reuse(ref i) => Pack::encoded_len(i),
             ClientAuthRead::Initiate(ref b) => Pack::encoded_len(b),
+            ClientAuthRead::Token(ref b) => Pack::encoded_len(b),
         }
     }
 
@@ -61,14 +73,19 @@ impl Pack for ClientAuthRead {
                 buf.put_u8(2);
                 Ok(<Bytes as Pack>::encode(tok, buf)?)
             }
+            ClientAuthRead::Token(ref tok) => {
+                buf.put_u8(3);
+                Ok(<Bytes as Pack>::encode(tok, buf)?)
+            }
         }
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(ClientAuthRead::Anonymous),
             1 => Ok(ClientAuthRead::Reuse(<CtxId as Pack>::decode(buf)?)),
             2 => Ok(ClientAuthRead::Initiate(<Bytes as Pack>::decode(buf)?)),
+            3 => Ok(ClientAuthRead::Token(<Bytes as Pack>::decode(buf)?)),
             _ => return Err(Error::UnknownTag),
         }
     }
@@ -79,6 +96,10 @@ pub enum ClientAuthWrite {
     Anonymous,
     Reuse,
     Initiate { spn: Option<Chars>, token: Bytes },
+    /// Start a Noise handshake, the server replies with the
+    /// handshake's answer, and the client then sends it's bearer
+    /// token over the encrypted connection.
+    Token(Bytes),
 }
 
 impl Pack for ClientAuthWrite {
@@ -90,6 +111,7 @@ impl Pack for ClientAuthWrite {
                 <Option<Chars> as Pack>::encoded_len(spn)
                     + <Bytes as Pack>::encoded_len(token)
             }
+            ClientAuthWrite::Token(token) => <Bytes as Pack>::encoded_len(token),
         }
     }
 
@@ -102,11 +124,15 @@ impl Pack for ClientAuthWrite {
                 <Option<Chars> as Pack>::encode(spn, buf)?;
                 <Bytes as Pack>::encode(token, buf)
             }
+            ClientAuthWrite::Token(token) => {
+                buf.put_u8(3);
+                <Bytes as Pack>::encode(token, buf)
+            }
         }
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(ClientAuthWrite::Anonymous),
             1 => Ok(ClientAuthWrite::Reuse),
             2 => {
@@ -114,6 +140,7 @@ impl Pack for ClientAuthWrite {
                 let token = <Bytes as Pack>::decode(buf)?;
                 Ok(ClientAuthWrite::Initiate { spn, token })
             }
+            3 => Ok(ClientAuthWrite::Token(<Bytes as Pack>::decode(buf)?)),
             _ => Err(Error::UnknownTag),
         }
     }
@@ -185,7 +212,7 @@ impl Pack for ClientHello {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(ClientHello::ReadOnly(ClientAuthRead::decode(buf)?)),
             1 => Ok(ClientHello::WriteOnly(ClientHelloWrite::decode(buf)?)),
             _ => Err(Error::UnknownTag),
@@ -198,6 +225,10 @@ pub enum ServerHelloRead {
     Anonymous,
     Reused,
     Accepted(Bytes, CtxId),
+    /// The client's token was accepted
+    Token,
+    /// The server refused the connection, e.g. because of a limit
+    Error(Chars),
 }
 
 impl Pack for ServerHelloRead {
@@ -208,6 +239,8 @@ impl Pack for ServerHelloRead {
             ServerHelloRead::Accepted(tok, id) => {
                 <Bytes as Pack>::encoded_len(tok) + CtxId::encoded_len(id)
             }
+            ServerHelloRead::Token => 0,
+            ServerHelloRead::Error(e) => <Chars as Pack>::encoded_len(e),
         }
     }
 
@@ -220,11 +253,16 @@ impl Pack for ServerHelloRead {
                 <Bytes as Pack>::encode(tok, buf)?;
                 CtxId::encode(id, buf)
             }
+            ServerHelloRead::Token => Ok(buf.put_u8(3)),
+            ServerHelloRead::Error(e) => {
+                buf.put_u8(4);
+                <Chars as Pack>::encode(e, buf)
+            }
         }
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(ServerHelloRead::Anonymous),
             1 => Ok(ServerHelloRead::Reused),
             2 => {
@@ -232,6 +270,8 @@ impl Pack for ServerHelloRead {
                 let id = CtxId::decode(buf)?;
                 Ok(ServerHelloRead::Accepted(tok, id))
             }
+            3 => Ok(ServerHelloRead::Token),
+            4 => Ok(ServerHelloRead::Error(<Chars as Pack>::decode(buf)?)),
             _ => Err(Error::UnknownTag),
         }
     }
@@ -242,6 +282,10 @@ pub enum ServerAuthWrite {
     Anonymous,
     Reused,
     Accepted(Bytes),
+    /// The client's token was accepted
+    Token,
+    /// The server refused the connection, e.g. because of a limit
+    Error(Chars),
 }
 
 impl Pack for ServerAuthWrite {
@@ -250,6 +294,8 @@ impl Pack for ServerAuthWrite {
             ServerAuthWrite::Anonymous => 0,
             ServerAuthWrite::Reused => 0,
             ServerAuthWrite::Accepted(b) => <Bytes as Pack>::encoded_len(b),
+            ServerAuthWrite::Token => 0,
+            ServerAuthWrite::Error(e) => <Chars as Pack>::encoded_len(e),
         }
     }
 
@@ -261,17 +307,24 @@ impl Pack for ServerAuthWrite {
                 buf.put_u8(2);
                 <Bytes as Pack>::encode(b, buf)
             }
+            ServerAuthWrite::Token => Ok(buf.put_u8(3)),
+            ServerAuthWrite::Error(e) => {
+                buf.put_u8(4);
+                <Chars as Pack>::encode(e, buf)
+            }
         }
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(ServerAuthWrite::Anonymous),
             1 => Ok(ServerAuthWrite::Reused),
             2 => {
                 let tok = <Bytes as Pack>::decode(buf)?;
                 Ok(ServerAuthWrite::Accepted(tok))
             }
+            3 => Ok(ServerAuthWrite::Token),
+            4 => Ok(ServerAuthWrite::Error(<Chars as Pack>::decode(buf)?)),
             _ => Err(Error::UnknownTag),
         }
     }
@@ -339,13 +392,63 @@ impl Pack for ReadyForOwnershipCheck {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(ReadyForOwnershipCheck),
             _ => Err(PackError::UnknownTag),
         }
     }
 }
 
+/// Administrative requests. They are only allowed for users with
+/// the admin permission on the root of the resolver server, and are
+/// answered by the server they are sent to, even in a cluster.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Admin {
+    /// List the clients of the server
+    ListSessions,
+    /// List the paths published by the publisher at the address
+    Published(SocketAddr),
+    /// Disconnect the publisher at the address and unpublish
+    /// everything it published, replying with the list of
+    /// unpublished paths. A live publisher will reconnect and publish
+    /// again.
+    Clear(SocketAddr),
+}
+
+impl Pack for Admin {
+    fn encoded_len(&self) -> usize {
+        1 + match self {
+            Admin::ListSessions => 0,
+            Admin::Published(addr) | Admin::Clear(addr) => {
+                <SocketAddr as Pack>::encoded_len(addr)
+            }
+        }
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) -> Result<()> {
+        match self {
+            Admin::ListSessions => Ok(buf.put_u8(0)),
+            Admin::Published(addr) => {
+                buf.put_u8(1);
+                <SocketAddr as Pack>::encode(addr, buf)
+            }
+            Admin::Clear(addr) => {
+                buf.put_u8(2);
+                <SocketAddr as Pack>::encode(addr, buf)
+            }
+        }
+    }
+
+    fn decode(buf: &mut impl Buf) -> Result<Self> {
+        match <u8 as Pack>::decode(buf)? {
+            0 => Ok(Admin::ListSessions),
+            1 => Ok(Admin::Published(<SocketAddr as Pack>::decode(buf)?)),
+            2 => Ok(Admin::Clear(<SocketAddr as Pack>::decode(buf)?)),
+            _ => Err(Error::UnknownTag),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ToRead {
     /// Resolve path to addresses/ports
@@ -358,6 +461,15 @@ pub enum ToRead {
     ListMatching(GlobSet),
     /// Get the change nr for the specified path
     GetChangeNr(Path),
+    /// An administrative request
+    Admin(Admin),
+    /// Watch for paths matching the glob set being published or
+    /// unpublished. The server replies with a `ListMatching` of the
+    /// published paths that match now, and then pushes `Changed`
+    /// messages as they change, sending an empty one at least every
+    /// `reader_ttl / 2`. The connection can't be used for anything
+    /// else afterwards.
+    Watch(GlobSet),
 }
 
 impl Pack for ToRead {
@@ -367,7 +479,10 @@ impl Pack for ToRead {
             | ToRead::List(path)
             | ToRead::Table(path)
             | ToRead::GetChangeNr(path) => <Path as Pack>::encoded_len(path),
-            ToRead::ListMatching(g) => <GlobSet as Pack>::encoded_len(g),
+            ToRead::ListMatching(g) | ToRead::Watch(g) => {
+                <GlobSet as Pack>::encoded_len(g)
+            }
+            ToRead::Admin(a) => <Admin as Pack>::encoded_len(a),
         }
     }
 
@@ -393,16 +508,26 @@ impl Pack for ToRead {
                 buf.put_u8(4);
                 <Path as Pack>::encode(path, buf)
             }
+            ToRead::Admin(a) => {
+                buf.put_u8(5);
+                <Admin as Pack>::encode(a, buf)
+            }
+            ToRead::Watch(globs) => {
+                buf.put_u8(6);
+                <GlobSet as Pack>::encode(globs, buf)
+            }
         }
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(ToRead::Resolve(<Path as Pack>::decode(buf)?)),
             1 => Ok(ToRead::List(<Path as Pack>::decode(buf)?)),
             2 => Ok(ToRead::Table(<Path as Pack>::decode(buf)?)),
             3 => Ok(ToRead::ListMatching(<GlobSet as Pack>::decode(buf)?)),
             4 => Ok(ToRead::GetChangeNr(<Path as Pack>::decode(buf)?)),
+            5 => Ok(ToRead::Admin(<Admin as Pack>::decode(buf)?)),
+            6 => Ok(ToRead::Watch(<GlobSet as Pack>::decode(buf)?)),
             _ => Err(Error::UnknownTag),
         }
     }
@@ -584,6 +709,66 @@ impl Pack for GetChangeNr {
     }
 }
 
+/// Paths that matched a watch and were published, or were
+/// unpublished by their last publisher
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Changed {
+    pub added: Pooled<Vec<Path>>,
+    pub removed: Pooled<Vec<Path>>,
+}
+
+impl Pack for Changed {
+    fn encoded_len(&self) -> usize {
+        <Pooled<Vec<Path>> as Pack>::encoded_len(&self.added)
+            + <Pooled<Vec<Path>> as Pack>::encoded_len(&self.removed)
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) -> Result<()> {
+        <Pooled<Vec<Path>> as Pack>::encode(&self.added, buf)?;
+        <Pooled<Vec<Path>> as Pack>::encode(&self.removed, buf)
+    }
+
+    fn decode(buf: &mut impl Buf) -> Result<Self> {
+        let added = <Pooled<Vec<Path>> as Pack>::decode(buf)?;
+        let removed = <Pooled<Vec<Path>> as Pack>::decode(buf)?;
+        Ok(Changed { added, removed })
+    }
+}
+
+/// A client of a resolver server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Session {
+    /// The address the client is connected from. None if it is a
+    /// publisher that has disconnected, but whose paths have not
+    /// expired yet.
+    pub addr: Option<SocketAddr>,
+    /// The address the client publishes on, None for subscribers
+    pub write_addr: Option<SocketAddr>,
+    /// The user the client authenticated as, None if anonymous
+    pub user: Option<Chars>,
+}
+
+impl Pack for Session {
+    fn encoded_len(&self) -> usize {
+        <Option<SocketAddr> as Pack>::encoded_len(&self.addr)
+            + <Option<SocketAddr> as Pack>::encoded_len(&self.write_addr)
+            + <Option<Chars> as Pack>::encoded_len(&self.user)
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) -> Result<()> {
+        <Option<SocketAddr> as Pack>::encode(&self.addr, buf)?;
+        <Option<SocketAddr> as Pack>::encode(&self.write_addr, buf)?;
+        <Option<Chars> as Pack>::encode(&self.user, buf)
+    }
+
+    fn decode(buf: &mut impl Buf) -> Result<Self> {
+        let addr = <Option<SocketAddr> as Pack>::decode(buf)?;
+        let write_addr = <Option<SocketAddr> as Pack>::decode(buf)?;
+        let user = <Option<Chars> as Pack>::decode(buf)?;
+        Ok(Session { addr, write_addr, user })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum FromRead {
     Resolved(Resolved),
@@ -594,6 +779,8 @@ pub enum FromRead {
     Referral(Referral),
     Denied,
     Error(Chars),
+    Sessions(Pooled<Vec<Session>>),
+    Changed(Changed),
 }
 
 impl Pack for FromRead {
@@ -607,6 +794,8 @@ impl Pack for FromRead {
             FromRead::GetChangeNr(m) => <GetChangeNr as Pack>::encoded_len(m),
             FromRead::Denied => 0,
             FromRead::Error(e) => <Chars as Pack>::encoded_len(e),
+            FromRead::Sessions(s) => <Pooled<Vec<Session>> as Pack>::encoded_len(s),
+            FromRead::Changed(c) => <Changed as Pack>::encoded_len(c),
         }
     }
 
@@ -641,11 +830,19 @@ impl Pack for FromRead {
                 buf.put_u8(7);
                 <GetChangeNr as Pack>::encode(l, buf)
             }
+            FromRead::Sessions(s) => {
+                buf.put_u8(8);
+                <Pooled<Vec<Session>> as Pack>::encode(s, buf)
+            }
+            FromRead::Changed(c) => {
+                buf.put_u8(9);
+                <Changed as Pack>::encode(c, buf)
+            }
         }
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(FromRead::Resolved(Resolved::decode(buf)?)),
             1 => Ok(FromRead::List(<Pooled<Vec<Path>> as Pack>::decode(buf)?)),
             2 => Ok(FromRead::Table(<Table as Pack>::decode(buf)?)),
@@ -654,6 +851,8 @@ impl Pack for FromRead {
             5 => Ok(FromRead::Error(<Chars as Pack>::decode(buf)?)),
             6 => Ok(FromRead::ListMatching(<ListMatching as Pack>::decode(buf)?)),
             7 => Ok(FromRead::GetChangeNr(<GetChangeNr as Pack>::decode(buf)?)),
+            8 => Ok(FromRead::Sessions(<Pooled<Vec<Session>> as Pack>::decode(buf)?)),
+            9 => Ok(FromRead::Changed(<Changed as Pack>::decode(buf)?)),
             _ => Err(Error::UnknownTag),
         }
     }
@@ -731,7 +930,7 @@ impl Pack for ToWrite {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(ToWrite::Publish(<Path as Pack>::decode(buf)?)),
             1 => Ok(ToWrite::PublishDefault(<Path as Pack>::decode(buf)?)),
             2 => Ok(ToWrite::Unpublish(<Path as Pack>::decode(buf)?)),
@@ -792,7 +991,7 @@ impl Pack for FromWrite {
     }
 
     fn decode(buf: &mut impl Buf) -> Result<Self> {
-        match buf.get_u8() {
+        match <u8 as Pack>::decode(buf)? {
             0 => Ok(FromWrite::Published),
             1 => Ok(FromWrite::Unpublished),
             2 => Ok(FromWrite::Referral(<Referral as Pack>::decode(buf)?)),
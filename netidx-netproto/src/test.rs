@@ -12,6 +12,10 @@ use std::fmt::Debug;
 fn check<T: Pack + Debug + PartialEq>(t: T) {
     let mut bytes = pack(&t).expect("encode failed");
     assert_eq!(t.encoded_len(), BytesMut::len(&bytes));
+    // a truncated message must be an error, not a panic
+    for i in (0..bytes.len()).step_by(1 + bytes.len() / 32) {
+        let _ = T::decode(&mut &bytes[..i]);
+    }
     let u = T::decode(&mut bytes).expect("decode failed");
     assert_eq!(t, u)
 }
@@ -31,9 +35,10 @@ fn path() -> impl Strategy<Value = Path> {
 mod resolver {
     use super::*;
     use crate::resolver::{
-        ClientAuthRead, ClientAuthWrite, ClientHello, ClientHelloWrite, CtxId, FromRead,
-        FromWrite, ReadyForOwnershipCheck, Referral, Resolved, Secret, ServerAuthWrite,
-        ServerHelloRead, ServerHelloWrite, Table, ToRead, ToWrite,
+        Admin, Changed, ClientAuthRead, ClientAuthWrite, ClientHello, ClientHelloWrite,
+        CtxId, FromRead, FromWrite, ReadyForOwnershipCheck, Referral, Resolved, Secret,
+        ServerAuthWrite, ServerHelloRead, ServerHelloWrite, Session, Table, ToRead,
+        ToWrite,
     };
     use fxhash::FxBuildHasher;
     use proptest::{collection, option};
@@ -43,7 +48,8 @@ mod resolver {
         prop_oneof![
             Just(ClientAuthRead::Anonymous),
             any::<u64>().prop_map(|i| ClientAuthRead::Reuse(CtxId::mk(i))),
-            bytes().prop_map(ClientAuthRead::Initiate)
+            bytes().prop_map(ClientAuthRead::Initiate),
+            bytes().prop_map(ClientAuthRead::Token)
         ]
     }
 
@@ -52,7 +58,8 @@ mod resolver {
             Just(ClientAuthWrite::Anonymous),
             Just(ClientAuthWrite::Reuse),
             (option::of(chars()), bytes())
-                .prop_map(|(spn, token)| ClientAuthWrite::Initiate { spn, token })
+                .prop_map(|(spn, token)| ClientAuthWrite::Initiate { spn, token }),
+            bytes().prop_map(ClientAuthWrite::Token)
         ]
     }
 
@@ -73,7 +80,9 @@ mod resolver {
             Just(ServerHelloRead::Anonymous),
             Just(ServerHelloRead::Reused),
             (bytes(), any::<u64>())
-                .prop_map(|(tok, id)| ServerHelloRead::Accepted(tok, CtxId::mk(id)))
+                .prop_map(|(tok, id)| ServerHelloRead::Accepted(tok, CtxId::mk(id))),
+            Just(ServerHelloRead::Token),
+            chars().prop_map(ServerHelloRead::Error)
         ]
     }
 
@@ -81,7 +90,9 @@ mod resolver {
         prop_oneof![
             Just(ServerAuthWrite::Anonymous),
             Just(ServerAuthWrite::Reused),
-            bytes().prop_map(ServerAuthWrite::Accepted)
+            bytes().prop_map(ServerAuthWrite::Accepted),
+            Just(ServerAuthWrite::Token),
+            chars().prop_map(ServerAuthWrite::Error)
         ]
     }
 
@@ -96,11 +107,20 @@ mod resolver {
         )
     }
 
+    fn admin() -> impl Strategy<Value = Admin> {
+        prop_oneof![
+            Just(Admin::ListSessions),
+            any::<SocketAddr>().prop_map(Admin::Published),
+            any::<SocketAddr>().prop_map(Admin::Clear),
+        ]
+    }
+
     fn to_read() -> impl Strategy<Value = ToRead> {
         prop_oneof![
             path().prop_map(ToRead::Resolve),
             path().prop_map(ToRead::List),
             path().prop_map(ToRead::Table),
+            admin().prop_map(ToRead::Admin),
         ]
     }
 
@@ -154,6 +174,28 @@ mod resolver {
             })
     }
 
+    fn session() -> impl Strategy<Value = Session> {
+        (
+            option::of(any::<SocketAddr>()),
+            option::of(any::<SocketAddr>()),
+            option::of(chars()),
+        )
+            .prop_map(|(addr, write_addr, user)| Session {
+                addr,
+                write_addr,
+                user,
+            })
+    }
+
+    fn changed() -> impl Strategy<Value = Changed> {
+        (collection::vec(path(), (0, 100)), collection::vec(path(), (0, 100))).prop_map(
+            |(added, removed)| Changed {
+                added: Pooled::orphan(added),
+                removed: Pooled::orphan(removed),
+            },
+        )
+    }
+
     fn from_read() -> impl Strategy<Value = FromRead> {
         prop_oneof![
             resolved().prop_map(FromRead::Resolved),
@@ -162,7 +204,10 @@ mod resolver {
             referral().prop_map(FromRead::Referral),
             table().prop_map(FromRead::Table),
             Just(FromRead::Denied),
-            chars().prop_map(FromRead::Error)
+            chars().prop_map(FromRead::Error),
+            collection::vec(session(), (0, 100))
+                .prop_map(|v| FromRead::Sessions(Pooled::orphan(v))),
+            changed().prop_map(FromRead::Changed)
         ]
     }
 
@@ -253,7 +298,8 @@ mod publisher {
             Just(Hello::Anonymous),
             bytes().prop_map(Hello::Token),
             (any::<SocketAddr>(), bytes())
-                .prop_map(|(i, b)| Hello::ResolverAuthenticate(i, b))
+                .prop_map(|(i, b)| Hello::ResolverAuthenticate(i, b)),
+            bytes().prop_map(Hello::Noise)
         ]
     }
 
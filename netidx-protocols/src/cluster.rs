@@ -3,19 +3,19 @@ use bytes::Bytes;
 use futures::{channel::mpsc, prelude::*};
 use log::{info, warn};
 use netidx::{
+    chars::Chars,
     path::Path,
     pool::Pooled,
+    protocol::{
+        glob::{Glob, GlobSet},
+        resolver::Changed,
+    },
     publisher::{Publisher, Val, Value, WriteRequest},
-    resolver::ChangeTracker,
     subscriber::{Dval, Event, Subscriber},
 };
 use serde::{de::DeserializeOwned, Serialize};
-use std::{
-    collections::{HashMap, HashSet},
-    iter,
-    marker::PhantomData,
-};
-use tokio::time;
+use std::{collections::HashMap, iter, marker::PhantomData};
+use tokio::time::{self, Duration, Instant};
 use uuid::{adapter::SimpleRef, Uuid};
 
 pub fn uuid_string(id: Uuid) -> String {
@@ -41,7 +41,7 @@ pub fn uuid_string(id: Uuid) -> String {
 /// which one is the primary.
 pub struct Cluster<T: Serialize + DeserializeOwned + 'static> {
     t: PhantomData<T>,
-    ctrack: ChangeTracker,
+    members: mpsc::UnboundedReceiver<Changed>,
     publisher: Publisher,
     subscriber: Subscriber,
     our_path: Path,
@@ -65,14 +65,16 @@ impl<T: Serialize + DeserializeOwned + 'static> Cluster<T> {
         let id = Uuid::new_v4();
         let our_path = base.append(&uuid_string(id));
         let us = publisher.publish(our_path.clone(), Value::Null)?;
-        let ctrack = ChangeTracker::new(base);
+        let members = Glob::new(Chars::from(String::from(&*base.append("*"))))?;
+        let members =
+            subscriber.resolver().watch(GlobSet::new(true, iter::once(members))?);
         publisher.writes(us.id(), tx);
         publisher.flushed().await;
         let others = HashMap::new();
         let t = PhantomData;
         let mut t = Cluster {
             t,
-            ctrack,
+            members,
             publisher,
             subscriber,
             our_path,
@@ -84,17 +86,25 @@ impl<T: Serialize + DeserializeOwned + 'static> Cluster<T> {
         while t.subscribed_others() < shards {
             info!("waiting for {} other shards", shards);
             t.poll_members().await?;
-            time::sleep(std::time::Duration::from_millis(100)).await;
+            time::sleep(Duration::from_millis(100)).await;
         }
         Ok(t)
     }
 
     /// Returns true if this cluster member is the primary, false
-    /// otherwise. May change after `poll_members`.
+    /// otherwise. May change after `poll_members`. During membership
+    /// changes more than one member may believe it is primary, use
+    /// `election::Election` if you need a lease with fencing tokens.
     pub fn primary(&self) -> bool {
         self.primary
     }
 
+    /// Returns the path this member published under `base`, which
+    /// uniquely identifies it within the cluster.
+    pub fn path(&self) -> &Path {
+        &self.our_path
+    }
+
     fn subscribed_others(&self) -> usize {
         self.others.len()
             - self.others.values().filter(|d| d.last() == Event::Unsubscribed).count()
@@ -104,28 +114,56 @@ impl<T: Serialize + DeserializeOwned + 'static> Cluster<T> {
         self.publisher.subscribed_len(&self.us.id())
     }
 
-    /// Poll the resolvers to see if any new members have joined the
-    /// cluster, return true if new members have potentially joined,
-    /// false if no new members have joined.
+    fn apply_changes(&mut self, mut c: Changed) {
+        for path in c.removed.drain(..) {
+            self.others.remove(&path);
+        }
+        for path in c.added.drain(..) {
+            if path != self.our_path && !self.others.contains_key(&path) {
+                let dv = self.subscriber.durable_subscribe(path.clone());
+                self.others.insert(path, dv);
+            }
+        }
+        let mut paths =
+            iter::once(&self.our_path).chain(self.others.keys()).collect::<Vec<_>>();
+        paths.sort();
+        self.primary = self.our_path == *paths[0];
+    }
+
+    /// Apply the membership changes the resolvers have reported since
+    /// the last call, return true if members joined or left, false
+    /// if nothing changed. This doesn't wait for changes.
     pub async fn poll_members(&mut self) -> Result<bool> {
-        if !self.subscriber.resolver().check_changed(&mut self.ctrack).await? {
-            Ok(false)
-        } else {
-            let path = self.ctrack.path().clone();
-            let mut l = self.subscriber.resolver().list(path).await?;
-            let all = l.drain(..).filter(|p| p != &self.our_path).collect::<HashSet<_>>();
-            self.others.retain(|p, _| all.contains(p));
-            for path in all {
-                if !self.others.contains_key(&path) {
-                    let dv = self.subscriber.durable_subscribe(path.clone());
-                    self.others.insert(path, dv);
+        let mut changed = false;
+        loop {
+            match self.members.next().now_or_never() {
+                None => break Ok(changed),
+                Some(None) => bail!("the resolver stopped reporting cluster members"),
+                Some(Some(c)) => {
+                    self.apply_changes(c);
+                    changed = true;
                 }
             }
-            let mut paths =
-                iter::once(&self.our_path).chain(self.others.keys()).collect::<Vec<_>>();
-            paths.sort();
-            self.primary = self.our_path == *paths[0];
-            Ok(true)
+        }
+    }
+
+    /// Wait up to `timeout` for the resolvers to report that `member`
+    /// joined the cluster, return true if it is a member.
+    pub async fn wait_member(
+        &mut self,
+        member: &Path,
+        timeout: Duration,
+    ) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if member == &self.our_path || self.others.contains_key(member) {
+                break Ok(true);
+            }
+            match time::timeout_at(deadline, self.members.next()).await {
+                Err(_) => break Ok(false),
+                Ok(None) => bail!("the resolver stopped reporting cluster members"),
+                Ok(Some(c)) => self.apply_changes(c),
+            }
         }
     }
 
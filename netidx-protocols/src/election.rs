@@ -0,0 +1,458 @@
+use crate::cluster::uuid_string;
+use anyhow::Result;
+use bytes::Bytes;
+use futures::future;
+use log::{info, warn};
+use netidx::{
+    path::Path,
+    publisher::{Publisher, Val, Value},
+    resolver::ChangeTracker,
+    subscriber::{Dval, Event, Subscriber},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+use uuid::Uuid;
+
+/// A claim on the lease, as published by each candidate under
+/// `base/candidates`. `expires` is in milliseconds since the unix
+/// epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Claim {
+    token: u64,
+    expires: u64,
+}
+
+impl Claim {
+    fn decode(v: &Value) -> Option<Claim> {
+        match v {
+            Value::Bytes(b) => serde_json::from_slice(&**b).ok(),
+            _ => None,
+        }
+    }
+
+    fn encode(&self) -> Value {
+        Value::Bytes(Bytes::from(serde_json::to_vec(self).unwrap()))
+    }
+
+    fn live(&self, now: u64) -> bool {
+        now < self.expires
+    }
+
+    // true if self should win over other
+    fn beats(&self, our_path: &Path, other: &Claim, other_path: &Path) -> bool {
+        self.token > other.token || (self.token == other.token && our_path < other_path)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_millis()
+        as u64
+}
+
+/// The current holder of the lease as published at `base/leader`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Leader {
+    /// the candidate path of the lease holder
+    pub member: Path,
+    /// the fencing token of the lease
+    pub token: u64,
+    /// when the lease expires unless it is renewed, in milliseconds
+    /// since the unix epoch
+    pub expires: u64,
+}
+
+impl Leader {
+    /// Decode the value published at `base/leader`. The value is
+    /// `Null` while nobody holds the lease.
+    pub fn decode(v: &Value) -> Option<Leader> {
+        match v {
+            Value::Bytes(b) => serde_json::from_slice(b).ok(),
+            _ => None,
+        }
+    }
+
+    fn encode(&self) -> Value {
+        Value::Bytes(Bytes::from(serde_json::to_vec(self).unwrap()))
+    }
+}
+
+/// A lease held by this member. The lease is only valid until
+/// `expires`, after which another member may acquire it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    /// The fencing token of this lease. Tokens increase every time
+    /// the lease changes hands, so a resource guarded by the lease
+    /// should reject any operation carrying a token lower than the
+    /// largest one it has seen.
+    pub token: u64,
+    /// The local time at which this lease expires unless it is
+    /// renewed.
+    pub expires: Instant,
+}
+
+impl Lease {
+    pub fn valid(&self) -> bool {
+        Instant::now() < self.expires
+    }
+}
+
+/// The path where the current leader is published under `base`
+pub fn leader_path(base: &Path) -> Path {
+    base.append("leader")
+}
+
+/// Lease based leader election on top of netidx. Each candidate
+/// publishes its claim on the lease under `base/candidates`, and the
+/// member that holds the lease publishes a `Leader` at
+/// `base/leader`, so anyone may observe who the leader is by
+/// subscribing to it.
+///
+/// To acquire the lease a candidate publishes a claim with a fencing
+/// token greater than any it has seen, waits `settle` for competing
+/// claims to propagate, and then checks whether any other live claim
+/// beats its own (higher token, or the same token and a lower
+/// path). The loser withdraws its claim. The holder must `renew`
+/// the lease before it expires, if it fails to do so it loses the
+/// lease and must acquire it again with a new token.
+///
+/// A claim is live on other members for `ttl` of wall clock time,
+/// while the holder considers its lease valid for only `ttl -
+/// settle` of local time, so member clocks must agree to within
+/// `settle`. Even so, during a partition two members may briefly
+/// both act as leader, resources guarded by the lease should check
+/// fencing tokens to be safe.
+///
+/// Fencing tokens are only remembered by live members, if every
+/// member of the election dies at once the tokens start again from
+/// 1.
+pub struct Election {
+    publisher: Publisher,
+    subscriber: Subscriber,
+    base: Path,
+    ctrack: ChangeTracker,
+    our_path: Path,
+    us: Val,
+    others: HashMap<Path, Dval>,
+    claim: Option<Claim>,
+    leader: Option<Val>,
+    lease: Option<Lease>,
+    max_token: u64,
+    ttl: Duration,
+    settle: Duration,
+}
+
+impl Election {
+    /// Join the election under `base` as a candidate. The lease will
+    /// be held for `ttl` each time it is acquired or renewed, and
+    /// `settle` is the maximum time we expect a claim to take to
+    /// propagate to all candidates. `settle` must be less than half
+    /// of `ttl`.
+    pub async fn new(
+        publisher: &Publisher,
+        subscriber: Subscriber,
+        base: Path,
+        ttl: Duration,
+        settle: Duration,
+    ) -> Result<Election> {
+        if settle * 2 >= ttl {
+            bail!("settle must be less than half of ttl")
+        }
+        let publisher = publisher.clone();
+        let candidates = base.append("candidates");
+        let our_path = candidates.append(&uuid_string(Uuid::new_v4()));
+        let us = publisher.publish(our_path.clone(), Value::Null)?;
+        publisher.flushed().await;
+        let mut t = Election {
+            publisher,
+            subscriber,
+            base,
+            ctrack: ChangeTracker::new(candidates),
+            our_path,
+            us,
+            others: HashMap::new(),
+            claim: None,
+            leader: None,
+            lease: None,
+            max_token: 0,
+            ttl,
+            settle,
+        };
+        t.poll_members().await?;
+        Ok(t)
+    }
+
+    /// Our candidate path
+    pub fn path(&self) -> &Path {
+        &self.our_path
+    }
+
+    /// Return the lease if we currently hold a valid one
+    pub fn lease(&self) -> Option<Lease> {
+        self.lease.filter(|l| l.valid())
+    }
+
+    /// Return the current leader as seen by this member, if there
+    /// is one.
+    pub fn leader(&self) -> Option<Leader> {
+        if let (Some(_), Some(claim)) = (self.lease(), self.claim) {
+            let member = self.our_path.clone();
+            return Some(Leader { member, token: claim.token, expires: claim.expires });
+        }
+        let now = now_ms();
+        let mut best: Option<(&Path, Claim)> = None;
+        for (path, claim) in self.claims() {
+            if claim.live(now) {
+                match best {
+                    Some((bpath, bclaim)) if bclaim.beats(bpath, &claim, path) => (),
+                    Some(_) | None => best = Some((path, claim)),
+                }
+            }
+        }
+        best.map(|(path, claim)| Leader {
+            member: path.clone(),
+            token: claim.token,
+            expires: claim.expires,
+        })
+    }
+
+    fn claims(&self) -> impl Iterator<Item = (&Path, Claim)> {
+        self.others.iter().filter_map(|(path, dv)| match dv.last() {
+            Event::Unsubscribed => None,
+            Event::Update(v) => Claim::decode(&v).map(|c| (path, c)),
+        })
+    }
+
+    fn observe_tokens(&mut self) {
+        let max = self.claims().map(|(_, c)| c.token).max().unwrap_or(0);
+        self.max_token = self.max_token.max(max);
+    }
+
+    async fn set_claim(&mut self, claim: Option<Claim>) {
+        self.claim = claim;
+        let mut batch = self.publisher.start_batch();
+        self.us.update(&mut batch, claim.map(|c| c.encode()).unwrap_or(Value::Null));
+        batch.commit(None).await
+    }
+
+    // publish that we are the leader, or update the lease we
+    // already published
+    async fn set_leader(&mut self, claim: Claim) -> Result<()> {
+        let leader = Leader {
+            member: self.our_path.clone(),
+            token: claim.token,
+            expires: claim.expires,
+        }
+        .encode();
+        match &self.leader {
+            Some(val) => {
+                let mut batch = self.publisher.start_batch();
+                val.update(&mut batch, leader);
+                batch.commit(None).await
+            }
+            None => {
+                let path = leader_path(&self.base);
+                self.leader = Some(self.publisher.publish(path, leader)?);
+                self.publisher.flushed().await
+            }
+        }
+        Ok(())
+    }
+
+    // tell subscribers the lease is gone before unpublishing the
+    // leader, so nobody keeps using our fencing token
+    async fn clear_leader(&mut self) {
+        if let Some(val) = self.leader.take() {
+            let mut batch = self.publisher.start_batch();
+            val.update(&mut batch, Value::Null);
+            batch.commit(None).await;
+        }
+    }
+
+    /// Poll the resolvers for candidates that have joined or left the
+    /// election. Return true if the candidate set potentially
+    /// changed.
+    pub async fn poll_members(&mut self) -> Result<bool> {
+        if !self.subscriber.resolver().check_changed(&mut self.ctrack).await? {
+            Ok(false)
+        } else {
+            let path = self.ctrack.path().clone();
+            let mut l = self.subscriber.resolver().list(path).await?;
+            let all = l.drain(..).filter(|p| p != &self.our_path).collect::<HashSet<_>>();
+            self.others.retain(|p, _| all.contains(p));
+            for path in all {
+                if !self.others.contains_key(&path) {
+                    let dv = self.subscriber.durable_subscribe(path.clone());
+                    self.others.insert(path, dv);
+                }
+            }
+            Ok(true)
+        }
+    }
+
+    // wait up to settle for newly discovered candidates to be
+    // subscribed, so we can see their claims. Candidates we can't
+    // subscribe to in that time are presumed dead.
+    async fn wait_others(&self) {
+        let wait = future::join_all(self.others.values().map(|dv| dv.wait_subscribed()));
+        let _ = time::timeout(self.settle, wait).await;
+    }
+
+    /// Try to acquire the lease, return the lease if we acquired it
+    /// (or already held it), or `None` if another member holds
+    /// it. This will take at least `settle` if the lease is free.
+    pub async fn try_acquire(&mut self) -> Result<Option<Lease>> {
+        if let Some(lease) = self.lease() {
+            return Ok(Some(lease));
+        }
+        if self.lease.is_some() {
+            // the lease expired, withdraw everything we published for it
+            self.release().await;
+        }
+        self.poll_members().await?;
+        self.wait_others().await;
+        self.observe_tokens();
+        let now = now_ms();
+        if self.claims().any(|(_, c)| c.live(now)) {
+            return Ok(None);
+        }
+        let token = self.max_token + 1;
+        let start = Instant::now();
+        let claim = Claim { token, expires: now + self.ttl.as_millis() as u64 };
+        self.set_claim(Some(claim)).await;
+        time::sleep(self.settle).await;
+        self.poll_members().await?;
+        self.wait_others().await;
+        self.observe_tokens();
+        let now = now_ms();
+        let beaten = self
+            .claims()
+            .any(|(path, c)| c.live(now) && c.beats(path, &claim, &self.our_path));
+        if beaten {
+            info!("{} lost the election for token {}", self.our_path, token);
+            self.set_claim(None).await;
+            return Ok(None);
+        }
+        let lease = Lease { token, expires: start + self.ttl - self.settle };
+        if !lease.valid() {
+            warn!("{} lease expired while it was being acquired", self.our_path);
+            self.set_claim(None).await;
+            return Ok(None);
+        }
+        self.max_token = token;
+        self.lease = Some(lease);
+        self.set_leader(claim).await?;
+        info!("{} acquired the lease with token {}", self.our_path, token);
+        Ok(Some(lease))
+    }
+
+    /// Wait until we acquire the lease.
+    pub async fn acquire(&mut self) -> Result<Lease> {
+        loop {
+            if let Some(lease) = self.try_acquire().await? {
+                break Ok(lease);
+            }
+            time::sleep(self.settle).await;
+        }
+    }
+
+    /// Extend the lease we hold by another `ttl`. Fails if we do not
+    /// hold a valid lease, in which case we must acquire it again,
+    /// and will get a new fencing token.
+    pub async fn renew(&mut self) -> Result<Lease> {
+        match self.lease() {
+            None => {
+                self.release().await;
+                bail!("the lease is not held or has expired")
+            }
+            Some(lease) => {
+                let start = Instant::now();
+                let expires = now_ms() + self.ttl.as_millis() as u64;
+                let claim = Claim { token: lease.token, expires };
+                self.set_claim(Some(claim)).await;
+                let lease =
+                    Lease { token: lease.token, expires: start + self.ttl - self.settle };
+                self.lease = Some(lease);
+                self.set_leader(claim).await?;
+                Ok(lease)
+            }
+        }
+    }
+
+    /// Give up the lease if we hold it, allowing another member to
+    /// acquire it immediately.
+    pub async fn release(&mut self) {
+        self.lease = None;
+        self.clear_leader().await;
+        if self.claim.is_some() {
+            self.set_claim(None).await;
+        }
+        self.publisher.flushed().await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use netidx::{config, resolver::Auth, resolver_server::Server};
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn acquire_release() {
+        Runtime::new().unwrap().block_on(async move {
+            let mut cfg =
+                config::Config::load("../cfg/simple.json").expect("load simple config");
+            let server = Server::new(cfg.clone(), config::PMap::default(), false, 0)
+                .await
+                .expect("start resolver server");
+            cfg.addrs[0] = *server.local_addr();
+            let publisher = Publisher::new(
+                cfg.clone(),
+                Auth::Anonymous,
+                "127.0.0.1/32".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+            let subscriber = Subscriber::new(cfg, Auth::Anonymous).unwrap();
+            let base = Path::from("/election");
+            let ttl = Duration::from_secs(10);
+            let settle = Duration::from_millis(250);
+            let mut a =
+                Election::new(&publisher, subscriber.clone(), base.clone(), ttl, settle)
+                    .await
+                    .unwrap();
+            let mut b =
+                Election::new(&publisher, subscriber.clone(), base.clone(), ttl, settle)
+                    .await
+                    .unwrap();
+            let lease = a.try_acquire().await.unwrap().expect("a acquires the lease");
+            assert_eq!(lease.token, 1);
+            assert!(b.try_acquire().await.unwrap().is_none());
+            assert_eq!(b.leader().map(|l| l.member), Some(a.path().clone()));
+            let leader =
+                subscriber.subscribe_one(leader_path(&base), None).await.unwrap();
+            let published = || match leader.last() {
+                Event::Update(v) => Leader::decode(&v),
+                Event::Unsubscribed => None,
+            };
+            let first = published().expect("leader is published");
+            assert_eq!(first.token, 1);
+            time::sleep(Duration::from_millis(10)).await;
+            assert_eq!(a.renew().await.unwrap().token, 1);
+            time::sleep(settle).await;
+            let renewed = published().expect("leader is still published");
+            assert_eq!(renewed.token, 1);
+            assert!(renewed.expires > first.expires);
+            a.release().await;
+            assert!(a.lease().is_none());
+            time::sleep(settle).await;
+            assert_eq!(published(), None);
+            time::sleep(settle).await;
+            let lease = b.try_acquire().await.unwrap().expect("b acquires the lease");
+            assert_eq!(lease.token, 2);
+            assert!(a.renew().await.is_err());
+        })
+    }
+}
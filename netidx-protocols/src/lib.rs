@@ -4,4 +4,6 @@
 
 pub mod view;
 pub mod cluster;
+pub mod election;
+pub mod replicated;
 pub mod rpc;
@@ -0,0 +1,520 @@
+use crate::{cluster::Cluster, election::Election};
+use anyhow::Result;
+use log::{info, warn};
+use netidx::{path::Path, publisher::Publisher, subscriber::Subscriber};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+use tokio::time;
+
+/// An operation on a `ReplicatedMap`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Op<K, V> {
+    Set(K, V),
+    Delete(K),
+    /// Replace the value of the key with `new` if it's current value
+    /// is `expected`. `None` means the key is absent.
+    CompareAndSwap {
+        key: K,
+        expected: Option<V>,
+        new: Option<V>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct OpId {
+    member: Path,
+    n: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Msg<K, V> {
+    /// a member asks the sequencer to sequence an op
+    Propose { id: OpId, op: Op<K, V> },
+    /// the sequencer holding the lease with fencing token `token`
+    /// has sequenced an op, all members should apply it
+    Apply { token: u64, seq: u64, id: OpId, op: Op<K, V> },
+    /// a new member asks the sequencer for the current state
+    SnapshotRequest { member: Path },
+    /// the state of the map as of `seq`
+    Snapshot { member: Path, token: u64, seq: u64, entries: Vec<(K, V)> },
+}
+
+/// A map whose contents are replicated to every member of a
+/// `Cluster`. Operations are proposed by any member, assigned a
+/// sequence number by the sequencer, and then applied by every
+/// member in sequence order. Members that join after startup
+/// receive a snapshot from the sequencer before they apply any
+/// operations.
+///
+/// The sequencer is the member holding the lease of an `Election`
+/// under `base/sequencer`, and every op it sequences carries the
+/// fencing token of its lease. Members reject ops carrying a token
+/// lower than the largest they have seen, so a deposed sequencer
+/// that still believes it holds the lease can't make them
+/// diverge. When the token changes hands a member whose sequence
+/// doesn't follow on from the new sequencer's reloads a snapshot
+/// from it, so ops applied by the old sequencer that the new one
+/// never saw are rolled back everywhere.
+///
+/// Like `Cluster`, `ReplicatedMap` does nothing in the background,
+/// the owner must call `process` in a loop to apply operations from
+/// other members and keep the lease. While the sequencer is changing
+/// operations may fail to be sequenced, in which case the proposing
+/// method returns an error and the operation may or may not have
+/// been applied.
+pub struct ReplicatedMap<K, V>
+where
+    K: Serialize + DeserializeOwned + Hash + Eq + Clone + 'static,
+    V: Serialize + DeserializeOwned + PartialEq + Clone + 'static,
+{
+    cluster: Cluster<Msg<K, V>>,
+    election: Election,
+    map: HashMap<K, V>,
+    token: u64,
+    seq: u64,
+    synced: bool,
+    buffered: BTreeMap<u64, (u64, OpId, Op<K, V>)>,
+    pending: HashSet<OpId>,
+    applied: HashMap<OpId, bool>,
+    next_id: u64,
+    snapshot_requested: Instant,
+    timeout: Duration,
+}
+
+impl<K, V> ReplicatedMap<K, V>
+where
+    K: Serialize + DeserializeOwned + Hash + Eq + Clone + 'static,
+    V: Serialize + DeserializeOwned + PartialEq + Clone + 'static,
+{
+    /// Join the replicated map under `base`, see `Cluster::new` for
+    /// the meaning of `shards`. `timeout` bounds how long an
+    /// operation may wait to be sequenced, and how long a new member
+    /// waits for a snapshot before asking again. The sequencer lease
+    /// is held for 4 times `timeout`.
+    ///
+    /// If nobody answers a snapshot request and this member is able
+    /// to acquire the sequencer lease it assumes the whole cluster is
+    /// starting and begins with an empty map.
+    pub async fn new(
+        publisher: &Publisher,
+        subscriber: Subscriber,
+        base: Path,
+        shards: usize,
+        timeout: Duration,
+    ) -> Result<ReplicatedMap<K, V>> {
+        let election = Election::new(
+            publisher,
+            subscriber.clone(),
+            base.append("sequencer"),
+            timeout * 4,
+            timeout / 2,
+        )
+        .await?;
+        let cluster =
+            Cluster::new(publisher, subscriber, base.append("members"), shards).await?;
+        let t = ReplicatedMap {
+            cluster,
+            election,
+            map: HashMap::new(),
+            token: 0,
+            seq: 0,
+            synced: false,
+            buffered: BTreeMap::new(),
+            pending: HashSet::new(),
+            applied: HashMap::new(),
+            next_id: 0,
+            snapshot_requested: Instant::now(),
+            timeout,
+        };
+        t.request_snapshot();
+        Ok(t)
+    }
+
+    /// Return true if this member holds the current state of the
+    /// map.
+    pub fn synced(&self) -> bool {
+        self.synced
+    }
+
+    /// The sequence number of the last operation applied.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The largest sequencer fencing token this member has seen.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// Return true if this member is currently the sequencer
+    pub fn sequencer(&self) -> bool {
+        self.election.lease().map(|l| l.token == self.token).unwrap_or(false)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.map.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.map.iter()
+    }
+
+    fn request_snapshot(&self) {
+        let member = self.cluster.path().clone();
+        info!("{} requesting a snapshot", member);
+        self.cluster.send_cmd(&Msg::SnapshotRequest { member });
+    }
+
+    fn apply(&mut self, seq: u64, id: OpId, op: Op<K, V>) {
+        self.seq = seq;
+        let res = match op {
+            Op::Set(k, v) => {
+                self.map.insert(k, v);
+                true
+            }
+            Op::Delete(k) => self.map.remove(&k).is_some(),
+            Op::CompareAndSwap { key, expected, new } => {
+                if self.map.get(&key) != expected.as_ref() {
+                    false
+                } else {
+                    match new {
+                        None => self.map.remove(&key),
+                        Some(v) => self.map.insert(key, v),
+                    };
+                    true
+                }
+            }
+        };
+        // a late result for an op that already timed out is dropped
+        if self.pending.contains(&id) {
+            self.applied.insert(id, res);
+        }
+    }
+
+    // as the sequencer, assign the next sequence number and apply
+    fn sequence(&mut self, id: OpId, op: Op<K, V>) {
+        let (token, seq) = (self.token, self.seq + 1);
+        self.cluster.send_cmd(&Msg::Apply { token, seq, id: id.clone(), op: op.clone() });
+        self.apply(seq, id, op);
+    }
+
+    fn apply_buffered(&mut self) {
+        while let Some(seq) = self.buffered.keys().next().copied() {
+            if seq <= self.seq {
+                self.buffered.remove(&seq);
+            } else if seq == self.seq + 1 {
+                let (token, id, op) = self.buffered.remove(&seq).unwrap();
+                if token == self.token {
+                    self.apply(seq, id, op);
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn resync(&mut self) {
+        self.synced = false;
+        self.buffered.clear();
+        self.snapshot_requested = Instant::now();
+        self.request_snapshot();
+    }
+
+    fn handle(&mut self, msg: Msg<K, V>) {
+        match msg {
+            Msg::Propose { id, op } => {
+                if self.synced && self.sequencer() {
+                    self.sequence(id, op)
+                }
+            }
+            Msg::Apply { token, seq, id, op } => {
+                if token < self.token {
+                    warn!(
+                        "{} rejecting op {} from a deposed sequencer, token {} < {}",
+                        self.cluster.path(),
+                        seq,
+                        token,
+                        self.token
+                    );
+                    return;
+                }
+                if token > self.token {
+                    self.token = token;
+                    // we may hold ops from the old sequencer that the
+                    // new one never saw, or be missing ops it did see
+                    if self.synced && seq != self.seq + 1 {
+                        warn!(
+                            "{} sequencer changed at seq {} (ours {}), resyncing",
+                            self.cluster.path(),
+                            seq,
+                            self.seq
+                        );
+                        self.resync();
+                    }
+                }
+                if self.synced && seq == self.seq + 1 {
+                    self.apply(seq, id, op);
+                    self.apply_buffered();
+                } else if seq > self.seq || !self.synced {
+                    self.buffered.insert(seq, (token, id, op));
+                }
+            }
+            Msg::SnapshotRequest { member } => {
+                if self.synced && self.sequencer() {
+                    let entries =
+                        self.map.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                    self.cluster.send_cmd(&Msg::Snapshot {
+                        member,
+                        token: self.token,
+                        seq: self.seq,
+                        entries,
+                    })
+                }
+            }
+            Msg::Snapshot { member, token, seq, entries } => {
+                if !self.synced && &member == self.cluster.path() && token >= self.token {
+                    info!("{} loaded snapshot at seq {} token {}", member, seq, token);
+                    self.map = entries.into_iter().collect();
+                    self.token = token;
+                    self.seq = seq;
+                    self.synced = true;
+                    self.apply_buffered();
+                }
+            }
+        }
+    }
+
+    async fn check_sync(&mut self) -> Result<()> {
+        if !self.synced && self.snapshot_requested.elapsed() > self.timeout {
+            if self.seq == 0 && self.buffered.is_empty() {
+                if let Some(lease) = self.election.try_acquire().await? {
+                    info!("{} no snapshot received, starting empty", self.cluster.path());
+                    self.token = lease.token;
+                    self.synced = true;
+                    return Ok(());
+                }
+            }
+            self.snapshot_requested = Instant::now();
+            self.request_snapshot();
+        }
+        Ok(())
+    }
+
+    // keep the sequencer lease if we hold it, and take it over if
+    // nobody else does
+    async fn check_lease(&mut self) -> Result<()> {
+        if !self.synced {
+            return Ok(());
+        }
+        match self.election.lease() {
+            Some(lease) if lease.token < self.token => {
+                info!("{} was deposed as sequencer", self.cluster.path());
+                self.election.release().await
+            }
+            Some(lease) => {
+                if lease.expires.saturating_duration_since(Instant::now())
+                    < self.timeout * 2
+                {
+                    if let Err(e) = self.election.renew().await {
+                        warn!("{} lost the sequencer lease {}", self.cluster.path(), e);
+                    }
+                }
+            }
+            None => {
+                if self.election.leader().is_none() {
+                    if let Some(lease) = self.election.try_acquire().await? {
+                        info!(
+                            "{} became sequencer with token {} at seq {}",
+                            self.cluster.path(),
+                            lease.token,
+                            self.seq
+                        );
+                        self.token = lease.token;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for operations from other members and
+    /// apply them. Also polls for cluster membership changes.
+    pub async fn process(&mut self) -> Result<()> {
+        match time::timeout(self.timeout, self.cluster.wait_cmds()).await {
+            Err(_) => {
+                self.cluster.poll_members().await?;
+                self.election.poll_members().await?;
+            }
+            Ok(cmds) => {
+                let cmds = cmds?;
+                // make sure we can reach new members before answering them
+                for msg in &cmds {
+                    if let Msg::SnapshotRequest { member } = msg {
+                        self.cluster.wait_member(member, self.timeout).await?;
+                    }
+                }
+                for msg in cmds {
+                    self.handle(msg)
+                }
+            }
+        }
+        self.check_sync().await?;
+        self.check_lease().await
+    }
+
+    async fn propose(&mut self, op: Op<K, V>) -> Result<bool> {
+        let id = OpId { member: self.cluster.path().clone(), n: self.next_id };
+        self.next_id += 1;
+        self.pending.insert(id.clone());
+        if self.synced && self.sequencer() {
+            self.sequence(id.clone(), op);
+        } else {
+            self.cluster.send_cmd(&Msg::Propose { id: id.clone(), op });
+        }
+        let deadline = Instant::now() + self.timeout;
+        let res = loop {
+            if let Some(res) = self.applied.remove(&id) {
+                break Ok(res);
+            }
+            if Instant::now() > deadline {
+                warn!("operation {:?} was not sequenced", id);
+                break Err(anyhow!(
+                    "operation was not sequenced in time, it may or may not be applied"
+                ));
+            }
+            if let Err(e) = self.process().await {
+                break Err(e);
+            }
+        };
+        self.pending.remove(&id);
+        self.applied.remove(&id);
+        res
+    }
+
+    /// Set `key` to `value` on all members
+    pub async fn set(&mut self, key: K, value: V) -> Result<()> {
+        self.propose(Op::Set(key, value)).await?;
+        Ok(())
+    }
+
+    /// Delete `key` on all members, return true if it existed
+    pub async fn delete(&mut self, key: K) -> Result<bool> {
+        self.propose(Op::Delete(key)).await
+    }
+
+    /// Set `key` to `new` on all members if it's value is currently
+    /// `expected`. `None` means absent. Return true if the swap
+    /// happened.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: K,
+        expected: Option<V>,
+        new: Option<V>,
+    ) -> Result<bool> {
+        self.propose(Op::CompareAndSwap { key, expected, new }).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future;
+    use netidx::{config, resolver::Auth, resolver_server::Server};
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn replicate() {
+        Runtime::new().unwrap().block_on(async move {
+            let mut cfg =
+                config::Config::load("../cfg/simple.json").expect("load simple config");
+            let server = Server::new(cfg.clone(), config::PMap::default(), false, 0)
+                .await
+                .expect("start resolver server");
+            cfg.addrs[0] = *server.local_addr();
+            let publisher = Publisher::new(
+                cfg.clone(),
+                Auth::Anonymous,
+                "127.0.0.1/32".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+            let subscriber = Subscriber::new(cfg, Auth::Anonymous).unwrap();
+            let base = Path::from("/replicated");
+            let timeout = Duration::from_millis(500);
+            let mut a: ReplicatedMap<String, u64> = ReplicatedMap::new(
+                &publisher,
+                subscriber.clone(),
+                base.clone(),
+                0,
+                timeout,
+            )
+            .await
+            .unwrap();
+            while !a.synced() {
+                a.process().await.unwrap()
+            }
+            a.set("x".into(), 1).await.unwrap();
+            a.set("y".into(), 2).await.unwrap();
+            assert!(a.delete("y".into()).await.unwrap());
+            let mut b: ReplicatedMap<String, u64> = ReplicatedMap::new(
+                &publisher,
+                subscriber.clone(),
+                base.clone(),
+                1,
+                timeout,
+            )
+            .await
+            .unwrap();
+            while !b.synced() {
+                let (r0, r1) = future::join(a.process(), b.process()).await;
+                r0.unwrap();
+                r1.unwrap();
+            }
+            assert_eq!(b.seq(), a.seq());
+            assert_eq!(b.get(&"x".into()), Some(&1));
+            assert_eq!(b.len(), 1);
+            let (swapped, r) = future::join(
+                b.compare_and_swap("x".into(), Some(1), Some(3)),
+                a.process(),
+            )
+            .await;
+            r.unwrap();
+            assert!(swapped.unwrap());
+            while a.seq() < b.seq() {
+                a.process().await.unwrap()
+            }
+            assert_eq!(a.get(&"x".into()), Some(&3));
+            let (swapped, r) =
+                future::join(a.compare_and_swap("x".into(), Some(1), None), b.process())
+                    .await;
+            r.unwrap();
+            assert!(!swapped.unwrap());
+            assert_eq!(a.get(&"x".into()), Some(&3));
+            assert!(a.sequencer() && !b.sequencer());
+            assert!(a.pending.is_empty() && a.applied.is_empty());
+            // an op from a sequencer with a stale token is rejected
+            let id = OpId { member: a.cluster.path().clone(), n: 1000 };
+            let seq = b.seq();
+            b.handle(Msg::Apply {
+                token: b.token() - 1,
+                seq: seq + 1,
+                id,
+                op: Op::Set("z".into(), 9),
+            });
+            assert_eq!(b.seq(), seq);
+            assert_eq!(b.get(&"z".into()), None);
+        })
+    }
+}
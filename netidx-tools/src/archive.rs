@@ -0,0 +1,93 @@
+use super::ArchiveCmd;
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use netidx::{
+    chars::Chars,
+    protocol::glob::{Glob, GlobSet},
+};
+use netidx_archive::{export, repair, ArchiveReader, Cursor, Seek};
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    ops::Bound,
+};
+
+fn bound(reader: &ArchiveReader, seek: Seek) -> Bound<DateTime<Utc>> {
+    match seek {
+        Seek::Beginning | Seek::End => Bound::Unbounded,
+        seek => {
+            let mut cursor = Cursor::new();
+            reader.seek(&mut cursor, seek);
+            match cursor.current() {
+                None => Bound::Unbounded,
+                Some(ts) => Bound::Included(ts),
+            }
+        }
+    }
+}
+
+fn export(
+    archive: String,
+    start: Seek,
+    end: Seek,
+    spec: Vec<String>,
+    format: export::Format,
+    output: Option<String>,
+) -> Result<()> {
+    let reader = ArchiveReader::open(&archive)
+        .with_context(|| format!("opening archive {}", archive))?;
+    let spec = if spec.is_empty() { vec![String::from("/**")] } else { spec };
+    let globs = spec
+        .into_iter()
+        .map(|s| Glob::new(Chars::from(s)))
+        .collect::<Result<Vec<_>>>()?;
+    let filter = GlobSet::new(true, globs)?;
+    let mut cursor = Cursor::new();
+    cursor.set_start(bound(&reader, start));
+    cursor.set_end(bound(&reader, end));
+    match output {
+        None => {
+            let out = BufWriter::new(io::stdout());
+            export::export(&reader, &cursor, &filter, format, out)
+        }
+        Some(file) => {
+            let out = BufWriter::new(
+                File::create(&file).with_context(|| format!("creating {}", file))?,
+            );
+            export::export(&reader, &cursor, &filter, format, out)
+        }
+    }
+}
+
+pub(crate) fn run(cmd: ArchiveCmd) {
+    let res = match cmd {
+        ArchiveCmd::Export { archive, start, end, spec, format, output } => {
+            export(archive, start, end, spec, format, output)
+        }
+        ArchiveCmd::Check { archive } => repair::check(&archive).map(|report| {
+            println!("{}", report);
+            if !report.is_ok() {
+                std::process::exit(2)
+            }
+        }),
+        ArchiveCmd::Repair { archive } => repair::repair(&archive).and_then(|report| {
+            if report.is_ok() {
+                println!("{}\nnothing to repair", report);
+            } else {
+                println!("before repair\n{}\n", report);
+                println!("after repair\n{}", repair::check(&archive)?);
+            }
+            Ok(())
+        }),
+        ArchiveCmd::Compact { archive, output, image_frequency } => {
+            let image_frequency =
+                if image_frequency == 0 { None } else { Some(image_frequency) };
+            repair::compact(&archive, &output, image_frequency)
+                .and_then(|()| Ok(println!("{}", repair::check(&output)?)))
+        }
+    };
+    if let Err(e) = res {
+        eprintln!("{:#}", e);
+        std::process::exit(1)
+    }
+}
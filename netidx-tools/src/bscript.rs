@@ -0,0 +1,178 @@
+use anyhow::{bail, Result};
+use futures::{
+    channel::oneshot,
+    prelude::*,
+    select_biased,
+    stream::{self, BoxStream},
+};
+use netidx::{
+    chars::Chars,
+    config::Config,
+    resolver::Auth,
+    subscriber::{Subscriber, Typ, Value},
+};
+use netidx_bscript::{
+    expr::{Expr, ExprKind},
+    rt::Runtime as BsRuntime,
+};
+use std::{collections::HashMap, fs, path::PathBuf};
+use structopt::StructOpt;
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    runtime::Runtime,
+};
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct Params {
+    #[structopt(
+        short = "e",
+        long = "expr",
+        help = "evaluate expr and print it's values instead of starting a repl"
+    )]
+    expr: Option<String>,
+    #[structopt(
+        short = "f",
+        long = "file",
+        help = "evaluate the expression in file instead of starting a repl",
+        conflicts_with = "expr"
+    )]
+    file: Option<PathBuf>,
+}
+
+fn format_value(v: &Value) -> String {
+    let typ = match Typ::get(v) {
+        None => "none",
+        Some(typ) => typ.name(),
+    };
+    format!("{}|{}\n", typ, v)
+}
+
+async fn run_batch(rt: BsRuntime, expr: Expr) -> Result<()> {
+    let mut stdout = io::stdout();
+    let mut values = rt.eval(expr).boxed();
+    while let Some(v) = values.next().await {
+        stdout.write_all(format_value(&v).as_bytes()).await?;
+        stdout.flush().await?;
+    }
+    Ok(())
+}
+
+enum Line {
+    Eval(Expr),
+    Drop(usize),
+    Set(Chars, Value),
+}
+
+fn parse_line(line: &str) -> Result<Option<Line>> {
+    let line = line.trim();
+    if line.is_empty() {
+        Ok(None)
+    } else if let Some(id) = line.strip_prefix(":drop ") {
+        Ok(Some(Line::Drop(id.trim().parse::<usize>()?)))
+    } else if let Some(set) = line.strip_prefix(":set ") {
+        let mut parts = set.trim().splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(expr)) => match expr.parse::<Expr>()?.kind {
+                ExprKind::Constant(v) => {
+                    Ok(Some(Line::Set(Chars::from(String::from(name)), v)))
+                }
+                _ => bail!(":set requires a constant value"),
+            },
+            (_, _) => bail!("usage :set name value"),
+        }
+    } else if line.starts_with(':') {
+        bail!("unknown command, expected :drop id, or :set name value")
+    } else {
+        Ok(Some(Line::Eval(line.parse::<Expr>()?)))
+    }
+}
+
+// Each line of input is an expression, it's values are printed as
+// id|typ|value for as long as it is running. :drop id stops an
+// expression, and :set name value sets a variable.
+async fn run_repl(rt: BsRuntime) -> Result<()> {
+    let mut stdout = io::stdout();
+    let mut stderr = io::stderr();
+    let mut lines = BufReader::new(io::stdin()).lines();
+    let mut running: HashMap<usize, oneshot::Sender<()>> = HashMap::new();
+    let mut values: stream::SelectAll<BoxStream<'static, (usize, Value)>> =
+        stream::SelectAll::new();
+    let mut next_id = 0;
+    loop {
+        select_biased! {
+            l = lines.next_line().fuse() => match l? {
+                None => break,
+                Some(l) => match parse_line(&l) {
+                    Err(e) => {
+                        stderr.write_all(format!("{}\n", e).as_bytes()).await?
+                    }
+                    Ok(None) => (),
+                    Ok(Some(Line::Drop(id))) => {
+                        if running.remove(&id).is_none() {
+                            stderr.write_all(b"no such expression\n").await?
+                        }
+                    }
+                    Ok(Some(Line::Set(name, value))) => rt.set_var(name, value),
+                    Ok(Some(Line::Eval(expr))) => {
+                        let id = next_id;
+                        next_id += 1;
+                        let (tx, rx) = oneshot::channel();
+                        running.insert(id, tx);
+                        let msg = format!("{}: {}\n", id, expr);
+                        stderr.write_all(msg.as_bytes()).await?;
+                        let s = rt.eval(expr).take_until(rx).map(move |v| (id, v));
+                        values.push(s.boxed());
+                    }
+                }
+            },
+            (id, v) = values.select_next_some() => {
+                let line = format!("{}|{}", id, format_value(&v));
+                stdout.write_all(line.as_bytes()).await?;
+            },
+        }
+        stdout.flush().await?;
+        stderr.flush().await?;
+    }
+    Ok(())
+}
+
+pub(crate) fn run(cfg: Config, auth: Auth, p: Params) -> Result<()> {
+    let expr = match (p.expr, p.file) {
+        (Some(e), _) => Some(e.parse::<Expr>()?),
+        (None, Some(f)) => Some(fs::read_to_string(f)?.parse::<Expr>()?),
+        (None, None) => None,
+    };
+    let rt = Runtime::new()?;
+    rt.block_on(async move {
+        let subscriber = Subscriber::new(cfg, auth)?;
+        let bs = BsRuntime::new(subscriber, |_| ())?;
+        match expr {
+            None => run_repl(bs).await,
+            Some(e) => run_batch(bs, e).await,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_lines() {
+        assert!(matches!(parse_line("   "), Ok(None)));
+        assert!(matches!(parse_line(":drop 3"), Ok(Some(Line::Drop(3)))));
+        assert!(parse_line(":drop x").is_err());
+        match parse_line(":set offset i64:10") {
+            Ok(Some(Line::Set(name, Value::I64(10)))) => assert_eq!(&*name, "offset"),
+            _ => panic!("expected set"),
+        }
+        assert!(parse_line(":set offset").is_err());
+        assert!(parse_line(":set offset load(\"/a\")").is_err());
+        assert!(parse_line(":foo").is_err());
+        match parse_line("sum(1, 2)") {
+            Ok(Some(Line::Eval(e))) => assert_eq!(e.to_string(), "sum(1, 2)"),
+            _ => panic!("expected eval"),
+        }
+        assert!(parse_line("sum(1,").is_err());
+    }
+}
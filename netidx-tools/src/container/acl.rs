@@ -0,0 +1,265 @@
+use super::rpcs::RpcRequestKind;
+use anyhow::{Error, Result};
+use netidx::{path::Path, publisher::Principal};
+use std::{
+    collections::{
+        BTreeMap,
+        Bound::{self, *},
+        HashMap,
+    },
+    convert::TryFrom,
+    fs,
+};
+
+bitflags! {
+    pub(super) struct Rights: u8 {
+        const DENY      = 0x01;
+        const DATA      = 0x02;
+        const FORMULA   = 0x04;
+        const STRUCTURE = 0x08;
+    }
+}
+
+impl TryFrom<&str> for Rights {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        let mut r = Rights::empty();
+        for (i, c) in s.chars().enumerate() {
+            match c {
+                '!' if i == 0 => r |= Rights::DENY,
+                '!' => bail!("! may only be used as the first character"),
+                'd' => r |= Rights::DATA,
+                'f' => r |= Rights::FORMULA,
+                's' => r |= Rights::STRUCTURE,
+                c => bail!("unrecognized right {}, valid rights are !dfs", c),
+            }
+        }
+        Ok(r)
+    }
+}
+
+/// Which users and groups may do what under which paths. Loaded
+/// from a json file of the same shape as the resolver server's
+/// permissions file, e.g.
+///
+/// `{"/app": {"ops": "dfs", "eric@EXAMPLE.COM": "d", "": "!dfs"}}`
+///
+/// where the empty name is the anonymous user, `d` allows setting
+/// data, `f` allows changing formulas and on write handlers, and `s`
+/// allows structural changes (sheets, tables, deletes, locks, roots,
+/// imports, and undo). As with the resolver rights are inherited by
+/// children, and a `!` entry denies the listed rights even if they
+/// were granted by a parent or another group. Operations on a whole
+/// subtree (deleting, locking, importing, undo, and revert) need
+/// their rights everywhere in it, so a `!` entry anywhere below the
+/// path also denies them.
+///
+/// On write handlers write to the container with the container's
+/// own identity, so it needs `d` wherever they write. Writes a
+/// handler makes in response to a client's write must also be
+/// allowed for that client.
+#[derive(Debug)]
+pub(super) struct Acls(BTreeMap<Path, HashMap<String, Rights>>);
+
+impl Acls {
+    pub(super) fn load(file: &str) -> Result<Self> {
+        Self::parse(&fs::read_to_string(file)?)
+    }
+
+    fn parse(s: &str) -> Result<Self> {
+        let tbl: HashMap<String, HashMap<String, String>> = serde_json::from_str(s)?;
+        let mut acls = BTreeMap::new();
+        for (path, ents) in tbl {
+            let mut entry = HashMap::with_capacity(ents.len());
+            for (ent, rights) in ents {
+                entry.insert(ent, Rights::try_from(rights.as_str())?);
+            }
+            acls.insert(Path::from(path), entry);
+        }
+        Ok(Acls(acls))
+    }
+
+    // the rights granted and denied to `principal` by one entry
+    fn entry(set: &HashMap<String, Rights>, principal: &Principal) -> (Rights, Rights) {
+        let anon = String::new();
+        let entities: Box<dyn Iterator<Item = &str>> = match principal {
+            Principal::Anonymous => Box::new(std::iter::once(anon.as_str())),
+            p => Box::new(p.entities().map(|e| &**e)),
+        };
+        entities.fold((Rights::empty(), Rights::empty()), |(ar, dr), e| {
+            match set.get(e) {
+                None => (ar, dr),
+                Some(r) if r.contains(Rights::DENY) => (ar, dr | *r),
+                Some(r) => (ar | *r, dr),
+            }
+        })
+    }
+
+    pub(super) fn rights(&self, path: &str, principal: &Principal) -> Rights {
+        Path::dirnames(path).fold(Rights::empty(), |r, s| match self.0.get(s) {
+            None => r,
+            Some(set) => {
+                let (ar, dr) = Self::entry(set, principal);
+                (r | ar) & !dr
+            }
+        })
+    }
+
+    /// The rights `principal` has everywhere in the subtree rooted
+    /// at `path`, that is the rights at `path` less any right that
+    /// is denied anywhere below it.
+    pub(super) fn rights_in_subtree(&self, path: &str, principal: &Principal) -> Rights {
+        self.0
+            .range::<str, (Bound<&str>, Bound<&str>)>((Excluded(path), Unbounded))
+            .filter(|(p, _)| Path::is_parent(path, p))
+            .fold(self.rights(path, principal), |r, (_, set)| {
+                r & !Self::entry(set, principal).1
+            })
+    }
+
+    pub(super) fn check(
+        &self,
+        path: &str,
+        desired: Rights,
+        principal: &Principal,
+    ) -> Result<()> {
+        if self.rights(path, principal).contains(desired) {
+            Ok(())
+        } else {
+            bail!("permission denied")
+        }
+    }
+
+    pub(super) fn check_subtree(
+        &self,
+        path: &str,
+        desired: Rights,
+        principal: &Principal,
+    ) -> Result<()> {
+        if self.rights_in_subtree(path, principal).contains(desired) {
+            Ok(())
+        } else {
+            bail!("permission denied")
+        }
+    }
+
+    /// Check that `principal` may perform `req`, including every
+    /// operation in a transaction. Operations on a whole subtree
+    /// need their rights everywhere in it.
+    pub(super) fn check_request(
+        &self,
+        req: &RpcRequestKind,
+        principal: &Principal,
+    ) -> Result<()> {
+        use RpcRequestKind::*;
+        match req {
+            ExportSubtree(_) | History { .. } => Ok(()),
+            SetData { path, .. } => self.check(path, Rights::DATA, principal),
+            SetFormula { path, .. } => self.check(path, Rights::FORMULA, principal),
+            DeleteSubtree(path) | LockSubtree(path) | UnlockSubtree(path) => {
+                self.check_subtree(path, Rights::STRUCTURE, principal)
+            }
+            Delete(path)
+            | CreateSheet { path, .. }
+            | AddSheetRows(path, _)
+            | AddSheetCols(path, _)
+            | DelSheetRows(path, _)
+            | DelSheetCols(path, _)
+            | CreateTable { path, .. }
+            | AddTableRows(path, _)
+            | AddTableCols(path, _)
+            | DelTableRows(path, _)
+            | DelTableCols(path, _)
+            | AddRoot(path)
+            | DelRoot(path) => self.check(path, Rights::STRUCTURE, principal),
+            ImportSubtree { path, .. } | Undo(path) | RevertTo { path, .. } => {
+                let all = Rights::DATA | Rights::FORMULA | Rights::STRUCTURE;
+                self.check_subtree(path, all, principal)
+            }
+            Transaction(reqs) => {
+                reqs.iter().try_for_each(|r| self.check_request(r, principal))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use netidx::chars::Chars;
+
+    fn user(name: &str, groups: &[&str]) -> Principal {
+        Principal::User {
+            name: Chars::from(String::from(name)),
+            groups: groups.iter().map(|g| Chars::from(String::from(*g))).collect(),
+        }
+    }
+
+    const ACLS: &str = r#"{
+        "/app": {"ops": "dfs", "eric@EXAMPLE.COM": "d", "": "!dfs"},
+        "/app/public": {"": "d"},
+        "/app/frozen": {"ops": "!f"}
+    }"#;
+
+    #[test]
+    fn rights() {
+        let acls = Acls::parse(ACLS).unwrap();
+        let eric = user("eric@EXAMPLE.COM", &[]);
+        let ops = user("bob@EXAMPLE.COM", &["ops"]);
+        let all = Rights::DATA | Rights::FORMULA | Rights::STRUCTURE;
+        assert_eq!(acls.rights("/app/a", &eric), Rights::DATA);
+        assert_eq!(acls.rights("/app/a", &ops), all);
+        assert_eq!(acls.rights("/app/frozen/a", &ops), Rights::DATA | Rights::STRUCTURE);
+        assert_eq!(acls.rights("/app/a", &Principal::Anonymous), Rights::empty());
+        assert_eq!(acls.rights("/app/public/a", &Principal::Anonymous), Rights::DATA);
+        assert_eq!(acls.rights("/other", &ops), Rights::empty());
+        assert!(acls.check("/app/a", Rights::DATA, &eric).is_ok());
+        assert!(acls.check("/app/a", Rights::FORMULA, &eric).is_err());
+    }
+
+    #[test]
+    fn requests() {
+        let acls = Acls::parse(ACLS).unwrap();
+        let eric = user("eric@EXAMPLE.COM", &[]);
+        let ops = user("bob@EXAMPLE.COM", &["ops"]);
+        let set = RpcRequestKind::SetData { path: Path::from("/app/a"), value: 1.into() };
+        let del = RpcRequestKind::DeleteSubtree(Path::from("/app/b"));
+        assert!(acls.check_request(&set, &eric).is_ok());
+        assert!(acls.check_request(&del, &eric).is_err());
+        assert!(acls.check_request(&del, &ops).is_ok());
+        let undo = RpcRequestKind::Undo(Path::from("/app/frozen"));
+        assert!(acls.check_request(&undo, &ops).is_err());
+        let txn = RpcRequestKind::Transaction(vec![set, del]);
+        assert!(acls.check_request(&txn, &eric).is_err());
+        assert!(acls.check_request(&txn, &ops).is_ok());
+    }
+
+    #[test]
+    fn subtree() {
+        let acls =
+            Acls::parse(r#"{"/app": {"ops": "dfs"}, "/app/frozen": {"ops": "!s"}}"#)
+                .unwrap();
+        let ops = user("bob@EXAMPLE.COM", &["ops"]);
+        let all = Rights::DATA | Rights::FORMULA | Rights::STRUCTURE;
+        assert_eq!(acls.rights("/app", &ops), all);
+        assert_eq!(acls.rights_in_subtree("/app", &ops), Rights::DATA | Rights::FORMULA);
+        assert_eq!(acls.rights_in_subtree("/app/b", &ops), all);
+        let del = RpcRequestKind::DeleteSubtree(Path::from("/app"));
+        assert!(acls.check_request(&del, &ops).is_err());
+        let del = RpcRequestKind::DeleteSubtree(Path::from("/app/b"));
+        assert!(acls.check_request(&del, &ops).is_ok());
+        let lock = RpcRequestKind::LockSubtree(Path::from("/app"));
+        assert!(acls.check_request(&lock, &ops).is_err());
+        let undo = RpcRequestKind::Undo(Path::from("/app"));
+        assert!(acls.check_request(&undo, &ops).is_err());
+        let set = RpcRequestKind::SetData { path: Path::from("/app"), value: 1.into() };
+        assert!(acls.check_request(&set, &ops).is_ok());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Acls::parse(r#"{"/app": {"ops": "dx"}}"#).is_err());
+        assert!(Acls::parse(r#"{"/app": {"ops": "d!"}}"#).is_err());
+    }
+}
@@ -1,7 +1,12 @@
-use super::ContainerConfig;
+use super::{
+    export::{self, Export},
+    history::{Change, History, Item},
+    ContainerConfig,
+};
 use anyhow::Result;
 use arcstr::ArcStr;
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, Bytes};
+use chrono::prelude::*;
 use futures::{
     channel::{
         mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
@@ -19,11 +24,23 @@ use netidx::{
     subscriber::Value,
     utils::{BatchItem, Batched},
 };
-use sled;
+use netidx_bscript::{
+    expr::Expr,
+    typ,
+    vm::{Ctx, ExecCtx},
+};
+use parking_lot::RwLock;
+use sled::{
+    self,
+    transaction::{ConflictableTransactionResult, TransactionalTree},
+    Transactional,
+};
 use std::{
-    cmp::{max, min},
-    collections::{HashMap, HashSet},
+    cmp::{self, max, min},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Write,
+    iter,
+    ops::Bound,
     str,
     sync::{
         atomic::{AtomicUsize, Ordering},
@@ -52,7 +69,24 @@ impl Sendable {
 }
 
 pub(super) type Reply = Option<Sendable>;
-type Txns = Vec<(TxnOp, Reply)>;
+
+fn reject(reply: Reply, e: anyhow::Error) {
+    if let Some(reply) = reply {
+        reply.send(Value::Error(Chars::from(format!("{}", e))))
+    }
+}
+
+/// Parse and type check a formula or on write handler
+pub(super) fn parse_expr<C: Ctx, E>(ctx: &ExecCtx<C, E>, value: &Value) -> Result<Expr> {
+    let expr = value.clone().cast_to::<Chars>()?.parse::<Expr>()?;
+    let types = typ::check(ctx, &expr);
+    if types.is_ok() {
+        Ok(expr)
+    } else {
+        bail!("type error: {}", types)
+    }
+}
+type Txns = Vec<(TxnOp, Reply, Option<Chars>)>;
 
 lazy_static! {
     static ref BUF: Pool<Vec<u8>> = Pool::new(8, 16384);
@@ -61,6 +95,22 @@ lazy_static! {
     static ref TXNS: Pool<Txns> = Pool::new(16, 65534);
     static ref STXNS: Pool<Txns> = Pool::new(65534, 32);
     static ref BYPATH: Pool<HashMap<Path, Pooled<Txns>>> = Pool::new(16, 65534);
+    static ref UNDO: Pool<Vec<Undo>> = Pool::new(256, 65534);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum UndoTree {
+    Data,
+    Locked,
+    Roots,
+}
+
+// the previous and new value of a key, used to record history
+pub(super) struct Undo {
+    pub(super) tree: UndoTree,
+    pub(super) key: sled::IVec,
+    pub(super) old: Option<sled::IVec>,
+    pub(super) new: Option<sled::IVec>,
 }
 
 pub(super) enum UpdateKind {
@@ -77,10 +127,14 @@ pub(super) struct Update {
     pub(super) unlocked: Pooled<Vec<Path>>,
     pub(super) added_roots: Pooled<Vec<Path>>,
     pub(super) removed_roots: Pooled<Vec<Path>>,
+    undo: Pooled<Vec<Undo>>,
+    undone: Vec<Bytes>,
+    journal: bool,
 }
 
 impl Update {
-    fn new() -> Update {
+    // if journal is false changes aren't recorded in the history
+    fn new(journal: bool) -> Update {
         Update {
             data: PDPAIR.take(),
             formula: PDPAIR.take(),
@@ -89,9 +143,31 @@ impl Update {
             unlocked: PATHS.take(),
             added_roots: PATHS.take(),
             removed_roots: PATHS.take(),
+            undo: UNDO.take(),
+            undone: Vec::new(),
+            journal,
         }
     }
 
+    fn child(&self) -> Update {
+        Update::new(self.journal)
+    }
+
+    fn journal(
+        &mut self,
+        tree: UndoTree,
+        key: &[u8],
+        old: Option<sled::IVec>,
+        new: Option<&[u8]>,
+    ) {
+        if !self.journal {
+            return;
+        }
+        let key = sled::IVec::from(key);
+        let new = new.map(sled::IVec::from);
+        self.undo.push(Undo { tree, key, old, new })
+    }
+
     fn merge_from(&mut self, mut other: Update) {
         self.data.extend(other.data.drain(..));
         self.formula.extend(other.formula.drain(..));
@@ -100,6 +176,8 @@ impl Update {
         self.unlocked.extend(other.unlocked.drain(..));
         self.added_roots.extend(other.added_roots.drain(..));
         self.removed_roots.extend(other.removed_roots.drain(..));
+        self.undo.extend(other.undo.drain(..));
+        self.undone.append(&mut other.undone);
     }
 
     fn merge(mut self, other: Update) -> Update {
@@ -175,6 +253,180 @@ impl Pack for Datum {
     }
 }
 
+// The tree operations needed to commit transactions. Implemented by
+// sled trees, and by `Staged`, which buffers writes so an atomic
+// transaction can be applied all at once or not at all.
+trait Store: Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<sled::IVec>>;
+    fn insert(&self, key: &[u8], val: &[u8]) -> Result<Option<sled::IVec>>;
+    fn remove(&self, key: &[u8]) -> Result<Option<sled::IVec>>;
+    fn flush(&self) -> Result<()>;
+
+    /// the keys starting with `prefix` in ascending order
+    fn scan_prefix_keys(&self, prefix: &[u8]) -> StoreIter<'_, sled::IVec>;
+
+    /// the pairs with keys below `end` in descending order
+    fn range_back(&self, end: Bound<&[u8]>) -> StoreIter<'_, (sled::IVec, sled::IVec)>;
+
+    /// the underlying sled tree, if this isn't a staging area
+    fn tree(&self) -> Option<&sled::Tree>;
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+}
+
+type StoreIter<'a, T> = Box<dyn Iterator<Item = Result<T>> + 'a>;
+
+impl Store for sled::Tree {
+    fn get(&self, key: &[u8]) -> Result<Option<sled::IVec>> {
+        Ok(sled::Tree::get(self, key)?)
+    }
+
+    fn insert(&self, key: &[u8], val: &[u8]) -> Result<Option<sled::IVec>> {
+        Ok(sled::Tree::insert(self, key, val)?)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<sled::IVec>> {
+        Ok(sled::Tree::remove(self, key)?)
+    }
+
+    fn flush(&self) -> Result<()> {
+        sled::Tree::flush(self)?;
+        Ok(())
+    }
+
+    fn scan_prefix_keys(&self, prefix: &[u8]) -> StoreIter<'_, sled::IVec> {
+        Box::new(self.scan_prefix(prefix).keys().map(|r| Ok(r?)))
+    }
+
+    fn range_back(&self, end: Bound<&[u8]>) -> StoreIter<'_, (sled::IVec, sled::IVec)> {
+        Box::new(self.range::<&[u8], _>((Bound::Unbounded, end)).rev().map(|r| Ok(r?)))
+    }
+
+    fn tree(&self) -> Option<&sled::Tree> {
+        Some(self)
+    }
+}
+
+// Buffers the writes made to a store. Reads see the buffered writes
+// on top of the store.
+struct Staged<'a> {
+    store: &'a dyn Store,
+    writes: RwLock<BTreeMap<sled::IVec, Option<sled::IVec>>>,
+}
+
+impl<'a> Staged<'a> {
+    fn new(store: &'a dyn Store) -> Self {
+        Staged { store, writes: RwLock::new(BTreeMap::new()) }
+    }
+
+    fn write(&self, key: &[u8], val: Option<&[u8]>) -> Result<Option<sled::IVec>> {
+        let mut writes = self.writes.write();
+        let val = val.map(sled::IVec::from);
+        match writes.insert(sled::IVec::from(key), val) {
+            Some(old) => Ok(old),
+            None => self.store.get(key),
+        }
+    }
+
+    fn apply(&self, tree: &TransactionalTree) -> ConflictableTransactionResult<(), ()> {
+        for (k, v) in self.writes.read().iter() {
+            match v {
+                Some(v) => tree.insert(k, v)?,
+                None => tree.remove(k)?,
+            };
+        }
+        Ok(())
+    }
+
+    fn commit(self, store: &dyn Store) -> Result<()> {
+        for (k, v) in self.writes.into_inner() {
+            match v {
+                Some(v) => store.insert(&k, &v)?,
+                None => store.remove(&k)?,
+            };
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Store for Staged<'a> {
+    fn get(&self, key: &[u8]) -> Result<Option<sled::IVec>> {
+        match self.writes.read().get(key) {
+            Some(v) => Ok(v.clone()),
+            None => self.store.get(key),
+        }
+    }
+
+    fn insert(&self, key: &[u8], val: &[u8]) -> Result<Option<sled::IVec>> {
+        self.write(key, Some(val))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<sled::IVec>> {
+        self.write(key, None)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn scan_prefix_keys(&self, prefix: &[u8]) -> StoreIter<'_, sled::IVec> {
+        let mut keys = BTreeSet::new();
+        for r in self.store.scan_prefix_keys(prefix) {
+            match r {
+                Ok(k) => {
+                    keys.insert(k);
+                }
+                Err(e) => return Box::new(iter::once(Err(e))),
+            }
+        }
+        let writes = self.writes.read();
+        let range = writes.range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded));
+        for (k, v) in range.take_while(|(k, _)| k.starts_with(prefix)) {
+            match v {
+                Some(_) => keys.insert(k.clone()),
+                None => keys.remove(k),
+            };
+        }
+        Box::new(keys.into_iter().map(Ok))
+    }
+
+    fn range_back(&self, end: Bound<&[u8]>) -> StoreIter<'_, (sled::IVec, sled::IVec)> {
+        let writes = self.writes.read();
+        let mut staged = writes
+            .range::<[u8], _>((Bound::Unbounded, end))
+            .rev()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .peekable();
+        let mut stored = self.store.range_back(end).peekable();
+        Box::new(iter::from_fn(move || loop {
+            let ord = match (staged.peek(), stored.peek()) {
+                (None, None) => break None,
+                (Some(_), None) => cmp::Ordering::Greater,
+                (None, Some(_)) | (_, Some(Err(_))) => cmp::Ordering::Less,
+                (Some((sk, _)), Some(Ok((k, _)))) => sk.cmp(k),
+            };
+            match ord {
+                cmp::Ordering::Less => break stored.next(),
+                cmp::Ordering::Equal => {
+                    stored.next();
+                }
+                cmp::Ordering::Greater => (),
+            }
+            if let Some((k, Some(v))) = staged.next() {
+                break Some(Ok((k, v)));
+            }
+        }))
+    }
+
+    fn tree(&self) -> Option<&sled::Tree> {
+        None
+    }
+}
+
 fn lookup_value<P: AsRef<[u8]>>(tree: &sled::Tree, path: P) -> Result<Option<Datum>> {
     match tree.get(path.as_ref())? {
         None => Ok(None),
@@ -242,6 +494,9 @@ enum TxnOp {
     AddRoot(Path),
     DelRoot(Path),
     RemoveSubtree(Path),
+    Atomic(Txn),
+    Undo(Path),
+    RevertTo(Path, DateTime<Utc>),
     Flush(oneshot::Sender<()>),
 }
 
@@ -268,24 +523,39 @@ impl TxnOp {
             RemoveSubtree(p) => p.clone(),
             AddRoot(p) => p.clone(),
             DelRoot(p) => p.clone(),
-            Flush(_) => Path::root(),
+            Undo(p) => p.clone(),
+            RevertTo(p, _) => p.clone(),
+            Atomic(_) | Flush(_) => Path::root(),
         }
     }
 }
 
-pub(super) struct Txn(Pooled<Txns>);
+pub(super) struct Txn {
+    ops: Pooled<Txns>,
+    author: Option<Chars>,
+}
 
 impl Txn {
     pub(super) fn new() -> Self {
-        Self(TXNS.take())
+        Self { ops: TXNS.take(), author: None }
     }
 
     pub(super) fn dirty(&self) -> bool {
-        self.0.len() > 0
+        self.ops.len() > 0
+    }
+
+    /// Operations added after this call will be recorded in the
+    /// history as having been done by `author`.
+    pub(super) fn set_author(&mut self, author: Option<Chars>) {
+        self.author = author;
+    }
+
+    fn push(&mut self, op: TxnOp, reply: Reply) {
+        self.ops.push((op, reply, self.author.clone()))
     }
 
     pub(super) fn remove(&mut self, path: Path, reply: Reply) {
-        self.0.push((TxnOp::Remove(path), reply))
+        self.push(TxnOp::Remove(path), reply)
     }
 
     pub(super) fn set_data(
@@ -295,15 +565,35 @@ impl Txn {
         value: Value,
         reply: Reply,
     ) {
-        self.0.push((TxnOp::SetData(update, path, value), reply))
+        self.push(TxnOp::SetData(update, path, value), reply)
     }
 
-    pub(super) fn set_formula(&mut self, path: Path, value: Value, reply: Reply) {
-        self.0.push((TxnOp::SetFormula(path, value), reply))
+    /// Formulas that don't parse or type check are rejected
+    pub(super) fn set_formula<C: Ctx, E>(
+        &mut self,
+        ctx: &ExecCtx<C, E>,
+        path: Path,
+        value: Value,
+        reply: Reply,
+    ) {
+        match parse_expr(ctx, &value) {
+            Ok(_) => self.push(TxnOp::SetFormula(path, value), reply),
+            Err(e) => reject(reply, e),
+        }
     }
 
-    pub(super) fn set_on_write(&mut self, path: Path, value: Value, reply: Reply) {
-        self.0.push((TxnOp::SetOnWrite(path, value), reply))
+    /// On write handlers that don't parse or type check are rejected
+    pub(super) fn set_on_write<C: Ctx, E>(
+        &mut self,
+        ctx: &ExecCtx<C, E>,
+        path: Path,
+        value: Value,
+        reply: Reply,
+    ) {
+        match parse_expr(ctx, &value) {
+            Ok(_) => self.push(TxnOp::SetOnWrite(path, value), reply),
+            Err(e) => reject(reply, e),
+        }
     }
 
     pub(super) fn create_sheet(
@@ -316,26 +606,26 @@ impl Txn {
         lock: bool,
         reply: Reply,
     ) {
-        self.0.push((
+        self.push(
             TxnOp::CreateSheet { base, rows, cols, max_rows, max_columns, lock },
             reply,
-        ))
+        )
     }
 
     pub(super) fn add_sheet_columns(&mut self, base: Path, cols: usize, reply: Reply) {
-        self.0.push((TxnOp::AddSheetColumns { base, cols }, reply))
+        self.push(TxnOp::AddSheetColumns { base, cols }, reply)
     }
 
     pub(super) fn add_sheet_rows(&mut self, base: Path, rows: usize, reply: Reply) {
-        self.0.push((TxnOp::AddSheetRows { base, rows }, reply))
+        self.push(TxnOp::AddSheetRows { base, rows }, reply)
     }
 
     pub(super) fn del_sheet_columns(&mut self, base: Path, cols: usize, reply: Reply) {
-        self.0.push((TxnOp::DelSheetColumns { base, cols }, reply))
+        self.push(TxnOp::DelSheetColumns { base, cols }, reply)
     }
 
     pub(super) fn del_sheet_rows(&mut self, base: Path, rows: usize, reply: Reply) {
-        self.0.push((TxnOp::DelSheetRows { base, rows }, reply))
+        self.push(TxnOp::DelSheetRows { base, rows }, reply)
     }
 
     pub(super) fn create_table(
@@ -346,7 +636,7 @@ impl Txn {
         lock: bool,
         reply: Reply,
     ) {
-        self.0.push((TxnOp::CreateTable { base, rows, cols, lock }, reply))
+        self.push(TxnOp::CreateTable { base, rows, cols, lock }, reply)
     }
 
     pub(super) fn add_table_columns(
@@ -355,11 +645,11 @@ impl Txn {
         cols: Vec<Chars>,
         reply: Reply,
     ) {
-        self.0.push((TxnOp::AddTableColumns { base, cols }, reply))
+        self.push(TxnOp::AddTableColumns { base, cols }, reply)
     }
 
     pub(super) fn add_table_rows(&mut self, base: Path, rows: Vec<Chars>, reply: Reply) {
-        self.0.push((TxnOp::AddTableRows { base, rows }, reply))
+        self.push(TxnOp::AddTableRows { base, rows }, reply)
     }
 
     pub(super) fn del_table_columns(
@@ -368,39 +658,59 @@ impl Txn {
         cols: Vec<Chars>,
         reply: Reply,
     ) {
-        self.0.push((TxnOp::DelTableColumns { base, cols }, reply))
+        self.push(TxnOp::DelTableColumns { base, cols }, reply)
     }
 
     pub(super) fn del_table_rows(&mut self, base: Path, rows: Vec<Chars>, reply: Reply) {
-        self.0.push((TxnOp::DelTableRows { base, rows }, reply))
+        self.push(TxnOp::DelTableRows { base, rows }, reply)
     }
 
     pub(super) fn set_locked(&mut self, path: Path, reply: Reply) {
-        self.0.push((TxnOp::SetLocked(path), reply))
+        self.push(TxnOp::SetLocked(path), reply)
     }
 
     pub(super) fn set_unlocked(&mut self, path: Path, reply: Reply) {
-        self.0.push((TxnOp::SetUnlocked(path), reply))
+        self.push(TxnOp::SetUnlocked(path), reply)
     }
 
     pub(super) fn add_root(&mut self, path: Path, reply: Reply) {
-        self.0.push((TxnOp::AddRoot(path), reply));
+        self.push(TxnOp::AddRoot(path), reply);
     }
 
     pub(super) fn del_root(&mut self, path: Path, reply: Reply) {
-        self.0.push((TxnOp::DelRoot(path), reply));
+        self.push(TxnOp::DelRoot(path), reply);
     }
 
     pub(super) fn remove_subtree(&mut self, path: Path, reply: Reply) {
-        self.0.push((TxnOp::RemoveSubtree(path), reply))
+        self.push(TxnOp::RemoveSubtree(path), reply)
+    }
+
+    /// Apply all the operations in `txn`, or none of them if any
+    /// fail. The replies of the individual operations in `txn` are
+    /// not sent, only `reply` is.
+    pub(super) fn atomic(&mut self, txn: Txn, reply: Reply) {
+        self.push(TxnOp::Atomic(txn), reply)
+    }
+
+    /// Undo the most recent change to the subtree at `path` that
+    /// hasn't already been undone.
+    pub(super) fn undo(&mut self, path: Path, reply: Reply) {
+        self.push(TxnOp::Undo(path), reply)
+    }
+
+    /// Put the subtree at `path` back the way it was at `ts`.
+    pub(super) fn revert_to(&mut self, path: Path, ts: DateTime<Utc>, reply: Reply) {
+        self.push(TxnOp::RevertTo(path, ts), reply)
     }
 }
 
-fn remove(data: &sled::Tree, pending: &mut Update, path: Path) -> Result<()> {
+fn remove(data: &dyn Store, pending: &mut Update, path: Path) -> Result<()> {
     let key = path.as_bytes();
     let mut val = BUF.take();
     Datum::Deleted.encode(&mut *val)?;
-    if let Some(data) = data.insert(key, &**val)? {
+    let old = data.insert(key, &**val)?;
+    pending.journal(UndoTree::Data, key, old.clone(), Some(&**val));
+    if let Some(data) = old {
         match DatumKind::decode(&mut &*data) {
             DatumKind::Data => pending.data.push((path, UpdateKind::Deleted)),
             DatumKind::Formula => {
@@ -414,7 +724,7 @@ fn remove(data: &sled::Tree, pending: &mut Update, path: Path) -> Result<()> {
 }
 
 fn set_data(
-    data: &sled::Tree,
+    data: &dyn Store,
     pending: &mut Update,
     update: bool,
     path: Path,
@@ -424,7 +734,9 @@ fn set_data(
     let mut val = BUF.take();
     let datum = Datum::Data(value.clone());
     datum.encode(&mut *val)?;
-    let up = match data.insert(key, &**val)? {
+    let old = data.insert(key, &**val)?;
+    pending.journal(UndoTree::Data, key, old.clone(), Some(&**val));
+    let up = match old {
         None => UpdateKind::Inserted(value),
         Some(data) => match DatumKind::decode(&mut &*data) {
             DatumKind::Data => UpdateKind::Updated(value),
@@ -440,7 +752,7 @@ fn set_data(
 }
 
 fn set_formula(
-    data: &sled::Tree,
+    data: &dyn Store,
     pending: &mut Update,
     path: Path,
     value: Value,
@@ -468,13 +780,14 @@ fn set_formula(
             }
         },
     };
-    data.insert(key, &**val)?;
+    let old = data.insert(key, &**val)?;
+    pending.journal(UndoTree::Data, key, old, Some(&**val));
     pending.formula.push((path, up));
     Ok(())
 }
 
 fn set_on_write(
-    data: &sled::Tree,
+    data: &dyn Store,
     pending: &mut Update,
     path: Path,
     value: Value,
@@ -502,7 +815,8 @@ fn set_on_write(
             }
         },
     };
-    data.insert(key, &**val)?;
+    let old = data.insert(key, &**val)?;
+    pending.journal(UndoTree::Data, key, old, Some(&**val));
     pending.on_write.push((path.clone(), up));
     Ok(())
 }
@@ -517,8 +831,8 @@ fn merge_err(e0: Result<()>, e1: Result<()>) -> Result<()> {
 }
 
 fn create_sheet(
-    data: &sled::Tree,
-    locked: &sled::Tree,
+    data: &dyn Store,
+    locked: &dyn Store,
     pending: &mut Update,
     base: Path,
     rows: usize,
@@ -535,7 +849,7 @@ fn create_sheet(
         .map(|row| (0..cols).into_par_iter().map(move |col| (row, col)))
         .flatten()
         .fold(
-            || (Update::new(), String::new(), Ok(())),
+            || (pending.child(), String::new(), Ok(())),
             |(mut pending, mut buf, res), (i, j)| {
                 buf.clear();
                 write!(buf, "{:0rwidth$}/{:0cwidth$}", i, j, rwidth = rd, cwidth = cd)
@@ -554,7 +868,7 @@ fn create_sheet(
         )
         .map(|(u, _, r)| (u, r))
         .reduce(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(u0, r0), (u1, r1)| (u0.merge(u1), merge_err(r0, r1)),
         );
     pending.merge_from(up);
@@ -571,12 +885,12 @@ struct SheetDescr {
 }
 
 impl SheetDescr {
-    fn new(data: &sled::Tree, base: &Path) -> Result<Self> {
+    fn new(data: &dyn Store, base: &Path) -> Result<Self> {
         let base_levels = Path::levels(base);
         let mut rows = PATHS.take();
         let mut max_col = 0;
         let mut max_col_width = 0;
-        for r in data.scan_prefix(base.as_bytes()).keys() {
+        for r in data.scan_prefix_keys(base.as_bytes()) {
             if let Ok(k) = r {
                 if let Ok(path) = str::from_utf8(&*k) {
                     if Path::is_parent(base, path) {
@@ -613,7 +927,7 @@ impl SheetDescr {
 }
 
 fn add_sheet_columns(
-    data: &sled::Tree,
+    data: &dyn Store,
     pending: &mut Update,
     base: Path,
     cols: usize,
@@ -629,7 +943,7 @@ fn add_sheet_columns(
         .rows
         .par_drain(..)
         .fold(
-            || (Update::new(), String::new(), Ok(())),
+            || (pending.child(), String::new(), Ok(())),
             |(mut pending, mut buf, mut res), row| {
                 for i in 1..cols + 1 {
                     let col = max_col + i;
@@ -651,7 +965,7 @@ fn add_sheet_columns(
         )
         .map(|(u, _, r)| (u, r))
         .reduce(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(u0, r0), (u1, r1)| (u0.merge(u1), merge_err(r0, r1)),
         );
     pending.merge_from(up);
@@ -659,7 +973,7 @@ fn add_sheet_columns(
 }
 
 fn del_sheet_columns(
-    data: &sled::Tree,
+    data: &dyn Store,
     pending: &mut Update,
     base: Path,
     cols: usize,
@@ -673,7 +987,7 @@ fn del_sheet_columns(
         .rows
         .par_drain(..)
         .fold(
-            || (Update::new(), String::new(), Ok(())),
+            || (pending.child(), String::new(), Ok(())),
             |(mut pending, mut buf, mut res), row| {
                 for col in (max_col - cols..max_col + 1).rev() {
                     buf.clear();
@@ -686,7 +1000,7 @@ fn del_sheet_columns(
         )
         .map(|(u, _, r)| (u, r))
         .reduce(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(u0, r0), (u1, r1)| (u0.merge(u1), merge_err(r0, r1)),
         );
     pending.merge_from(up);
@@ -694,7 +1008,7 @@ fn del_sheet_columns(
 }
 
 fn add_sheet_rows(
-    data: &sled::Tree,
+    data: &dyn Store,
     pending: &mut Update,
     base: Path,
     rows: usize,
@@ -706,7 +1020,7 @@ fn add_sheet_rows(
     let mut max_row_width = 0;
     let mut max_col_width = 0;
     let mut cols = HashSet::new();
-    for r in data.scan_prefix(base.as_bytes()).keys() {
+    for r in data.scan_prefix_keys(base.as_bytes()) {
         let k = r?;
         let path = str::from_utf8(&k)?;
         if Path::is_parent(&base, path) {
@@ -738,7 +1052,7 @@ fn add_sheet_rows(
     let (up, res) = (max_row + 1..max_row + rows)
         .into_par_iter()
         .fold(
-            || (Update::new(), String::new(), Ok(())),
+            || (pending.child(), String::new(), Ok(())),
             |(mut pending, mut buf, mut res), row| {
                 for col in &cols {
                     buf.clear();
@@ -766,7 +1080,7 @@ fn add_sheet_rows(
         )
         .map(|(u, _, r)| (u, r))
         .reduce(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(u0, r0), (u1, r1)| (u0.merge(u1), merge_err(r0, r1)),
         );
     pending.merge_from(up);
@@ -774,7 +1088,7 @@ fn add_sheet_rows(
 }
 
 fn del_sheet_rows(
-    data: &sled::Tree,
+    data: &dyn Store,
     pending: &mut Update,
     base: Path,
     rows: usize,
@@ -787,14 +1101,14 @@ fn del_sheet_rows(
         .rows
         .par_drain(len - rows..len + 1)
         .fold(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(mut pending, res), row| {
                 let res = merge_err(remove_subtree(data, &mut pending, row), res);
                 (pending, res)
             },
         )
         .reduce(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(u0, r0), (u1, r1)| (u0.merge(u1), merge_err(r0, r1)),
         );
     pending.merge_from(up);
@@ -802,8 +1116,8 @@ fn del_sheet_rows(
 }
 
 fn create_table(
-    data: &sled::Tree,
-    locked: &sled::Tree,
+    data: &dyn Store,
+    locked: &dyn Store,
     pending: &mut Update,
     base: Path,
     rows: Vec<Chars>,
@@ -820,7 +1134,7 @@ fn create_table(
         .map(|row| cols.par_iter().map(move |col| (row, col)))
         .flatten()
         .fold(
-            || (Update::new(), String::new(), Ok(())),
+            || (pending.child(), String::new(), Ok(())),
             |(mut pending, mut buf, res), (row, col)| {
                 buf.clear();
                 write!(buf, "{}/{}", row, col).unwrap();
@@ -838,7 +1152,7 @@ fn create_table(
         )
         .map(|(u, _, r)| (u, r))
         .reduce(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(u0, r0), (u1, r1)| (u0.merge(u1), merge_err(r0, r1)),
         );
     pending.merge_from(up);
@@ -848,10 +1162,10 @@ fn create_table(
     res
 }
 
-fn table_rows(data: &sled::Tree, base: &Path) -> Result<Pooled<Vec<Path>>> {
+fn table_rows(data: &dyn Store, base: &Path) -> Result<Pooled<Vec<Path>>> {
     let base_levels = Path::levels(&base);
     let mut paths = PATHS.take();
-    for r in data.scan_prefix(base.as_bytes()).keys() {
+    for r in data.scan_prefix_keys(base.as_bytes()) {
         let k = r?;
         if let Ok(path) = str::from_utf8(&*k) {
             if Path::is_parent(base, path) {
@@ -876,7 +1190,7 @@ fn table_rows(data: &sled::Tree, base: &Path) -> Result<Pooled<Vec<Path>>> {
 }
 
 fn add_table_columns(
-    data: &sled::Tree,
+    data: &dyn Store,
     pending: &mut Update,
     base: Path,
     cols: Vec<Chars>,
@@ -887,7 +1201,7 @@ fn add_table_columns(
     let (up, res) = table_rows(data, &base)?
         .par_drain(..)
         .fold(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(mut pending, mut res), row| {
                 for col in &cols {
                     let path = row.append(col);
@@ -904,7 +1218,7 @@ fn add_table_columns(
             },
         )
         .reduce(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(u0, r0), (u1, r1)| (u0.merge(u1), merge_err(r0, r1)),
         );
     pending.merge_from(up);
@@ -912,7 +1226,7 @@ fn add_table_columns(
 }
 
 fn del_table_columns(
-    data: &sled::Tree,
+    data: &dyn Store,
     pending: &mut Update,
     base: Path,
     cols: Vec<Chars>,
@@ -923,7 +1237,7 @@ fn del_table_columns(
     let (up, res) = table_rows(data, &base)?
         .par_drain(..)
         .fold(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(mut pending, mut res), row| {
                 for col in &cols {
                     let path = row.append(col);
@@ -933,7 +1247,7 @@ fn del_table_columns(
             },
         )
         .reduce(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(u0, r0), (u1, r1)| (u0.merge(u1), merge_err(r0, r1)),
         );
     pending.merge_from(up);
@@ -941,7 +1255,7 @@ fn del_table_columns(
 }
 
 fn add_table_rows(
-    data: &sled::Tree,
+    data: &dyn Store,
     pending: &mut Update,
     base: Path,
     rows: Vec<Chars>,
@@ -951,7 +1265,7 @@ fn add_table_rows(
         rows.into_iter().map(|c| Path::escape(&c).into_owned()).collect();
     let base_levels = Path::levels(&base);
     let mut cols = HashSet::new();
-    for r in data.scan_prefix(base.as_bytes()).keys() {
+    for r in data.scan_prefix_keys(base.as_bytes()) {
         let k = r?;
         let path = str::from_utf8(&k)?;
         if Path::is_parent(&base, path) {
@@ -968,7 +1282,7 @@ fn add_table_rows(
     let (up, res) = rows
         .into_par_iter()
         .fold(
-            || (Update::new(), String::new(), Ok(())),
+            || (pending.child(), String::new(), Ok(())),
             |(mut pending, mut buf, mut res), row| {
                 for col in &cols {
                     buf.clear();
@@ -988,7 +1302,7 @@ fn add_table_rows(
         )
         .map(|(u, _, r)| (u, r))
         .reduce(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(u0, r0), (u1, r1)| (u0.merge(u1), merge_err(r0, r1)),
         );
     pending.merge_from(up);
@@ -996,7 +1310,7 @@ fn add_table_rows(
 }
 
 fn del_table_rows(
-    data: &sled::Tree,
+    data: &dyn Store,
     pending: &mut Update,
     base: Path,
     rows: Vec<Chars>,
@@ -1007,7 +1321,7 @@ fn del_table_rows(
     let (up, res) = rows
         .into_par_iter()
         .fold(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(mut pending, res), row| {
                 let path = base.append(&row);
                 let res = merge_err(remove_subtree(data, &mut pending, path), res);
@@ -1015,21 +1329,21 @@ fn del_table_rows(
             },
         )
         .reduce(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(u0, r0), (u1, r1)| (u0.merge(u1), merge_err(r0, r1)),
         );
     pending.merge_from(up);
     res
 }
 
-fn is_locked(locked: &sled::Tree, path: &Path, parent_only: bool) -> Result<bool> {
+fn is_locked(locked: &dyn Store, path: &Path, parent_only: bool) -> Result<bool> {
     let mut iter = if parent_only {
-        locked.range(..path.as_bytes())
+        locked.range_back(Bound::Excluded(path.as_bytes()))
     } else {
-        locked.range(..=path.as_bytes())
+        locked.range_back(Bound::Included(path.as_bytes()))
     };
     loop {
-        match iter.next_back() {
+        match iter.next() {
             None => break Ok(false),
             Some(r) => {
                 let (k, v) = r?;
@@ -1042,30 +1356,32 @@ fn is_locked(locked: &sled::Tree, path: &Path, parent_only: bool) -> Result<bool
     }
 }
 
-fn set_locked(locked: &sled::Tree, pending: &mut Update, path: Path) -> Result<()> {
-    if is_locked(locked, &path, true)? {
-        locked.remove(path.as_bytes())?;
+fn set_locked(locked: &dyn Store, pending: &mut Update, path: Path) -> Result<()> {
+    let (old, new) = if is_locked(locked, &path, true)? {
+        (locked.remove(path.as_bytes())?, None)
     } else {
-        locked.insert(path.as_bytes(), &[1u8])?;
-    }
+        (locked.insert(path.as_bytes(), &[1u8])?, Some(&[1u8][..]))
+    };
+    pending.journal(UndoTree::Locked, path.as_bytes(), old, new);
     pending.locked.push(path);
     Ok(())
 }
 
-fn set_unlocked(locked: &sled::Tree, pending: &mut Update, path: Path) -> Result<()> {
-    if !is_locked(locked, &path, true)? {
-        locked.remove(path.as_bytes())?;
+fn set_unlocked(locked: &dyn Store, pending: &mut Update, path: Path) -> Result<()> {
+    let (old, new) = if !is_locked(locked, &path, true)? {
+        (locked.remove(path.as_bytes())?, None)
     } else {
-        locked.insert(path.as_bytes(), &[0u8])?;
-    }
+        (locked.insert(path.as_bytes(), &[0u8])?, Some(&[0u8][..]))
+    };
+    pending.journal(UndoTree::Locked, path.as_bytes(), old, new);
     pending.unlocked.push(path);
     Ok(())
 }
 
-fn remove_subtree(data: &sled::Tree, pending: &mut Update, path: Path) -> Result<()> {
+fn remove_subtree(data: &dyn Store, pending: &mut Update, path: Path) -> Result<()> {
     use rayon::prelude::*;
     let mut paths = PATHS.take();
-    for res in data.scan_prefix(path.as_ref()).keys() {
+    for res in data.scan_prefix_keys(path.as_bytes()) {
         let key = res?;
         let key = str::from_utf8(&key)?;
         if Path::is_parent(&path, &key) {
@@ -1075,23 +1391,23 @@ fn remove_subtree(data: &sled::Tree, pending: &mut Update, path: Path) -> Result
     let (up, res) = paths
         .par_drain(..)
         .fold(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(mut pending, res), path| {
                 let res = merge_err(remove(data, &mut pending, path), res);
                 (pending, res)
             },
         )
         .reduce(
-            || (Update::new(), Ok(())),
+            || (pending.child(), Ok(())),
             |(u0, r0), (u1, r1)| (u0.merge(u1), merge_err(r0, r1)),
         );
     pending.merge_from(up);
     res
 }
 
-fn add_root(roots: &sled::Tree, pending: &mut Update, path: Path) -> Result<()> {
+fn add_root(roots: &dyn Store, pending: &mut Update, path: Path) -> Result<()> {
     let key = path.as_bytes();
-    if let Some(r) = roots.range(..key).next_back() {
+    if let Some(r) = roots.range_back(Bound::Excluded(key)).next() {
         let (prev, _) = r?;
         let prev = str::from_utf8(&prev)?;
         if Path::is_parent(prev, &path) {
@@ -1099,27 +1415,28 @@ fn add_root(roots: &sled::Tree, pending: &mut Update, path: Path) -> Result<()>
         }
     }
     if !roots.contains_key(key)? {
-        roots.insert(key, &[])?;
+        let old = roots.insert(key, &[])?;
+        pending.journal(UndoTree::Roots, key, old, Some(&[]));
         pending.added_roots.push(path);
     }
     Ok(())
 }
 
 fn del_root(
-    data: &sled::Tree,
-    roots: &sled::Tree,
-    locked: &sled::Tree,
+    data: &dyn Store,
+    roots: &dyn Store,
+    locked: &dyn Store,
     pending: &mut Update,
     path: Path,
 ) -> Result<()> {
     let key = path.as_bytes();
     if roots.contains_key(key)? {
-        let mut iter = roots.range(..key).keys();
+        let mut iter = roots.range_back(Bound::Excluded(key));
         let remove = loop {
-            match iter.next_back() {
+            match iter.next() {
                 None => break false,
                 Some(r) => {
-                    let k = r?;
+                    let (k, _) = r?;
                     let k = str::from_utf8(&k)?;
                     if Path::is_parent(k, &path) {
                         break true
@@ -1130,19 +1447,21 @@ fn del_root(
         if remove {
             remove_subtree(data, pending, path.clone())?
         }
-        for r in roots.scan_prefix(key).keys() {
+        for r in roots.scan_prefix_keys(key) {
             let k = r?;
             let k = str::from_utf8(&k)?;
             if Path::is_parent(&path, k) {
-                roots.remove(&k)?;
+                let old = roots.remove(k.as_bytes())?;
+                pending.journal(UndoTree::Roots, k.as_bytes(), old, None);
                 pending.removed_roots.push(Path::from(ArcStr::from(k)));
             }
         }
-        for r in locked.scan_prefix(key).keys() {
+        for r in locked.scan_prefix_keys(key) {
             let k = r?;
             let k = str::from_utf8(&k)?;
             if Path::is_parent(&path, k) {
-                locked.remove(&k)?;
+                let old = locked.remove(k.as_bytes())?;
+                pending.journal(UndoTree::Locked, k.as_bytes(), old, None);
                 pending.unlocked.push(Path::from(ArcStr::from(k)));
             }
         }
@@ -1150,6 +1469,108 @@ fn del_root(
     Ok(())
 }
 
+// put the key changed by `c` back to it's old value
+fn restore(
+    data: &dyn Store,
+    locked: &dyn Store,
+    roots: &dyn Store,
+    pending: &mut Update,
+    c: Change,
+) -> Result<()> {
+    let path = match c.path() {
+        Some(p) => Path::from(ArcStr::from(p)),
+        None => bail!("invalid path in history"),
+    };
+    match c.tree {
+        UndoTree::Data => match &c.old {
+            Some(v) => match Datum::decode(&mut &**v)? {
+                Datum::Data(v) => set_data(data, pending, true, path, v),
+                Datum::Formula(f, w) => {
+                    set_formula(data, pending, path.clone(), f)?;
+                    set_on_write(data, pending, path, w)
+                }
+                Datum::Deleted => remove(data, pending, path),
+            },
+            None => match data.get(&c.key)? {
+                None => Ok(()),
+                Some(_) => remove(data, pending, path),
+            },
+        },
+        UndoTree::Locked => match (c.old.as_deref(), locked.get(&c.key)?.as_deref()) {
+            (Some([1u8]), Some([1u8])) | (Some([0u8]), Some([0u8])) | (None, None) => {
+                Ok(())
+            }
+            (Some([1u8]), _) | (None, Some([0u8])) => set_locked(locked, pending, path),
+            (Some(_), _) | (None, Some(_)) => set_unlocked(locked, pending, path),
+        },
+        UndoTree::Roots => match c.old {
+            Some(_) => {
+                if !roots.contains_key(&c.key)? {
+                    let old = roots.insert(&c.key, &[])?;
+                    pending.journal(UndoTree::Roots, &c.key, old, Some(&[]));
+                    pending.added_roots.push(path);
+                }
+                Ok(())
+            }
+            None => {
+                if let Some(old) = roots.remove(&c.key)? {
+                    pending.journal(UndoTree::Roots, &c.key, Some(old), None);
+                    pending.removed_roots.push(path);
+                }
+                Ok(())
+            }
+        },
+    }
+}
+
+fn undo(
+    data: &dyn Store,
+    locked: &dyn Store,
+    roots: &dyn Store,
+    history: &History,
+    pending: &mut Update,
+    path: Path,
+) -> Result<()> {
+    match history.undo(&path)? {
+        None => bail!("nothing to undo"),
+        Some((key, changes)) => {
+            for c in changes {
+                restore(data, locked, roots, pending, c)?
+            }
+            pending.undone.push(key);
+            Ok(())
+        }
+    }
+}
+
+fn revert_to(
+    data: &dyn Store,
+    locked: &dyn Store,
+    roots: &dyn Store,
+    history: &History,
+    pending: &mut Update,
+    path: Path,
+    ts: DateTime<Utc>,
+) -> Result<()> {
+    // changes come newest first, so the last change seen to each key
+    // holds the value it had at ts
+    let mut changes: Vec<Change> = Vec::new();
+    let mut by_key: HashMap<(UndoTree, Bytes), usize> = HashMap::new();
+    for c in history.since(&path, ts)? {
+        match by_key.get(&(c.tree, c.key.clone())) {
+            Some(i) => changes[*i] = c,
+            None => {
+                by_key.insert((c.tree, c.key.clone()), changes.len());
+                changes.push(c);
+            }
+        }
+    }
+    for c in changes.into_iter().rev() {
+        restore(data, locked, roots, pending, c)?
+    }
+    Ok(())
+}
+
 fn send_reply(reply: Reply, r: Result<()>) {
     match (r, reply) {
         (Ok(()), Some(reply)) => {
@@ -1163,94 +1584,167 @@ fn send_reply(reply: Reply, r: Result<()>) {
     }
 }
 
+fn commit_op(
+    data: &dyn Store,
+    locked: &dyn Store,
+    roots: &dyn Store,
+    history: &History,
+    pending: &mut Update,
+    op: TxnOp,
+) -> Result<()> {
+    match op {
+        TxnOp::CreateSheet { base, rows, cols, max_rows, max_columns, lock } => {
+            create_sheet(
+                data,
+                locked,
+                pending,
+                base,
+                rows,
+                cols,
+                max_rows,
+                max_columns,
+                lock,
+            )
+        }
+        TxnOp::AddSheetColumns { base, cols } => {
+            add_sheet_columns(data, pending, base, cols)
+        }
+        TxnOp::AddSheetRows { base, rows } => add_sheet_rows(data, pending, base, rows),
+        TxnOp::DelSheetColumns { base, cols } => {
+            del_sheet_columns(data, pending, base, cols)
+        }
+        TxnOp::DelSheetRows { base, rows } => del_sheet_rows(data, pending, base, rows),
+        TxnOp::CreateTable { base, rows, cols, lock } => {
+            create_table(data, locked, pending, base, rows, cols, lock)
+        }
+        TxnOp::AddTableColumns { base, cols } => {
+            add_table_columns(data, pending, base, cols)
+        }
+        TxnOp::AddTableRows { base, rows } => add_table_rows(data, pending, base, rows),
+        TxnOp::DelTableColumns { base, cols } => {
+            del_table_columns(data, pending, base, cols)
+        }
+        TxnOp::DelTableRows { base, rows } => del_table_rows(data, pending, base, rows),
+        TxnOp::Remove(path) => remove(data, pending, path),
+        TxnOp::RemoveSubtree(path) => remove_subtree(data, pending, path),
+        TxnOp::SetData(update, path, value) => {
+            set_data(data, pending, update, path, value)
+        }
+        TxnOp::SetFormula(path, value) => set_formula(data, pending, path, value),
+        TxnOp::SetOnWrite(path, value) => set_on_write(data, pending, path, value),
+        TxnOp::SetLocked(path) => set_locked(locked, pending, path),
+        TxnOp::SetUnlocked(path) => set_unlocked(locked, pending, path),
+        TxnOp::AddRoot(path) => add_root(roots, pending, path),
+        TxnOp::DelRoot(path) => del_root(data, roots, locked, pending, path),
+        TxnOp::Atomic(txn) => commit_atomic(data, locked, roots, history, pending, txn),
+        TxnOp::Undo(path) => undo(data, locked, roots, history, pending, path),
+        TxnOp::RevertTo(path, ts) => {
+            revert_to(data, locked, roots, history, pending, path, ts)
+        }
+        TxnOp::Flush(finished) => {
+            let _: Result<_, _> = data.flush();
+            let _: Result<_, _> = locked.flush();
+            let _: Result<_, _> = history.flush();
+            let _: Result<_, _> = finished.send(());
+            Ok(())
+        }
+    }
+}
+
+// sled transactions can't scan, which sheets and tables need, so
+// the ops are run against a staging area that buffers their writes,
+// and only if all of them succeed are the writes applied, in one
+// sled transaction.
+fn commit_atomic(
+    data: &dyn Store,
+    locked: &dyn Store,
+    roots: &dyn Store,
+    history: &History,
+    pending: &mut Update,
+    mut txn: Txn,
+) -> Result<()> {
+    let (sdata, slocked, sroots) =
+        (Staged::new(data), Staged::new(locked), Staged::new(roots));
+    let mut up = pending.child();
+    for (op, _, _) in txn.ops.drain(..) {
+        if let Err(e) = commit_op(&sdata, &slocked, &sroots, history, &mut up, op) {
+            bail!("transaction aborted: {}", e)
+        }
+    }
+    match (data.tree(), locked.tree(), roots.tree()) {
+        (Some(data), Some(locked), Some(roots)) => {
+            (data, locked, roots)
+                .transaction(|(data, locked, roots)| {
+                    sdata.apply(data)?;
+                    slocked.apply(locked)?;
+                    sroots.apply(roots)
+                })
+                .map_err(|e| anyhow!("transaction failed: {:?}", e))?;
+        }
+        // nested in another atomic transaction, which will commit
+        // or discard our writes along with it's own
+        _ => {
+            sdata.commit(data)?;
+            slocked.commit(locked)?;
+            sroots.commit(roots)?;
+        }
+    }
+    pending.merge_from(up);
+    Ok(())
+}
+
+// record everything op changed since `n` journal entries and `u`
+// undone entries in the history
+fn record(
+    history: &History,
+    pending: &Update,
+    author: &Option<Chars>,
+    n: usize,
+    u: usize,
+    r: Result<()>,
+) -> Result<()> {
+    let rec = history.record(author, &pending.undone[u..], &pending.undo[n..]);
+    merge_err(r, rec)
+}
+
 fn commit_complex(
     data: &sled::Tree,
     locked: &sled::Tree,
     roots: &sled::Tree,
+    history: &History,
     mut txn: Txn,
 ) -> Update {
-    let mut pending = Update::new();
-    for (op, reply) in txn.0.drain(..) {
-        let r = match op {
-            TxnOp::CreateSheet { base, rows, cols, max_rows, max_columns, lock } => {
-                create_sheet(
-                    &data,
-                    &locked,
-                    &mut pending,
-                    base,
-                    rows,
-                    cols,
-                    max_rows,
-                    max_columns,
-                    lock,
-                )
-            }
-            TxnOp::AddSheetColumns { base, cols } => {
-                add_sheet_columns(&data, &mut pending, base, cols)
-            }
-            TxnOp::AddSheetRows { base, rows } => {
-                add_sheet_rows(&data, &mut pending, base, rows)
-            }
-            TxnOp::DelSheetColumns { base, cols } => {
-                del_sheet_columns(&data, &mut pending, base, cols)
-            }
-            TxnOp::DelSheetRows { base, rows } => {
-                del_sheet_rows(&data, &mut pending, base, rows)
-            }
-            TxnOp::CreateTable { base, rows, cols, lock } => {
-                create_table(&data, &locked, &mut pending, base, rows, cols, lock)
-            }
-            TxnOp::AddTableColumns { base, cols } => {
-                add_table_columns(&data, &mut pending, base, cols)
-            }
-            TxnOp::AddTableRows { base, rows } => {
-                add_table_rows(&data, &mut pending, base, rows)
-            }
-            TxnOp::DelTableColumns { base, cols } => {
-                del_table_columns(&data, &mut pending, base, cols)
-            }
-            TxnOp::DelTableRows { base, rows } => {
-                del_table_rows(&data, &mut pending, base, rows)
-            }
-            TxnOp::Remove(path) => remove(&data, &mut pending, path),
-            TxnOp::RemoveSubtree(path) => remove_subtree(&data, &mut pending, path),
-            TxnOp::SetData(update, path, value) => {
-                set_data(&data, &mut pending, update, path, value)
-            }
-            TxnOp::SetFormula(path, value) => {
-                set_formula(&data, &mut pending, path, value)
-            }
-            TxnOp::SetOnWrite(path, value) => {
-                set_on_write(&data, &mut pending, path, value)
-            }
-            TxnOp::SetLocked(path) => set_locked(&locked, &mut pending, path),
-            TxnOp::SetUnlocked(path) => set_unlocked(&locked, &mut pending, path),
-            TxnOp::AddRoot(path) => add_root(&roots, &mut pending, path),
-            TxnOp::DelRoot(path) => del_root(&data, &roots, &locked, &mut pending, path),
-            TxnOp::Flush(finished) => {
-                let _: Result<_, _> = data.flush();
-                let _: Result<_, _> = locked.flush();
-                let _: Result<_, _> = finished.send(());
-                Ok(())
-            }
-        };
-        send_reply(reply, r);
+    let mut pending = Update::new(history.enabled());
+    for (op, reply, author) in txn.ops.drain(..) {
+        let (n, u) = (pending.undo.len(), pending.undone.len());
+        let r = commit_op(data, locked, roots, history, &mut pending, op);
+        send_reply(reply, record(history, &pending, &author, n, u, r));
     }
     pending
 }
 
-fn commit_simple(data: &sled::Tree, locked: &sled::Tree, mut txn: Txn) -> Update {
+fn commit_simple(
+    data: &sled::Tree,
+    locked: &sled::Tree,
+    history: &History,
+    mut txn: Txn,
+) -> Update {
     use rayon::prelude::*;
+    let journal = history.enabled();
     let mut by_path = BYPATH.take();
-    for (op, reply) in txn.0.drain(..) {
-        by_path.entry(op.path()).or_insert_with(|| STXNS.take()).push((op, reply));
+    for (op, reply, author) in txn.ops.drain(..) {
+        by_path
+            .entry(op.path())
+            .or_insert_with(|| STXNS.take())
+            .push((op, reply, author));
     }
     by_path
         .par_drain()
         .fold(
-            || Update::new(),
+            || Update::new(journal),
             |mut pending, (_, mut ops)| {
-                for (op, reply) in ops.drain(..) {
+                for (op, reply, author) in ops.drain(..) {
+                    let (n, u) = (pending.undo.len(), pending.undone.len());
                     let r = match op {
                         TxnOp::SetData(update, path, value) => {
                             set_data(data, &mut pending, update, path, value)
@@ -1279,14 +1773,17 @@ fn commit_simple(data: &sled::Tree, locked: &sled::Tree, mut txn: Txn) -> Update
                         | TxnOp::RemoveSubtree { .. }
                         | TxnOp::AddRoot(_)
                         | TxnOp::DelRoot(_)
+                        | TxnOp::Atomic(_)
+                        | TxnOp::Undo(_)
+                        | TxnOp::RevertTo(_, _)
                         | TxnOp::Flush(_) => unreachable!(),
                     };
-                    send_reply(reply, r)
+                    send_reply(reply, record(history, &pending, &author, n, u, r))
                 }
                 pending
             },
         )
-        .reduce(|| Update::new(), |u0, u1| u0.merge(u1))
+        .reduce(|| Update::new(journal), |u0, u1| u0.merge(u1))
 }
 
 struct Stats {
@@ -1387,6 +1884,7 @@ async fn commit_txns_task(
     data: sled::Tree,
     locked: sled::Tree,
     roots: sled::Tree,
+    history: Arc<History>,
     incoming: UnboundedReceiver<Txn>,
     outgoing: UnboundedSender<Update>,
 ) {
@@ -1410,7 +1908,7 @@ async fn commit_txns_task(
             txn = incoming.select_next_some() => {
                 stats.dec_queued();
                 let (simple, delete) =
-                    txn.0.iter().fold((true, false), |(simple, delete), op| match &op.0 {
+                    txn.ops.iter().fold((true, false), |(simple, delete), op| match &op.0 {
                         TxnOp::CreateSheet { .. }
                         | TxnOp::AddSheetColumns { .. }
                         | TxnOp::AddSheetRows { .. }
@@ -1424,7 +1922,10 @@ async fn commit_txns_task(
                         | TxnOp::DelTableRows { .. }
                         | TxnOp::DelSheetColumns { .. }
                         | TxnOp::DelSheetRows { .. }
-                        | TxnOp::DelRoot(_) => (false, true),
+                        | TxnOp::DelRoot(_)
+                        | TxnOp::Atomic(_)
+                        | TxnOp::Undo(_)
+                        | TxnOp::RevertTo(_, _) => (false, true),
                         TxnOp::Remove(_) => (simple, true),
                         TxnOp::SetData(_, _, _)
                         | TxnOp::SetFormula(_, _)
@@ -1433,11 +1934,18 @@ async fn commit_txns_task(
                         | TxnOp::SetUnlocked(_) => (simple, delete),
                     });
                 delete_required |= delete;
-                let pending = if simple {
-                    task::block_in_place(|| commit_simple(&data, &locked, txn))
-                } else {
-                    task::block_in_place(|| commit_complex(&data, &locked, &roots, txn))
-                };
+                let mut pending = task::block_in_place(|| {
+                    let pending = if simple {
+                        commit_simple(&data, &locked, &history, txn)
+                    } else {
+                        commit_complex(&data, &locked, &roots, &history, txn)
+                    };
+                    // CR estokes: log this
+                    let _: Result<_> = history.trim();
+                    pending
+                });
+                pending.undo.clear();
+                pending.undone.clear();
                 stats.set_busy(false);
                 match outgoing.unbounded_send(pending) {
                     Ok(()) => (),
@@ -1459,6 +1967,7 @@ pub(super) struct Db {
     data: sled::Tree,
     locked: sled::Tree,
     roots: sled::Tree,
+    history: Arc<History>,
     submit_txn: UnboundedSender<Txn>,
     stats: Arc<Stats>,
 }
@@ -1474,11 +1983,16 @@ impl Db {
             .use_compression(cfg.compress)
             .compression_factor(cfg.compress_level.unwrap_or(5) as i32)
             .cache_capacity(cfg.cache_size.unwrap_or(16 * 1024 * 1024))
-            .path(&cfg.db)
+            .path(cfg.db.as_ref().ok_or_else(|| anyhow!("--db is required"))?)
             .open()?;
         let data = db.open_tree("data")?;
         let locked = db.open_tree("locked")?;
         let roots = db.open_tree("roots")?;
+        let history = Arc::new(History::new(
+            db.open_tree("history")?,
+            db.open_tree("history_paths")?,
+            cfg.history,
+        ));
         let (tx_incoming, rx_incoming) = unbounded();
         let (tx_outgoing, rx_outgoing) = unbounded();
         task::spawn(commit_txns_task(
@@ -1486,11 +2000,12 @@ impl Db {
             data.clone(),
             locked.clone(),
             roots.clone(),
+            history.clone(),
             rx_incoming,
             tx_outgoing,
         ));
         Ok((
-            Db { _db: db, data, locked, roots, submit_txn: tx_incoming, stats },
+            Db { _db: db, data, locked, roots, history, submit_txn: tx_incoming, stats },
             rx_outgoing,
         ))
     }
@@ -1503,7 +2018,7 @@ impl Db {
     pub(super) async fn flush_async(&self) -> Result<()> {
         let (tx, rx) = oneshot::channel();
         let mut txn = Txn::new();
-        txn.0.push((TxnOp::Flush(tx), None));
+        txn.push(TxnOp::Flush(tx), None);
         self.commit(txn);
         let _: Result<_, _> = rx.await;
         Ok(())
@@ -1666,4 +2181,247 @@ impl Db {
     pub(super) fn roots(&self) -> impl Iterator<Item = Result<Path>> + 'static {
         iter_paths(&self.roots)
     }
+
+    /// Copy the data, formulas, and lock state of the subtree at
+    /// `base`
+    pub(super) fn export(&self, base: &Path) -> Result<Export> {
+        fn relative<'a>(base: &Path, path: &'a str) -> &'a str {
+            path[base.len()..].trim_start_matches('/')
+        }
+        let mut export = Export::new();
+        for r in self.data.scan_prefix(base.as_bytes()) {
+            let (k, v) = r?;
+            let path = str::from_utf8(&k)?;
+            if Path::is_parent(base, path) {
+                let item = match Datum::decode(&mut &*v)? {
+                    Datum::Deleted => continue,
+                    Datum::Data(v) => export::Item::Data(v),
+                    Datum::Formula(f, w) => export::Item::formula(f, w),
+                };
+                export.data.push((String::from(relative(base, path)), item));
+            }
+        }
+        if !self.locked.contains_key(base.as_bytes())? {
+            let locked = is_locked(&self.locked, base, false)?;
+            export.locked.push((String::new(), locked));
+        }
+        for r in self.locked.scan_prefix(base.as_bytes()) {
+            let (k, v) = r?;
+            let path = str::from_utf8(&k)?;
+            if Path::is_parent(base, path) {
+                let locked = *v == [1u8];
+                export.locked.push((String::from(relative(base, path)), locked));
+            }
+        }
+        export.describe();
+        Ok(export)
+    }
+
+    pub(super) fn history(&self, path: &Path, limit: usize) -> Result<Vec<Item>> {
+        self.history.items(path, limit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Trees {
+        data: sled::Tree,
+        locked: sled::Tree,
+        roots: sled::Tree,
+        history: History,
+        history_tree: sled::Tree,
+        history_index: sled::Tree,
+    }
+
+    type Contents = Vec<(sled::IVec, sled::IVec)>;
+
+    impl Trees {
+        fn new(history: usize) -> Self {
+            let db = sled::Config::new().temporary(true).open().unwrap();
+            let history_tree = db.open_tree("history").unwrap();
+            let history_index = db.open_tree("history_paths").unwrap();
+            Trees {
+                data: db.open_tree("data").unwrap(),
+                locked: db.open_tree("locked").unwrap(),
+                roots: db.open_tree("roots").unwrap(),
+                history: History::new(
+                    history_tree.clone(),
+                    history_index.clone(),
+                    history,
+                ),
+                history_tree,
+                history_index,
+            }
+        }
+
+        fn commit(&self, txn: Txn) -> Update {
+            commit_complex(&self.data, &self.locked, &self.roots, &self.history, txn)
+        }
+
+        fn contents(&self) -> (Contents, Contents, Contents) {
+            let get = |t: &sled::Tree| t.iter().map(|r| r.unwrap()).collect::<Vec<_>>();
+            (get(&self.data), get(&self.locked), get(&self.roots))
+        }
+    }
+
+    fn atomic(txn: Txn) -> (Txn, oneshot::Receiver<Value>) {
+        let (tx, rx) = oneshot::channel();
+        let mut outer = Txn::new();
+        outer.atomic(txn, Some(Sendable::Rpc(tx)));
+        (outer, rx)
+    }
+
+    #[test]
+    fn atomic_abort() {
+        let trees = Trees::new(0);
+        let mut txn = Txn::new();
+        txn.add_root(Path::from("/app"), None);
+        txn.set_data(true, Path::from("/app/a"), Value::I64(1), None);
+        trees.commit(txn);
+        let before = trees.contents();
+        let mut txn = Txn::new();
+        txn.set_data(true, Path::from("/app/a"), Value::I64(2), None);
+        txn.set_locked(Path::from("/app"), None);
+        txn.add_root(Path::from("/app/b"), None);
+        txn.set_data(true, Path::from("/app/c"), Value::I64(3), None);
+        let (txn, mut reply) = atomic(txn);
+        let pending = trees.commit(txn);
+        assert!(matches!(reply.try_recv(), Ok(Some(Value::Error(_)))));
+        assert!(pending.data.is_empty());
+        assert!(pending.locked.is_empty());
+        assert_eq!(before, trees.contents());
+    }
+
+    #[test]
+    fn atomic_commit() {
+        let trees = Trees::new(0);
+        let mut txn = Txn::new();
+        let base = Path::from("/app/t");
+        let rows = vec![Chars::from("r0"), Chars::from("r1")];
+        txn.create_table(base.clone(), rows, vec![Chars::from("c0")], false, None);
+        txn.add_table_columns(base.clone(), vec![Chars::from("c1")], None);
+        txn.remove(Path::from("/app/t/r1/c0"), None);
+        txn.set_locked(base.clone(), None);
+        let (txn, mut reply) = atomic(txn);
+        let pending = trees.commit(txn);
+        assert_eq!(reply.try_recv().unwrap(), Some(Value::Ok));
+        assert_eq!(pending.data.len(), 5);
+        let v = lookup_value(&trees.data, "/app/t/r1/c1").unwrap();
+        assert!(matches!(v, Some(Datum::Data(Value::Null))));
+        let v = lookup_value(&trees.data, "/app/t/r1/c0").unwrap();
+        assert!(matches!(v, Some(Datum::Deleted)));
+        assert!(is_locked(&trees.locked, &Path::from("/app/t/r0"), false).unwrap());
+    }
+
+    #[test]
+    fn staged_range_back() {
+        let trees = Trees::new(0);
+        for k in ["/a", "/b", "/d"] {
+            trees.locked.insert(k, &[1u8]).unwrap();
+        }
+        let staged = Staged::new(&trees.locked);
+        Store::remove(&staged, b"/b").unwrap();
+        Store::insert(&staged, b"/c", &[0u8]).unwrap();
+        Store::insert(&staged, b"/e", &[0u8]).unwrap();
+        let keys = staged
+            .range_back(Bound::Excluded(b"/e"))
+            .map(|r| r.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![&b"/d"[..], &b"/c"[..], &b"/a"[..]]);
+        let keys = staged.scan_prefix_keys(b"/").map(|r| r.unwrap()).collect::<Vec<_>>();
+        assert_eq!(keys, vec![&b"/a"[..], &b"/c"[..], &b"/d"[..], &b"/e"[..]]);
+        assert_eq!(trees.locked.len(), 3);
+    }
+
+    fn set(trees: &Trees, path: &'static str, v: i64) {
+        let mut txn = Txn::new();
+        txn.set_author(Some(Chars::from("alice")));
+        txn.set_data(true, Path::from(path), Value::I64(v), None);
+        trees.commit(txn);
+    }
+
+    fn get(trees: &Trees, path: &str) -> Option<Datum> {
+        lookup_value(&trees.data, path).unwrap()
+    }
+
+    fn data(v: i64) -> Option<Datum> {
+        Some(Datum::Data(Value::I64(v)))
+    }
+
+    fn assert_datum(d: Option<Datum>, expected: Option<Datum>) {
+        assert_eq!(format!("{:?}", d), format!("{:?}", expected))
+    }
+
+    #[test]
+    fn undo() {
+        let trees = Trees::new(100);
+        set(&trees, "/app/a", 1);
+        set(&trees, "/app/a", 2);
+        set(&trees, "/other/b", 1);
+        let mut txn = Txn::new();
+        txn.undo(Path::from("/app"), None);
+        trees.commit(txn);
+        assert_datum(get(&trees, "/app/a"), data(1));
+        assert_datum(get(&trees, "/other/b"), data(1));
+        let mut txn = Txn::new();
+        txn.undo(Path::from("/app"), None);
+        trees.commit(txn);
+        assert_datum(get(&trees, "/app/a"), Some(Datum::Deleted));
+        let (txn, mut reply) = atomic({
+            let mut txn = Txn::new();
+            txn.undo(Path::from("/app"), None);
+            txn
+        });
+        trees.commit(txn);
+        assert!(matches!(reply.try_recv(), Ok(Some(Value::Error(_)))));
+        let items = trees.history.items(&Path::from("/app/a"), 10).unwrap();
+        assert_eq!(items.len(), 4);
+        assert_eq!(items[3].author.as_deref(), Some("alice"));
+        assert_eq!(items[3].value, Value::I64(1));
+    }
+
+    #[test]
+    fn revert_to() {
+        let trees = Trees::new(100);
+        set(&trees, "/app/a", 1);
+        set(&trees, "/app/b", 1);
+        std::thread::sleep(Duration::from_millis(2));
+        let ts = Utc::now();
+        std::thread::sleep(Duration::from_millis(2));
+        set(&trees, "/app/a", 2);
+        set(&trees, "/app/a", 3);
+        set(&trees, "/app/c", 1);
+        let mut txn = Txn::new();
+        txn.remove_subtree(Path::from("/app/b"), None);
+        trees.commit(txn);
+        let mut txn = Txn::new();
+        txn.revert_to(Path::from("/app"), ts, None);
+        trees.commit(txn);
+        assert_datum(get(&trees, "/app/a"), data(1));
+        assert_datum(get(&trees, "/app/b"), data(1));
+        assert_datum(get(&trees, "/app/c"), Some(Datum::Deleted));
+    }
+
+    #[test]
+    fn history_disabled_and_trim() {
+        let trees = Trees::new(0);
+        set(&trees, "/app/a", 1);
+        assert!(trees.history.undo(&Path::from("/app")).is_err());
+        assert_eq!(trees.history_tree.len(), 0);
+        let trees = Trees::new(10);
+        for i in 0..11 {
+            set(&trees, "/app/a", i);
+        }
+        trees.history.trim().unwrap();
+        assert_eq!(trees.history_tree.len(), 11);
+        set(&trees, "/app/a", 11);
+        trees.history.trim().unwrap();
+        assert_eq!(trees.history_tree.len(), 10);
+        assert_eq!(trees.history_index.len(), 10);
+        let items = trees.history.items(&Path::from("/app/a"), 100).unwrap();
+        assert_eq!(items.len(), 10);
+        assert_eq!(items[0].value, Value::I64(11));
+    }
 }
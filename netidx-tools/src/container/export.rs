@@ -0,0 +1,385 @@
+use super::rpcs::RpcRequestKind;
+use anyhow::Result;
+use netidx::{
+    chars::Chars,
+    config::Config,
+    path::Path,
+    resolver::Auth,
+    subscriber::{Subscriber, Value},
+};
+use netidx_protocols::rpc::client::Proc;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+use structopt::StructOpt;
+use tokio::runtime::Runtime;
+
+const VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(super) enum Item {
+    Data(Value),
+    #[serde(rename_all = "kebab-case")]
+    Formula {
+        formula: Option<String>,
+        on_write: Option<String>,
+    },
+}
+
+impl Item {
+    pub(super) fn formula(formula: Value, on_write: Value) -> Self {
+        fn opt(v: Value) -> Option<String> {
+            match v {
+                Value::Null => None,
+                v => v.cast_to::<String>().ok(),
+            }
+        }
+        Item::Formula { formula: opt(formula), on_write: opt(on_write) }
+    }
+}
+
+/// The shape of a sheet or a table. The container doesn't store
+/// these, they are worked out from the layout of the cells.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(super) enum Descriptor {
+    /// `max_rows` and `max_columns` are the smallest maximums that
+    /// give the same zero padding as the original sheet.
+    #[serde(rename_all = "kebab-case")]
+    Sheet {
+        rows: usize,
+        columns: usize,
+        max_rows: usize,
+        max_columns: usize,
+    },
+    Table {
+        rows: Vec<String>,
+        columns: Vec<String>,
+    },
+}
+
+/// A portable copy of a subtree. Paths are relative to the root of
+/// the subtree, so it can be restored anywhere. Sheets and tables
+/// are described as well as their cells, and are re-created before
+/// the data is restored.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct Export {
+    pub(super) version: u32,
+    #[serde(default)]
+    pub(super) descriptors: Vec<(String, Descriptor)>,
+    pub(super) data: Vec<(String, Item)>,
+    pub(super) locked: Vec<(String, bool)>,
+}
+
+// If `names` are the zero padded numbers 0..n then the padding
+// width, otherwise None
+fn sheet_width(names: &BTreeSet<&str>) -> Option<u32> {
+    let width = names.iter().next()?.len();
+    let numbered = names.iter().enumerate().all(|(i, n)| {
+        n.len() == width
+            && n.bytes().all(|b| b.is_ascii_digit())
+            && n.parse::<usize>().ok() == Some(i)
+    });
+    if numbered {
+        Some(width as u32)
+    } else {
+        None
+    }
+}
+
+impl Export {
+    pub(super) fn new() -> Self {
+        Export {
+            version: VERSION,
+            descriptors: Vec::new(),
+            data: Vec::new(),
+            locked: Vec::new(),
+        }
+    }
+
+    /// Find the sheets and tables in the data. A directory is a
+    /// table if all its data is exactly two levels below it, and
+    /// every row has the same columns. It is a sheet if the rows and
+    /// columns are also zero padded numbers counting up from 0.
+    pub(super) fn describe(&mut self) {
+        fn parent(path: &str) -> Option<&str> {
+            if path.is_empty() {
+                None
+            } else {
+                Some(Path::dirname(path).unwrap_or(""))
+            }
+        }
+        // the cells two levels below each directory, None if it has
+        // data at any other level
+        let mut dirs: BTreeMap<&str, Option<Vec<(&str, &str)>>> = BTreeMap::new();
+        for (path, _) in &self.data {
+            let mut level = 0;
+            let mut cur = path.as_str();
+            while let Some(dir) = parent(cur) {
+                level += 1;
+                match dirs.entry(dir).or_insert_with(|| Some(Vec::new())) {
+                    Some(cells) if level == 2 => {
+                        let row = Path::basename(cur).unwrap_or("");
+                        let col = Path::basename(path).unwrap_or("");
+                        cells.push((row, col))
+                    }
+                    cells => *cells = None,
+                }
+                cur = dir;
+            }
+        }
+        for (dir, cells) in dirs {
+            let cells = match cells {
+                Some(cells) => cells,
+                None => continue,
+            };
+            let rows = cells.iter().map(|(r, _)| *r).collect::<BTreeSet<_>>();
+            let cols = cells.iter().map(|(_, c)| *c).collect::<BTreeSet<_>>();
+            if cells.len() != rows.len() * cols.len() {
+                continue;
+            }
+            let descr = match (sheet_width(&rows), sheet_width(&cols)) {
+                (Some(rw), Some(cw)) => Descriptor::Sheet {
+                    rows: rows.len(),
+                    columns: cols.len(),
+                    max_rows: 10usize.pow(rw - 1),
+                    max_columns: 10usize.pow(cw - 1),
+                },
+                _ => {
+                    let names = |s: &BTreeSet<&str>| {
+                        s.iter().map(|n| Path::unescape(n).into_owned()).collect()
+                    };
+                    Descriptor::Table { rows: names(&rows), columns: names(&cols) }
+                }
+            };
+            self.descriptors.push((String::from(dir), descr));
+        }
+    }
+
+    /// The operations needed to restore the export under `base`
+    pub(super) fn into_requests(self, base: &Path) -> Result<Vec<RpcRequestKind>> {
+        if self.version != VERSION {
+            bail!("unsupported export version {}", self.version)
+        }
+        let mut reqs = Vec::with_capacity(
+            self.descriptors.len() + self.data.len() + self.locked.len(),
+        );
+        for (path, descr) in self.descriptors {
+            let path = base.append(&path);
+            reqs.push(match descr {
+                Descriptor::Sheet { rows, columns, max_rows, max_columns } => {
+                    RpcRequestKind::CreateSheet {
+                        path,
+                        rows,
+                        columns,
+                        max_rows,
+                        max_columns,
+                        lock: false,
+                    }
+                }
+                Descriptor::Table { rows, columns } => RpcRequestKind::CreateTable {
+                    path,
+                    rows: rows.into_iter().map(Chars::from).collect(),
+                    columns: columns.into_iter().map(Chars::from).collect(),
+                    lock: false,
+                },
+            })
+        }
+        for (path, item) in self.data {
+            let path = base.append(&path);
+            reqs.push(match item {
+                Item::Data(value) => RpcRequestKind::SetData { path, value },
+                Item::Formula { formula, on_write } => RpcRequestKind::SetFormula {
+                    path,
+                    formula: formula.map(Chars::from),
+                    on_write: on_write.map(Chars::from),
+                },
+            })
+        }
+        for (path, locked) in self.locked {
+            let path = base.append(&path);
+            reqs.push(if locked {
+                RpcRequestKind::LockSubtree(path)
+            } else {
+                RpcRequestKind::UnlockSubtree(path)
+            })
+        }
+        Ok(reqs)
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) enum Cmd {
+    #[structopt(name = "dump", about = "export a subtree of a running container")]
+    Dump {
+        #[structopt(long = "path", help = "the subtree to export")]
+        path: Path,
+        #[structopt(long = "file", help = "write to file instead of stdout")]
+        file: Option<PathBuf>,
+    },
+    #[structopt(name = "load", about = "import a subtree into a running container")]
+    Load {
+        #[structopt(long = "path", help = "where to put the subtree")]
+        path: Path,
+        #[structopt(long = "file", help = "read from file instead of stdin")]
+        file: Option<PathBuf>,
+    },
+}
+
+async fn call(
+    subscriber: &Subscriber,
+    api_path: &Path,
+    name: &str,
+    args: Vec<(&str, Value)>,
+) -> Result<Value> {
+    let proc = Proc::new(subscriber, api_path.append("rpcs").append(name)).await?;
+    match proc.call(args).await? {
+        Value::Error(e) => bail!("{}", e),
+        v => Ok(v),
+    }
+}
+
+async fn dump(
+    subscriber: &Subscriber,
+    api_path: &Path,
+    path: Path,
+    file: Option<PathBuf>,
+) -> Result<()> {
+    let path = Value::from(String::from(&*path));
+    let data =
+        match call(subscriber, api_path, "export-subtree", vec![("path", path)]).await? {
+            Value::String(s) => s,
+            v => bail!("unexpected reply {}", v),
+        };
+    match file {
+        None => io::stdout().write_all(data.as_bytes())?,
+        Some(file) => fs::write(file, data.as_bytes())?,
+    }
+    Ok(())
+}
+
+async fn load(
+    subscriber: &Subscriber,
+    api_path: &Path,
+    path: Path,
+    file: Option<PathBuf>,
+) -> Result<()> {
+    let data = match file {
+        Some(file) => fs::read_to_string(file)?,
+        None => {
+            let mut data = String::new();
+            io::stdin().read_to_string(&mut data)?;
+            data
+        }
+    };
+    let args =
+        vec![("path", Value::from(String::from(&*path))), ("data", Value::from(data))];
+    call(subscriber, api_path, "import-subtree", args).await?;
+    Ok(())
+}
+
+pub(crate) fn run(cfg: Config, auth: Auth, api_path: Path, cmd: Cmd) -> Result<()> {
+    let rt = Runtime::new()?;
+    rt.block_on(async move {
+        let subscriber = Subscriber::new(cfg, auth)?;
+        match cmd {
+            Cmd::Dump { path, file } => dump(&subscriber, &api_path, path, file).await,
+            Cmd::Load { path, file } => load(&subscriber, &api_path, path, file).await,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn params() {
+        use super::super::ContainerConfig;
+        let args = ["container", "--api-path", "/app/api", "dump", "--path", "/app/a"];
+        let c = ContainerConfig::from_iter_safe(&args).unwrap();
+        assert_eq!(&*c.api_path, "/app/api");
+        assert!(
+            matches!(c.cmd, Some(Cmd::Dump { path, file: None }) if &*path == "/app/a")
+        );
+        let args = ["container", "dump", "--path", "/a"];
+        assert!(ContainerConfig::from_iter_safe(&args).is_err());
+        let args = ["container", "--api-path", "/a", "load"];
+        assert!(ContainerConfig::from_iter_safe(&args).is_err());
+        let args =
+            ["container", "--bind", "127.0.0.1/32", "--api-path", "/a", "--db", "db"];
+        assert!(ContainerConfig::from_iter_safe(&args).unwrap().cmd.is_none());
+        let args = ["container", "--api-path", "/a", "--db", "db"];
+        let c = ContainerConfig::from_iter_safe(&args).unwrap();
+        let cfg = Config::load("../cfg/simple.json").unwrap();
+        assert!(super::super::run(cfg, Auth::Anonymous, c).is_err());
+    }
+
+    #[test]
+    fn describe() {
+        let mut export = Export::new();
+        let paths = [
+            "misc/a",
+            "misc/b/c",
+            "r/0/0",
+            "r/0/1",
+            "r/1/0",
+            "s/00/0",
+            "s/00/1",
+            "s/01/0",
+            "s/01/1",
+            "t/a/x",
+            "t/b\\/c/x",
+        ];
+        for p in &paths {
+            export.data.push((String::from(*p), Item::Data(Value::Null)));
+        }
+        export.describe();
+        assert_eq!(export.descriptors.len(), 2);
+        assert!(matches!(&export.descriptors[0], (p, Descriptor::Sheet {
+            rows: 2, columns: 2, max_rows: 10, max_columns: 1
+        }) if p == "s"));
+        assert!(
+            matches!(&export.descriptors[1], (p, Descriptor::Table { rows, columns })
+            if p == "t" && rows == &["a", "b/c"] && columns == &["x"])
+        );
+        let reqs = export.into_requests(&Path::from("/new")).unwrap();
+        assert_eq!(reqs.len(), 2 + paths.len());
+        assert!(matches!(&reqs[0], RpcRequestKind::CreateSheet {
+            path, rows: 2, columns: 2, max_rows: 10, max_columns: 1, lock: false
+        } if &**path == "/new/s"));
+        assert!(matches!(&reqs[1], RpcRequestKind::CreateTable { path, rows, .. }
+            if &**path == "/new/t" && &*rows[1] == "b/c"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut export = Export::new();
+        export.data.push((String::from("a"), Item::Data(Value::I64(42))));
+        let f = Item::formula(Value::from("sum(1, 2)"), Value::Null);
+        export.data.push((String::from("b/c"), f));
+        export.locked.push((String::from("b"), true));
+        let s = serde_json::to_string(&export).unwrap();
+        let export = serde_json::from_str::<Export>(&s).unwrap();
+        let reqs = export.into_requests(&Path::from("/new")).unwrap();
+        assert_eq!(reqs.len(), 3);
+        assert!(matches!(&reqs[0],
+            RpcRequestKind::SetData { path, value: Value::I64(42) } if &**path == "/new/a"));
+        assert!(matches!(&reqs[1],
+            RpcRequestKind::SetFormula { path, formula: Some(f), on_write: None }
+            if &**path == "/new/b/c" && &**f == "sum(1, 2)"));
+        assert!(matches!(&reqs[2],
+            RpcRequestKind::LockSubtree(path) if &**path == "/new/b"));
+    }
+
+    #[test]
+    fn version() {
+        let mut export = Export::new();
+        export.version = VERSION + 1;
+        assert!(export.into_requests(&Path::from("/new")).is_err());
+    }
+}
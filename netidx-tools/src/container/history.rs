@@ -0,0 +1,377 @@
+use super::db::{Datum, Undo, UndoTree};
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes};
+use chrono::prelude::*;
+use fxhash::FxHashSet;
+use netidx::{
+    chars::Chars,
+    pack::{Pack, PackError},
+    path::Path,
+    publisher::{Publisher, UpdateBatch, Val},
+    subscriber::Value,
+};
+use std::{
+    convert::TryInto,
+    str,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// One change to one key in one of the db trees
+#[derive(Debug, Clone)]
+pub(super) struct Change {
+    pub(super) tree: UndoTree,
+    pub(super) key: Bytes,
+    pub(super) old: Option<Bytes>,
+    pub(super) new: Option<Bytes>,
+}
+
+impl Change {
+    fn from_undo(u: &Undo) -> Self {
+        Change {
+            tree: u.tree,
+            key: Bytes::copy_from_slice(&u.key),
+            old: u.old.as_ref().map(|v| Bytes::copy_from_slice(v)),
+            new: u.new.as_ref().map(|v| Bytes::copy_from_slice(v)),
+        }
+    }
+
+    pub(super) fn path(&self) -> Option<&str> {
+        str::from_utf8(&self.key).ok()
+    }
+}
+
+impl Pack for Change {
+    fn encoded_len(&self) -> usize {
+        1 + self.key.encoded_len() + self.old.encoded_len() + self.new.encoded_len()
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) -> Result<(), PackError> {
+        buf.put_u8(match self.tree {
+            UndoTree::Data => 0,
+            UndoTree::Locked => 1,
+            UndoTree::Roots => 2,
+        });
+        self.key.encode(buf)?;
+        self.old.encode(buf)?;
+        self.new.encode(buf)
+    }
+
+    fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        if buf.remaining() == 0 {
+            return Err(PackError::InvalidFormat);
+        }
+        let tree = match buf.get_u8() {
+            0 => UndoTree::Data,
+            1 => UndoTree::Locked,
+            2 => UndoTree::Roots,
+            _ => return Err(PackError::UnknownTag),
+        };
+        let key = Bytes::decode(buf)?;
+        let old = Option::<Bytes>::decode(buf)?;
+        let new = Option::<Bytes>::decode(buf)?;
+        Ok(Change { tree, key, old, new })
+    }
+}
+
+/// Everything changed by one operation
+#[derive(Debug, Clone)]
+pub(super) struct Entry {
+    pub(super) author: Option<Chars>,
+    /// the keys of the entries this entry undid
+    pub(super) undoes: Vec<Bytes>,
+    pub(super) changes: Vec<Change>,
+}
+
+impl Pack for Entry {
+    fn encoded_len(&self) -> usize {
+        self.author.encoded_len() + self.undoes.encoded_len() + self.changes.encoded_len()
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) -> Result<(), PackError> {
+        self.author.encode(buf)?;
+        self.undoes.encode(buf)?;
+        self.changes.encode(buf)
+    }
+
+    fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        let author = Option::<Chars>::decode(buf)?;
+        let undoes = Vec::<Bytes>::decode(buf)?;
+        let changes = Vec::<Change>::decode(buf)?;
+        Ok(Entry { author, undoes, changes })
+    }
+}
+
+/// A change to one path, as shown in the history view
+#[derive(Debug, Clone)]
+pub(super) struct Item {
+    pub(super) timestamp: DateTime<Utc>,
+    pub(super) author: Option<Chars>,
+    pub(super) value: Value,
+}
+
+// keys are the timestamp in microseconds followed by a sequence
+// number, so they sort by time and are unique.
+const KEY_LEN: usize = 16;
+
+fn timestamp(key: &[u8]) -> DateTime<Utc> {
+    let us = key.get(0..8).and_then(|k| k.try_into().ok()).map(u64::from_be_bytes);
+    DateTime::from(UNIX_EPOCH + Duration::from_micros(us.unwrap_or(0)))
+}
+
+// the index maps each changed path to the entries that changed it,
+// it's keys are the path followed by the entry key
+fn index_key(path: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut k = Vec::with_capacity(path.len() + key.len());
+    k.extend_from_slice(path);
+    k.extend_from_slice(key);
+    k
+}
+
+fn split_index_key(k: &[u8]) -> Option<(&str, &[u8])> {
+    if k.len() < KEY_LEN {
+        None
+    } else {
+        let (path, key) = k.split_at(k.len() - KEY_LEN);
+        str::from_utf8(path).ok().map(|path| (path, key))
+    }
+}
+
+fn is_in(path: &Path, c: &Change) -> bool {
+    c.path().map(|p| Path::is_parent(path, p)).unwrap_or(false)
+}
+
+/// The change log of the container db. Each committed operation
+/// is recorded with it's author and the old and new value of every
+/// key it changed, so it can be undone later. History is disabled
+/// if `max` is 0.
+pub(super) struct History {
+    tree: sled::Tree,
+    index: sled::Tree,
+    max: usize,
+    len: AtomicUsize,
+    seq: AtomicU64,
+}
+
+impl History {
+    pub(super) fn new(tree: sled::Tree, index: sled::Tree, max: usize) -> Self {
+        let len = AtomicUsize::new(tree.len());
+        History { tree, index, max, len, seq: AtomicU64::new(0) }
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.max > 0
+    }
+
+    fn check_enabled(&self) -> Result<()> {
+        if self.enabled() {
+            Ok(())
+        } else {
+            bail!("history is disabled")
+        }
+    }
+
+    pub(super) fn record(
+        &self,
+        author: &Option<Chars>,
+        undoes: &[Bytes],
+        changes: &[Undo],
+    ) -> Result<()> {
+        if !self.enabled() || changes.is_empty() {
+            return Ok(());
+        }
+        let entry = Entry {
+            author: author.clone(),
+            undoes: undoes.to_vec(),
+            changes: changes.iter().map(Change::from_undo).collect(),
+        };
+        let mut key = [0u8; KEY_LEN];
+        let now = Utc::now();
+        let us =
+            now.timestamp() as u64 * 1_000_000 + now.timestamp_subsec_micros() as u64;
+        key[0..8].copy_from_slice(&us.to_be_bytes());
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        key[8..16].copy_from_slice(&seq.to_be_bytes());
+        let mut batch = sled::Batch::default();
+        for c in &entry.changes {
+            batch.insert(index_key(&c.key, &key), &[]);
+        }
+        self.index.apply_batch(batch)?;
+        let mut buf = Vec::with_capacity(entry.encoded_len());
+        entry.encode(&mut buf)?;
+        self.tree.insert(key, buf)?;
+        self.len.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Once there are 10% more than max entries remove the oldest
+    /// ones until there are max.
+    pub(super) fn trim(&self) -> Result<()> {
+        let len = self.len.load(Ordering::Relaxed);
+        if !self.enabled() || len <= self.max + self.max / 10 {
+            return Ok(());
+        }
+        let mut batch = sled::Batch::default();
+        let mut index = sled::Batch::default();
+        let mut n = 0;
+        for r in self.tree.iter().take(len - self.max) {
+            let (k, v) = r?;
+            if let Ok(e) = Entry::decode(&mut &*v) {
+                for c in &e.changes {
+                    index.remove(index_key(&c.key, &k));
+                }
+            }
+            batch.remove(k);
+            n += 1;
+        }
+        self.index.apply_batch(index)?;
+        self.tree.apply_batch(batch)?;
+        self.len.fetch_sub(n, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // the keys of the entries that changed the subtree at `path`,
+    // or only `path` itself if `exact`, newest first
+    fn keys(&self, path: &Path, exact: bool) -> Result<Vec<sled::IVec>> {
+        let mut keys = Vec::new();
+        for r in self.index.scan_prefix(path.as_bytes()).keys() {
+            let k = r?;
+            if let Some((p, key)) = split_index_key(&k) {
+                let matches = if exact { p == &**path } else { Path::is_parent(path, p) };
+                if matches {
+                    keys.push(sled::IVec::from(key));
+                }
+            }
+        }
+        keys.sort_unstable_by(|k0, k1| k1.cmp(k0));
+        keys.dedup();
+        Ok(keys)
+    }
+
+    fn entries(
+        &self,
+        path: &Path,
+        exact: bool,
+    ) -> Result<impl Iterator<Item = Result<(sled::IVec, Entry)>> + '_> {
+        Ok(self.keys(path, exact)?.into_iter().filter_map(move |k| {
+            match self.tree.get(&k) {
+                Err(e) => Some(Err(e.into())),
+                Ok(None) => None, // trimmed
+                Ok(Some(v)) => {
+                    Some(Entry::decode(&mut &*v).map(|e| (k, e)).map_err(Into::into))
+                }
+            }
+        }))
+    }
+
+    pub(super) fn flush(&self) -> Result<()> {
+        self.index.flush()?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    /// Find the most recent change to the subtree at `path` that
+    /// hasn't already been undone, and return it's key along with
+    /// the changes it made under `path`, newest first. Undos
+    /// themselves are never undone.
+    pub(super) fn undo(&self, path: &Path) -> Result<Option<(Bytes, Vec<Change>)>> {
+        self.check_enabled()?;
+        let mut undone = FxHashSet::default();
+        for r in self.entries(path, false)? {
+            let (k, e) = r?;
+            let k = Bytes::copy_from_slice(&k);
+            if !e.undoes.is_empty() {
+                undone.extend(e.undoes);
+            } else if !undone.contains(&k) {
+                let mut changes =
+                    e.changes.into_iter().filter(|c| is_in(path, c)).collect::<Vec<_>>();
+                changes.reverse();
+                return Ok(Some((k, changes)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Return every change to the subtree at `path` made after
+    /// `ts`, newest first.
+    pub(super) fn since(&self, path: &Path, ts: DateTime<Utc>) -> Result<Vec<Change>> {
+        self.check_enabled()?;
+        let mut res = Vec::new();
+        for r in self.entries(path, false)? {
+            let (k, e) = r?;
+            if timestamp(&k) <= ts {
+                break;
+            }
+            res.extend(e.changes.into_iter().rev().filter(|c| is_in(path, c)));
+        }
+        Ok(res)
+    }
+
+    /// The most recent `limit` changes to the value or formula at
+    /// `path`, newest first.
+    pub(super) fn items(&self, path: &Path, limit: usize) -> Result<Vec<Item>> {
+        self.check_enabled()?;
+        let mut res = Vec::new();
+        for r in self.entries(path, true)? {
+            if res.len() >= limit {
+                break;
+            }
+            let (k, e) = r?;
+            for c in e.changes.iter().rev() {
+                if let (UndoTree::Data, Some(p)) = (c.tree, c.path()) {
+                    if p == &**path {
+                        let value = match &c.new {
+                            None => Value::from("deleted"),
+                            Some(v) => match Datum::decode(&mut &**v)? {
+                                Datum::Data(v) => v,
+                                Datum::Formula(f, _) => f,
+                                Datum::Deleted => Value::from("deleted"),
+                            },
+                        };
+                        let author = e.author.clone();
+                        res.push(Item { timestamp: timestamp(&k), author, value });
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// The recent changes to one path, published as a table with a row
+/// per change, newest first.
+pub(super) struct View {
+    publisher: Publisher,
+    base_path: Path,
+    pub(super) limit: usize,
+    rows: Vec<(Val, Val, Val)>,
+}
+
+impl View {
+    pub(super) fn new(publisher: Publisher, base_path: Path, limit: usize) -> Self {
+        View { publisher, base_path, limit, rows: Vec::new() }
+    }
+
+    pub(super) fn set(&mut self, batch: &mut UpdateBatch, items: &[Item]) -> Result<()> {
+        while self.rows.len() > items.len() {
+            self.rows.pop();
+        }
+        while self.rows.len() < items.len() {
+            let p = self.base_path.append(&format!("{:06}", self.rows.len()));
+            let time = self.publisher.publish(p.append("time"), Value::Null)?;
+            let author = self.publisher.publish(p.append("author"), Value::Null)?;
+            let value = self.publisher.publish(p.append("value"), Value::Null)?;
+            self.rows.push((time, author, value));
+        }
+        for (item, (time, author, value)) in items.iter().zip(self.rows.iter()) {
+            time.update(batch, Value::DateTime(item.timestamp));
+            author.update(
+                batch,
+                item.author.clone().map(Value::String).unwrap_or(Value::Null),
+            );
+            value.update(batch, item.value.clone());
+        }
+        Ok(())
+    }
+}
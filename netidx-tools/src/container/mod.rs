@@ -1,7 +1,11 @@
+mod acl;
 mod db;
+pub(crate) mod export;
+mod history;
 mod rpcs;
 mod stats;
 
+use acl::Rights;
 use anyhow::Result;
 use arcstr::ArcStr;
 use db::{Datum, DatumKind, Reply, Sendable, Txn};
@@ -20,16 +24,18 @@ use netidx::{
     path::Path,
     pool::{Pool, Pooled},
     publisher::{
-        BindCfg, DefaultHandle, Event as PEvent, Id, PublishFlags, Publisher,
-        UpdateBatch, Val, WriteRequest,
+        BindCfg, ClId, DefaultHandle, Event as PEvent, Id, Principal, PublishFlags,
+        Publisher, UpdateBatch, Val, WriteRequest,
     },
     resolver::Auth,
-    subscriber::{Dval, Event, SubId, Subscriber, UpdatesFlags, Value},
+    subscriber::{Dval, Event, SubId, Subscriber, Typ, UpdatesFlags, Value},
     utils::BatchItem,
 };
 use netidx_bscript::{
-    expr::{Expr, ExprId},
-    vm::{self, Apply, Ctx, ExecCtx, InitFn, Node, Register, RpcCallId},
+    expr::ExprId,
+    rt::{add_eid_to_set, remove_eid_from_set, Refs},
+    typ::{Arg, Ret, Signature},
+    vm::{self, Apply, Ctx, ExecCtx, InitFn, Node, Register, RpcCallId, TimerId},
 };
 use netidx_protocols::rpc;
 use parking_lot::Mutex;
@@ -37,12 +43,10 @@ use rpcs::{RpcRequest, RpcRequestKind};
 use stats::Stats;
 use std::{
     collections::{
-        hash_map::Entry,
         BTreeMap,
         Bound::{self, *},
         HashMap, HashSet,
     },
-    hash::Hash,
     mem,
     ops::{Deref, DerefMut},
     pin::Pin,
@@ -66,6 +70,9 @@ lazy_static! {
 
 macro_rules! or_reply {
     ($reply:expr, $r:expr) => {
+        or_reply!($reply, $r, return)
+    };
+    ($reply:expr, $r:expr, $or:expr) => {
         match $r {
             Ok(r) => r,
             Err(e) => {
@@ -73,30 +80,12 @@ macro_rules! or_reply {
                     let e = Value::Error(Chars::from(format!("{}", e)));
                     reply.send(e);
                 }
-                return;
+                $or;
             }
         }
     };
 }
 
-struct Refs {
-    refs: FxHashSet<Path>,
-    rpcs: FxHashSet<Path>,
-    subs: FxHashSet<SubId>,
-    vars: FxHashSet<Chars>,
-}
-
-impl Refs {
-    fn new() -> Self {
-        Refs {
-            refs: HashSet::with_hasher(FxBuildHasher::default()),
-            rpcs: HashSet::with_hasher(FxBuildHasher::default()),
-            subs: HashSet::with_hasher(FxBuildHasher::default()),
-            vars: HashSet::with_hasher(FxBuildHasher::default()),
-        }
-    }
-}
-
 struct Fifo {
     data_path: Path,
     data: Val,
@@ -146,6 +135,7 @@ enum LcEvent {
     Refs,
     RpcCall { name: Path, args: Vec<(Chars, Value)>, id: RpcCallId },
     RpcReply { name: Path, id: RpcCallId, result: Value },
+    Timer { id: TimerId, ref_by: ExprId },
 }
 
 struct Lc {
@@ -157,6 +147,8 @@ struct Lc {
     refs: FxHashMap<Path, FxHashSet<ExprId>>,
     rels: FxHashMap<Path, FxHashSet<ExprId>>,
     forward_refs: FxHashMap<ExprId, Refs>,
+    // the cells each expression refers to with ref, the reverse of refs
+    forward_cell_refs: FxHashMap<ExprId, FxHashSet<Path>>,
     subscriber: Subscriber,
     publisher: Publisher,
     sub_updates: mpsc::Sender<Pooled<Vec<(SubId, Event)>>>,
@@ -165,20 +157,10 @@ struct Lc {
     by_id: FxHashMap<Id, Published>,
     by_path: HashMap<Path, Published>,
     events: mpsc::UnboundedSender<LcEvent>,
-}
-
-fn remove_eid_from_set<K: Hash + Eq>(
-    tbl: &mut FxHashMap<K, FxHashSet<ExprId>>,
-    key: K,
-    expr_id: &ExprId,
-) {
-    if let Entry::Occupied(mut e) = tbl.entry(key) {
-        let set = e.get_mut();
-        set.remove(expr_id);
-        if set.is_empty() {
-            e.remove();
-        }
-    }
+    timers: FxHashMap<ExprId, Vec<task::JoinHandle<()>>>,
+    // while an on write handler is running it's writes are held here
+    // so they can be checked against the rights of the writer
+    handler_writes: Option<Vec<(Path, Dval, Value)>>,
 }
 
 impl Lc {
@@ -197,6 +179,7 @@ impl Lc {
             refs: HashMap::with_hasher(FxBuildHasher::default()),
             rels: HashMap::with_hasher(FxBuildHasher::default()),
             forward_refs: HashMap::with_hasher(FxBuildHasher::default()),
+            forward_cell_refs: HashMap::with_hasher(FxBuildHasher::default()),
             db,
             subscriber,
             publisher,
@@ -206,14 +189,18 @@ impl Lc {
             by_id: HashMap::with_hasher(FxBuildHasher::default()),
             by_path: HashMap::new(),
             events,
+            timers: HashMap::with_hasher(FxBuildHasher::default()),
+            handler_writes: None,
         }
     }
 
     fn unref(&mut self, expr_id: ExprId) {
-        if let Some(refs) = self.forward_refs.remove(&expr_id) {
-            for path in refs.refs {
+        if let Some(paths) = self.forward_cell_refs.remove(&expr_id) {
+            for path in paths {
                 remove_eid_from_set(&mut self.refs, path, &expr_id);
             }
+        }
+        if let Some(refs) = self.forward_refs.remove(&expr_id) {
             for path in refs.rpcs {
                 remove_eid_from_set(&mut self.rpc, path, &expr_id);
             }
@@ -224,6 +211,11 @@ impl Lc {
                 remove_eid_from_set(&mut self.var, name, &expr_id);
             }
         }
+        if let Some(timers) = self.timers.remove(&expr_id) {
+            for t in timers {
+                t.abort()
+            }
+        }
     }
 
     fn remove_rel(&mut self, path: &Path, id: ExprId) {
@@ -253,19 +245,13 @@ impl Ctx for Lc {
     ) -> Dval {
         let dv = self.subscriber.durable_subscribe(path);
         dv.updates(flags, self.sub_updates.clone());
-        self.sub
-            .entry(dv.id())
-            .or_insert_with(|| HashSet::with_hasher(FxBuildHasher::default()))
-            .insert(ref_id);
+        add_eid_to_set(&mut self.sub, dv.id(), ref_id);
         self.forward_refs.entry(ref_id).or_insert_with(Refs::new).subs.insert(dv.id());
         dv
     }
 
     fn ref_var(&mut self, name: Chars, ref_id: ExprId) {
-        self.var
-            .entry(name.clone())
-            .or_insert_with(|| HashSet::with_hasher(FxBuildHasher::default()))
-            .insert(ref_id);
+        add_eid_to_set(&mut self.var, name.clone(), ref_id);
         self.forward_refs.entry(ref_id).or_insert_with(Refs::new).vars.insert(name);
     }
 
@@ -286,10 +272,7 @@ impl Ctx for Lc {
         ref_id: ExprId,
         id: RpcCallId,
     ) {
-        self.rpc
-            .entry(name.clone())
-            .or_insert_with(|| HashSet::with_hasher(FxBuildHasher::default()))
-            .insert(ref_id);
+        add_eid_to_set(&mut self.rpc, name.clone(), ref_id);
         self.forward_refs
             .entry(ref_id)
             .or_insert_with(Refs::new)
@@ -298,6 +281,25 @@ impl Ctx for Lc {
         let _: Result<_, _> =
             self.events.unbounded_send(LcEvent::RpcCall { name, args, id });
     }
+
+    fn set_timer(&mut self, id: TimerId, timeout: Duration, ref_by: ExprId) {
+        let events = self.events.clone();
+        let timers = self.timers.entry(ref_by).or_default();
+        timers.retain(|t| !t.is_finished());
+        timers.push(task::spawn(async move {
+            time::sleep(timeout).await;
+            let _: Result<_, _> = events.unbounded_send(LcEvent::Timer { id, ref_by });
+        }));
+    }
+
+    fn write(&mut self, path: &Path, dv: &Dval, value: Value) {
+        match &mut self.handler_writes {
+            Some(writes) => writes.push((path.clone(), dv.clone(), value)),
+            None => {
+                dv.write(value);
+            }
+        }
+    }
 }
 
 struct Ref {
@@ -365,6 +367,7 @@ impl Ref {
             | vm::Event::User(UserEv::Rel)
             | vm::Event::Netidx(_, _)
             | vm::Event::Rpc(_, _)
+            | vm::Event::Timer(_)
             | vm::Event::Variable(_, _) => None,
         }
     }
@@ -372,31 +375,19 @@ impl Ref {
     fn set_ref(&mut self, ctx: &mut ExecCtx<Lc, UserEv>, path: Option<Chars>) {
         if let Some(path) = self.path.take() {
             let path = Path::from(path);
-            ctx.user
-                .refs
-                .entry(path.clone())
-                .or_insert_with(|| HashSet::with_hasher(FxBuildHasher::default()))
-                .remove(&self.id);
-            ctx.user
-                .forward_refs
-                .entry(self.id)
-                .or_insert_with(Refs::new)
-                .refs
-                .remove(&path);
+            remove_eid_from_set(&mut ctx.user.refs, path.clone(), &self.id);
+            if let Some(paths) = ctx.user.forward_cell_refs.get_mut(&self.id) {
+                paths.remove(&path);
+            }
         }
         if let Some(path) = path.clone() {
             let path = Path::from(path);
+            add_eid_to_set(&mut ctx.user.refs, path.clone(), self.id);
             ctx.user
-                .refs
-                .entry(path.clone())
-                .or_insert_with(|| HashSet::with_hasher(FxBuildHasher::default()))
-                .insert(self.id);
-            ctx.user
-                .forward_refs
+                .forward_cell_refs
                 .entry(self.id)
-                .or_insert_with(Refs::new)
-                .refs
-                .insert(path.clone());
+                .or_insert_with(|| HashSet::with_hasher(FxBuildHasher::default()))
+                .insert(path);
         }
         self.path = path;
     }
@@ -412,6 +403,8 @@ impl Register<Lc, UserEv> for Ref {
             t
         });
         ctx.functions.insert("ref".into(), f);
+        let sig = Signature::fixed(vec![Arg::Is(Typ::String)], Ret::Unknown);
+        ctx.signatures.insert("ref".into(), sig);
     }
 }
 
@@ -514,6 +507,13 @@ impl Register<Lc, UserEv> for Rel {
             Box::new(t)
         });
         ctx.functions.insert("rel".into(), f);
+        let sig = Signature {
+            args: vec![],
+            optional: vec![Arg::Any, Arg::Any],
+            variadic: None,
+            ret: Ret::Is(Typ::String),
+        };
+        ctx.signatures.insert("rel".into(), sig);
     }
 }
 
@@ -552,6 +552,7 @@ impl Register<Lc, UserEv> for OnWriteEvent {
             Box::new(OnWriteEvent { cur: None, invalid: from.len() > 0 })
         });
         ctx.functions.insert("event".into(), f);
+        ctx.signatures.insert("event".into(), Signature::fixed(vec![], Ret::Unknown));
     }
 }
 
@@ -575,6 +576,7 @@ impl Apply<Lc, UserEv> for OnWriteEvent {
             vm::Event::Variable(_, _)
             | vm::Event::Netidx(_, _)
             | vm::Event::Rpc(_, _)
+            | vm::Event::Timer(_)
             | vm::Event::User(UserEv::Ref(_, _))
             | vm::Event::User(UserEv::Rel) => None,
             vm::Event::User(UserEv::OnWriteEvent(value)) => {
@@ -591,6 +593,8 @@ impl OnWriteEvent {
     }
 }
 
+/// Run a container, or with a subcommand, work with a running one.
+/// `--bind` and `--db` are required to run a container.
 #[derive(StructOpt, Debug)]
 pub(super) struct ContainerConfig {
     #[structopt(
@@ -598,7 +602,7 @@ pub(super) struct ContainerConfig {
         long = "bind",
         help = "configure the bind address e.g. 192.168.0.0/16, 127.0.0.1:5000"
     )]
-    bind: BindCfg,
+    bind: Option<BindCfg>,
     #[structopt(long = "spn", help = "krb5 use <spn>")]
     pub(super) spn: Option<String>,
     #[structopt(
@@ -609,7 +613,7 @@ pub(super) struct ContainerConfig {
     #[structopt(long = "api-path", help = "the netidx path of the container api")]
     api_path: Path,
     #[structopt(long = "db", help = "the db file")]
-    db: String,
+    db: Option<String>,
     #[structopt(long = "compress", help = "use zstd compression")]
     compress: bool,
     #[structopt(long = "compress-level", help = "zstd compression level")]
@@ -618,6 +622,27 @@ pub(super) struct ContainerConfig {
     cache_size: Option<u64>,
     #[structopt(long = "sparse", help = "don't even advertise the contents of the db")]
     sparse: bool,
+    #[structopt(
+        long = "history",
+        help = "keep the last n changes for undo and revert (default disabled)",
+        default_value = "0"
+    )]
+    history: usize,
+    #[structopt(
+        long = "acls",
+        help = "the access control file, by default anyone may do anything"
+    )]
+    acls: Option<String>,
+    #[structopt(subcommand)]
+    pub(super) cmd: Option<export::Cmd>,
+}
+
+// the author of a change, as recorded in the history
+fn author(principal: &Principal) -> Option<Chars> {
+    match principal {
+        Principal::User { name, .. } => Some(name.clone()),
+        Principal::Anonymous => None,
+    }
 }
 
 fn to_chars(value: Value) -> Chars {
@@ -684,6 +709,8 @@ struct Container {
     roots: Roots,
     db_updates: mpsc::UnboundedReceiver<db::Update>,
     api: rpcs::RpcApi,
+    acls: Option<acl::Acls>,
+    history: FxHashMap<Path, history::View>,
     bscript_event: mpsc::UnboundedReceiver<LcEvent>,
     rpcs: FxHashMap<
         Path,
@@ -694,7 +721,9 @@ struct Container {
 impl Container {
     async fn new(cfg: config::Config, auth: Auth, ccfg: ContainerConfig) -> Result<Self> {
         let (publish_events_tx, publish_events) = mpsc::unbounded();
-        let publisher = Publisher::new(cfg.clone(), auth.clone(), ccfg.bind).await?;
+        let acls = ccfg.acls.as_ref().map(|f| acl::Acls::load(f)).transpose()?;
+        let bind = ccfg.bind.ok_or_else(|| anyhow!("--bind is required"))?;
+        let publisher = Publisher::new(cfg.clone(), auth.clone(), bind).await?;
         publisher.events(publish_events_tx);
         let (db, db_updates) =
             db::Db::new(&ccfg, publisher.clone(), ccfg.api_path.clone())?;
@@ -723,6 +752,8 @@ impl Container {
             publish_events,
             db_updates,
             api,
+            acls,
+            history: HashMap::with_hasher(FxBuildHasher::default()),
             bscript_event: bs_rx,
             rpcs: HashMap::with_hasher(FxBuildHasher::default()),
             compiled: HashMap::with_hasher(FxBuildHasher::default()),
@@ -764,16 +795,10 @@ impl Container {
         formula_txt: Value,
         on_write_txt: Value,
     ) -> Result<()> {
-        let expr = formula_txt
-            .clone()
-            .cast_to::<Chars>()
-            .map_err(|e| anyhow!(e))
-            .and_then(|c| c.parse::<Expr>());
-        let on_write_expr = on_write_txt
-            .clone()
-            .cast_to::<Chars>()
-            .map_err(|e| anyhow!(e))
-            .and_then(|c| c.parse::<Expr>());
+        // formulas restored from the history, or stored before they
+        // were checked, might not type check
+        let expr = db::parse_expr(&self.ctx, &formula_txt);
+        let on_write_expr = db::parse_expr(&self.ctx, &on_write_txt);
         let expr_id = expr.as_ref().map(|e| e.id).unwrap_or_else(|_| ExprId::new());
         let on_write_expr_id =
             on_write_expr.as_ref().map(|e| e.id).unwrap_or_else(|_| ExprId::new());
@@ -981,7 +1006,7 @@ impl Container {
         self.ctx.user.unref(*expr_id);
         self.ctx.user.remove_rel(&fifo.data_path, *expr_id);
         self.compiled.remove(&expr_id);
-        let dv = match value.parse::<Expr>() {
+        let dv = match db::parse_expr(&self.ctx, &Value::String(value.clone())) {
             Ok(expr) => {
                 *expr_id = expr.id;
                 self.ctx.user.current_path = fifo.data_path.clone();
@@ -1014,7 +1039,7 @@ impl Container {
         self.ctx.user.unref(*expr_id);
         self.ctx.user.remove_rel(&fifo.data_path, *expr_id);
         self.compiled.remove(&expr_id);
-        match value.parse::<Expr>() {
+        match db::parse_expr(&self.ctx, &Value::String(value.clone())) {
             Ok(expr) => {
                 *expr_id = expr.id;
                 self.ctx.user.current_path = fifo.data_path.clone();
@@ -1038,30 +1063,90 @@ impl Container {
         // CR estokes: log this
         for req in writes.drain(..) {
             let reply = req.send_result.map(Sendable::Write);
+            let principal = req.principal;
+            txn.set_author(author(&principal));
             refs.clear();
             match self.ctx.user.by_id.get(&req.id) {
                 None => (), // CR estokes: log
                 Some(Published::Data(p)) => {
+                    let r = self.check_acl(&p.path, Rights::DATA, &principal);
+                    or_reply!(reply, r, continue);
                     txn.set_data(true, p.path.clone(), req.value, reply);
                 }
                 Some(Published::Formula(fifo)) => {
                     let fifo = fifo.clone();
                     if fifo.src.id() == req.id {
-                        txn.set_formula(fifo.data_path.clone(), req.value, reply);
+                        let r =
+                            self.check_acl(&fifo.data_path, Rights::FORMULA, &principal);
+                        or_reply!(reply, r, continue);
+                        let path = fifo.data_path.clone();
+                        txn.set_formula(&self.ctx, path, req.value, reply);
                     } else if fifo.on_write.id() == req.id {
-                        txn.set_on_write(fifo.data_path.clone(), req.value, reply);
+                        let r =
+                            self.check_acl(&fifo.data_path, Rights::FORMULA, &principal);
+                        or_reply!(reply, r, continue);
+                        let path = fifo.data_path.clone();
+                        txn.set_on_write(&self.ctx, path, req.value, reply);
                     } else if fifo.data.id() == req.id {
+                        let r = self.check_acl(&fifo.data_path, Rights::DATA, &principal);
+                        or_reply!(reply, r, continue);
                         if let Some(Compiled::OnWrite(node)) =
                             self.compiled.get_mut(&fifo.on_write_expr_id.lock())
                         {
                             let ev = vm::Event::User(UserEv::OnWriteEvent(req.value));
+                            self.ctx.user.handler_writes = Some(Vec::new());
                             node.update(&mut self.ctx, &ev);
+                            let writes = self.ctx.user.handler_writes.take();
+                            let r = self.handler_writes(writes, &principal);
                             self.update_refs(batch);
+                            or_reply!(reply, r, continue);
                         }
                     }
                 }
             }
         }
+        txn.set_author(None);
+    }
+
+    fn principal(&self, client: ClId) -> Arc<Principal> {
+        let principal = self.ctx.user.publisher.principal(&client);
+        principal.unwrap_or_else(|| Arc::new(Principal::Anonymous))
+    }
+
+    fn check_acl(
+        &self,
+        path: &str,
+        desired: Rights,
+        principal: &Principal,
+    ) -> Result<()> {
+        match &self.acls {
+            None => Ok(()),
+            Some(acls) => acls.check(path, desired, principal),
+        }
+    }
+
+    // The container writes to it's own paths with it's own rights,
+    // so the writes an on write handler makes to them in response to
+    // a client's write must also be allowed for that client.
+    fn handler_writes(
+        &self,
+        writes: Option<Vec<(Path, Dval, Value)>>,
+        principal: &Principal,
+    ) -> Result<()> {
+        let mut res = Ok(());
+        for (path, dv, value) in writes.into_iter().flatten() {
+            let r = match self.get_root(&path) {
+                None => Ok(()),
+                Some(_) => self.check_acl(&path, Rights::DATA, principal),
+            };
+            match r {
+                Ok(()) => {
+                    dv.write(value);
+                }
+                Err(e) => res = Err(e),
+            }
+        }
+        res
     }
 
     fn is_locked_gen(&self, path: &Path, parent_only: bool) -> bool {
@@ -1115,7 +1200,7 @@ impl Container {
 
     fn process_publish_event(&mut self, e: PEvent) {
         match e {
-            PEvent::Subscribe(_, _) | PEvent::Unsubscribe(_, _) => (),
+            PEvent::Subscribe(_, _, _) | PEvent::Unsubscribe(_, _) => (),
             PEvent::Destroyed(id) => {
                 match self.ctx.user.by_id.remove(&id) {
                     None => (),
@@ -1191,6 +1276,12 @@ impl Container {
                 self.update_expr_ids(batch, &mut refs, &vm::Event::Rpc(id, result));
                 self.update_refs(batch);
             }
+            LcEvent::Timer { id, ref_by } => {
+                let mut refs = REFIDS.take();
+                refs.push(ref_by);
+                self.update_expr_ids(batch, &mut refs, &vm::Event::Timer(id));
+                self.update_refs(batch);
+            }
             LcEvent::RpcCall { name, mut args, id } => {
                 for _ in 1..3 {
                     let proc = self.get_rpc_proc(&name);
@@ -1253,11 +1344,16 @@ impl Container {
         reply: Reply,
     ) {
         let path = or_reply!(reply, self.check_path(path));
+        let on_write = on_write.map(Value::from);
+        // the reply goes with the formula, so check on_write first
+        if let Some(on_write) = &on_write {
+            or_reply!(reply, db::parse_expr(&self.ctx, on_write));
+        }
         if let Some(formula) = formula {
-            txn.set_formula(path.clone(), Value::from(formula), reply);
+            txn.set_formula(&self.ctx, path.clone(), Value::from(formula), reply);
         }
         if let Some(on_write) = on_write {
-            txn.set_on_write(path, Value::from(on_write), None);
+            txn.set_on_write(&self.ctx, path, on_write, None);
         }
     }
 
@@ -1297,80 +1393,207 @@ impl Container {
         txn.create_table(path, rows, columns, lock, reply);
     }
 
-    fn process_rpc_requests(&mut self, txn: &mut Txn, reqs: &mut Vec<RpcRequest>) {
-        for req in reqs.drain(..) {
-            let reply = Sendable::Rpc(req.reply);
-            match req.kind {
-                RpcRequestKind::Delete(path) => self.delete_path(txn, path, Some(reply)),
-                RpcRequestKind::DeleteSubtree(path) => {
-                    self.delete_subtree(txn, path, Some(reply))
-                }
-                RpcRequestKind::LockSubtree(path) => {
-                    self.lock_subtree(txn, path, Some(reply))
-                }
-                RpcRequestKind::UnlockSubtree(path) => {
-                    self.unlock_subtree(txn, path, Some(reply))
-                }
-                RpcRequestKind::SetData { path, value } => {
-                    self.set_data(txn, path, value, Some(reply))
-                }
-                RpcRequestKind::SetFormula { path, formula, on_write } => {
-                    self.set_formula(txn, path, formula, on_write, Some(reply))
-                }
-                RpcRequestKind::CreateSheet {
-                    path,
-                    rows,
-                    columns,
-                    max_rows,
-                    max_columns,
-                    lock,
-                } => self.create_sheet(
-                    txn,
-                    path,
-                    rows,
-                    columns,
-                    max_rows,
-                    max_columns,
-                    lock,
-                    Some(reply),
-                ),
-                RpcRequestKind::AddSheetRows(path, rows) => {
-                    txn.add_sheet_rows(path, rows, Some(reply));
-                }
-                RpcRequestKind::AddSheetCols(path, cols) => {
-                    txn.add_sheet_columns(path, cols, Some(reply));
-                }
-                RpcRequestKind::DelSheetRows(path, rows) => {
-                    txn.del_sheet_rows(path, rows, Some(reply));
-                }
-                RpcRequestKind::DelSheetCols(path, cols) => {
-                    txn.del_sheet_columns(path, cols, Some(reply));
-                }
-                RpcRequestKind::CreateTable { path, rows, columns, lock } => {
-                    self.create_table(txn, path, rows, columns, lock, Some(reply))
-                }
-                RpcRequestKind::AddTableRows(path, rows) => {
-                    txn.add_table_rows(path, rows, Some(reply));
-                }
-                RpcRequestKind::AddTableCols(path, cols) => {
-                    txn.add_table_columns(path, cols, Some(reply));
-                }
-                RpcRequestKind::DelTableRows(path, rows) => {
-                    txn.del_table_rows(path, rows, Some(reply));
-                }
-                RpcRequestKind::DelTableCols(path, cols) => {
-                    txn.del_table_columns(path, cols, Some(reply));
-                }
-                RpcRequestKind::AddRoot(path) => {
-                    txn.add_root(path, Some(reply));
+    // check every operation before any of them is committed, so a
+    // transaction is rejected as a whole if any of them is invalid.
+    fn transaction(
+        &mut self,
+        batch: &mut UpdateBatch,
+        txn: &mut Txn,
+        kinds: Vec<RpcRequestKind>,
+        reply: Reply,
+    ) {
+        for kind in &kinds {
+            let name = match kind {
+                RpcRequestKind::History { .. } => "history",
+                RpcRequestKind::ExportSubtree(_) => "export-subtree",
+                _ => continue,
+            };
+            if let Some(reply) = reply {
+                let e = format!("{} is not allowed in a transaction", name);
+                reply.send(Value::Error(Chars::from(e)));
+            }
+            return;
+        }
+        let mut ops = Txn::new();
+        let mut checks = Vec::new();
+        for kind in kinds {
+            let (tx, rx) = oneshot::channel();
+            self.process_rpc_request(batch, &mut ops, kind, Some(Sendable::Rpc(tx)));
+            checks.push(rx);
+        }
+        for mut rx in checks {
+            if let Ok(Some(e @ Value::Error(_))) = rx.try_recv() {
+                if let Some(reply) = reply {
+                    reply.send(e);
                 }
-                RpcRequestKind::DelRoot(path) => {
-                    txn.del_root(path, Some(reply));
+                return;
+            }
+        }
+        txn.atomic(ops, reply)
+    }
+
+    fn export_subtree(&self, path: Path, reply: Reply) {
+        let path = or_reply!(reply, self.check_path(path));
+        let db = &self.ctx.user.db;
+        let export = or_reply!(reply, task::block_in_place(|| db.export(&path)));
+        let json = or_reply!(reply, serde_json::to_string(&export));
+        if let Some(reply) = reply {
+            reply.send(Value::String(Chars::from(json)))
+        }
+    }
+
+    // the import is checked and committed like a transaction, so a
+    // bad file can't leave half a subtree behind.
+    fn import_subtree(
+        &mut self,
+        batch: &mut UpdateBatch,
+        txn: &mut Txn,
+        path: Path,
+        data: Chars,
+        reply: Reply,
+    ) {
+        let export = or_reply!(reply, serde_json::from_str::<export::Export>(&data));
+        let kinds = or_reply!(reply, export.into_requests(&path));
+        self.transaction(batch, txn, kinds, reply)
+    }
+
+    fn show_history(
+        &mut self,
+        batch: &mut UpdateBatch,
+        path: Path,
+        limit: usize,
+        reply: Reply,
+    ) {
+        if limit == 0 {
+            self.history.remove(&path);
+        } else {
+            let items = or_reply!(reply, self.ctx.user.db.history(&path, limit));
+            let publisher = &self.ctx.user.publisher;
+            let base = self.cfg.api_path.append("history").append(&path);
+            let view = self
+                .history
+                .entry(path)
+                .or_insert_with(|| history::View::new(publisher.clone(), base, limit));
+            view.limit = limit;
+            or_reply!(reply, view.set(batch, &items));
+        }
+        if let Some(reply) = reply {
+            reply.send(Value::Ok)
+        }
+    }
+
+    fn update_history(&mut self, batch: &mut UpdateBatch, paths: FxHashSet<Path>) {
+        for path in paths {
+            if let Some(view) = self.history.get_mut(&path) {
+                // CR estokes: log this
+                if let Ok(items) = self.ctx.user.db.history(&path, view.limit) {
+                    let _: Result<_> = view.set(batch, &items);
                 }
             }
         }
     }
 
+    fn process_rpc_request(
+        &mut self,
+        batch: &mut UpdateBatch,
+        txn: &mut Txn,
+        kind: RpcRequestKind,
+        reply: Reply,
+    ) {
+        match kind {
+            RpcRequestKind::Delete(path) => self.delete_path(txn, path, reply),
+            RpcRequestKind::DeleteSubtree(path) => self.delete_subtree(txn, path, reply),
+            RpcRequestKind::LockSubtree(path) => self.lock_subtree(txn, path, reply),
+            RpcRequestKind::UnlockSubtree(path) => self.unlock_subtree(txn, path, reply),
+            RpcRequestKind::SetData { path, value } => {
+                self.set_data(txn, path, value, reply)
+            }
+            RpcRequestKind::SetFormula { path, formula, on_write } => {
+                self.set_formula(txn, path, formula, on_write, reply)
+            }
+            RpcRequestKind::CreateSheet {
+                path,
+                rows,
+                columns,
+                max_rows,
+                max_columns,
+                lock,
+            } => self.create_sheet(
+                txn,
+                path,
+                rows,
+                columns,
+                max_rows,
+                max_columns,
+                lock,
+                reply,
+            ),
+            RpcRequestKind::AddSheetRows(path, rows) => {
+                txn.add_sheet_rows(path, rows, reply);
+            }
+            RpcRequestKind::AddSheetCols(path, cols) => {
+                txn.add_sheet_columns(path, cols, reply);
+            }
+            RpcRequestKind::DelSheetRows(path, rows) => {
+                txn.del_sheet_rows(path, rows, reply);
+            }
+            RpcRequestKind::DelSheetCols(path, cols) => {
+                txn.del_sheet_columns(path, cols, reply);
+            }
+            RpcRequestKind::CreateTable { path, rows, columns, lock } => {
+                self.create_table(txn, path, rows, columns, lock, reply)
+            }
+            RpcRequestKind::AddTableRows(path, rows) => {
+                txn.add_table_rows(path, rows, reply);
+            }
+            RpcRequestKind::AddTableCols(path, cols) => {
+                txn.add_table_columns(path, cols, reply);
+            }
+            RpcRequestKind::DelTableRows(path, rows) => {
+                txn.del_table_rows(path, rows, reply);
+            }
+            RpcRequestKind::DelTableCols(path, cols) => {
+                txn.del_table_columns(path, cols, reply);
+            }
+            RpcRequestKind::AddRoot(path) => {
+                txn.add_root(path, reply);
+            }
+            RpcRequestKind::DelRoot(path) => {
+                txn.del_root(path, reply);
+            }
+            RpcRequestKind::ExportSubtree(path) => self.export_subtree(path, reply),
+            RpcRequestKind::ImportSubtree { path, data } => {
+                self.import_subtree(batch, txn, path, data, reply)
+            }
+            RpcRequestKind::Undo(path) => txn.undo(path, reply),
+            RpcRequestKind::RevertTo { path, time } => txn.revert_to(path, time, reply),
+            RpcRequestKind::Transaction(kinds) => {
+                self.transaction(batch, txn, kinds, reply)
+            }
+            RpcRequestKind::History { path, limit } => {
+                self.show_history(batch, path, limit, reply)
+            }
+        }
+    }
+
+    fn process_rpc_requests(
+        &mut self,
+        batch: &mut UpdateBatch,
+        txn: &mut Txn,
+        reqs: &mut Vec<RpcRequest>,
+    ) {
+        for req in reqs.drain(..) {
+            let reply = Some(Sendable::Rpc(req.reply));
+            let principal = self.principal(req.client);
+            if let Some(acls) = &self.acls {
+                or_reply!(reply, acls.check_request(&req.kind, &principal), continue);
+            }
+            txn.set_author(author(&principal));
+            self.process_rpc_request(batch, txn, req.kind, reply)
+        }
+        txn.set_author(None);
+    }
+
     fn remove_deleted_published(&mut self, batch: &mut UpdateBatch, path: &Path) {
         let ref_err = Value::Error(Chars::from("#REF"));
         self.remove_advertisement(&path);
@@ -1429,6 +1652,15 @@ impl Container {
                 }
             }
         }
+        let mut history = HashSet::with_hasher(FxBuildHasher::default());
+        if !self.history.is_empty() {
+            let changed = update.data.iter().chain(update.formula.iter());
+            for (path, _) in changed.chain(update.on_write.iter()) {
+                if self.history.contains_key(path) {
+                    history.insert(path.clone());
+                }
+            }
+        }
         for path in update.added_roots.drain(..) {
             roots = true;
             match self.ctx.user.publisher.publish_default(path.clone()) {
@@ -1545,6 +1777,9 @@ impl Container {
             // CR estokes: log this
             let _: Result<_> = self.stats.set_roots(batch, &self.roots);
         }
+        if !history.is_empty() {
+            self.update_history(batch, history);
+        }
     }
 
     async fn run(mut self) -> Result<()> {
@@ -1570,7 +1805,9 @@ impl Container {
                 }
                 r = self.api.rx.select_next_some() => match r {
                     BatchItem::InBatch(v) => rpcbatch.push(v),
-                    BatchItem::EndBatch => self.process_rpc_requests(&mut txn, &mut rpcbatch)
+                    BatchItem::EndBatch => {
+                        self.process_rpc_requests(&mut batch, &mut txn, &mut rpcbatch)
+                    }
                 },
                 r = self.bscript_event.select_next_some() => {
                     self.process_bscript_event(&mut batch, r)
@@ -1602,9 +1839,87 @@ impl Container {
     }
 }
 
-pub(super) fn run(cfg: config::Config, auth: Auth, ccfg: ContainerConfig) {
+pub(super) fn run(
+    cfg: config::Config,
+    auth: Auth,
+    mut ccfg: ContainerConfig,
+) -> Result<()> {
+    if let Some(cmd) = ccfg.cmd.take() {
+        return export::run(cfg, auth, ccfg.api_path, cmd);
+    }
+    if ccfg.bind.is_none() || ccfg.db.is_none() {
+        bail!("--bind and --db are required to run a container")
+    }
     Runtime::new().expect("failed to create runtime").block_on(async move {
         let t = Container::new(cfg, auth, ccfg).await.expect("failed to create context");
         t.run().await.expect("container main loop failed")
-    })
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use netidx::resolver_server::Server;
+
+    async fn rpc(c: &mut Container, kind: RpcRequestKind) -> Value {
+        let (tx, mut rx) = oneshot::channel();
+        let mut batch = c.ctx.user.publisher.start_batch();
+        let mut txn = Txn::new();
+        c.process_rpc_request(&mut batch, &mut txn, kind, Some(Sendable::Rpc(tx)));
+        if txn.dirty() {
+            c.ctx.user.db.commit(txn);
+            let u = c.db_updates.next().await.unwrap();
+            c.process_update(&mut batch, u);
+        }
+        batch.commit(None).await;
+        rx.try_recv().unwrap().unwrap()
+    }
+
+    #[test]
+    fn mixed_transaction() {
+        Runtime::new().unwrap().block_on(async {
+            let mut cfg = config::Config::load("../cfg/simple.json").unwrap();
+            let server = Server::new(cfg.clone(), config::PMap::default(), false, 0)
+                .await
+                .unwrap();
+            cfg.addrs[0] = *server.local_addr();
+            let db = std::env::temp_dir()
+                .join(format!("netidx-container-test-{}", std::process::id()));
+            let db = db.to_string_lossy().into_owned();
+            let args = [
+                "container",
+                "--bind",
+                "127.0.0.1/32",
+                "--api-path",
+                "/api",
+                "--db",
+                &db,
+            ];
+            let ccfg = ContainerConfig::from_iter(&args);
+            let mut c = Container::new(cfg, Auth::Anonymous, ccfg).await.unwrap();
+            let r = rpc(&mut c, RpcRequestKind::AddRoot(Path::from("/app"))).await;
+            assert_eq!(r, Value::Ok);
+            let set = |p: &str, v: i64| RpcRequestKind::SetData {
+                path: Path::from(String::from(p)),
+                value: Value::I64(v),
+            };
+            let history = RpcRequestKind::History { path: Path::from("/app"), limit: 1 };
+            let export = RpcRequestKind::ExportSubtree(Path::from("/app"));
+            for bad in [history, export] {
+                let txn = RpcRequestKind::Transaction(vec![set("/app/a", 1), bad]);
+                let r = rpc(&mut c, txn).await;
+                assert!(matches!(r, Value::Error(_)));
+                assert!(c.ctx.user.db.lookup("/app/a").unwrap().is_none());
+            }
+            let txn =
+                RpcRequestKind::Transaction(vec![set("/app/a", 1), set("/app/b", 2)]);
+            assert_eq!(rpc(&mut c, txn).await, Value::Ok);
+            assert!(c.ctx.user.db.lookup("/app/a").unwrap().is_some());
+            assert!(c.ctx.user.db.lookup("/app/b").unwrap().is_some());
+            drop(c);
+            drop(server);
+            let _ = std::fs::remove_dir_all(&db);
+        })
+    }
 }
@@ -1,16 +1,26 @@
 use anyhow::Result;
 use arcstr::ArcStr;
+use chrono::prelude::*;
 use futures::{
     channel::{mpsc, oneshot},
     prelude::*,
 };
 use netidx::{
-    chars::Chars, path::Path, pool::Pooled, publisher::Publisher, subscriber::Value,
+    chars::Chars,
+    path::Path,
+    pool::Pooled,
+    publisher::{ClId, Publisher},
+    subscriber::Value,
     utils::Batched,
 };
 use netidx_protocols::rpc::server::Proc;
 use std::{collections::HashMap, sync::Arc};
 
+/// The operations that may be performed by rpc. In a transaction
+/// each operation is written as json, named like the rpc that
+/// performs it, e.g. `{"set-data": {"path": "/foo", "value": {"I64": 42}}}`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub(super) enum RpcRequestKind {
     Delete(Path),
     DeleteSubtree(Path),
@@ -20,11 +30,13 @@ pub(super) enum RpcRequestKind {
         path: Path,
         value: Value,
     },
+    #[serde(rename_all = "kebab-case")]
     SetFormula {
         path: Path,
         formula: Option<Chars>,
         on_write: Option<Chars>,
     },
+    #[serde(rename_all = "kebab-case")]
     CreateSheet {
         path: Path,
         rows: usize,
@@ -34,8 +46,11 @@ pub(super) enum RpcRequestKind {
         lock: bool,
     },
     AddSheetRows(Path, usize),
+    #[serde(rename = "add-sheet-columns")]
     AddSheetCols(Path, usize),
+    #[serde(rename = "delete-sheet-rows")]
     DelSheetRows(Path, usize),
+    #[serde(rename = "delete-sheet-columns")]
     DelSheetCols(Path, usize),
     CreateTable {
         path: Path,
@@ -44,16 +59,40 @@ pub(super) enum RpcRequestKind {
         lock: bool,
     },
     AddTableRows(Path, Vec<Chars>),
+    #[serde(rename = "add-table-columns")]
     AddTableCols(Path, Vec<Chars>),
+    #[serde(rename = "delete-table-rows")]
     DelTableRows(Path, Vec<Chars>),
+    #[serde(rename = "delete-table-columns")]
     DelTableCols(Path, Vec<Chars>),
     AddRoot(Path),
+    #[serde(rename = "remove-root")]
     DelRoot(Path),
+    ImportSubtree {
+        path: Path,
+        data: Chars,
+    },
+    Undo(Path),
+    RevertTo {
+        path: Path,
+        time: DateTime<Utc>,
+    },
+    /// all or none of the operations are performed
+    #[serde(skip)]
+    Transaction(Vec<RpcRequestKind>),
+    #[serde(skip)]
+    ExportSubtree(Path),
+    #[serde(skip)]
+    History {
+        path: Path,
+        limit: usize,
+    },
 }
 
 pub(super) struct RpcRequest {
     pub(super) kind: RpcRequestKind,
     pub(super) reply: oneshot::Sender<Value>,
+    pub(super) client: ClId,
 }
 
 pub(super) struct RpcApi {
@@ -75,6 +114,12 @@ pub(super) struct RpcApi {
     _del_table_cols: Proc,
     _add_root: Proc,
     _del_root: Proc,
+    _transaction: Proc,
+    _export_subtree: Proc,
+    _import_subtree: Proc,
+    _undo: Proc,
+    _revert_to: Proc,
+    _history: Proc,
     pub(super) rx: Batched<mpsc::Receiver<RpcRequest>>,
 }
 
@@ -114,6 +159,12 @@ impl RpcApi {
             start_add_root_rpc(&publisher, &base_path, tx.clone())?;
         let _del_root =
             start_del_root_rpc(&publisher, &base_path, tx.clone())?;
+        let _transaction = start_transaction_rpc(&publisher, &base_path, tx.clone())?;
+        let _export_subtree = start_export_subtree_rpc(publisher, base_path, tx.clone())?;
+        let _import_subtree = start_import_subtree_rpc(publisher, base_path, tx.clone())?;
+        let _undo = start_undo_rpc(publisher, base_path, tx.clone())?;
+        let _revert_to = start_revert_to_rpc(publisher, base_path, tx.clone())?;
+        let _history = start_history_rpc(publisher, base_path, tx.clone())?;
         Ok(RpcApi {
             _delete_path_rpc,
             _delete_subtree_rpc,
@@ -133,6 +184,12 @@ impl RpcApi {
             _del_table_cols,
             _add_root,
             _del_root,
+            _transaction,
+            _export_subtree,
+            _import_subtree,
+            _undo,
+            _revert_to,
+            _history,
             rx: Batched::new(rx, 1_000_000),
         })
     }
@@ -193,7 +250,7 @@ fn start_path_arg_rpc(
         vec![(Arc::from("path"), (Value::Null, Value::from(argdoc)))]
             .into_iter()
             .collect(),
-        Arc::new(move |_addr, mut args| {
+        Arc::new(move |client, mut args| {
             let mut tx = tx.clone();
             Box::pin(async move {
                 match args.remove("path") {
@@ -202,8 +259,9 @@ fn start_path_arg_rpc(
                         for path in paths.drain(..) {
                             let path = get_path!(path);
                             let (reply, reply_rx) = oneshot::channel();
-                            let _: Result<_, _> =
-                                tx.send(RpcRequest { kind: f(path), reply }).await;
+                            let _: Result<_, _> = tx
+                                .send(RpcRequest { kind: f(path), reply, client })
+                                .await;
                             match reply_rx.await {
                                 Err(_) => return err("internal error"),
                                 Ok(v) => match v {
@@ -331,7 +389,7 @@ pub(super) fn start_set_data_rpc(
         ]
         .into_iter()
         .collect(),
-        Arc::new(move |_addr, mut args| {
+        Arc::new(move |client, mut args| {
             let mut tx = tx.clone();
             Box::pin(async move {
                 match args.remove("path") {
@@ -347,7 +405,7 @@ pub(super) fn start_set_data_rpc(
                             let (reply, reply_rx) = oneshot::channel();
                             let kind = RpcRequestKind::SetData { path, value };
                             let _: Result<_, _> =
-                                tx.send(RpcRequest { kind, reply }).await;
+                                tx.send(RpcRequest { kind, reply, client }).await;
                             match reply_rx.await {
                                 Err(_) => return err("internal error"),
                                 Ok(v) => match v {
@@ -380,7 +438,7 @@ pub(super) fn start_set_formula_rpc(
         ]
         .into_iter()
         .collect(),
-        Arc::new(move |_addr, mut args| {
+        Arc::new(move |client, mut args| {
             let mut tx = tx.clone();
             Box::pin(async move {
                 match args.remove("path") {
@@ -397,7 +455,7 @@ pub(super) fn start_set_formula_rpc(
                                 on_write: on_write.clone(),
                             };
                             let _: Result<_, _> =
-                                tx.send(RpcRequest { kind, reply }).await;
+                                tx.send(RpcRequest { kind, reply, client }).await;
                             match reply_rx.await {
                                 Err(_) => return err("internal error"),
                                 Ok(v) => match v {
@@ -433,7 +491,7 @@ pub(super) fn start_create_sheet_rpc(
         ]
         .into_iter()
         .collect(),
-        Arc::new(move |_addr, mut args| {
+        Arc::new(move |client, mut args| {
             let mut tx = tx.clone();
             Box::pin(async move {
                 match args.remove("path") {
@@ -459,7 +517,7 @@ pub(super) fn start_create_sheet_rpc(
                                 lock,
                             };
                             let _: Result<_, _> =
-                                tx.send(RpcRequest { kind, reply }).await;
+                                tx.send(RpcRequest { kind, reply, client }).await;
                             match reply_rx.await {
                                 Err(_) => return err("internal error"),
                                 Ok(v) => match v {
@@ -491,7 +549,7 @@ pub(super) fn start_add_sheet_rows_rpc(
         ]
         .into_iter()
         .collect(),
-        Arc::new(move |_addr, mut args| {
+        Arc::new(move |client, mut args| {
             let mut tx = tx.clone();
             Box::pin(async move {
                 match args.remove("path") {
@@ -503,7 +561,7 @@ pub(super) fn start_add_sheet_rows_rpc(
                             let (reply, reply_rx) = oneshot::channel();
                             let kind = RpcRequestKind::AddSheetRows(path, rows as usize);
                             let _: Result<_, _> =
-                                tx.send(RpcRequest { kind, reply }).await;
+                                tx.send(RpcRequest { kind, reply, client }).await;
                             match reply_rx.await {
                                 Err(_) => return err("internal error"),
                                 Ok(v) => match v {
@@ -535,7 +593,7 @@ pub(super) fn start_add_sheet_cols_rpc(
         ]
         .into_iter()
         .collect(),
-        Arc::new(move |_addr, mut args| {
+        Arc::new(move |client, mut args| {
             let mut tx = tx.clone();
             Box::pin(async move {
                 match args.remove("path") {
@@ -548,7 +606,7 @@ pub(super) fn start_add_sheet_cols_rpc(
                             let kind =
                                 RpcRequestKind::AddSheetCols(path, columns as usize);
                             let _: Result<_, _> =
-                                tx.send(RpcRequest { kind, reply }).await;
+                                tx.send(RpcRequest { kind, reply, client }).await;
                             match reply_rx.await {
                                 Err(_) => return err("internal error"),
                                 Ok(v) => match v {
@@ -580,7 +638,7 @@ pub(super) fn start_del_sheet_rows_rpc(
         ]
         .into_iter()
         .collect(),
-        Arc::new(move |_addr, mut args| {
+        Arc::new(move |client, mut args| {
             let mut tx = tx.clone();
             Box::pin(async move {
                 match args.remove("path") {
@@ -592,7 +650,7 @@ pub(super) fn start_del_sheet_rows_rpc(
                             let (reply, reply_rx) = oneshot::channel();
                             let kind = RpcRequestKind::DelSheetRows(path, rows as usize);
                             let _: Result<_, _> =
-                                tx.send(RpcRequest { kind, reply }).await;
+                                tx.send(RpcRequest { kind, reply, client }).await;
                             match reply_rx.await {
                                 Err(_) => return err("internal error"),
                                 Ok(v) => match v {
@@ -624,7 +682,7 @@ pub(super) fn start_del_sheet_cols_rpc(
         ]
         .into_iter()
         .collect(),
-        Arc::new(move |_addr, mut args| {
+        Arc::new(move |client, mut args| {
             let mut tx = tx.clone();
             Box::pin(async move {
                 match args.remove("path") {
@@ -637,7 +695,7 @@ pub(super) fn start_del_sheet_cols_rpc(
                             let kind =
                                 RpcRequestKind::DelSheetCols(path, columns as usize);
                             let _: Result<_, _> =
-                                tx.send(RpcRequest { kind, reply }).await;
+                                tx.send(RpcRequest { kind, reply, client }).await;
                             match reply_rx.await {
                                 Err(_) => return err("internal error"),
                                 Ok(v) => match v {
@@ -690,7 +748,7 @@ pub(super) fn start_create_table_rpc(
         ]
         .into_iter()
         .collect(),
-        Arc::new(move |_addr, mut args| {
+        Arc::new(move |client, mut args| {
             let mut tx = tx.clone();
             Box::pin(async move {
                 match args.remove("path") {
@@ -715,7 +773,7 @@ pub(super) fn start_create_table_rpc(
                                 lock,
                             };
                             let _: Result<_, _> =
-                                tx.send(RpcRequest { kind, reply }).await;
+                                tx.send(RpcRequest { kind, reply, client }).await;
                             match reply_rx.await {
                                 Err(_) => return err("internal error"),
                                 Ok(v) => match v {
@@ -747,7 +805,7 @@ pub(super) fn start_add_table_rows_rpc(
         ]
         .into_iter()
         .collect(),
-        Arc::new(move |_addr, mut args| {
+        Arc::new(move |client, mut args| {
             let mut tx = tx.clone();
             Box::pin(async move {
                 match args.remove("path") {
@@ -762,7 +820,7 @@ pub(super) fn start_add_table_rows_rpc(
                             let (reply, reply_rx) = oneshot::channel();
                             let kind = RpcRequestKind::AddTableRows(path, rows.clone());
                             let _: Result<_, _> =
-                                tx.send(RpcRequest { kind, reply }).await;
+                                tx.send(RpcRequest { kind, reply, client }).await;
                             match reply_rx.await {
                                 Err(_) => return err("internal error"),
                                 Ok(v) => match v {
@@ -794,7 +852,7 @@ pub(super) fn start_add_table_cols_rpc(
         ]
         .into_iter()
         .collect(),
-        Arc::new(move |_addr, mut args| {
+        Arc::new(move |client, mut args| {
             let mut tx = tx.clone();
             Box::pin(async move {
                 match args.remove("path") {
@@ -809,7 +867,7 @@ pub(super) fn start_add_table_cols_rpc(
                             let (reply, reply_rx) = oneshot::channel();
                             let kind = RpcRequestKind::AddTableCols(path, cols.clone());
                             let _: Result<_, _> =
-                                tx.send(RpcRequest { kind, reply }).await;
+                                tx.send(RpcRequest { kind, reply, client }).await;
                             match reply_rx.await {
                                 Err(_) => return err("internal error"),
                                 Ok(v) => match v {
@@ -842,7 +900,7 @@ pub(super) fn start_del_table_rows_rpc(
         ]
         .into_iter()
         .collect(),
-        Arc::new(move |_addr, mut args| {
+        Arc::new(move |client, mut args| {
             let mut tx = tx.clone();
             Box::pin(async move {
                 match args.remove("path") {
@@ -857,7 +915,7 @@ pub(super) fn start_del_table_rows_rpc(
                             let (reply, reply_rx) = oneshot::channel();
                             let kind = RpcRequestKind::DelTableRows(path, rows.clone());
                             let _: Result<_, _> =
-                                tx.send(RpcRequest { kind, reply }).await;
+                                tx.send(RpcRequest { kind, reply, client }).await;
                             match reply_rx.await {
                                 Err(_) => return err("internal error"),
                                 Ok(v) => match v {
@@ -889,7 +947,7 @@ pub(super) fn start_del_table_cols_rpc(
         ]
         .into_iter()
         .collect(),
-        Arc::new(move |_addr, mut args| {
+        Arc::new(move |client, mut args| {
             let mut tx = tx.clone();
             Box::pin(async move {
                 match args.remove("path") {
@@ -904,7 +962,245 @@ pub(super) fn start_del_table_cols_rpc(
                             let (reply, reply_rx) = oneshot::channel();
                             let kind = RpcRequestKind::DelTableCols(path, cols.clone());
                             let _: Result<_, _> =
-                                tx.send(RpcRequest { kind, reply }).await;
+                                tx.send(RpcRequest { kind, reply, client }).await;
+                            match reply_rx.await {
+                                Err(_) => return err("internal error"),
+                                Ok(v) => match v {
+                                    Value::Ok => (),
+                                    v => return v,
+                                },
+                            }
+                        }
+                        Value::Ok
+                    }
+                }
+            })
+        }),
+    )?)
+}
+
+pub(super) fn start_transaction_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    Ok(Proc::new(
+        publisher,
+        base_path.append("transaction"),
+        Value::from("perform all of the operations, or none of them"),
+        vec![(
+            Arc::from("op"),
+            (Value::Null, Value::from("the operation(s) as json, in order")),
+        )]
+        .into_iter()
+        .collect(),
+        Arc::new(move |client, mut args| {
+            let mut tx = tx.clone();
+            Box::pin(async move {
+                match args.remove("op") {
+                    None => err("invalid argument, expected op"),
+                    Some(mut ops) => {
+                        let mut kinds = Vec::new();
+                        for op in ops.drain(..) {
+                            let op = match op {
+                                Value::String(op) => op,
+                                _ => {
+                                    return err("invalid argument type, expected string")
+                                }
+                            };
+                            match serde_json::from_str::<RpcRequestKind>(&op) {
+                                Ok(kind) => kinds.push(kind),
+                                Err(e) => {
+                                    let msg = format!("invalid op {}: {}", op, e);
+                                    return Value::Error(Chars::from(msg));
+                                }
+                            }
+                        }
+                        let (reply, reply_rx) = oneshot::channel();
+                        let kind = RpcRequestKind::Transaction(kinds);
+                        let _: Result<_, _> =
+                            tx.send(RpcRequest { kind, reply, client }).await;
+                        match reply_rx.await {
+                            Err(_) => err("internal error"),
+                            Ok(v) => v,
+                        }
+                    }
+                }
+            })
+        }),
+    )?)
+}
+
+pub(super) fn start_export_subtree_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    Ok(Proc::new(
+        publisher,
+        base_path.append("export-subtree"),
+        Value::from("export the subtree at path as json"),
+        vec![(Arc::from("path"), (Value::Null, Value::from("the subtree to export")))]
+            .into_iter()
+            .collect(),
+        Arc::new(move |client, mut args| {
+            let mut tx = tx.clone();
+            Box::pin(async move {
+                match args.remove("path").and_then(|mut v| v.pop()) {
+                    None => err("invalid argument, expected path"),
+                    Some(path) => {
+                        let path = get_path!(path);
+                        let (reply, reply_rx) = oneshot::channel();
+                        let kind = RpcRequestKind::ExportSubtree(path);
+                        let _: Result<_, _> =
+                            tx.send(RpcRequest { kind, reply, client }).await;
+                        match reply_rx.await {
+                            Err(_) => err("internal error"),
+                            Ok(v) => v,
+                        }
+                    }
+                }
+            })
+        }),
+    )?)
+}
+
+pub(super) fn start_import_subtree_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    Ok(Proc::new(
+        publisher,
+        base_path.append("import-subtree"),
+        Value::from("import a subtree exported by export-subtree under path"),
+        vec![
+            (Arc::from("path"), (Value::Null, Value::from("where to put the subtree"))),
+            (Arc::from("data"), (Value::Null, Value::from("the exported subtree"))),
+        ]
+        .into_iter()
+        .collect(),
+        Arc::new(move |client, mut args| {
+            let mut tx = tx.clone();
+            Box::pin(async move {
+                let data = match get_arg_opt!(Chars, args, "data") {
+                    None => return err("invalid argument, expected data"),
+                    Some(data) => data,
+                };
+                match args.remove("path").and_then(|mut v| v.pop()) {
+                    None => err("invalid argument, expected path"),
+                    Some(path) => {
+                        let path = get_path!(path);
+                        let (reply, reply_rx) = oneshot::channel();
+                        let kind = RpcRequestKind::ImportSubtree { path, data };
+                        let _: Result<_, _> =
+                            tx.send(RpcRequest { kind, reply, client }).await;
+                        match reply_rx.await {
+                            Err(_) => err("internal error"),
+                            Ok(v) => v,
+                        }
+                    }
+                }
+            })
+        }),
+    )?)
+}
+
+pub(super) fn start_undo_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    start_path_arg_rpc(
+        publisher,
+        base_path,
+        "undo",
+        "undo the most recent change to the subtree(s)",
+        "the subtree(s) to undo",
+        RpcRequestKind::Undo,
+        tx,
+    )
+}
+
+pub(super) fn start_revert_to_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    Ok(Proc::new(
+        publisher,
+        base_path.append("revert-to"),
+        Value::from("put the subtree(s) back the way they were at the specified time"),
+        vec![
+            (Arc::from("path"), (Value::Null, Value::from("the subtree(s) to revert"))),
+            (Arc::from("time"), (Value::Null, Value::from("the time to revert to"))),
+        ]
+        .into_iter()
+        .collect(),
+        Arc::new(move |client, mut args| {
+            let mut tx = tx.clone();
+            Box::pin(async move {
+                let time = args.remove("time").and_then(|mut v| v.pop());
+                let time = match time.map(|v| v.cast_to::<DateTime<Utc>>()) {
+                    Some(Ok(time)) => time,
+                    Some(Err(_)) | None => return err("invalid argument, expected time"),
+                };
+                match args.remove("path") {
+                    None => err("invalid argument, expected path"),
+                    Some(mut paths) => {
+                        for path in paths.drain(..) {
+                            let path = get_path!(path);
+                            let (reply, reply_rx) = oneshot::channel();
+                            let kind = RpcRequestKind::RevertTo { path, time };
+                            let _: Result<_, _> =
+                                tx.send(RpcRequest { kind, reply, client }).await;
+                            match reply_rx.await {
+                                Err(_) => return err("internal error"),
+                                Ok(v) => match v {
+                                    Value::Ok => (),
+                                    v => return v,
+                                },
+                            }
+                        }
+                        Value::Ok
+                    }
+                }
+            })
+        }),
+    )?)
+}
+
+pub(super) fn start_history_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    Ok(Proc::new(
+        publisher,
+        base_path.append("history"),
+        Value::from("publish the recent changes to path(s) under history"),
+        vec![
+            (Arc::from("path"), (Value::Null, Value::from("the path(s) to show"))),
+            (
+                Arc::from("limit"),
+                (Value::U64(50), Value::from("the number of changes, 0 to stop")),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        Arc::new(move |client, mut args| {
+            let mut tx = tx.clone();
+            Box::pin(async move {
+                let limit = get_arg!(u64, args, "limit", 50) as usize;
+                match args.remove("path") {
+                    None => err("invalid argument, expected path"),
+                    Some(mut paths) => {
+                        for path in paths.drain(..) {
+                            let path = get_path!(path);
+                            let (reply, reply_rx) = oneshot::channel();
+                            let kind = RpcRequestKind::History { path, limit };
+                            let _: Result<_, _> =
+                                tx.send(RpcRequest { kind, reply, client }).await;
                             match reply_rx.await {
                                 Err(_) => return err("internal error"),
                                 Ok(v) => match v {
@@ -4,12 +4,17 @@ extern crate lazy_static;
 #[macro_use]
 extern crate anyhow;
 #[macro_use]
+extern crate bitflags;
+#[macro_use]
 extern crate serde_derive;
+use anyhow::Result;
 use log::warn;
 use netidx::{config, path::Path, publisher::BindCfg, resolver::Auth};
-use std::net::SocketAddr;
+use std::{fs, io::Write, net::SocketAddr, process, time::SystemTime};
 use structopt::StructOpt;
 
+mod archive;
+mod bscript;
 mod container;
 mod publisher;
 mod recorder;
@@ -27,7 +32,9 @@ mod resolver_server {
 
     pub(crate) fn run(
         _config: config::Config,
+        _config_file: Option<String>,
         _permissions: config::PMap,
+        _permissions_file: Option<String>,
         _daemonize: bool,
         _delay_reads: bool,
         _id: usize,
@@ -49,6 +56,13 @@ struct Opt {
     anon: bool,
     #[structopt(long = "upn", help = "krb5 use <upn> instead of the current user")]
     upn: Option<String>,
+    #[structopt(
+        long = "token",
+        env = "NETIDX_TOKEN",
+        hide_env_values = true,
+        help = "the token to present to a resolver using token auth"
+    )]
+    token: Option<String>,
     #[structopt(subcommand)]
     cmd: Sub,
 }
@@ -73,7 +87,7 @@ enum Sub {
         #[structopt(
             short = "p",
             long = "permissions",
-            help = "location of the permissions file"
+            help = "location of the permissions file, reloaded on SIGHUP"
         )]
         permissions: Option<String>,
     },
@@ -121,6 +135,8 @@ enum Sub {
         #[structopt(name = "paths")]
         paths: Vec<String>,
     },
+    #[structopt(name = "bscript", about = "evaluate bscript expressions")]
+    Bscript(bscript::Params),
     #[structopt(name = "container", about = "a hierarchical database in netidx")]
     Container(container::ContainerConfig),
     #[structopt(name = "record", about = "record and republish archives")]
@@ -146,12 +162,6 @@ enum Sub {
             default_value = "67108864"
         )]
         image_frequency: usize,
-        #[structopt(
-            long = "poll-interval",
-            help = "How often to poll the resolver, 0 for never (5)",
-            default_value = "5"
-        )]
-        poll_interval: u64,
         #[structopt(
             long = "flush-frequency",
             help = "How often to flush changes in pages, 0 only on exit (65534 pages)",
@@ -186,12 +196,45 @@ enum Sub {
         archive: String,
         #[structopt(long = "spec", help = "glob pattern to archive, can be repeated")]
         spec: Vec<String>,
+        #[structopt(
+            long = "retention",
+            help = "path to a retention and downsampling policy file"
+        )]
+        retention: Option<String>,
+    },
+    #[structopt(name = "archive", about = "work with archive files")]
+    Archive {
+        #[structopt(subcommand)]
+        cmd: ArchiveCmd,
     },
     #[structopt(name = "stress", about = "stress test")]
     Stress {
         #[structopt(subcommand)]
         cmd: Stress,
     },
+    #[structopt(name = "token", about = "create a signed resolver token")]
+    Token {
+        #[structopt(long = "key-file", help = "the resolver's token key file")]
+        key_file: String,
+        #[structopt(long = "group", help = "add the user to <group>, can be repeated")]
+        groups: Vec<String>,
+        #[structopt(
+            long = "valid",
+            help = "seconds until the token expires",
+            default_value = "86400"
+        )]
+        valid: u64,
+        #[structopt(name = "user")]
+        user: String,
+    },
+    #[structopt(
+        name = "noise-key",
+        about = "create the resolver's noise key, and print the public key"
+    )]
+    NoiseKey {
+        #[structopt(long = "key-file", help = "write the private key to <key-file>")]
+        key_file: String,
+    },
 }
 
 #[derive(StructOpt, Debug)]
@@ -209,7 +252,7 @@ enum ResolverCmd {
         #[structopt(
             long = "watch",
             short = "w",
-            help = "poll the resolver for new paths matching the specified pattern"
+            help = "keep printing new paths matching the specified pattern as they are published"
         )]
         watch: bool,
         #[structopt(name = "pattern")]
@@ -234,6 +277,105 @@ enum ResolverCmd {
         #[structopt(name = "socketaddr")]
         socketaddr: SocketAddr,
     },
+    #[structopt(
+        name = "admin",
+        about = "administer a resolver server (needs the admin permission, anonymous servers refuse)"
+    )]
+    Admin {
+        #[structopt(
+            long = "server",
+            help = "the server in the cluster to administer (default the first, every server for clear)"
+        )]
+        server: Option<SocketAddr>,
+        #[structopt(subcommand)]
+        cmd: AdminCmd,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum AdminCmd {
+    #[structopt(name = "sessions", about = "list the clients of the server")]
+    Sessions,
+    #[structopt(name = "published", about = "list the paths published by a publisher")]
+    Published {
+        #[structopt(name = "socketaddr")]
+        socketaddr: SocketAddr,
+    },
+    #[structopt(
+        name = "clear",
+        about = "disconnect a publisher and unpublish everything it published"
+    )]
+    Clear {
+        #[structopt(name = "socketaddr")]
+        socketaddr: SocketAddr,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum ArchiveCmd {
+    #[structopt(name = "export", about = "export part of an archive to csv or parquet")]
+    Export {
+        #[structopt(long = "archive", help = "path to the archive file")]
+        archive: String,
+        #[structopt(
+            long = "start",
+            help = "where to start the export, see the recorder docs for syntax",
+            default_value = "beginning"
+        )]
+        start: netidx_archive::Seek,
+        #[structopt(
+            long = "end",
+            help = "where to end the export, see the recorder docs for syntax",
+            default_value = "end"
+        )]
+        end: netidx_archive::Seek,
+        #[structopt(
+            long = "spec",
+            help = "glob pattern of paths to export, can be repeated (default all)"
+        )]
+        spec: Vec<String>,
+        #[structopt(
+            long = "format",
+            help = "the output format, csv or parquet",
+            default_value = "csv"
+        )]
+        format: netidx_archive::export::Format,
+        #[structopt(
+            short = "o",
+            long = "output",
+            help = "the file to write to, default stdout"
+        )]
+        output: Option<String>,
+    },
+    #[structopt(name = "check", about = "check the integrity of an archive")]
+    Check {
+        #[structopt(long = "archive", help = "path to the archive file")]
+        archive: String,
+    },
+    #[structopt(
+        name = "repair",
+        about = "truncate an archive to the last valid record"
+    )]
+    Repair {
+        #[structopt(long = "archive", help = "path to the archive file")]
+        archive: String,
+    },
+    #[structopt(
+        name = "compact",
+        about = "rewrite an archive dropping unused paths and adding images"
+    )]
+    Compact {
+        #[structopt(long = "archive", help = "path to the archive file")]
+        archive: String,
+        #[structopt(short = "o", long = "output", help = "path to the new archive file")]
+        output: String,
+        #[structopt(
+            long = "image-frequency",
+            help = "How often to write a full image, 0 for never (67108864)",
+            default_value = "67108864"
+        )]
+        image_frequency: usize,
+    },
 }
 
 #[derive(StructOpt, Debug)]
@@ -267,6 +409,7 @@ fn auth(
     anon: bool,
     cfg: &config::Config,
     upn: Option<String>,
+    token: Option<String>,
     spn: Option<String>,
 ) -> Auth {
     if anon {
@@ -275,52 +418,115 @@ fn auth(
         match cfg.auth {
             config::Auth::Anonymous => Auth::Anonymous,
             config::Auth::Krb5(_) => Auth::Krb5 { upn, spn },
+            config::Auth::Token { .. } => match token {
+                Some(token) => Auth::Token(token),
+                None => panic!("--token or NETIDX_TOKEN is required by the resolver"),
+            },
         }
     }
 }
 
+fn make_token(
+    key_file: &str,
+    user: &str,
+    groups: &[String],
+    valid: u64,
+) -> Result<String> {
+    let key = fs::read_to_string(key_file)?;
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    let groups = groups.iter().map(|g| g.as_str()).collect::<Vec<_>>();
+    netidx::resolver_server::make_token(key.trim().as_bytes(), user, &groups, now + valid)
+}
+
+fn make_noise_key(key_file: &str) -> Result<String> {
+    let (private, public) = netidx::resolver_server::make_noise_key()?;
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    writeln!(opts.open(key_file)?, "{}", private)?;
+    Ok(public)
+}
+
+fn load_config(path: &Option<String>) -> config::Config {
+    match path {
+        None => config::Config::load_default().unwrap(),
+        Some(path) => config::Config::load(path).unwrap(),
+    }
+}
+
 fn main() {
     env_logger::init();
     let opt = Opt::from_args();
-    let cfg = match opt.config {
-        None => config::Config::load_default().unwrap(),
-        Some(path) => config::Config::load(path).unwrap(),
-    };
     match opt.cmd {
+        // archive tools work on local files and don't need a config
+        Sub::Archive { cmd } => archive::run(cmd),
+        Sub::Token { key_file, groups, valid, user } => {
+            println!("{}", make_token(&key_file, &user, &groups, valid).unwrap())
+        }
+        Sub::NoiseKey { key_file } => println!("{}", make_noise_key(&key_file).unwrap()),
         Sub::ResolverServer { foreground, delay_reads, id, permissions } => {
+            let cfg = load_config(&opt.config);
             if !cfg!(unix) {
                 todo!("the resolver server is not yet ported to this platform")
             }
             let anon = match cfg.auth {
                 config::Auth::Anonymous => true,
-                config::Auth::Krb5(_) => false,
+                config::Auth::Krb5(_) | config::Auth::Token { .. } => false,
             };
-            let permissions = match permissions {
-                None if anon => config::PMap::default(),
-                None => panic!("--permissions is required when using Kerberos"),
+            let permissions_file = match permissions {
+                None if anon => None,
+                None => panic!("--permissions is required when using authentication"),
                 Some(_) if anon => {
-                    warn!("ignoring --permissions, server not using Kerberos");
-                    config::PMap::default()
+                    warn!("ignoring --permissions, server not using authentication");
+                    None
                 }
-                Some(p) => config::PMap::load(&p).unwrap(),
+                Some(p) => Some(p),
+            };
+            let permissions = match &permissions_file {
+                None => config::PMap::default(),
+                Some(p) => config::PMap::load(p).unwrap(),
             };
-            resolver_server::run(cfg, permissions, !foreground, delay_reads, id)
+            resolver_server::run(
+                cfg,
+                opt.config,
+                permissions,
+                permissions_file,
+                !foreground,
+                delay_reads,
+                id,
+            )
         }
         Sub::Resolver { cmd } => {
-            let auth = auth(opt.anon, &cfg, opt.upn, None);
+            let cfg = load_config(&opt.config);
+            let auth = auth(opt.anon, &cfg, opt.upn, opt.token, None);
             resolver::run(cfg, cmd, auth)
         }
         Sub::Publisher { bind, spn, timeout } => {
-            let auth = auth(opt.anon, &cfg, opt.upn, spn);
+            let cfg = load_config(&opt.config);
+            let auth = auth(opt.anon, &cfg, opt.upn, opt.token, spn);
             publisher::run(cfg, bind, timeout, auth)
         }
         Sub::Subscriber { no_stdin, oneshot, subscribe_timeout, paths } => {
-            let auth = auth(opt.anon, &cfg, opt.upn, None);
+            let cfg = load_config(&opt.config);
+            let auth = auth(opt.anon, &cfg, opt.upn, opt.token, None);
             subscriber::run(cfg, no_stdin, oneshot, subscribe_timeout, paths, auth)
         }
+        Sub::Bscript(p) => {
+            let cfg = load_config(&opt.config);
+            let auth = auth(opt.anon, &cfg, opt.upn, opt.token, None);
+            if let Err(e) = bscript::run(cfg, auth, p) {
+                eprintln!("{}", e);
+                process::exit(1)
+            }
+        }
         Sub::Container(ccfg) => {
-            let auth = auth(opt.anon, &cfg, opt.upn, ccfg.spn.clone());
-            container::run(cfg, auth, ccfg)
+            let cfg = load_config(&opt.config);
+            let auth = auth(opt.anon, &cfg, opt.upn, opt.token, ccfg.spn.clone());
+            if let Err(e) = container::run(cfg, auth, ccfg) {
+                eprintln!("{}", e);
+                process::exit(1)
+            }
         }
         Sub::Record {
             foreground,
@@ -328,7 +534,6 @@ fn main() {
             spn,
             publish_base,
             image_frequency,
-            poll_interval,
             flush_frequency,
             flush_interval,
             shards,
@@ -336,8 +541,10 @@ fn main() {
             max_sessions_per_client,
             archive,
             spec,
+            retention,
         } => {
-            let auth = auth(opt.anon, &cfg, opt.upn, spn);
+            let cfg = load_config(&opt.config);
+            let auth = auth(opt.anon, &cfg, opt.upn, opt.token, spn);
             recorder::run(
                 cfg,
                 foreground,
@@ -345,7 +552,6 @@ fn main() {
                 publish_base,
                 auth,
                 image_frequency,
-                poll_interval,
                 flush_frequency,
                 flush_interval,
                 shards,
@@ -353,15 +559,18 @@ fn main() {
                 max_sessions_per_client,
                 archive,
                 spec,
+                retention,
             )
         }
         Sub::Stress { cmd } => match cmd {
             Stress::Subscriber => {
-                let auth = auth(opt.anon, &cfg, opt.upn, None);
+                let cfg = load_config(&opt.config);
+                let auth = auth(opt.anon, &cfg, opt.upn, opt.token, None);
                 stress_subscriber::run(cfg, auth)
             }
             Stress::Publisher { bind, spn, delay, rows, cols } => {
-                let auth = auth(opt.anon, &cfg, opt.upn, spn);
+                let cfg = load_config(&opt.config);
+                let auth = auth(opt.anon, &cfg, opt.upn, opt.token, spn);
                 stress_publisher::run(cfg, bind, delay, rows, cols, auth)
             }
         },
@@ -20,7 +20,7 @@ use netidx::{
     publisher::{
         BindCfg, ClId, PublishFlags, Publisher, UpdateBatch, Val, Value, WriteRequest,
     },
-    resolver::{Auth, ChangeTracker, ResolverRead},
+    resolver::Auth,
     subscriber::{Dval, Event, SubId, Subscriber, UpdatesFlags},
     utils,
 };
@@ -41,9 +41,12 @@ use std::{
     sync::Arc,
     time::Duration,
 };
-use tokio::{runtime::Runtime, sync::broadcast, task, time};
+use tokio::{
+    runtime::Runtime,
+    sync::{broadcast, watch},
+    task, time,
+};
 use uuid::{adapter::SimpleRef, Uuid};
-use arcstr::ArcStr;
 
 #[derive(Debug, Clone)]
 enum BCastMsg {
@@ -147,7 +150,7 @@ mod publish {
             } else if s.as_str() == "tail" {
                 Ok(State::Tail)
             } else {
-                bail!("expected state {play, pause, tail}")
+                bail!("expected state {{play, pause, tail}}")
             }
         }
     }
@@ -720,6 +723,14 @@ mod publish {
         }
     }
 
+    // wait for the recorder to switch to a new archive file
+    async fn reopened(archive: &mut watch::Receiver<ArchiveReader>) -> ArchiveReader {
+        match archive.changed().await {
+            Ok(()) => archive.borrow().clone(),
+            Err(_) => future::pending().await,
+        }
+    }
+
     fn not_idle(idle: &mut bool, cluster: &Cluster<ClusterCmd>) {
         *idle = false;
         cluster.send_cmd(&ClusterCmd::NotIdle);
@@ -727,7 +738,7 @@ mod publish {
 
     async fn session(
         mut bcast: broadcast::Receiver<BCastMsg>,
-        archive: ArchiveReader,
+        mut archive: watch::Receiver<ArchiveReader>,
         subscriber: Subscriber,
         publisher: Publisher,
         publish_base: Path,
@@ -740,8 +751,9 @@ mod publish {
         let mut cluster =
             Cluster::new(&publisher, subscriber, session_base.append("cluster"), shards)
                 .await?;
-        archive.check_remap_rescan()?;
-        let mut t = T::new(publisher.clone(), archive, session_base, &control_tx).await?;
+        let reader = archive.borrow().clone();
+        reader.check_remap_rescan()?;
+        let mut t = T::new(publisher.clone(), reader, session_base, &control_tx).await?;
         let mut batch = publisher.start_batch();
         t.seek(&mut batch, Seek::Beginning)?;
         if let Some(cfg) = cfg {
@@ -769,6 +781,7 @@ mod publish {
                     }
                 },
                 m = bcast.recv().fuse() => t.process_bcast(m).await?,
+                r = reopened(&mut archive).fuse() => t.archive = r,
                 cmds = cluster.wait_cmds().fuse() => {
                     let mut cbatch = publisher.start_batch();
                     for cmd in cmds? {
@@ -846,7 +859,7 @@ mod publish {
         session_token: Session,
         bcast: &broadcast::Sender<BCastMsg>,
         subscriber: &Subscriber,
-        archive: &ArchiveReader,
+        archive: &watch::Receiver<ArchiveReader>,
         shards: usize,
         publish_base: &Path,
         cfg: Option<NewSessionConfig>,
@@ -883,7 +896,7 @@ mod publish {
 
     pub(super) async fn run(
         bcast: broadcast::Sender<BCastMsg>,
-        archive: ArchiveReader,
+        archive: watch::Receiver<ArchiveReader>,
         resolver: Config,
         desired_auth: Auth,
         bind_cfg: BindCfg,
@@ -1024,47 +1037,9 @@ mod publish {
 
 mod record {
     use super::*;
+    use retention::Compaction;
 
-    #[derive(Debug)]
-    struct CTS(BTreeMap<Path, ChangeTracker>);
-
-    impl CTS {
-        fn new(globs: &Vec<Glob>) -> CTS {
-            let mut btm = BTreeMap::new();
-            for glob in globs {
-                let base = glob.base();
-                match btm
-                    .range::<str, (Bound<&str>, Bound<&str>)>((
-                        Bound::Unbounded,
-                        Bound::Excluded(base),
-                    ))
-                    .next_back()
-                {
-                    Some((p, _)) if Path::is_parent(p, base) => (),
-                    None | Some(_) => {
-                        let base = Path::from(ArcStr::from(base));
-                        let ct = ChangeTracker::new(base.clone());
-                        btm.insert(base, ct);
-                    }
-                }
-            }
-            CTS(btm)
-        }
-
-        async fn changed(&mut self, r: &ResolverRead) -> Result<bool> {
-            let res =
-                future::join_all(self.0.iter_mut().map(|(_, ct)| r.check_changed(ct)))
-                    .await;
-            for r in res {
-                if r? {
-                    return Ok(true);
-                }
-            }
-            Ok(false)
-        }
-    }
-
-    async fn maybe_interval(poll: &mut Option<time::Interval>) {
+    pub(super) async fn maybe_interval(poll: &mut Option<time::Interval>) {
         match poll {
             None => future::pending().await,
             Some(poll) => {
@@ -1073,75 +1048,41 @@ mod record {
         }
     }
 
-    type Lst = Option<Pooled<Vec<Pooled<Vec<Path>>>>>;
-
-    async fn list_task(
-        mut rx: mpsc::UnboundedReceiver<oneshot::Sender<Lst>>,
-        resolver: ResolverRead,
-        spec: Vec<Glob>,
-    ) -> Result<()> {
-        let mut cts = CTS::new(&spec);
-        let spec = GlobSet::new(true, spec)?;
-        while let Some(reply) = rx.next().await {
-            match cts.changed(&resolver).await {
-                Ok(true) => match resolver.list_matching(&spec).await {
-                    Ok(lst) => {
-                        let _ = reply.send(Some(lst));
-                    }
-                    Err(e) => {
-                        warn!("list_task: list_matching failed {}, will retry", e);
-                        let _ = reply.send(None);
-                    }
-                },
-                Ok(false) => {
-                    let _ = reply.send(None);
-                }
-                Err(e) => {
-                    warn!("list_task: check_changed failed {}, will retry", e);
-                    let _ = reply.send(None);
-                }
-            }
-        }
-        Ok(())
-    }
-
-    fn start_list_task(
-        rx: mpsc::UnboundedReceiver<oneshot::Sender<Lst>>,
-        resolver: ResolverRead,
-        spec: Vec<Glob>,
-    ) {
-        task::spawn(async move {
-            let r = list_task(rx, resolver, spec).await;
-            match r {
-                Err(e) => error!("list task exited with error {}", e),
-                Ok(()) => info!("list task exited"),
-            }
-        });
-    }
-
-    async fn wait_list(pending: &mut Option<Fuse<oneshot::Receiver<Lst>>>) -> Lst {
+    async fn wait_compaction(
+        pending: &mut Option<Fuse<task::JoinHandle<Result<Option<Compaction>>>>>,
+    ) -> Result<Option<Compaction>> {
         match pending {
             None => future::pending().await,
-            Some(r) => match r.await {
-                Ok(r) => r,
-                Err(_) => None,
-            },
+            Some(c) => c.await?,
         }
     }
 
     pub(super) async fn run(
         bcast: broadcast::Sender<BCastMsg>,
         mut archive: ArchiveWriter,
+        path: String,
+        reopen: watch::Sender<ArchiveReader>,
         resolver: Config,
         desired_auth: Auth,
-        poll_interval: Option<time::Duration>,
         image_frequency: Option<usize>,
         flush_frequency: Option<usize>,
         flush_interval: Option<time::Duration>,
         spec: Vec<Glob>,
+        retention: Option<Arc<Mutex<retention::Retention>>>,
     ) -> Result<()> {
+        if let Some(r) = &retention {
+            r.lock().reset();
+        }
+        let pruner = retention.as_ref().and_then(|r| r.lock().pruner()).map(Arc::new);
+        let mut prune =
+            retention.as_ref().map(|r| time::interval(r.lock().prune_interval));
+        let mut compacting = None;
+        let mut last_ts = {
+            let mut cursor = Cursor::new();
+            archive.reader()?.seek(&mut cursor, Seek::End);
+            cursor.current()
+        };
         let (tx_batch, rx_batch) = mpsc::channel(10);
-        let (tx_list, rx_list) = mpsc::unbounded();
         let mut rx_batch = utils::Batched::new(rx_batch.fuse(), 10);
         let mut by_subid: HashMap<SubId, Id, FxBuildHasher> =
             HashMap::with_hasher(FxBuildHasher::default());
@@ -1151,52 +1092,85 @@ mod record {
         let subscriber = Subscriber::new(resolver, desired_auth)?;
         let flush_frequency = flush_frequency.map(|f| archive.block_size() * f);
         let mut bcast_rx = bcast.subscribe();
-        let mut poll = poll_interval.map(time::interval);
         let mut flush = flush_interval.map(time::interval);
         let mut to_add = Vec::new();
         let mut timest = MonotonicTimestamper::new();
         let mut last_image = archive.len();
         let mut last_flush = archive.len();
-        let mut pending_list: Option<Fuse<oneshot::Receiver<Lst>>> = None;
         let mut pending_batches: Vec<Pooled<Vec<(SubId, Event)>>> = Vec::new();
-        start_list_task(rx_list, subscriber.resolver(), spec);
+        let mut changes = subscriber.resolver().watch(GlobSet::new(true, spec)?).fuse();
         loop {
             select_biased! {
                 m = bcast_rx.recv().fuse() => match m {
                     Err(_) | Ok(BCastMsg::Batch(_, _)) => (),
                     Ok(BCastMsg::Stop) => break,
                 },
-                _ = maybe_interval(&mut poll).fuse() => {
-                    if pending_list.is_none() {
-                        let (tx, rx) = oneshot::channel();
-                        let _ = tx_list.unbounded_send(tx);
-                        pending_list = Some(rx.fuse());
+                _ = maybe_interval(&mut prune).fuse() => {
+                    // the archive is copied up to last_ts in the
+                    // background, and everything after is copied
+                    // when it's done, so recording doesn't stop.
+                    let idle = compacting.is_none();
+                    if let (Some(pruner), Some(upto), true) = (&pruner, last_ts, idle) {
+                        task::block_in_place(|| archive.flush())?;
+                        let pruner = pruner.clone();
+                        let reader = archive.reader()?;
+                        let tmp = format!("{}.prune", path);
+                        info!("pruning the archive up to {}", upto);
+                        compacting = Some(task::spawn_blocking(move || {
+                            pruner.compact(reader, tmp, upto)
+                        }).fuse());
+                    }
+                },
+                r = wait_compaction(&mut compacting).fuse() => {
+                    compacting = None;
+                    let r = r.and_then(|c| match c {
+                        None => Ok(None),
+                        Some(c) => task::block_in_place(|| {
+                            archive.flush()?;
+                            c.finish(&path)
+                        }).map(Some)
+                    });
+                    match r {
+                        Err(e) => error!("failed to prune the archive {}", e),
+                        Ok(None) => info!("nothing to prune"),
+                        Ok(Some((writer, ts))) => {
+                            archive = writer;
+                            timest = ts;
+                            last_image = archive.len();
+                            last_flush = archive.len();
+                            let reader = archive.reader()?;
+                            task::block_in_place(|| reader.check_remap_rescan())?;
+                            let _ = reopen.send(reader);
+                        }
                     }
                 },
                 _ = maybe_interval(&mut flush).fuse() => {
                     if archive.len() > last_flush {
                         task::block_in_place(|| -> Result<()> {
                             archive.flush()?;
+                            if let Some(r) = &retention {
+                                r.lock().flush()?;
+                            }
                             Ok(last_flush = archive.len())
                         })?;
                     }
                 }
-                r = wait_list(&mut pending_list).fuse() => {
-                    pending_list = None;
-                    if let Some(mut batches) = r {
-                        for mut batch in batches.drain(..) {
-                            for path in batch.drain(..) {
-                                if !subscribed.contains_key(&path) {
-                                    let dv = subscriber.durable_subscribe(path.clone());
-                                    let id = dv.id();
-                                    dv.updates(
-                                        UpdatesFlags::BEGIN_WITH_LAST
-                                            | UpdatesFlags::STOP_COLLECTING_LAST,
-                                        tx_batch.clone()
-                                    );
-                                    subscribed.insert(path.clone(), dv);
-                                    to_add.push((path, id));
-                                }
+                r = changes.next() => match r {
+                    None => error!("the resolver stopped reporting new paths"),
+                    Some(mut changed) => {
+                        // paths that go away stay subscribed, they
+                        // might come back
+                        for path in changed.added.drain(..) {
+                            if !subscribed.contains_key(&path) {
+                                let dv = subscriber.durable_subscribe(path.clone());
+                                let id = dv.id();
+                                dv.updates(
+                                    UpdatesFlags::BEGIN_WITH_LAST
+                                        | UpdatesFlags::STOP_COLLECTING_LAST,
+                                    tx_batch.clone()
+                                );
+                                subscribed.insert(path.clone(), dv);
+                                to_add.push((path, id));
                             }
                         }
                         task::block_in_place(|| {
@@ -1237,6 +1211,10 @@ mod record {
                                     }
                                     Err(e) => bail!(e),
                                     Ok(()) => {
+                                        last_ts = Some(ts.datetime());
+                                        if let Some(r) = &retention {
+                                            r.lock().record(ts.datetime(), &archive, &tbatch)?;
+                                        }
                                         let m = BCastMsg::Batch(ts, Arc::new(tbatch));
                                         let _ = bcast.send(m);
                                         match overflow.pop() {
@@ -1256,6 +1234,7 @@ mod record {
                                         b.push(BatchItem(by_subid[id], ev.clone()));
                                     }
                                     archive.add_batch(true, ts, &b)?;
+                                    last_ts = Some(ts.datetime());
                                     last_image = archive.len();
                                 }
                             }
@@ -1264,6 +1243,9 @@ mod record {
                                 Some(freq) if archive.len() - last_flush < freq => (),
                                 Some(_) => {
                                     archive.flush()?;
+                                    if let Some(r) = &retention {
+                                        r.lock().flush()?;
+                                    }
                                     last_flush = archive.len();
                                 }
                             }
@@ -1273,10 +1255,550 @@ mod record {
                 }
             }
         }
+        if let Some(r) = &retention {
+            task::block_in_place(|| r.lock().finish())?;
+        }
         Ok(())
     }
 }
 
+mod retention {
+    use super::*;
+    use std::{collections::HashSet, fs, path::Path as FilePath};
+
+    fn default_prune_interval() -> u64 {
+        24
+    }
+
+    #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+    enum Mode {
+        /// numeric values are summarized as path/{open, high, low,
+        /// close}, other values are treated as Last
+        Ohlc,
+        /// the last value in each interval is kept
+        Last,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct DownsampleConfig {
+        /// the archive the summaries will be written to, each policy
+        /// must use a different archive
+        archive: String,
+        /// the summary interval in seconds
+        resolution: u64,
+        mode: Mode,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct PolicyConfig {
+        /// the paths this policy applies to
+        spec: Vec<String>,
+        /// keep full resolution data for this many days, forever if
+        /// not specified
+        #[serde(default)]
+        keep_days: Option<u64>,
+        #[serde(default)]
+        downsample: Option<DownsampleConfig>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct RetentionConfig {
+        /// the first policy that matches a path applies to it
+        policies: Vec<PolicyConfig>,
+        /// how often to prune the archive in hours (24)
+        #[serde(default = "default_prune_interval")]
+        prune_interval: u64,
+    }
+
+    #[derive(Debug)]
+    enum Acc {
+        Ohlc { open: f64, high: f64, low: f64, close: f64 },
+        Last(Event),
+    }
+
+    impl Acc {
+        fn new(mode: Mode, ev: Event) -> Acc {
+            match (mode, ev) {
+                (Mode::Ohlc, Event::Update(v)) if v.is_number() => {
+                    match v.cast_to::<f64>() {
+                        Ok(f) => Acc::Ohlc { open: f, high: f, low: f, close: f },
+                        Err(_) => Acc::Last(Event::Unsubscribed),
+                    }
+                }
+                (_, ev) => Acc::Last(ev),
+            }
+        }
+
+        fn add(&mut self, mode: Mode, ev: Event) {
+            match (self, &ev) {
+                (Acc::Ohlc { high, low, close, .. }, Event::Update(v))
+                    if v.is_number() =>
+                {
+                    if let Ok(f) = v.clone().cast_to::<f64>() {
+                        *high = high.max(f);
+                        *low = low.min(f);
+                        *close = f;
+                    }
+                }
+                (acc, _) => *acc = Acc::new(mode, ev),
+            }
+        }
+    }
+
+    struct Downsampler {
+        writer: ArchiveWriter,
+        timest: MonotonicTimestamper,
+        resolution: i64,
+        mode: Mode,
+        // the end of the current interval
+        bucket: Option<DateTime<Utc>>,
+        acc: HashMap<Path, Acc>,
+    }
+
+    impl Downsampler {
+        fn bucket_end(&self, ts: DateTime<Utc>) -> DateTime<Utc> {
+            let t = ts.timestamp();
+            let end = t - t.rem_euclid(self.resolution) + self.resolution;
+            Utc.timestamp_opt(end, 0).unwrap()
+        }
+
+        // write out the current interval if it ended before ts
+        fn roll(&mut self, ts: DateTime<Utc>) -> Result<()> {
+            match self.bucket {
+                Some(end) if ts >= end => {
+                    self.bucket = None;
+                    let mut items = Vec::new();
+                    for (path, acc) in self.acc.drain() {
+                        match acc {
+                            Acc::Last(ev) => items.push((path, ev)),
+                            Acc::Ohlc { open, high, low, close } => {
+                                for (name, v) in [
+                                    ("open", open),
+                                    ("high", high),
+                                    ("low", low),
+                                    ("close", close),
+                                ] {
+                                    items.push((path.append(name), Event::Update(v.into())))
+                                }
+                            }
+                        }
+                    }
+                    self.writer.add_paths(items.iter().map(|(p, _)| p))?;
+                    let mut batch = BATCH_POOL.take();
+                    for (path, ev) in items {
+                        batch.push(BatchItem(self.writer.id_for_path(&path).unwrap(), ev));
+                    }
+                    self.writer.add_batch(false, self.timest.timestamp_at(end), &batch)
+                }
+                None | Some(_) => Ok(()),
+            }
+        }
+
+        fn add(&mut self, ts: DateTime<Utc>, path: &Path, ev: Event) {
+            if self.bucket.is_none() {
+                self.bucket = Some(self.bucket_end(ts));
+            }
+            match self.acc.get_mut(path) {
+                Some(acc) => acc.add(self.mode, ev),
+                None => {
+                    self.acc.insert(path.clone(), Acc::new(self.mode, ev));
+                }
+            }
+        }
+    }
+
+    struct Policy {
+        spec: GlobSet,
+        keep: Option<chrono::Duration>,
+        downsample: Option<Downsampler>,
+    }
+
+    /// Retention and downsampling policies for the recorder. Data
+    /// matching a policy with `keep_days` is removed from the main
+    /// archive once it is older than that, and data matching a policy
+    /// with `downsample` is summarized into a separate archive as it
+    /// is recorded.
+    pub(super) struct Retention {
+        policies: Vec<Policy>,
+        by_id: HashMap<Id, Option<usize>, FxBuildHasher>,
+        pub(super) prune_interval: time::Duration,
+    }
+
+    impl Retention {
+        pub(super) fn load(file: &str) -> Result<Retention> {
+            let cfg: RetentionConfig = serde_json::from_str(&fs::read_to_string(file)?)?;
+            let mut archives = HashSet::new();
+            let mut policies = Vec::new();
+            for p in cfg.policies {
+                let spec = p
+                    .spec
+                    .into_iter()
+                    .map(|s| Glob::new(Chars::from(s)))
+                    .collect::<Result<Vec<_>>>()?;
+                let downsample = match p.downsample {
+                    None => None,
+                    Some(d) => {
+                        if d.resolution == 0 {
+                            bail!("downsample resolution must be at least 1 second")
+                        }
+                        if !archives.insert(d.archive.clone()) {
+                            bail!("downsample archive {} is used twice", d.archive)
+                        }
+                        Some(Downsampler {
+                            writer: ArchiveWriter::open(&d.archive)?,
+                            timest: MonotonicTimestamper::new(),
+                            resolution: d.resolution as i64,
+                            mode: d.mode,
+                            bucket: None,
+                            acc: HashMap::new(),
+                        })
+                    }
+                };
+                policies.push(Policy {
+                    spec: GlobSet::new(false, spec)?,
+                    keep: p.keep_days.map(|d| chrono::Duration::days(d as i64)),
+                    downsample,
+                })
+            }
+            Ok(Retention {
+                policies,
+                by_id: HashMap::with_hasher(FxBuildHasher::default()),
+                prune_interval: time::Duration::from_secs(cfg.prune_interval * 3600),
+            })
+        }
+
+        fn policy(&self, path: &Path) -> Option<usize> {
+            self.policies.iter().position(|p| p.spec.is_match(path))
+        }
+
+        /// Must be called when the archive is reopened, since ids may
+        /// change.
+        pub(super) fn reset(&mut self) {
+            self.by_id.clear();
+        }
+
+        /// Feed a batch written to `archive` at `ts` to the
+        /// downsamplers.
+        pub(super) fn record(
+            &mut self,
+            ts: DateTime<Utc>,
+            archive: &ArchiveWriter,
+            batch: &[BatchItem],
+        ) -> Result<()> {
+            for p in self.policies.iter_mut() {
+                if let Some(ds) = &mut p.downsample {
+                    ds.roll(ts)?;
+                }
+            }
+            for BatchItem(id, ev) in batch {
+                let path = match archive.path_for_id(id) {
+                    None => continue,
+                    Some(path) => path,
+                };
+                let i = match self.by_id.get(id) {
+                    Some(i) => *i,
+                    None => {
+                        let i = self.policy(path);
+                        self.by_id.insert(*id, i);
+                        i
+                    }
+                };
+                if let Some(ds) = i.and_then(|i| self.policies[i].downsample.as_mut()) {
+                    ds.add(ts, path, ev.clone());
+                }
+            }
+            Ok(())
+        }
+
+        pub(super) fn flush(&mut self) -> Result<()> {
+            for p in self.policies.iter_mut() {
+                if let Some(ds) = &mut p.downsample {
+                    ds.writer.flush()?;
+                }
+            }
+            Ok(())
+        }
+
+        /// Write out the summaries still being accumulated and flush
+        /// the downsampled archives. Called when the recorder stops.
+        pub(super) fn finish(&mut self) -> Result<()> {
+            for p in self.policies.iter_mut() {
+                if let Some(ds) = &mut p.downsample {
+                    if let Some(end) = ds.bucket {
+                        ds.roll(end)?;
+                    }
+                }
+            }
+            self.flush()
+        }
+
+        /// Return the part of the policy needed to prune the archive,
+        /// or `None` if no policy ever expires data.
+        pub(super) fn pruner(&self) -> Option<Pruner> {
+            if self.policies.iter().all(|p| p.keep.is_none()) {
+                None
+            } else {
+                Some(Pruner(
+                    self.policies.iter().map(|p| (p.spec.clone(), p.keep)).collect(),
+                ))
+            }
+        }
+    }
+
+    /// Prunes expired data from the archive while it is being
+    /// recorded, see `Pruner::compact`.
+    pub(super) struct Pruner(Vec<(GlobSet, Option<chrono::Duration>)>);
+
+    impl Pruner {
+        /// Copy the archive read by `reader` up to and including
+        /// `upto` into `tmp` without the data that has expired. The
+        /// last value of each expired path is carried into the first
+        /// batch after it expired, so the state at any retained time
+        /// is still correct. Image batches are copied without the
+        /// expired values. Path ids are preserved.
+        ///
+        /// This can take a long time, and doesn't touch the writer,
+        /// so it should run in the background while recording
+        /// continues. Returns `None` if nothing has expired, otherwise
+        /// the returned `Compaction` must be finished to replace the
+        /// archive.
+        pub(super) fn compact(
+            &self,
+            reader: ArchiveReader,
+            tmp: String,
+            upto: DateTime<Utc>,
+        ) -> Result<Option<Compaction>> {
+            if FilePath::is_file(FilePath::new(&tmp)) {
+                fs::remove_file(&tmp)?;
+            }
+            let writer = ArchiveWriter::open(&tmp)?;
+            let mut c = Compaction {
+                reader,
+                writer,
+                timest: MonotonicTimestamper::new(),
+                tmp,
+                upto,
+                cutoffs: HashMap::new(),
+                pending: BTreeMap::new(),
+                state: HashMap::new(),
+                dropped: 0,
+            };
+            c.add_paths()?;
+            for (id, path) in c.reader.get_index().iter() {
+                let keep = self.0.iter().find(|(spec, _)| spec.is_match(path));
+                if let Some(keep) = keep.and_then(|(_, keep)| *keep) {
+                    c.cutoffs.insert(*id, upto - keep);
+                    c.pending.entry(upto - keep).or_default().push(*id);
+                }
+            }
+            c.copy(Bound::Unbounded, Bound::Included(upto))?;
+            let mut out = BATCH_POOL.take();
+            c.expire(upto, &mut out);
+            if !out.is_empty() {
+                c.writer.add_batch(false, c.timest.timestamp_at(upto), &out)?;
+            }
+            if c.dropped > 0 {
+                Ok(Some(c))
+            } else {
+                let Compaction { writer, tmp, .. } = c;
+                drop(writer);
+                fs::remove_file(&tmp)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// A pruned copy of the archive, see `Pruner::compact`
+    pub(super) struct Compaction {
+        reader: ArchiveReader,
+        writer: ArchiveWriter,
+        timest: MonotonicTimestamper,
+        tmp: String,
+        upto: DateTime<Utc>,
+        cutoffs: HashMap<Id, DateTime<Utc>>,
+        pending: BTreeMap<DateTime<Utc>, Vec<Id>>,
+        state: HashMap<Id, Event>,
+        dropped: usize,
+    }
+
+    impl Compaction {
+        // path ids must not change, since the publisher and the
+        // recorder keep using them after the switch
+        fn add_paths(&mut self) -> Result<()> {
+            self.reader.check_remap_rescan()?;
+            let index = self.reader.get_index();
+            self.writer.add_paths(index.iter().map(|(_, p)| p))?;
+            for (id, path) in index.iter() {
+                if self.writer.id_for_path(path) != Some(*id) {
+                    bail!("the id of {} changed during compaction", path)
+                }
+            }
+            Ok(())
+        }
+
+        // carry the last value of the paths that expired at or before ts
+        fn expire(&mut self, ts: DateTime<Utc>, out: &mut Vec<BatchItem>) {
+            while let Some(c) = self.pending.keys().next().copied() {
+                if c > ts {
+                    break;
+                }
+                for id in self.pending.remove(&c).unwrap() {
+                    if let Some(ev) = self.state.remove(&id) {
+                        out.push(BatchItem(id, ev))
+                    }
+                }
+            }
+        }
+
+        fn copy_batch(
+            &mut self,
+            image: bool,
+            ts: DateTime<Utc>,
+            mut batch: Pooled<Vec<BatchItem>>,
+        ) -> Result<()> {
+            let mut out = BATCH_POOL.take();
+            if !image {
+                self.expire(ts, &mut out);
+            }
+            for BatchItem(id, ev) in batch.drain(..) {
+                match self.cutoffs.get(&id) {
+                    Some(c) if ts < *c => {
+                        self.dropped += 1;
+                        self.state.insert(id, ev);
+                    }
+                    None | Some(_) => out.push(BatchItem(id, ev)),
+                }
+            }
+            // an empty batch isn't written, so it must not take a timestamp
+            if !out.is_empty() {
+                self.writer.add_batch(image, self.timest.timestamp_at(ts), &out)?;
+            }
+            Ok(())
+        }
+
+        // copy the image and delta batches between start and end in
+        // timestamp order
+        fn copy(
+            &mut self,
+            start: Bound<DateTime<Utc>>,
+            end: Bound<DateTime<Utc>>,
+        ) -> Result<()> {
+            let mut dcursor = Cursor::new();
+            dcursor.set_start(start);
+            dcursor.set_end(end);
+            let mut icursor = dcursor;
+            let mut deltas = self.reader.read_deltas(&mut dcursor, 100)?;
+            let mut images = self.reader.read_images(&mut icursor, 100)?;
+            loop {
+                let image = match (deltas.front(), images.front()) {
+                    (None, None) => break Ok(()),
+                    (Some(_), None) => false,
+                    (None, Some(_)) => true,
+                    (Some((dts, _)), Some((its, _))) => its < dts,
+                };
+                let (ts, batch) = if image {
+                    images.pop_front().unwrap()
+                } else {
+                    deltas.pop_front().unwrap()
+                };
+                self.copy_batch(image, ts, batch)?;
+                if deltas.is_empty() {
+                    deltas = self.reader.read_deltas(&mut dcursor, 100)?;
+                }
+                if images.is_empty() {
+                    images = self.reader.read_images(&mut icursor, 100)?;
+                }
+            }
+        }
+
+        /// Copy everything recorded since the compaction started and
+        /// replace `archive` with the pruned copy. The archive writer
+        /// must be flushed first, and the returned writer and
+        /// timestamper must be used for all further writes.
+        pub(super) fn finish(
+            mut self,
+            archive: &str,
+        ) -> Result<(ArchiveWriter, MonotonicTimestamper)> {
+            self.add_paths()?;
+            self.copy(Bound::Excluded(self.upto), Bound::Unbounded)?;
+            self.writer.flush()?;
+            fs::rename(&self.tmp, archive)?;
+            info!("pruned {} expired values from {}", self.dropped, archive);
+            Ok((self.writer, self.timest))
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        fn item(w: &ArchiveWriter, path: &'static str, v: u64) -> BatchItem {
+            BatchItem(w.id_for_path(&Path::from(path)).unwrap(), Event::Update(v.into()))
+        }
+
+        #[test]
+        fn compact() {
+            let file = "test-recorder-compact";
+            let tmp = format!("{}.prune", file);
+            for f in [file, tmp.as_str()] {
+                if FilePath::is_file(FilePath::new(f)) {
+                    fs::remove_file(f).unwrap();
+                }
+            }
+            let now = Utc::now();
+            let old = now - chrono::Duration::days(2);
+            let mut timest = MonotonicTimestamper::new();
+            let mut w = ArchiveWriter::open(file).unwrap();
+            w.add_paths(&[Path::from("/a"), Path::from("/b")]).unwrap();
+            let mut batch = BATCH_POOL.take();
+            batch.extend([item(&w, "/a", 1), item(&w, "/b", 1)]);
+            w.add_batch(false, timest.timestamp_at(old), &batch).unwrap();
+            w.add_batch(true, timest.timestamp_at(old), &batch).unwrap();
+            batch.clear();
+            batch.push(item(&w, "/b", 2));
+            w.add_batch(false, timest.timestamp_at(now), &batch).unwrap();
+            w.flush().unwrap();
+            let glob = Glob::new(Chars::from("/a")).unwrap();
+            let pruner = Pruner(vec![(
+                GlobSet::new(false, [glob]).unwrap(),
+                Some(chrono::Duration::days(1)),
+            )]);
+            let c = pruner.compact(w.reader().unwrap(), tmp, now).unwrap().unwrap();
+            // recorded while the compaction was running
+            batch.clear();
+            batch.push(item(&w, "/a", 3));
+            let later = now + chrono::Duration::seconds(1);
+            w.add_batch(false, timest.timestamp_at(later), &batch).unwrap();
+            w.flush().unwrap();
+            let (w, _) = c.finish(file).unwrap();
+            let r = w.reader().unwrap();
+            r.check_remap_rescan().unwrap();
+            let (a, b) = (
+                r.id_for_path(&Path::from("/a")).unwrap(),
+                r.id_for_path(&Path::from("/b")).unwrap(),
+            );
+            let mut cursor = Cursor::new();
+            let deltas = r.read_deltas(&mut cursor, 100).unwrap();
+            let items = |b: &Pooled<Vec<BatchItem>>| {
+                b.iter().map(|BatchItem(id, ev)| (*id, ev.clone())).collect::<Vec<_>>()
+            };
+            let u = |id, v: u64| (id, Event::Update(v.into()));
+            let deltas = deltas.iter().map(|(_, b)| items(b)).collect::<Vec<_>>();
+            assert_eq!(
+                deltas,
+                vec![vec![u(b, 1)], vec![u(a, 1), u(b, 2)], vec![u(a, 3)]]
+            );
+            let mut cursor = Cursor::new();
+            let images = r.read_images(&mut cursor, 100).unwrap();
+            assert_eq!(images.len(), 1);
+            assert_eq!(items(&images[0].1), vec![u(b, 1)]);
+            drop(r);
+            drop(w);
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
+
 #[cfg(unix)]
 async fn should_exit() -> Result<()> {
     use tokio::signal::unix::{signal, SignalKind};
@@ -1300,7 +1822,6 @@ async fn run_async(
     publish_args: Option<(BindCfg, Path)>,
     auth: Auth,
     image_frequency: Option<usize>,
-    poll_interval: Option<time::Duration>,
     flush_frequency: Option<usize>,
     flush_interval: Option<time::Duration>,
     shards: usize,
@@ -1308,7 +1829,16 @@ async fn run_async(
     max_sessions_per_client: usize,
     archive: String,
     spec: Vec<Glob>,
+    retention: Option<retention::Retention>,
 ) {
+    let retention = match retention {
+        Some(r) if !spec.is_empty() => Some(Arc::new(Mutex::new(r))),
+        Some(_) => {
+            warn!("ignoring retention policy, not recording");
+            None
+        }
+        None => None,
+    };
     let mut wait = Vec::new();
     let (bcast_tx, bcast_rx) = broadcast::channel(100);
     drop(bcast_rx);
@@ -1317,18 +1847,20 @@ async fn run_async(
     } else {
         Some(ArchiveWriter::open(archive.as_str()).unwrap())
     };
+    let reader = writer
+        .as_ref()
+        .map(|w| w.reader().unwrap())
+        .unwrap_or_else(|| ArchiveReader::open(archive.as_str()).unwrap());
+    // the recorder switches to a new archive file after pruning
+    let (reopen_tx, reopen_rx) = watch::channel(reader);
     if let Some((bind_cfg, publish_base)) = publish_args {
-        let reader = writer
-            .as_ref()
-            .map(|w| w.reader().unwrap())
-            .unwrap_or_else(|| ArchiveReader::open(archive.as_str()).unwrap());
         let bcast_tx = bcast_tx.clone();
         let config = config.clone();
         let auth = auth.clone();
         wait.push(task::spawn(async move {
             let res = publish::run(
                 bcast_tx,
-                reader,
+                reopen_rx,
                 config,
                 auth,
                 bind_cfg,
@@ -1350,13 +1882,15 @@ async fn run_async(
             let res = record::run(
                 bcast_tx,
                 writer.unwrap(),
+                archive,
+                reopen_tx,
                 config,
                 auth,
-                poll_interval,
                 image_frequency,
                 flush_frequency,
                 flush_interval,
                 spec,
+                retention,
             )
             .await;
             match res {
@@ -1371,7 +1905,7 @@ async fn run_async(
             _ = should_exit().fuse() => {
                 let _ = bcast_tx.send(BCastMsg::Stop);
             },
-            _ = dead => break
+            _ = dead => break,
         }
     }
 }
@@ -1383,7 +1917,6 @@ pub(crate) fn run(
     publish_base: Option<Path>,
     auth: Auth,
     image_frequency: usize,
-    poll_interval: u64,
     flush_frequency: usize,
     flush_interval: u64,
     shards: usize,
@@ -1391,13 +1924,9 @@ pub(crate) fn run(
     max_sessions_per_client: usize,
     archive: String,
     spec: Vec<String>,
+    retention: Option<String>,
 ) {
     let image_frequency = if image_frequency == 0 { None } else { Some(image_frequency) };
-    let poll_interval = if poll_interval == 0 {
-        None
-    } else {
-        Some(time::Duration::from_secs(poll_interval))
-    };
     let flush_frequency = if flush_frequency == 0 { None } else { Some(flush_frequency) };
     let flush_interval = if flush_interval == 0 {
         None
@@ -1429,12 +1958,13 @@ pub(crate) fn run(
         .collect::<Result<Vec<Glob>>>()
         .unwrap();
     let rt = Runtime::new().expect("failed to init tokio runtime");
+    let retention =
+        retention.map(|r| retention::Retention::load(&r).expect("invalid retention policy"));
     rt.block_on(run_async(
         config,
         publish_args,
         auth,
         image_frequency,
-        poll_interval,
         flush_frequency,
         flush_interval,
         shards,
@@ -1442,5 +1972,9 @@ pub(crate) fn run(
         max_sessions_per_client,
         archive,
         spec,
-    ))
+        retention,
+    ));
+    // don't wait for a prune that is still running, it will start
+    // again from the beginning next time
+    rt.shutdown_background()
 }
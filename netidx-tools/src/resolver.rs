@@ -1,15 +1,26 @@
-use super::ResolverCmd;
+use super::{AdminCmd, ResolverCmd};
+use futures::prelude::*;
 use netidx::{
     chars::Chars,
     config::Config,
     path::Path,
     protocol::glob::{Glob, GlobSet},
-    resolver::{Auth, ChangeTracker, ResolverRead, ResolverWrite},
+    resolver::{Auth, ResolverRead, ResolverWrite},
 };
-use std::{collections::HashSet, iter, time::Duration};
-use tokio::{runtime::Runtime, time};
+use std::{collections::HashSet, iter, net::SocketAddr, process};
+use tokio::runtime::Runtime;
 use arcstr::ArcStr;
 
+// admin requests are answered by the server they are sent to
+fn admin_resolver(config: &Config, auth: &Auth, server: SocketAddr) -> ResolverRead {
+    if !config.addrs.contains(&server) {
+        panic!("{} is not a server in the cluster", server)
+    }
+    let mut config = config.clone();
+    config.addrs = vec![server];
+    ResolverRead::new(config, auth.clone())
+}
+
 pub(crate) fn run(config: Config, cmd: ResolverCmd, auth: Auth) {
     let rt = Runtime::new().expect("failed to init runtime");
     rt.block_on(async {
@@ -37,24 +48,24 @@ pub(crate) fn run(config: Config, cmd: ResolverCmd, auth: Auth) {
                     }
                 };
                 let glob = Glob::new(Chars::from(String::from(&*pat))).unwrap();
-                let mut ct = ChangeTracker::new(Path::from(ArcStr::from(glob.base())));
                 let globs = GlobSet::new(no_structure, iter::once(glob)).unwrap();
                 let mut paths = HashSet::new();
-                loop {
-                    if resolver.check_changed(&mut ct).await.unwrap() {
-                        for b in resolver.list_matching(&globs).await.unwrap().iter() {
-                            for p in b.iter() {
-                                if !paths.contains(p) {
-                                    paths.insert(p.clone());
-                                    println!("{}", p);
-                                }
-                            }
+                for b in resolver.list_matching(&globs).await.unwrap().iter() {
+                    for p in b.iter() {
+                        if paths.insert(p.clone()) {
+                            println!("{}", p);
                         }
                     }
-                    if watch {
-                        time::sleep(Duration::from_secs(5)).await
-                    } else {
-                        break;
+                }
+                if watch {
+                    let mut changes = resolver.watch(globs);
+                    while let Some(mut c) = changes.next().await {
+                        for p in c.added.drain(..) {
+                            if !paths.contains(&p) {
+                                println!("{}", p);
+                                paths.insert(p);
+                            }
+                        }
                     }
                 }
             }
@@ -79,6 +90,58 @@ pub(crate) fn run(config: Config, cmd: ResolverCmd, auth: Auth) {
                 let resolver = ResolverWrite::new(config, auth, socketaddr);
                 resolver.unpublish(vec![path]).await.unwrap();
             }
+            ResolverCmd::Admin { server, cmd: AdminCmd::Clear { socketaddr } } => {
+                // the publisher is registered with every server in the
+                // cluster, so it must be cleared from all of them
+                let servers = match server {
+                    Some(server) => vec![server],
+                    None => config.addrs.clone(),
+                };
+                let mut failed = false;
+                for server in servers {
+                    let resolver = admin_resolver(&config, &auth, server);
+                    match resolver.clear_publisher(socketaddr).await {
+                        Err(e) => {
+                            failed = true;
+                            eprintln!("{}: {}", server, e)
+                        }
+                        Ok(paths) => {
+                            println!("{}: cleared {} paths", server, paths.len());
+                            for path in paths.iter() {
+                                println!("{}", path)
+                            }
+                        }
+                    }
+                }
+                if failed {
+                    process::exit(1)
+                }
+            }
+            ResolverCmd::Admin { server, cmd } => {
+                let resolver =
+                    admin_resolver(&config, &auth, server.unwrap_or(config.addrs[0]));
+                match cmd {
+                    AdminCmd::Sessions => {
+                        for s in resolver.sessions().await.unwrap().iter() {
+                            let addr = match s.addr {
+                                None => String::from("disconnected"),
+                                Some(addr) => addr.to_string(),
+                            };
+                            let user = s.user.as_deref().unwrap_or("anonymous");
+                            match s.write_addr {
+                                None => println!("{} {} subscriber", addr, user),
+                                Some(w) => println!("{} {} publisher {}", addr, user, w),
+                            }
+                        }
+                    }
+                    AdminCmd::Published { socketaddr } => {
+                        for path in resolver.published(socketaddr).await.unwrap().iter() {
+                            println!("{}", path)
+                        }
+                    }
+                    AdminCmd::Clear { .. } => unreachable!(),
+                }
+            }
         }
     });
 }
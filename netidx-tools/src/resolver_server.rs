@@ -1,11 +1,32 @@
+use anyhow::Result;
 use daemonize::Daemonize;
-use futures::future;
+use log::{error, info};
 use netidx::{config, resolver_server::Server};
-use tokio::runtime::Runtime;
+use tokio::{
+    runtime::Runtime,
+    signal::unix::{signal, SignalKind},
+};
+
+fn load(
+    config_file: &Option<String>,
+    permissions_file: &Option<String>,
+) -> Result<(config::Config, config::PMap)> {
+    let config = match config_file {
+        None => config::Config::load_default()?,
+        Some(path) => config::Config::load(path)?,
+    };
+    let permissions = match permissions_file {
+        None => config::PMap::default(),
+        Some(path) => config::PMap::load(path)?,
+    };
+    Ok((config, permissions))
+}
 
 pub(crate) fn run(
     config: config::Config,
+    config_file: Option<String>,
     permissions: config::PMap,
+    permissions_file: Option<String>,
     daemonize: bool,
     delay_reads: bool,
     id: usize,
@@ -20,7 +41,17 @@ pub(crate) fn run(
         let server = Server::new(config, permissions, delay_reads, id)
             .await
             .expect("starting server");
-        future::pending::<()>().await;
+        let mut hup = signal(SignalKind::hangup()).expect("failed to handle SIGHUP");
+        while let Some(()) = hup.recv().await {
+            info!("SIGHUP received, reloading config and permissions");
+            let res = match load(&config_file, &permissions_file) {
+                Ok((config, permissions)) => server.reload(config, permissions).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                error!("reload failed, keeping the old config, {}", e)
+            }
+        }
         drop(server)
     });
 }
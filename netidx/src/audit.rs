@@ -0,0 +1,202 @@
+use crate::{
+    auth::UserInfo,
+    chars::Chars,
+    config,
+    path::Path,
+    publisher::{BindCfg, Publisher, Val, Value},
+    resolver::Auth,
+};
+use anyhow::Result;
+use futures::{
+    channel::mpsc::{channel, Receiver, Sender},
+    prelude::*,
+};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use log::{error, info, warn};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+use tokio::task;
+
+// records waiting to be written, beyond this they are dropped rather
+// than slowing down the resolver server
+const QUEUE: usize = 100_000;
+
+#[derive(Debug, Serialize)]
+struct Record {
+    timestamp: u64,
+    user: Option<String>,
+    addr: SocketAddr,
+    op: &'static str,
+    path: String,
+    allowed: bool,
+}
+
+// written in place of the records that were dropped because the
+// queue was full
+#[derive(Debug, Serialize)]
+struct Dropped {
+    timestamp: u64,
+    dropped: u64,
+}
+
+/// A handle to the audit log, cheap to clone.
+#[derive(Clone)]
+pub(crate) struct Audit {
+    filter: Option<Arc<GlobSet>>,
+    denied_only: bool,
+    records: Sender<Record>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Audit {
+    /// Open the audit log file and start the task that writes and
+    /// publishes records. `id` is the address of this server.
+    pub(crate) fn new(
+        cfg: &config::Audit,
+        resolver: &config::Config,
+        id: SocketAddr,
+    ) -> Result<Audit> {
+        let filter = if cfg.filter.is_empty() {
+            None
+        } else {
+            let mut builder = GlobSetBuilder::new();
+            for g in cfg.filter.iter() {
+                builder.add(GlobBuilder::new(g).literal_separator(true).build()?);
+            }
+            Some(Arc::new(builder.build()?))
+        };
+        let file = cfg
+            .file
+            .as_ref()
+            .map(|f| OpenOptions::new().create(true).append(true).open(f))
+            .transpose()?
+            .map(BufWriter::new);
+        let publish = cfg.publish.as_ref().map(|p| {
+            let path = Path::from(p.clone()).append(&id.to_string());
+            let auth = match &resolver.auth {
+                config::Auth::Anonymous => Auth::Anonymous,
+                config::Auth::Krb5(spns) => {
+                    Auth::Krb5 { upn: None, spn: Some(spns[&id].clone()) }
+                }
+                // the server has no token of its own, so it publishes
+                // as the anonymous user
+                config::Auth::Token { .. } => Auth::Anonymous,
+            };
+            (resolver.clone(), auth, BindCfg::Exact(SocketAddr::new(id.ip(), 0)), path)
+        });
+        let (records, rx) = channel(QUEUE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let dropped_run = dropped.clone();
+        task::spawn(async move {
+            let publish = match publish {
+                None => None,
+                Some((resolver, auth, bind, path)) => {
+                    match Publisher::new(resolver, auth, bind).await {
+                        Err(e) => {
+                            error!("audit: failed to create publisher {}", e);
+                            None
+                        }
+                        Ok(publisher) => {
+                            match publisher.publish(path.clone(), Value::Null) {
+                                Err(e) => {
+                                    error!("audit: failed to publish {} {}", path, e);
+                                    None
+                                }
+                                Ok(val) => Some((publisher, val)),
+                            }
+                        }
+                    }
+                }
+            };
+            run(rx, dropped_run, file, publish).await;
+            info!("audit log closed")
+        });
+        Ok(Audit { filter, denied_only: cfg.denied_only, records, dropped })
+    }
+
+    /// Record a decision about `path`, unless it is filtered out.
+    pub(crate) fn log(
+        &self,
+        timestamp: u64,
+        user: &UserInfo,
+        addr: SocketAddr,
+        op: &'static str,
+        path: &str,
+        allowed: bool,
+    ) {
+        if self.denied_only && allowed {
+            return;
+        }
+        if let Some(filter) = &self.filter {
+            if !Path::dirnames(path).any(|p| filter.is_match(p)) {
+                return;
+            }
+        }
+        let rec = Record {
+            timestamp,
+            user: user.principal.name().map(|n| String::from(&**n)),
+            addr,
+            op,
+            path: String::from(path),
+            allowed,
+        };
+        if let Err(e) = self.records.clone().try_send(rec) {
+            if e.is_full() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+async fn run(
+    rx: Receiver<Record>,
+    dropped: Arc<AtomicU64>,
+    mut file: Option<BufWriter<File>>,
+    publish: Option<(Publisher, Val)>,
+) {
+    let mut rx = rx.ready_chunks(10000);
+    while let Some(mut batch) = rx.next().await {
+        let mut updates = publish.as_ref().map(|(p, _)| p.start_batch());
+        // serializing a record can't fail
+        let mut lines = batch
+            .drain(..)
+            .map(|rec| serde_json::to_string(&rec).unwrap())
+            .collect::<Vec<_>>();
+        let n = dropped.swap(0, Ordering::Relaxed);
+        if n > 0 {
+            warn!("audit: dropped {} records, the log isn't keeping up", n);
+            let timestamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            lines
+                .push(serde_json::to_string(&Dropped { timestamp, dropped: n }).unwrap());
+        }
+        for line in lines {
+            if let Some(f) = &mut file {
+                if let Err(e) = writeln!(f, "{}", line) {
+                    error!("audit: failed to write log {}", e)
+                }
+            }
+            if let (Some((_, val)), Some(updates)) = (&publish, &mut updates) {
+                val.update(updates, Value::String(Chars::from(line)));
+            }
+        }
+        if let Some(f) = &mut file {
+            if let Err(e) = task::block_in_place(|| f.flush()) {
+                error!("audit: failed to flush log {}", e)
+            }
+        }
+        if let Some(updates) = updates {
+            updates.commit(None).await;
+        }
+    }
+}
@@ -1,10 +1,25 @@
-use crate::{config, os::Mapper, path::Path, protocol::{glob::Scope, resolver::Referral}};
+use crate::{
+    chars::Chars,
+    config,
+    os::{self, GroupMapper},
+    pack::{Pack, PackError},
+    path::Path,
+    protocol::{
+        glob::{Glob, Scope},
+        resolver::Referral,
+    },
+    utils,
+};
 use anyhow::{anyhow, Error, Result};
+use bytes::{Buf, BufMut};
+use globset::{GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use parking_lot::Mutex;
 use std::{
     collections::{BTreeMap, Bound, HashMap},
     convert::TryFrom,
-    iter,
+    fs, iter, mem, str,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 bitflags! {
@@ -15,6 +30,7 @@ bitflags! {
         const LIST             = 0x08;
         const PUBLISH          = 0x10;
         const PUBLISH_DEFAULT  = 0x20;
+        const ADMIN            = 0x40;
     }
 }
 
@@ -47,9 +63,12 @@ impl TryFrom<&str> for Permissions {
                 'd' => {
                     p |= Permissions::PUBLISH_DEFAULT;
                 }
+                'a' => {
+                    p |= Permissions::ADMIN;
+                }
                 c => {
                     return Err(anyhow!(
-                        "unrecognized permission bit {}, valid bits are !swlpd",
+                        "unrecognized permission bit {}, valid bits are !swlpda",
                         c
                     ))
                 }
@@ -59,6 +78,75 @@ impl TryFrom<&str> for Permissions {
     }
 }
 
+/// The identity of a user, as established by authentication
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Principal {
+    Anonymous,
+    User {
+        /// the authenticated name, e.g. user@REALM
+        name: Chars,
+        /// the groups the user is a member of
+        groups: Vec<Chars>,
+    },
+}
+
+impl Principal {
+    /// The user name and the names of all it's groups
+    pub fn entities(&self) -> impl Iterator<Item = &Chars> {
+        let (name, groups) = match self {
+            Principal::Anonymous => (None, &[][..]),
+            Principal::User { name, groups } => (Some(name), &groups[..]),
+        };
+        name.into_iter().chain(groups.iter())
+    }
+
+    /// The user name, None if anonymous
+    pub fn name(&self) -> Option<&Chars> {
+        match self {
+            Principal::Anonymous => None,
+            Principal::User { name, .. } => Some(name),
+        }
+    }
+}
+
+impl Pack for Principal {
+    fn encoded_len(&self) -> usize {
+        1 + match self {
+            Principal::Anonymous => 0,
+            Principal::User { name, groups } => name.encoded_len() + groups.encoded_len(),
+        }
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) -> Result<(), PackError> {
+        match self {
+            Principal::Anonymous => {
+                buf.put_u8(0);
+                Ok(())
+            }
+            Principal::User { name, groups } => {
+                buf.put_u8(1);
+                name.encode(buf)?;
+                groups.encode(buf)
+            }
+        }
+    }
+
+    fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        if buf.remaining() == 0 {
+            return Err(PackError::InvalidFormat);
+        }
+        match <u8 as Pack>::decode(buf)? {
+            0 => Ok(Principal::Anonymous),
+            1 => {
+                let name = Chars::decode(buf)?;
+                let groups = Vec::<Chars>::decode(buf)?;
+                Ok(Principal::User { name, groups })
+            }
+            _ => Err(PackError::UnknownTag),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Entity(u32);
 
@@ -66,6 +154,7 @@ pub struct Entity(u32);
 pub(crate) struct UserInfo {
     pub(crate) id: Entity,
     pub(crate) groups: Vec<Entity>,
+    pub(crate) principal: Principal,
 }
 
 impl UserInfo {
@@ -75,22 +164,98 @@ impl UserInfo {
 }
 
 lazy_static! {
-    pub(crate) static ref ANONYMOUS: Arc<UserInfo> =
-        Arc::new(UserInfo { id: Entity(0), groups: Vec::new() });
+    pub(crate) static ref ANONYMOUS: Arc<UserInfo> = Arc::new(UserInfo {
+        id: Entity(0),
+        groups: Vec::new(),
+        principal: Principal::Anonymous
+    });
+}
+
+/// Groups from a static file of user name to list of groups. The
+/// file is yaml if its name ends in .yaml or .yml, otherwise json.
+pub(crate) struct FileMapper(HashMap<String, Vec<String>>);
+
+impl FileMapper {
+    pub(crate) fn load(file: &str) -> Result<FileMapper> {
+        let s = fs::read_to_string(file)?;
+        if file.ends_with(".yaml") || file.ends_with(".yml") {
+            Ok(FileMapper(serde_yaml::from_str(&s)?))
+        } else {
+            Ok(FileMapper(serde_json::from_str(&s)?))
+        }
+    }
+}
+
+impl GroupMapper for FileMapper {
+    fn groups(&mut self, user: &str) -> Result<Vec<String>> {
+        Ok(self.0.get(user).cloned().unwrap_or_else(Vec::new))
+    }
+}
+
+/// Remember the groups returned by another mapper for a while
+pub(crate) struct CachedMapper {
+    ttl: Duration,
+    inner: Box<dyn GroupMapper>,
+    cache: HashMap<String, (Instant, Vec<String>)>,
+}
+
+impl CachedMapper {
+    pub(crate) fn new(ttl: Duration, inner: Box<dyn GroupMapper>) -> CachedMapper {
+        CachedMapper { ttl, inner, cache: HashMap::new() }
+    }
+}
+
+impl GroupMapper for CachedMapper {
+    fn groups(&mut self, user: &str) -> Result<Vec<String>> {
+        let now = Instant::now();
+        let ttl = self.ttl;
+        match self.cache.get(user) {
+            Some((ts, groups)) if now - *ts < ttl => Ok(groups.clone()),
+            None | Some(_) => {
+                let groups = self.inner.groups(user)?;
+                self.cache.retain(|_, (ts, _)| now - *ts < ttl);
+                self.cache.insert(String::from(user), (now, groups.clone()));
+                Ok(groups)
+            }
+        }
+    }
+}
+
+pub(crate) fn mapper(cfg: &config::GroupMapper) -> Result<Box<dyn GroupMapper>> {
+    Ok(match cfg {
+        config::GroupMapper::Id => Box::new(os::IdMapper::new()?),
+        #[cfg(all(unix, not(target_os = "macos")))]
+        config::GroupMapper::GetGroupList => Box::new(os::GetGroupListMapper),
+        #[cfg(not(all(unix, not(target_os = "macos"))))]
+        config::GroupMapper::GetGroupList => {
+            bail!("getgrouplist is not supported on this platform")
+        }
+        config::GroupMapper::File(file) => Box::new(FileMapper::load(file)?),
+        config::GroupMapper::Cached { ttl, mapper: inner } => {
+            Box::new(CachedMapper::new(Duration::from_secs(*ttl), mapper(inner)?))
+        }
+    })
 }
 
 pub(crate) struct UserDb {
     next: u32,
-    mapper: Mapper,
+    mapper: Arc<Mutex<Box<dyn GroupMapper>>>,
     entities: HashMap<String, Entity>,
     users: HashMap<String, Arc<UserInfo>>,
 }
 
 impl UserDb {
-    pub(crate) fn new(mapper: Mapper) -> UserDb {
+    pub(crate) fn new(mapper: Box<dyn GroupMapper>) -> UserDb {
+        let mapper = Arc::new(Mutex::new(mapper));
         UserDb { next: 1, mapper, entities: HashMap::new(), users: HashMap::new() }
     }
 
+    /// The group mapper, so groups can be looked up without holding
+    /// the user db.
+    pub(crate) fn mapper(&self) -> Arc<Mutex<Box<dyn GroupMapper>>> {
+        self.mapper.clone()
+    }
+
     fn entity(&mut self, name: &str) -> Entity {
         match self.entities.get(name) {
             Some(e) => *e,
@@ -103,31 +268,253 @@ impl UserDb {
         }
     }
 
+    // The mapper is consulted every time, so that group membership
+    // changes take effect for new connections. Use a cached mapper
+    // if that is expensive.
     pub(crate) fn ifo(&mut self, user: Option<&str>) -> Result<Arc<UserInfo>> {
         match user {
             None => Ok(ANONYMOUS.clone()),
-            Some(user) => match self.users.get(user) {
-                Some(user) => Ok(user.clone()),
-                None => {
-                    let ifo = Arc::new(UserInfo {
-                        id: self.entity(user),
-                        groups: self
-                            .mapper
-                            .groups(user)?
-                            .into_iter()
-                            .map(|b| self.entity(&b))
-                            .collect::<Vec<_>>(),
-                    });
-                    self.users.insert(String::from(user), ifo.clone());
-                    Ok(ifo)
+            Some(user) => {
+                let names = self.mapper.lock().groups(user)?;
+                Ok(self.with_groups(user, names))
+            }
+        }
+    }
+
+    /// The user info for a user whose groups are already known,
+    /// e.g. because they were in its token.
+    pub(crate) fn with_groups(
+        &mut self,
+        user: &str,
+        names: Vec<String>,
+    ) -> Arc<UserInfo> {
+        let groups = names.iter().map(|b| self.entity(b)).collect::<Vec<_>>();
+        match self.users.get(user) {
+            Some(ifo) if ifo.groups == groups => ifo.clone(),
+            None | Some(_) => {
+                let ifo = Arc::new(UserInfo {
+                    id: self.entity(user),
+                    groups,
+                    principal: Principal::User {
+                        name: Chars::from(String::from(user)),
+                        groups: names.into_iter().map(Chars::from).collect(),
+                    },
+                });
+                self.users.insert(String::from(user), ifo.clone());
+                ifo
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TokenUser {
+    user: String,
+    #[serde(default)]
+    groups: Vec<String>,
+    /// seconds since the unix epoch, never if missing
+    #[serde(default)]
+    expires: Option<u64>,
+}
+
+// compare in time that depends only on the length of the inputs
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.iter().zip(b.iter()).fold(a.len() ^ b.len(), |d, (a, b)| d | (a ^ b) as usize) == 0
+}
+
+// sign the text of a token with the key, keyed sha3 is a mac
+// because sha3 isn't vulnerable to length extension.
+fn token_sig(key: &[u8], payload: &str) -> String {
+    let sig = utils::make_sha3_token(Some(0), &[key, payload.as_bytes()]);
+    sig[mem::size_of::<u64>()..].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Create a signed token of the form
+/// `user:group0,group1:expires:signature`, expires is in seconds
+/// since the unix epoch.
+pub(crate) fn make_token(
+    key: &[u8],
+    user: &str,
+    groups: &[&str],
+    expires: u64,
+) -> Result<String> {
+    let bad = |s: &str| s.is_empty() || s.contains(&[':', ','][..]);
+    if bad(user) || groups.iter().any(|g| bad(g)) {
+        bail!("user and group names must not be empty or contain ':' or ','")
+    }
+    let payload = format!("{}:{}:{}", user, groups.join(","), expires);
+    let sig = token_sig(key, &payload);
+    Ok(format!("{}:{}", payload, sig))
+}
+
+/// The tokens the resolver server will accept
+pub(crate) struct Tokens {
+    key: Option<Vec<u8>>,
+    shared: HashMap<String, TokenUser>,
+}
+
+impl Tokens {
+    pub(crate) fn load(
+        key_file: Option<&str>,
+        tokens_file: Option<&str>,
+    ) -> Result<Self> {
+        let key = key_file
+            .map(|f| -> Result<Vec<u8>> {
+                let key = fs::read_to_string(f)?;
+                let key = key.trim();
+                if key.is_empty() {
+                    bail!("the token key file {} is empty", f)
+                }
+                Ok(Vec::from(key.as_bytes()))
+            })
+            .transpose()?;
+        let shared = match tokens_file {
+            None => HashMap::new(),
+            Some(f) => serde_json::from_str(&fs::read_to_string(f)?)?,
+        };
+        if key.is_none() && shared.is_empty() {
+            bail!("token auth requires a key_file, a tokens_file, or both")
+        }
+        Ok(Tokens { key, shared })
+    }
+
+    /// Check `token`, returning the user and groups it authenticates,
+    /// and when it expires.
+    pub(crate) fn check(
+        &self,
+        token: &[u8],
+        now: u64,
+    ) -> Result<(String, Vec<String>, u64)> {
+        let token = str::from_utf8(token)?;
+        // look at every shared token so the time taken doesn't
+        // reveal how much of one matched
+        let shared = self.shared.iter().fold(None, |found, (t, u)| {
+            if ct_eq(t.as_bytes(), token.as_bytes()) {
+                Some(u)
+            } else {
+                found
+            }
+        });
+        if let Some(u) = shared {
+            let expires = u.expires.unwrap_or(u64::MAX);
+            if expires <= now {
+                bail!("token expired")
+            }
+            return Ok((u.user.clone(), u.groups.clone(), expires));
+        }
+        let key = self.key.as_ref().ok_or_else(|| anyhow!("invalid token"))?;
+        let (payload, sig) = match token.rfind(':') {
+            None => bail!("invalid token"),
+            Some(i) => (&token[..i], &token[i + 1..]),
+        };
+        // both are hex strings of the same length
+        let expected = token_sig(key, payload);
+        if !ct_eq(expected.as_bytes(), sig.as_bytes()) {
+            bail!("invalid token")
+        }
+        let mut parts = payload.split(':');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(user), Some(groups), Some(expires), None) => {
+                let expires = expires.parse::<u64>()?;
+                if expires <= now {
+                    bail!("token expired")
+                }
+                let groups = groups
+                    .split(',')
+                    .filter(|g| !g.is_empty())
+                    .map(String::from)
+                    .collect();
+                Ok((String::from(user), groups, expires))
+            }
+            _ => bail!("invalid token"),
+        }
+    }
+}
+
+// Apply the entries at one level of the tree to the rights
+// inherited from the level above. Rights granted to any of the
+// user's entities are added, then rights denied to any of them are
+// removed.
+fn apply<'a>(
+    p: Permissions,
+    sets: impl IntoIterator<Item = &'a HashMap<Entity, Permissions>>,
+    user: &UserInfo,
+) -> Permissions {
+    let mut found = false;
+    let (ap, dp) = sets.into_iter().fold((p, Permissions::empty()), |acc, set| {
+        found = true;
+        user.entities().fold(acc, |(ap, dp), e| match set.get(e) {
+            None => (ap, dp),
+            Some(p_) => {
+                if p_.contains(Permissions::DENY) {
+                    (ap, dp | *p_)
+                } else {
+                    (ap | *p_, dp)
                 }
-            },
+            }
+        })
+    });
+    if found {
+        ap & !dp
+    } else {
+        p
+    }
+}
+
+fn denied(set: &HashMap<Entity, Permissions>, user: &UserInfo) -> Permissions {
+    user.entities().fold(Permissions::empty(), |dp, e| match set.get(e) {
+        Some(p) if p.contains(Permissions::DENY) => dp | *p,
+        None | Some(_) => dp,
+    })
+}
+
+#[derive(Debug)]
+struct GlobEntry {
+    // a matcher for each level of the glob, None for **
+    parts: Vec<Option<GlobMatcher>>,
+    set: HashMap<Entity, Permissions>,
+}
+
+impl GlobEntry {
+    fn new(glob: &str, set: HashMap<Entity, Permissions>) -> Self {
+        let parts = Path::parts(glob)
+            .map(|part| match part {
+                "**" => None,
+                part => GlobBuilder::new(part).build().ok().map(|g| g.compile_matcher()),
+            })
+            .collect();
+        GlobEntry { parts, set }
+    }
+
+    // true if the glob might match some path below `path`. A level
+    // we can't match on it's own is treated like **.
+    fn may_match_below(&self, path: &str) -> bool {
+        for (i, part) in Path::parts(path).enumerate() {
+            match self.parts.get(i) {
+                None => return false,
+                Some(None) => return true,
+                Some(Some(m)) if !m.is_match(part) => return false,
+                Some(Some(_)) => (),
+            }
         }
+        self.parts.len() > Path::levels(path)
     }
 }
 
+/// Permission entries are keyed either by a path, which applies to
+/// the path and all it's children, or by a glob pattern
+/// (e.g. `/apps/*/control`), which applies to every path it matches
+/// and all of their children. Rights are computed by walking from
+/// the root down to the path. At each level the glob entries
+/// matching that level are applied first, and then the path entry
+/// for that level, so a path entry overrides a glob that matches the
+/// same level, and any entry deeper in the tree overrides both.
 #[derive(Debug)]
-pub(crate) struct PMap(BTreeMap<Path, HashMap<Entity, Permissions>>);
+pub(crate) struct PMap {
+    paths: BTreeMap<Path, HashMap<Entity, Permissions>>,
+    globs: Vec<GlobEntry>,
+    globset: GlobSet,
+}
 
 impl PMap {
     pub(crate) fn from_file(
@@ -136,14 +523,26 @@ impl PMap {
         root: &str,
         children: &BTreeMap<Path, Referral>,
     ) -> Result<Self> {
-        let mut pmap = BTreeMap::new();
+        let mut paths = BTreeMap::new();
+        let mut globs = Vec::new();
+        let mut builder = GlobSetBuilder::new();
         for (path, tbl) in file.0.iter() {
-            let path = Path::from(path);
-            if !Path::is_parent(root, &path) {
+            let glob = if Glob::is_glob(path) {
+                let glob = Glob::new(Chars::from(path.clone()))?;
+                builder.add(GlobBuilder::new(path).literal_separator(true).build()?);
+                Some(glob)
+            } else {
+                None
+            };
+            let base = match &glob {
+                None => path.as_str(),
+                Some(glob) => glob.base(),
+            };
+            if !Path::is_parent(root, base) {
                 bail!("permission entry for parent: {}, entry: {}", root, path)
             }
             for child in children.keys() {
-                if Path::is_parent(child, &path) {
+                if Path::is_parent(child, base) {
                     bail!("permission entry for child: {}, entry: {}", child, path)
                 }
             }
@@ -152,9 +551,14 @@ impl PMap {
                 let entity = if ent == "" { ANONYMOUS.id } else { db.entity(ent) };
                 entry.insert(entity, Permissions::try_from(perm.as_str())?);
             }
-            pmap.insert(path, entry);
+            match glob {
+                None => {
+                    paths.insert(Path::from(path), entry);
+                }
+                Some(_) => globs.push(GlobEntry::new(path, entry)),
+            }
         }
-        Ok(PMap(pmap))
+        Ok(PMap { paths, globs, globset: builder.build()? })
     }
 
     pub(crate) fn allowed(
@@ -176,7 +580,7 @@ impl PMap {
     ) -> bool {
         let rights_at_base = self.permissions(base_path, user);
         let mut rights = rights_at_base;
-        let mut iter = self.0.range::<str, (Bound<&str>, Bound<&str>)>((
+        let mut iter = self.paths.range::<str, (Bound<&str>, Bound<&str>)>((
             Bound::Excluded(base_path),
             Bound::Unbounded,
         ));
@@ -185,40 +589,25 @@ impl PMap {
             {
                 break;
             }
-            let deny =
-                user.entities().fold(Permissions::empty(), |dp, e| match set.get(e) {
-                    None => dp,
-                    Some(p) => {
-                        if p.contains(Permissions::DENY) {
-                            dp | *p
-                        } else {
-                            dp
-                        }
-                    }
-                });
-            rights &= !deny;
+            rights &= !denied(set, user);
+        }
+        for glob in self.globs.iter() {
+            if glob.may_match_below(base_path) {
+                rights &= !denied(&glob.set, user);
+            }
         }
         rights & desired_rights == desired_rights
     }
 
     pub(crate) fn permissions(&self, path: &str, user: &UserInfo) -> Permissions {
-        Path::dirnames(path).fold(Permissions::empty(), |p, s| match self.0.get(s) {
-            None => p,
-            Some(set) => {
-                let init = (p, Permissions::empty());
-                let (ap, dp) =
-                    user.entities().fold(init, |(ap, dp), e| match set.get(e) {
-                        None => (ap, dp),
-                        Some(p_) => {
-                            if p_.contains(Permissions::DENY) {
-                                (ap, dp | *p_)
-                            } else {
-                                (ap | *p_, dp)
-                            }
-                        }
-                    });
-                ap & !dp
-            }
+        Path::dirnames(path).fold(Permissions::empty(), |p, s| {
+            let p = if self.globs.is_empty() {
+                p
+            } else {
+                let matched = self.globset.matches(s);
+                apply(p, matched.into_iter().map(|i| &self.globs[i].set), user)
+            };
+            apply(p, self.paths.get(s), user)
         })
     }
 }
@@ -59,10 +59,15 @@ impl<T: Send + Sync + 'static> Drop for BatchReceiver<T> {
 
 impl<T: Send + Sync + 'static> BatchReceiver<T> {
     pub(crate) fn close(&self) {
-        let mut inner = self.0.lock();
-        inner.recv_closed = true;
-        inner.queue.clear();
-        inner.notify = None;
+        // queued messages may hold senders to this channel, so they
+        // must be dropped after the lock is released
+        let _queue = {
+            let mut inner = self.0.lock();
+            inner.recv_closed = true;
+            inner.notify = None;
+            let v = inner.pool.take();
+            mem::replace(&mut inner.queue, v)
+        };
     }
 
     pub(crate) fn len(&self) -> usize {
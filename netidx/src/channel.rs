@@ -1,4 +1,4 @@
-use crate::{os::Krb5Ctx, pack::Pack};
+use crate::{noise::NoiseCtx, pack::Pack};
 use anyhow::{anyhow, Error, Result};
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{Buf, BufMut, BytesMut};
@@ -26,6 +26,54 @@ const LEN_MASK: u32 = 0x7FFFFFFF;
 const MAX_BATCH: usize = 0x3FFFFFFF;
 const ENC_MASK: u32 = 0x80000000;
 
+/// Something that can encrypt and decrypt the frames of a
+/// channel. Kerberos contexts are the original implementation.
+pub(crate) trait CipherCtx {
+    /// Encrypt `data` in place, possibly using the header, padding,
+    /// and trailer buffers, which will be sent in that order around
+    /// it.
+    fn wrap_iov(
+        &self,
+        header: &mut BytesMut,
+        data: &mut BytesMut,
+        padding: &mut BytesMut,
+        trailer: &mut BytesMut,
+    ) -> Result<()>;
+
+    /// Decrypt the first `len` bytes of `msg`, and remove them from it.
+    fn unwrap_iov(&self, len: usize, msg: &mut BytesMut) -> Result<BytesMut>;
+}
+
+/// The cipher of a connection that may be encrypted either by
+/// Kerberos or by Noise.
+#[derive(Debug, Clone)]
+pub(crate) enum Cipher<K> {
+    Krb5(K),
+    Noise(NoiseCtx),
+}
+
+impl<K: CipherCtx> CipherCtx for Cipher<K> {
+    fn wrap_iov(
+        &self,
+        header: &mut BytesMut,
+        data: &mut BytesMut,
+        padding: &mut BytesMut,
+        trailer: &mut BytesMut,
+    ) -> Result<()> {
+        match self {
+            Cipher::Krb5(ctx) => ctx.wrap_iov(header, data, padding, trailer),
+            Cipher::Noise(ctx) => ctx.wrap_iov(header, data, padding, trailer),
+        }
+    }
+
+    fn unwrap_iov(&self, len: usize, msg: &mut BytesMut) -> Result<BytesMut> {
+        match self {
+            Cipher::Krb5(ctx) => ctx.unwrap_iov(len, msg),
+            Cipher::Noise(ctx) => ctx.unwrap_iov(len, msg),
+        }
+    }
+}
+
 #[derive(Debug)]
 enum ToFlush<C> {
     Flush(BytesMut),
@@ -50,7 +98,7 @@ async fn flush_buf<B: Buf>(
     Ok(())
 }
 
-fn flush_task<C: Krb5Ctx + Debug + Send + Sync + 'static>(
+fn flush_task<C: CipherCtx + Debug + Send + Sync + 'static>(
     mut soc: WriteHalf<TcpStream>,
 ) -> Sender<ToFlush<C>> {
     let (tx, mut rx): (Sender<ToFlush<C>>, Receiver<ToFlush<C>>) = mpsc::channel(3);
@@ -70,7 +118,6 @@ fn flush_task<C: Krb5Ctx + Debug + Send + Sync + 'static>(
                         None => try_cf!(flush_buf(&mut soc, data, false).await),
                         Some(ref ctx) => {
                             try_cf!(ctx.wrap_iov(
-                                true,
                                 &mut header,
                                 &mut data,
                                 &mut padding,
@@ -96,7 +143,7 @@ pub(crate) struct WriteChannel<C> {
     boundries: Vec<usize>,
 }
 
-impl<C: Krb5Ctx + Debug + Clone + Send + Sync + 'static> WriteChannel<C> {
+impl<C: CipherCtx + Debug + Clone + Send + Sync + 'static> WriteChannel<C> {
     pub(crate) fn new(socket: WriteHalf<TcpStream>) -> WriteChannel<C> {
         WriteChannel {
             to_flush: flush_task(socket),
@@ -201,7 +248,7 @@ impl<C: Krb5Ctx + Debug + Clone + Send + Sync + 'static> WriteChannel<C> {
     }
 }
 
-fn read_task<C: Krb5Ctx + Clone + Debug + Send + Sync + 'static>(
+fn read_task<C: CipherCtx + Clone + Debug + Send + Sync + 'static>(
     stop: oneshot::Receiver<()>,
     mut soc: ReadHalf<TcpStream>,
     mut set_ctx: oneshot::Receiver<C>,
@@ -267,7 +314,7 @@ pub(crate) struct ReadChannel<C> {
     incoming: stream::Fuse<Receiver<BytesMut>>,
 }
 
-impl<C: Krb5Ctx + Debug + Clone + Send + Sync + 'static> ReadChannel<C> {
+impl<C: CipherCtx + Debug + Clone + Send + Sync + 'static> ReadChannel<C> {
     pub(crate) fn new(socket: ReadHalf<TcpStream>) -> ReadChannel<C> {
         let (set_ctx, read_ctx) = oneshot::channel();
         let (stop_tx, stop_rx) = oneshot::channel();
@@ -319,7 +366,7 @@ pub(crate) struct Channel<C> {
     write: WriteChannel<C>,
 }
 
-impl<C: Krb5Ctx + Debug + Clone + Send + Sync + 'static> Channel<C> {
+impl<C: CipherCtx + Debug + Clone + Send + Sync + 'static> Channel<C> {
     pub(crate) fn new(socket: TcpStream) -> Channel<C> {
         let (rh, wh) = io::split(socket);
         Channel { read: ReadChannel::new(rh), write: WriteChannel::new(wh) }
@@ -1,5 +1,5 @@
 use crate::{
-    chars::Chars, path::Path, pool::Pooled, protocol::resolver::Referral, utils,
+    chars::Chars, noise, path::Path, pool::Pooled, protocol::resolver::Referral, utils,
 };
 use anyhow::Result;
 use fxhash::FxBuildHasher;
@@ -21,7 +21,7 @@ use std::{
 };
 
 pub(crate) mod file {
-    use super::Auth;
+    use super::{Audit, Auth, Encryption, GroupMapper, Limits};
     use crate::{
         chars::Chars, path::Path, pool::Pooled, protocol::resolver::Referral as Pref,
         utils,
@@ -94,6 +94,13 @@ pub(crate) mod file {
         pub(super) hello_timeout: u64,
         pub(super) addrs: Vec<SocketAddr>,
         pub(super) auth: Auth,
+        #[serde(default)]
+        pub(super) group_mapper: GroupMapper,
+        pub(super) audit: Option<Audit>,
+        #[serde(default)]
+        pub(super) encryption: Encryption,
+        #[serde(default)]
+        pub(super) limits: Limits,
     }
 }
 
@@ -129,6 +136,129 @@ impl PMap {
 pub enum Auth {
     Anonymous,
     Krb5(HashMap<SocketAddr, String>),
+    /// Authenticate clients with bearer tokens instead of
+    /// Kerberos. Connections to the resolver server are encrypted
+    /// with the Noise protocol before the token is sent, and the
+    /// server must prove it holds the private half of `public_key`,
+    /// so neither eavesdroppers nor an active man in the middle can
+    /// learn the token. Clients only send tokens to the servers in
+    /// this config, never to other clusters they are referred
+    /// to. Use `Encryption::Noise` to protect connections to
+    /// publishers. The files are only read by the resolver server.
+    Token {
+        /// The resolver servers' Noise public key in hex, see
+        /// `resolver_server::make_noise_key`.
+        public_key: String,
+        /// A file holding the matching private key in hex. Required
+        /// by the resolver server.
+        #[serde(default)]
+        private_key_file: Option<String>,
+        /// A file holding the key used to check signed tokens, see
+        /// `resolver_server::make_token`.
+        #[serde(default)]
+        key_file: Option<String>,
+        /// A json file mapping pre shared tokens to the user they
+        /// authenticate, e.g. `{"secret": {"user": "ci", "groups": ["ci"]}}`.
+        /// A token may also have an `expires` time, in seconds since
+        /// the unix epoch.
+        #[serde(default)]
+        tokens_file: Option<String>,
+    },
+}
+
+/// How connections between subscribers and publishers that aren't
+/// using Kerberos are protected. Kerberos connections are always
+/// encrypted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encryption {
+    /// Send everything in the clear.
+    #[default]
+    Cleartext,
+    /// Subscribers encrypt their connections with the Noise
+    /// protocol, and publishers refuse cleartext subscribers. Since
+    /// neither side has a long term key this stops eavesdroppers,
+    /// but not an active man in the middle.
+    Noise,
+}
+
+/// How the resolver server discovers the groups a user is a member
+/// of. Only used with krb5 auth.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum GroupMapper {
+    /// Run the POSIX `id` command.
+    Id,
+    /// Call `getgrouplist` via libc. Not available on macOS.
+    GetGroupList,
+    /// Read a static json file mapping users to a list of groups,
+    /// e.g. `{"eric@EXAMPLE.COM": ["ops", "dev"]}`, or the same in
+    /// yaml if the file name ends in .yaml or .yml. Users not in the
+    /// file are not a member of any group.
+    File(String),
+    /// Cache the groups returned by `mapper` for `ttl` seconds.
+    Cached { ttl: u64, mapper: Box<GroupMapper> },
+}
+
+impl Default for GroupMapper {
+    fn default() -> Self {
+        GroupMapper::Cached { ttl: 3600, mapper: Box::new(GroupMapper::Id) }
+    }
+}
+
+/// Record the resolver server's authorization decisions. Each
+/// record is a json object with the fields `timestamp` (seconds since
+/// the epoch), `user` (null for anonymous), `addr` (the client's
+/// address, or for writes the publisher's address), `op` (the
+/// request, e.g. `Resolve` or `Publish`), `path`, and `allowed`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Audit {
+    /// Append records, one per line, to this file.
+    pub file: Option<String>,
+    /// Publish each record as an update to a string value at this
+    /// path followed by the address of the server. The resolver
+    /// server must be allowed to publish there, and in krb5 mode it
+    /// must be able to get client credentials for it's own
+    /// principal.
+    pub publish: Option<String>,
+    /// Only record requests under paths matching one of these globs,
+    /// e.g. `/secret` or `/app/*/control`. If empty all requests are
+    /// recorded.
+    #[serde(default)]
+    pub filter: Vec<String>,
+    /// Only record requests that were denied.
+    #[serde(default)]
+    pub denied_only: bool,
+}
+
+/// Limits on the resources one user, or one client address, may use
+/// in the resolver server. A missing limit is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Limit {
+    /// The maximum number of concurrent connections.
+    pub max_connections: Option<usize>,
+    /// The sustained number of requests per second. Up to one
+    /// second's worth of requests may be sent at once, requests over
+    /// the limit get an error reply.
+    pub requests_per_second: Option<u32>,
+    /// The maximum number of paths published at once, including
+    /// default publishers. Once it is reached further publish
+    /// requests get an error reply, even for paths that are already
+    /// published.
+    pub max_published: Option<usize>,
+}
+
+/// The resource limits enforced by the resolver server in addition
+/// to the global `max_connections`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Applied to each authenticated user across all of their
+    /// connections. Anonymous clients are only subject to the per
+    /// address limits.
+    #[serde(default)]
+    pub per_user: Limit,
+    /// Applied to each client ip address. For published paths this
+    /// is the address of the publisher.
+    #[serde(default)]
+    pub per_addr: Limit,
 }
 
 #[derive(Debug, Clone)]
@@ -142,6 +272,10 @@ pub struct Config {
     pub hello_timeout: Duration,
     pub addrs: Vec<SocketAddr>,
     pub auth: Auth,
+    pub group_mapper: GroupMapper,
+    pub audit: Option<Audit>,
+    pub encryption: Encryption,
+    pub limits: Limits,
 }
 
 impl From<Referral> for Config {
@@ -163,6 +297,10 @@ impl From<Referral> for Config {
                     Auth::Krb5(r.krb5_spns.drain().map(|(k, v)| (k, v.into())).collect())
                 }
             },
+            group_mapper: GroupMapper::default(),
+            audit: None,
+            encryption: Encryption::default(),
+            limits: Limits::default(),
         }
     }
 }
@@ -174,7 +312,7 @@ impl Into<Referral> for Config {
             ttl: u32::MAX as u64,
             addrs: Pooled::orphan(self.addrs),
             krb5_spns: match self.auth {
-                Auth::Anonymous => {
+                Auth::Anonymous | Auth::Token { .. } => {
                     Pooled::orphan(HashMap::with_hasher(FxBuildHasher::default()))
                 }
                 Auth::Krb5(mut spns) => {
@@ -235,6 +373,15 @@ impl Config {
             }
             children
         };
+        if let Auth::Token { public_key, .. } = &cfg.auth {
+            noise::parse_key(public_key)?;
+        }
+        if let Some(publish) = cfg.audit.as_ref().and_then(|a| a.publish.as_ref()) {
+            let root = parent.as_ref().map(|r| r.path.as_ref()).unwrap_or("/");
+            if !Path::is_absolute(publish) || !Path::is_parent(root, publish) {
+                bail!("the audit publish path must be under the root {}", root)
+            }
+        }
         Ok(Config {
             parent,
             children,
@@ -245,6 +392,10 @@ impl Config {
             writer_ttl: Duration::from_secs(cfg.writer_ttl),
             hello_timeout: Duration::from_secs(cfg.hello_timeout),
             auth: cfg.auth,
+            group_mapper: cfg.group_mapper,
+            audit: cfg.audit,
+            encryption: cfg.encryption,
+            limits: cfg.limits,
         })
     }
 
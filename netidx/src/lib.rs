@@ -126,10 +126,13 @@ pub use netidx_core::{chars, pack, pool, path, utils};
 pub use netidx_netproto as protocol;
 
 mod batch_channel;
+mod audit;
 mod auth;
 mod channel;
 pub mod config;
+mod noise;
 mod os;
+mod quotas;
 pub mod publisher;
 pub mod resolver;
 pub mod resolver_server;
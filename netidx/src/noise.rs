@@ -0,0 +1,176 @@
+use crate::{channel::CipherCtx, utils};
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::{
+    fmt, fs, result,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+// Neither side has a static key, so the handshake protects against
+// eavesdropping, but not against an active man in the middle. Use
+// Kerberos if you need that.
+const PATTERN: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
+// The responder has a static key the initiator already knows, so
+// only the holder of the private key can read what the initiator
+// sends after the handshake. Tokens are never sent any other way.
+const KEY_PATTERN: &str = "Noise_NK_25519_ChaChaPoly_BLAKE2s";
+const KEY_LEN: usize = 32;
+const MAX_MSG: usize = 65535;
+const TAG: usize = 16;
+const CHUNK: usize = MAX_MSG - TAG;
+
+struct NoiseCtxInner {
+    state: StatelessTransportState,
+    // the flush task is the only writer and the read task the only
+    // reader, so the nonces just count messages in each direction.
+    send: AtomicU64,
+    recv: AtomicU64,
+}
+
+/// An encrypted session established by a Noise handshake. A frame
+/// larger than the maximum noise message is encrypted as a series of
+/// messages, every one but the last full size.
+#[derive(Clone)]
+pub(crate) struct NoiseCtx(Arc<NoiseCtxInner>);
+
+impl fmt::Debug for NoiseCtx {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NoiseCtx")
+    }
+}
+
+impl NoiseCtx {
+    fn new(hs: HandshakeState) -> Result<Self> {
+        Ok(NoiseCtx(Arc::new(NoiseCtxInner {
+            state: hs.into_stateless_transport_mode()?,
+            send: AtomicU64::new(0),
+            recv: AtomicU64::new(0),
+        })))
+    }
+}
+
+impl CipherCtx for NoiseCtx {
+    fn wrap_iov(
+        &self,
+        _header: &mut BytesMut,
+        data: &mut BytesMut,
+        _padding: &mut BytesMut,
+        _trailer: &mut BytesMut,
+    ) -> Result<()> {
+        let mut out =
+            BytesMut::with_capacity(data.len() + (data.len() / CHUNK + 1) * TAG);
+        for chunk in data.chunks(CHUNK) {
+            let start = out.len();
+            out.resize(start + chunk.len() + TAG, 0x0);
+            let nonce = self.0.send.fetch_add(1, Ordering::Relaxed);
+            self.0.state.write_message(nonce, chunk, &mut out[start..])?;
+        }
+        *data = out;
+        Ok(())
+    }
+
+    fn unwrap_iov(&self, len: usize, msg: &mut BytesMut) -> Result<BytesMut> {
+        let msg = msg.split_to(len);
+        let mut out = BytesMut::with_capacity(len);
+        out.resize(len, 0x0);
+        let mut n = 0;
+        for chunk in msg.chunks(MAX_MSG) {
+            let nonce = self.0.recv.fetch_add(1, Ordering::Relaxed);
+            n += self.0.state.read_message(nonce, chunk, &mut out[n..])?;
+        }
+        out.truncate(n);
+        Ok(out)
+    }
+}
+
+/// The subscriber's half of a handshake
+pub(crate) struct Initiator(HandshakeState);
+
+impl Initiator {
+    /// Start a handshake, returning the message to send to the
+    /// publisher.
+    pub(crate) fn new() -> Result<(Initiator, Bytes)> {
+        let mut hs = Builder::new(PATTERN.parse()?).build_initiator()?;
+        let mut buf = [0u8; MAX_MSG];
+        let len = hs.write_message(&[], &mut buf)?;
+        Ok((Initiator(hs), utils::bytes(&buf[..len])))
+    }
+
+    /// Start a handshake with a responder whose static public key is
+    /// `remote`, returning the message to send to it.
+    pub(crate) fn with_key(remote: &[u8]) -> Result<(Initiator, Bytes)> {
+        let mut hs = Builder::new(KEY_PATTERN.parse()?)
+            .remote_public_key(remote)
+            .build_initiator()?;
+        let mut buf = [0u8; MAX_MSG];
+        let len = hs.write_message(&[], &mut buf)?;
+        Ok((Initiator(hs), utils::bytes(&buf[..len])))
+    }
+
+    /// Finish the handshake with the publisher's reply.
+    pub(crate) fn finish(mut self, reply: &[u8]) -> Result<NoiseCtx> {
+        let mut buf = [0u8; MAX_MSG];
+        self.0.read_message(reply, &mut buf)?;
+        NoiseCtx::new(self.0)
+    }
+}
+
+/// The publisher's half of a handshake, returns the context and the
+/// reply to send to the subscriber.
+pub(crate) fn respond(msg: &[u8]) -> Result<(NoiseCtx, Bytes)> {
+    let mut hs = Builder::new(PATTERN.parse()?).build_responder()?;
+    let mut buf = [0u8; MAX_MSG];
+    hs.read_message(msg, &mut buf)?;
+    let len = hs.write_message(&[], &mut buf)?;
+    Ok((NoiseCtx::new(hs)?, utils::bytes(&buf[..len])))
+}
+
+/// The responder's half of a handshake started by
+/// `Initiator::with_key`, `private` is our static private key.
+pub(crate) fn respond_with_key(msg: &[u8], private: &[u8]) -> Result<(NoiseCtx, Bytes)> {
+    let mut hs = Builder::new(KEY_PATTERN.parse()?)
+        .local_private_key(private)
+        .build_responder()?;
+    let mut buf = [0u8; MAX_MSG];
+    hs.read_message(msg, &mut buf)?;
+    let len = hs.write_message(&[], &mut buf)?;
+    Ok((NoiseCtx::new(hs)?, utils::bytes(&buf[..len])))
+}
+
+/// Generate a static key pair, returning the private and public keys
+/// in hex.
+pub(crate) fn generate_key() -> Result<(String, String)> {
+    let kp = Builder::new(KEY_PATTERN.parse()?).generate_keypair()?;
+    Ok((to_hex(&kp.private), to_hex(&kp.public)))
+}
+
+fn to_hex(key: &[u8]) -> String {
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parse a hex encoded key
+pub(crate) fn parse_key(s: &str) -> Result<Bytes> {
+    let s = s.trim();
+    if s.len() != KEY_LEN * 2 || !s.is_ascii() {
+        bail!("a noise key must be {} hex digits", KEY_LEN * 2)
+    }
+    let key = (0..KEY_LEN)
+        .map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16))
+        .collect::<result::Result<Vec<u8>, _>>()?;
+    Ok(Bytes::from(key))
+}
+
+/// Read the private key in `file`, and check that it belongs to the
+/// hex encoded `public` key by running a handshake with it.
+pub(crate) fn load_key(file: &str, public: &str) -> Result<Bytes> {
+    let private = parse_key(&fs::read_to_string(file)?)?;
+    let (_, msg) = Initiator::with_key(&parse_key(public)?)?;
+    if respond_with_key(&msg, &private).is_err() {
+        bail!("the private key in {} does not match the public key", file)
+    }
+    Ok(private)
+}
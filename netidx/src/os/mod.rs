@@ -1,3 +1,4 @@
+use crate::channel::CipherCtx;
 use anyhow::Result;
 use bytes::BytesMut;
 use std::{ops::Deref, time::Duration};
@@ -24,6 +25,11 @@ pub(crate) trait Krb5ServerCtx: Krb5Ctx {
     fn client(&self) -> Result<String>;
 }
 
+/// Find the groups a user is a member of.
+pub(crate) trait GroupMapper: Send + Sync {
+    fn groups(&mut self, user: &str) -> Result<Vec<String>>;
+}
+
 #[cfg(unix)]
 pub(crate) mod unix;
 
@@ -35,3 +41,27 @@ pub(crate) mod windows;
 
 #[cfg(windows)]
 pub(crate) use windows::*;
+
+// channels are always encrypted, never just signed
+macro_rules! krb5_cipher {
+    ($t:ty) => {
+        impl CipherCtx for $t {
+            fn wrap_iov(
+                &self,
+                header: &mut BytesMut,
+                data: &mut BytesMut,
+                padding: &mut BytesMut,
+                trailer: &mut BytesMut,
+            ) -> Result<()> {
+                Krb5Ctx::wrap_iov(self, true, header, data, padding, trailer)
+            }
+
+            fn unwrap_iov(&self, len: usize, msg: &mut BytesMut) -> Result<BytesMut> {
+                Krb5Ctx::unwrap_iov(self, len, msg)
+            }
+        }
+    };
+}
+
+krb5_cipher!(ClientCtx);
+krb5_cipher!(ServerCtx);
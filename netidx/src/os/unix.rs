@@ -1,4 +1,4 @@
-use super::{Krb5Ctx, Krb5ServerCtx};
+use super::{GroupMapper, Krb5Ctx, Krb5ServerCtx};
 use anyhow::{anyhow, Error, Result};
 #[cfg(feature = "krb5_iov")]
 use bytes::Buf as _;
@@ -15,6 +15,11 @@ use libgssapi::{
     oid::{OidSet, GSS_MECH_KRB5, GSS_NT_KRB5_PRINCIPAL},
     util::Buf,
 };
+#[cfg(not(target_os = "macos"))]
+use std::{
+    ffi::{CStr, CString},
+    io, mem, ptr,
+};
 use std::{process::Command, time::Duration};
 use tokio::task;
 
@@ -244,23 +249,16 @@ pub(crate) fn create_server_ctx(principal: Option<&str>) -> Result<ServerCtx> {
 // level, it seems libc provides getgrouplist on most platforms,
 // but unfortunatly Apple doesn't implement it. Luckily the 'id'
 // command is specified in POSIX.
-pub(crate) struct Mapper(String);
+pub(crate) struct IdMapper(String);
 
-impl Mapper {
-    pub(crate) fn new() -> Result<Mapper> {
+impl IdMapper {
+    pub(crate) fn new() -> Result<IdMapper> {
         task::block_in_place(|| {
             let out = Command::new("sh").arg("-c").arg("which id").output()?;
             let buf = String::from_utf8_lossy(&out.stdout);
             let path =
                 buf.lines().next().ok_or_else(|| anyhow!("can't find the id command"))?;
-            Ok(Mapper(String::from(path)))
-        })
-    }
-
-    pub(crate) fn groups(&mut self, user: &str) -> Result<Vec<String>> {
-        task::block_in_place(|| {
-            let out = Command::new(&self.0).arg(user).output()?;
-            Mapper::parse_output(&String::from_utf8_lossy(&out.stdout))
+            Ok(IdMapper(String::from(path)))
         })
     }
 
@@ -288,3 +286,91 @@ impl Mapper {
         }
     }
 }
+
+impl GroupMapper for IdMapper {
+    fn groups(&mut self, user: &str) -> Result<Vec<String>> {
+        let out = Command::new(&self.0).arg(user).output()?;
+        IdMapper::parse_output(&String::from_utf8_lossy(&out.stdout))
+    }
+}
+
+// call one of the reentrant libc lookup functions, growing the
+// scratch buffer until it is big enough. `f` must copy what it needs
+// out of the buffer, None means the entry wasn't found.
+#[cfg(not(target_os = "macos"))]
+fn lookup<T>(
+    mut f: impl FnMut(&mut [libc::c_char]) -> (libc::c_int, Option<T>),
+) -> Result<Option<T>> {
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        match f(&mut buf) {
+            (libc::ERANGE, _) if buf.len() < 1024 * 1024 => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            }
+            (0, res) => break Ok(res),
+            (e, _) => break Err(Error::from(io::Error::from_raw_os_error(e))),
+        }
+    }
+}
+
+/// Find groups by calling getgrouplist directly, this uses the same
+/// nss machinery as `id`, but without running a process per user.
+#[cfg(not(target_os = "macos"))]
+pub(crate) struct GetGroupListMapper;
+
+#[cfg(not(target_os = "macos"))]
+impl GroupMapper for GetGroupListMapper {
+    fn groups(&mut self, user: &str) -> Result<Vec<String>> {
+        let name = CString::new(user)?;
+        let gid = lookup(|buf| {
+            let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+            let mut res = ptr::null_mut();
+            let e = unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut res,
+                )
+            };
+            (e, if res.is_null() { None } else { Some(pwd.pw_gid) })
+        })?;
+        let gid = match gid {
+            None => return Ok(Vec::new()),
+            Some(gid) => gid,
+        };
+        let mut gids: Vec<libc::gid_t> = vec![0; 64];
+        loop {
+            let mut n = gids.len() as libc::c_int;
+            let r = unsafe {
+                libc::getgrouplist(name.as_ptr(), gid, gids.as_mut_ptr(), &mut n)
+            };
+            if r >= 0 {
+                gids.truncate(n as usize);
+                break;
+            }
+            let len = usize::max(n as usize, gids.len() * 2);
+            gids.resize(len, 0);
+        }
+        let mut groups = Vec::with_capacity(gids.len());
+        for gid in gids {
+            let name = lookup(|buf| {
+                let mut grp: libc::group = unsafe { mem::zeroed() };
+                let mut res = ptr::null_mut();
+                let e = unsafe {
+                    libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut res)
+                };
+                if res.is_null() {
+                    (e, None)
+                } else {
+                    let name = unsafe { CStr::from_ptr(grp.gr_name) };
+                    (e, Some(name.to_string_lossy().into_owned()))
+                }
+            })?;
+            groups.extend(name);
+        }
+        Ok(groups)
+    }
+}
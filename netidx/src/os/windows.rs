@@ -1,4 +1,4 @@
-use super::{Krb5Ctx, Krb5ServerCtx};
+use super::{GroupMapper, Krb5Ctx, Krb5ServerCtx};
 use anyhow::{bail, Result};
 use bytes::{Buf, BytesMut};
 use log::debug;
@@ -650,14 +650,16 @@ pub(crate) fn create_server_ctx(principal: Option<&str>) -> Result<ServerCtx> {
     })
 }
 
-pub(crate) struct Mapper;
+pub(crate) struct IdMapper;
 
-impl Mapper {
-    pub(crate) fn new() -> Result<Mapper> {
-        Ok(Mapper)
+impl IdMapper {
+    pub(crate) fn new() -> Result<IdMapper> {
+        Ok(IdMapper)
     }
+}
 
-    pub(crate) fn groups(&mut self, _user: &str) -> Result<Vec<String>> {
+impl GroupMapper for IdMapper {
+    fn groups(&mut self, _user: &str) -> Result<Vec<String>> {
         todo!("group listing is not implemented on windows")
     }
 }
@@ -1,19 +1,24 @@
-pub use crate::protocol::{
-    publisher::Id,
-    value::{FromValue, Typ, Value},
-};
 use crate::{
     auth::Permissions,
-    channel::Channel,
+    channel::{Channel, Cipher},
     chars::Chars,
-    config::Config,
-    os::{self, Krb5Ctx, ServerCtx},
+    config::{Config, Encryption},
+    noise,
+    os::{self, Krb5Ctx, Krb5ServerCtx, ServerCtx},
+    pack::Pack,
     path::Path,
     pool::{Pool, Pooled},
     protocol::{self, publisher},
     resolver::{Auth, ResolverWrite},
     utils::{self, BatchItem, Batched, ChanId, ChanWrap},
 };
+pub use crate::{
+    auth::Principal,
+    protocol::{
+        publisher::Id,
+        value::{FromValue, Typ, Value},
+    },
+};
 use anyhow::{anyhow, Error, Result};
 use bytes::Buf;
 use futures::{
@@ -286,15 +291,19 @@ pub struct WriteRequest {
     pub path: Path,
     /// the unique id of the client requesting the write
     pub client: ClId,
+    /// the identity of the client requesting the write
+    pub principal: Arc<Principal>,
     /// the value being written
     pub value: Value,
     pub send_result: Option<SendResult>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum Event {
     Destroyed(Id),
-    Subscribe(Id, ClId),
+    /// a client subscribed, the principal is the identity the
+    /// resolver signed for the subscription
+    Subscribe(Id, ClId, Arc<Principal>),
     Unsubscribe(Id, ClId),
 }
 
@@ -564,6 +573,7 @@ impl ToClientMsg {
 struct Client {
     msg_queue: MsgQ,
     subscribed: HashMap<Id, Permissions, FxBuildHasher>,
+    principal: Arc<Principal>,
 }
 
 struct Published {
@@ -650,7 +660,7 @@ impl PublisherInner {
     }
 
     fn send_event(&mut self, event: Event) {
-        self.on_event_chans.retain(|chan| chan.unbounded_send(event).is_ok());
+        self.on_event_chans.retain(|chan| chan.unbounded_send(event.clone()).is_ok());
     }
 
     fn trigger_publish(&mut self) {
@@ -737,6 +747,7 @@ impl Publisher {
                 }
             }
         };
+        let encryption = resolver.encryption;
         let resolver = ResolverWrite::new(resolver, desired_auth.clone(), addr);
         let (stop, receive_stop) = oneshot::channel();
         let (tx_trigger, rx_trigger) = unbounded();
@@ -767,7 +778,14 @@ impl Publisher {
         task::spawn({
             let pb_weak = pb.downgrade();
             async move {
-                accept_loop(pb_weak.clone(), listener, receive_stop, desired_auth).await;
+                accept_loop(
+                    pb_weak.clone(),
+                    listener,
+                    receive_stop,
+                    desired_auth,
+                    encryption,
+                )
+                .await;
                 info!("accept loop shutdown");
             }
         });
@@ -1032,6 +1050,12 @@ impl Publisher {
         self.0.lock().by_id.get(&id).map(|p| p.subscribed.len()).unwrap_or(0)
     }
 
+    /// Get the identity of a connected client. Returns None if the
+    /// client is no longer connected.
+    pub fn principal(&self, client: &ClId) -> Option<Arc<Principal>> {
+        self.0.lock().clients.get(client).map(|c| c.principal.clone())
+    }
+
     /// Register `tx` to receive writes to the specified published
     /// value. You can register multiple channels, and you can
     /// register the same channel on multiple ids. If no channels are
@@ -1094,7 +1118,7 @@ type DeferredSubs =
 
 fn subscribe(
     t: &mut PublisherInner,
-    con: &mut Channel<ServerCtx>,
+    con: &mut Channel<Cipher<ServerCtx>>,
     client: ClId,
     path: Path,
     permissions: Permissions,
@@ -1130,9 +1154,13 @@ fn subscribe(
         Some(id) => {
             let id = *id;
             if let Some(ut) = t.by_id.get_mut(&id) {
-                if let Some(cl) = t.clients.get_mut(&client) {
-                    cl.subscribed.insert(id, permissions);
-                }
+                let principal = match t.clients.get_mut(&client) {
+                    None => Arc::new(Principal::Anonymous),
+                    Some(cl) => {
+                        cl.subscribed.insert(id, permissions);
+                        cl.principal.clone()
+                    }
+                };
                 let subs = BTreeSet::from_iter(
                     iter::once(client).chain(ut.subscribed.iter().copied()),
                 );
@@ -1154,7 +1182,7 @@ fn subscribe(
                         let _ = tx.send(());
                     }
                 }
-                t.send_event(Event::Subscribe(id, client));
+                t.send_event(Event::Subscribe(id, client, principal));
             }
         }
     }
@@ -1189,7 +1217,7 @@ fn unsubscribe(t: &mut PublisherInner, client: ClId, id: Id) {
 
 fn write(
     t: &mut PublisherInner,
-    con: &mut Channel<ServerCtx>,
+    con: &mut Channel<Cipher<ServerCtx>>,
     client: ClId,
     gc_on_write: &mut Vec<ChanWrap<Pooled<Vec<WriteRequest>>>>,
     wait_write_res: &mut Vec<(Id, oneshot::Receiver<Value>)>,
@@ -1247,6 +1275,7 @@ fn write(
                 id,
                 path: pbv.path.clone(),
                 client,
+                principal: cl.principal.clone(),
                 value: v.clone(),
                 send_result: send_result.clone(),
             };
@@ -1260,11 +1289,46 @@ fn write(
     Ok(())
 }
 
+// the length of a sha3-512 signature
+const SIG_LEN: usize = 64;
+
+// The principal signed by the resolver must be the same user who
+// authenticated to us, if so remember it, with it's groups. Token
+// clients don't authenticate to us, so when `adopt` is true an
+// anonymous client takes the principal of the first token it
+// presents, and must present the same one from then on.
+fn set_principal(
+    t: &mut PublisherInner,
+    client: ClId,
+    principal: &[u8],
+    adopt: bool,
+) -> bool {
+    let mut buf = principal;
+    let principal = match Principal::decode(&mut buf) {
+        Ok(p) if !buf.has_remaining() => p,
+        Ok(_) | Err(_) => return false,
+    };
+    match t.clients.get_mut(&client) {
+        None => false,
+        Some(cl) if adopt && cl.principal.name().is_none() => {
+            cl.principal = Arc::new(principal);
+            true
+        }
+        Some(cl) if cl.principal.name() != principal.name() => false,
+        Some(cl) => {
+            if *cl.principal != principal {
+                cl.principal = Arc::new(principal);
+            }
+            true
+        }
+    }
+}
+
 async fn handle_batch(
     t: &PublisherWeak,
     client: ClId,
     msgs: impl Iterator<Item = publisher::To>,
-    con: &mut Channel<ServerCtx>,
+    con: &mut Channel<Cipher<ServerCtx>>,
     write_batches: &mut HashMap<
         ChanId,
         (Pooled<Vec<WriteRequest>>, Sender<Pooled<Vec<WriteRequest>>>),
@@ -1296,52 +1360,82 @@ async fn handle_batch(
                             Permissions::all(),
                             deferred_subs,
                         )?,
-                        Auth::Krb5 { .. } => match secrets.get(&resolver) {
-                            None => {
-                                debug!("denied, no stored secret for {}", resolver);
-                                con.queue_send(&From::Denied(path))?
-                            }
-                            Some(secret) => {
-                                if token.len() < mem::size_of::<u64>() {
-                                    bail!("error, token too short");
-                                }
-                                let salt = token.get_u64();
-                                let expected = utils::make_sha3_token(
-                                    Some(salt),
-                                    &[
-                                        &secret.to_be_bytes(),
-                                        &timestamp.to_be_bytes(),
-                                        &permissions.to_be_bytes(),
-                                        path.as_bytes(),
-                                    ],
-                                );
-                                let permissions =
-                                    Permissions::from_bits(permissions as u16)
-                                        .ok_or_else(|| {
-                                            anyhow!("invalid permission bits")
-                                        })?;
-                                let age = std::cmp::max(
-                                    u64::saturating_sub(now, timestamp),
-                                    u64::saturating_sub(timestamp, now),
-                                );
-                                if age > 300
-                                    || !permissions.contains(Permissions::SUBSCRIBE)
-                                    || &*token != &expected[mem::size_of::<u64>()..]
-                                {
-                                    debug!("subscribe permission denied");
+                        Auth::Krb5 { .. } | Auth::Token(_) => {
+                            match secrets.get(&resolver) {
+                                None => {
+                                    debug!("denied, no stored secret for {}", resolver);
                                     con.queue_send(&From::Denied(path))?
-                                } else {
-                                    subscribe(
-                                        &mut *pb,
-                                        con,
-                                        client,
-                                        path,
-                                        permissions,
-                                        deferred_subs,
-                                    )?
+                                }
+                                Some(secret) => {
+                                    if token.len() < mem::size_of::<u64>() {
+                                        bail!("error, token too short");
+                                    }
+                                    let salt = token.get_u64();
+                                    if token.len() < SIG_LEN {
+                                        bail!("error, token too short");
+                                    }
+                                    // resolvers that predate protocol
+                                    // version 2, or that think we do,
+                                    // don't send the principal
+                                    let principal = token.split_off(SIG_LEN);
+                                    let expected = if principal.is_empty() {
+                                        utils::make_sha3_token(
+                                            Some(salt),
+                                            &[
+                                                &secret.to_be_bytes(),
+                                                &timestamp.to_be_bytes(),
+                                                &permissions.to_be_bytes(),
+                                                path.as_bytes(),
+                                            ],
+                                        )
+                                    } else {
+                                        utils::make_sha3_token(
+                                            Some(salt),
+                                            &[
+                                                &secret.to_be_bytes(),
+                                                &timestamp.to_be_bytes(),
+                                                &permissions.to_be_bytes(),
+                                                &(principal.len() as u32).to_be_bytes(),
+                                                &principal,
+                                                path.as_bytes(),
+                                            ],
+                                        )
+                                    };
+                                    let permissions =
+                                        Permissions::from_bits(permissions as u16)
+                                            .ok_or_else(|| {
+                                                anyhow!("invalid permission bits")
+                                            })?;
+                                    let age = std::cmp::max(
+                                        u64::saturating_sub(now, timestamp),
+                                        u64::saturating_sub(timestamp, now),
+                                    );
+                                    if age > 300
+                                        || !permissions.contains(Permissions::SUBSCRIBE)
+                                        || &*token != &expected[mem::size_of::<u64>()..]
+                                        || (!principal.is_empty()
+                                            && !set_principal(
+                                                &mut pb,
+                                                client,
+                                                &principal,
+                                                matches!(auth, Auth::Token(_)),
+                                            ))
+                                    {
+                                        debug!("subscribe permission denied");
+                                        con.queue_send(&From::Denied(path))?
+                                    } else {
+                                        subscribe(
+                                            &mut *pb,
+                                            con,
+                                            client,
+                                            path,
+                                            permissions,
+                                            deferred_subs,
+                                        )?
+                                    }
                                 }
                             }
-                        },
+                        }
                     }
                 }
                 Write(id, v, r) => write(
@@ -1396,9 +1490,10 @@ fn client_arrived(publisher: &PublisherWeak) {
 async fn hello_client(
     publisher: &PublisherWeak,
     secrets: &Arc<RwLock<HashMap<SocketAddr, u128, FxBuildHasher>>>,
-    con: &mut Channel<ServerCtx>,
+    con: &mut Channel<Cipher<ServerCtx>>,
     auth: &Auth,
-) -> Result<()> {
+    encryption: Encryption,
+) -> Result<Principal> {
     use protocol::publisher::Hello::{self, *};
     debug!("hello_client");
     // negotiate protocol version
@@ -1407,13 +1502,17 @@ async fn hello_client(
     debug!("protocol version {}", _ver);
     let hello: Hello = con.receive().await?;
     debug!("hello_client received {:?}", hello);
-    match hello {
+    let principal = match hello {
+        Anonymous if encryption == Encryption::Noise => {
+            bail!("encryption is required")
+        }
         Anonymous => {
             con.send_one(&Anonymous).await?;
             client_arrived(publisher);
+            Principal::Anonymous
         }
         Token(tok) => match auth {
-            Auth::Anonymous => bail!("authentication not supported"),
+            Auth::Anonymous | Auth::Token(_) => bail!("authentication not supported"),
             Auth::Krb5 { upn, spn } => {
                 let p = spn.as_ref().or(upn.as_ref()).map(|s| s.as_str());
                 let ctx = os::create_server_ctx(p)?;
@@ -1421,11 +1520,21 @@ async fn hello_client(
                     .step(Some(&*tok))?
                     .map(|b| utils::bytes(&*b))
                     .ok_or_else(|| anyhow!("expected step to generate a token"))?;
+                let name = Chars::from(ctx.client()?);
                 con.send_one(&Token(tok)).await?;
-                con.set_ctx(ctx).await;
+                con.set_ctx(Cipher::Krb5(ctx)).await;
                 client_arrived(publisher);
+                // the groups arrive with the first subscription token
+                Principal::User { name, groups: Vec::new() }
             }
         },
+        Noise(msg) => {
+            let (ctx, reply) = noise::respond(&msg)?;
+            con.send_one(&Noise(reply)).await?;
+            con.set_ctx(Cipher::Noise(ctx)).await;
+            client_arrived(publisher);
+            Principal::Anonymous
+        }
         ResolverAuthenticate(id, _) => {
             info!("hello_client processing listener ownership check from resolver");
             let secret =
@@ -1434,8 +1543,8 @@ async fn hello_client(
             con.send_one(&ResolverAuthenticate(id, reply)).await?;
             bail!("resolver authentication complete");
         }
-    }
-    Ok(())
+    };
+    Ok(principal)
 }
 
 async fn client_loop(
@@ -1445,8 +1554,9 @@ async fn client_loop(
     updates: Receiver<(Option<Duration>, Pooled<Vec<ToClientMsg>>)>,
     s: TcpStream,
     desired_auth: Auth,
+    encryption: Encryption,
 ) -> Result<()> {
-    let mut con: Channel<ServerCtx> = Channel::new(s);
+    let mut con: Channel<Cipher<ServerCtx>> = Channel::new(s);
     let mut batch: Vec<publisher::To> = Vec::new();
     let mut write_batches: HashMap<
         ChanId,
@@ -1460,7 +1570,13 @@ async fn client_loop(
     let mut deferred_subs_batch: Vec<(Path, Permissions)> = Vec::new();
     // make sure the deferred subs stream never ends
     deferred_subs.inner_mut().push(Box::new(stream::pending()));
-    hello_client(&t, &secrets, &mut con, &desired_auth).await?;
+    let principal =
+        hello_client(&t, &secrets, &mut con, &desired_auth, encryption).await?;
+    if let Some(t) = t.upgrade() {
+        if let Some(cl) = t.0.lock().clients.get_mut(&client) {
+            cl.principal = Arc::new(principal);
+        }
+    }
     loop {
         select_biased! {
             _ = hb.tick().fuse() => {
@@ -1551,6 +1667,7 @@ async fn accept_loop(
     serv: TcpListener,
     stop: oneshot::Receiver<()>,
     desired_auth: Auth,
+    encryption: Encryption,
 ) {
     let mut stop = stop.fuse();
     loop {
@@ -1574,11 +1691,13 @@ async fn accept_loop(
                         pb.clients.insert(clid, Client {
                             msg_queue: tx,
                             subscribed: HashMap::with_hasher(FxBuildHasher::default()),
+                            principal: Arc::new(Principal::Anonymous),
                         });
                         let desired_auth = desired_auth.clone();
                         task::spawn(async move {
                             let r = client_loop(
-                                t_weak.clone(), secrets, clid, rx, s, desired_auth
+                                t_weak.clone(), secrets, clid, rx, s, desired_auth,
+                                encryption
                             ).await;
                             info!("accept_loop client shutdown {:?}", r);
                             if let Some(t) = t_weak.upgrade() {
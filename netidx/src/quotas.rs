@@ -0,0 +1,214 @@
+use crate::{
+    auth::{Entity, Principal, UserInfo},
+    config::{Limit, Limits},
+};
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::{
+    cmp::min,
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Who a limit applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Key {
+    User(Entity),
+    Addr(IpAddr),
+}
+
+impl Key {
+    /// The key for an authenticated user, anonymous users have none.
+    pub(crate) fn user(uifo: &UserInfo) -> Option<Key> {
+        match uifo.principal {
+            Principal::Anonymous => None,
+            Principal::User { .. } => Some(Key::User(uifo.id)),
+        }
+    }
+
+    fn limit<'a>(&self, limits: &'a Limits) -> &'a Limit {
+        match self {
+            Key::User(_) => &limits.per_user,
+            Key::Addr(_) => &limits.per_addr,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Usage {
+    connections: usize,
+    published: usize,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Usage {
+    fn new(limit: &Limit) -> Self {
+        Usage {
+            connections: 0,
+            published: 0,
+            tokens: limit.requests_per_second.unwrap_or(0) as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    // Nothing is using the key, and its bucket is full, so
+    // forgetting it doesn't give anyone any more requests.
+    fn idle(&mut self, limit: &Limit) -> bool {
+        self.connections == 0
+            && self.published == 0
+            && self.available(limit) as f64
+                >= limit.requests_per_second.map(|r| r as f64).unwrap_or(0.)
+    }
+
+    // refill the bucket, returning the number of requests it allows
+    fn available(&mut self, limit: &Limit) -> usize {
+        match limit.requests_per_second {
+            None => usize::MAX,
+            Some(rate) => {
+                let now = Instant::now();
+                let rate = rate as f64;
+                let elapsed = now.duration_since(self.refilled).as_secs_f64();
+                self.tokens = f64::min(rate, self.tokens + elapsed * rate);
+                self.refilled = now;
+                self.tokens as usize
+            }
+        }
+    }
+
+    fn take(&mut self, limit: &Limit, n: usize) {
+        if limit.requests_per_second.is_some() {
+            self.tokens -= n as f64;
+        }
+    }
+}
+
+// the keys a request from `uifo` at `addr` is counted against
+fn keys(uifo: &UserInfo, addr: IpAddr) -> impl Iterator<Item = Key> {
+    Key::user(uifo).into_iter().chain(Some(Key::Addr(addr)))
+}
+
+// how often to look for keys whose buckets have filled up since
+// they were last used
+const SWEEP: Duration = Duration::from_secs(1);
+
+struct QuotasInner {
+    limits: Limits,
+    usage: HashMap<Key, Usage>,
+    swept: Instant,
+}
+
+impl QuotasInner {
+    fn usage(&mut self, key: Key) -> &mut Usage {
+        let limits = &self.limits;
+        self.usage.entry(key).or_insert_with(|| Usage::new(key.limit(limits)))
+    }
+
+    fn gc(&mut self, key: Key) {
+        let limit = *key.limit(&self.limits);
+        if self.usage.get_mut(&key).map(|u| u.idle(&limit)).unwrap_or(false) {
+            self.usage.remove(&key);
+        }
+        let now = Instant::now();
+        if now.duration_since(self.swept) >= SWEEP {
+            let limits = &self.limits;
+            self.usage.retain(|k, u| !u.idle(k.limit(limits)));
+            self.swept = now;
+        }
+    }
+}
+
+/// The resources used by each user and client address, checked
+/// against the configured limits. Cheap to clone.
+#[derive(Clone)]
+pub(crate) struct Quotas(Arc<Mutex<QuotasInner>>);
+
+impl Quotas {
+    pub(crate) fn new(limits: Limits) -> Self {
+        Quotas(Arc::new(Mutex::new(QuotasInner {
+            limits,
+            usage: HashMap::new(),
+            swept: Instant::now(),
+        })))
+    }
+
+    pub(crate) fn set_limits(&self, limits: Limits) {
+        self.0.lock().limits = limits;
+    }
+
+    /// Count a new connection for `key`, or fail if it already has
+    /// the maximum.
+    pub(crate) fn connect(&self, key: Key) -> Result<()> {
+        let mut inner = self.0.lock();
+        let max = key.limit(&inner.limits).max_connections;
+        let usage = inner.usage(key);
+        if max.map(|max| usage.connections < max).unwrap_or(true) {
+            usage.connections += 1;
+            Ok(())
+        } else {
+            inner.gc(key);
+            bail!("too many connections for {:?}", key)
+        }
+    }
+
+    pub(crate) fn disconnect(&self, key: Key) {
+        let mut inner = self.0.lock();
+        if let Some(usage) = inner.usage.get_mut(&key) {
+            usage.connections = usage.connections.saturating_sub(1);
+        }
+        inner.gc(key)
+    }
+
+    /// Take `n` requests from the rate limits of the user and the
+    /// client address, returning how many of them are allowed.
+    pub(crate) fn requests(&self, uifo: &UserInfo, addr: IpAddr, n: usize) -> usize {
+        let mut inner = self.0.lock();
+        let mut allowed = n;
+        for key in keys(uifo, addr) {
+            let limit = *key.limit(&inner.limits);
+            allowed = min(allowed, inner.usage(key).available(&limit));
+        }
+        for key in keys(uifo, addr) {
+            let limit = *key.limit(&inner.limits);
+            inner.usage(key).take(&limit, allowed);
+            inner.gc(key);
+        }
+        allowed
+    }
+
+    /// How many more paths the user may publish from `addr`.
+    pub(crate) fn publish_allowance(&self, uifo: &UserInfo, addr: IpAddr) -> usize {
+        let inner = self.0.lock();
+        keys(uifo, addr).fold(usize::MAX, |allowance, key| {
+            match key.limit(&inner.limits).max_published {
+                None => allowance,
+                Some(max) => {
+                    let published = inner.usage.get(&key).map(|u| u.published);
+                    min(allowance, max.saturating_sub(published.unwrap_or(0)))
+                }
+            }
+        })
+    }
+
+    /// Record that the user added and removed published paths at
+    /// `addr`.
+    pub(crate) fn published(
+        &self,
+        uifo: &UserInfo,
+        addr: IpAddr,
+        added: usize,
+        removed: usize,
+    ) {
+        if added == removed {
+            return;
+        }
+        let mut inner = self.0.lock();
+        for key in keys(uifo, addr) {
+            let usage = inner.usage(key);
+            usage.published = (usage.published + added).saturating_sub(removed);
+            inner.gc(key);
+        }
+    }
+}
@@ -1,23 +1,30 @@
 use crate::{
-    config::Config,
+    config::{self, Config},
+    noise,
     pack::Z64,
     path::Path,
     pool::{Pool, Pooled},
-    protocol::resolver::{FromRead, FromWrite, Referral, ToRead, ToWrite},
+    protocol::resolver::{Admin, FromRead, FromWrite, Referral, ToRead, ToWrite},
     resolver_single::{
-        ResolverRead as SingleRead, ResolverWrite as SingleWrite, RAWFROMREADPOOL,
+        self, ResolverRead as SingleRead, ResolverWrite as SingleWrite, RAWFROMREADPOOL,
         RAWFROMWRITEPOOL,
     },
 };
 pub use crate::{
     protocol::{
         glob::{Glob, GlobSet},
-        resolver::{Resolved, Table},
+        resolver::{Changed, Resolved, Session, Table},
     },
     resolver_single::Auth,
 };
 use anyhow::Result;
-use futures::{channel::oneshot, future};
+use bytes::Bytes;
+use futures::{
+    channel::{mpsc, oneshot},
+    future,
+    prelude::*,
+    select_biased, stream,
+};
 use fxhash::FxBuildHasher;
 use parking_lot::{Mutex, RwLock};
 use std::{
@@ -34,7 +41,7 @@ use std::{
     sync::Arc,
     time::Duration,
 };
-use tokio::time::Instant;
+use tokio::{task, time::Instant};
 
 const MAX_REFERRALS: usize = 128;
 
@@ -46,7 +53,10 @@ impl ToPath for ToRead {
     fn path(&self) -> Option<&Path> {
         match self {
             ToRead::List(p) | ToRead::Table(p) | ToRead::Resolve(p) => Some(p),
-            ToRead::ListMatching(_) | ToRead::GetChangeNr(_) => None,
+            ToRead::ListMatching(_)
+            | ToRead::GetChangeNr(_)
+            | ToRead::Admin(_)
+            | ToRead::Watch(_) => None,
         }
     }
 }
@@ -177,6 +187,7 @@ where
     fn new(
         resolver: Arc<Referral>,
         desired_auth: Auth,
+        noise_key: Option<Bytes>,
         writer_addr: SocketAddr,
         secrets: Arc<RwLock<HashMap<SocketAddr, u128, FxBuildHasher>>>,
     ) -> Self;
@@ -190,10 +201,11 @@ impl Connection<ToRead, FromRead> for SingleRead {
     fn new(
         resolver: Arc<Referral>,
         desired_auth: Auth,
+        noise_key: Option<Bytes>,
         _writer_addr: SocketAddr,
         _secrets: Arc<RwLock<HashMap<SocketAddr, u128, FxBuildHasher>>>,
     ) -> Self {
-        SingleRead::new(resolver, desired_auth)
+        SingleRead::new(resolver, desired_auth, noise_key)
     }
 
     fn send(
@@ -208,10 +220,11 @@ impl Connection<ToWrite, FromWrite> for SingleWrite {
     fn new(
         resolver: Arc<Referral>,
         desired_auth: Auth,
+        noise_key: Option<Bytes>,
         writer_addr: SocketAddr,
         secrets: Arc<RwLock<HashMap<SocketAddr, u128, FxBuildHasher>>>,
     ) -> Self {
-        SingleWrite::new(resolver, desired_auth, writer_addr, secrets)
+        SingleWrite::new(resolver, desired_auth, noise_key, writer_addr, secrets)
     }
 
     fn send(
@@ -241,6 +254,9 @@ where
 {
     router: Router,
     desired_auth: Auth,
+    // the public key of the servers in our config, we don't know
+    // the keys of other clusters, so they never get our token
+    noise_key: Option<Bytes>,
     default: Arc<Referral>,
     by_referral: HashMap<Arc<Referral>, C>,
     writer_addr: SocketAddr,
@@ -257,6 +273,14 @@ where
     T: ToPath + Clone + Send + Sync + 'static,
     F: ToReferral + Clone + Send + Sync + 'static,
 {
+    fn noise_key(&self, r: &Referral) -> Option<Bytes> {
+        if r == &*self.default {
+            self.noise_key.clone()
+        } else {
+            None
+        }
+    }
+
     fn send_to_server(
         &mut self,
         server: Option<Arc<Referral>>,
@@ -269,6 +293,7 @@ where
                 let mut con = C::new(
                     r.clone(),
                     self.desired_auth.clone(),
+                    self.noise_key(&r),
                     self.writer_addr,
                     self.secrets.clone(),
                 );
@@ -301,9 +326,14 @@ where
         let secrets =
             Arc::new(RwLock::new(HashMap::with_hasher(FxBuildHasher::default())));
         let router = Router::new();
+        let noise_key = match &default.auth {
+            config::Auth::Token { public_key, .. } => noise::parse_key(public_key).ok(),
+            config::Auth::Anonymous | config::Auth::Krb5(_) => None,
+        };
         ResolverWrap(Arc::new(Mutex::new(ResolverWrapInner {
             router,
             desired_auth,
+            noise_key,
             default: Arc::new(default.into()),
             by_referral: HashMap::new(),
             writer_addr,
@@ -360,6 +390,12 @@ where
     }
 }
 
+/// Tracks whether anything under a path changed by polling the
+/// resolver. To discover paths as they are published use
+/// `ResolverRead::watch` instead, which pushes changes. Polling is
+/// still useful when an up to date answer is needed at a particular
+/// moment, as in netidx-protocols' election, since a watch only
+/// guarantees changes arrive eventually.
 #[derive(Debug, Clone)]
 pub struct ChangeTracker {
     path: Path,
@@ -380,18 +416,22 @@ impl ChangeTracker {
 }
 
 #[derive(Debug, Clone)]
-pub struct ResolverRead(ResolverWrap<SingleRead, ToRead, FromRead>);
+pub struct ResolverRead(ResolverWrap<SingleRead, ToRead, FromRead>, Duration);
 
 impl ResolverRead {
     pub fn new(default: Config, desired_auth: Auth) -> Self {
-        ResolverRead(ResolverWrap::new(
-            default,
-            desired_auth,
-            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
-            RAWFROMREADPOOL.clone(),
-            FROMREADPOOL.clone(),
-            TOREADPOOL.clone(),
-        ))
+        let reader_ttl = default.reader_ttl;
+        ResolverRead(
+            ResolverWrap::new(
+                default,
+                desired_auth,
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+                RAWFROMREADPOOL.clone(),
+                FROMREADPOOL.clone(),
+                TOREADPOOL.clone(),
+            ),
+            reader_ttl,
+        )
     }
 
     /// send the specified messages to the resolver, and return the answers (in send order)
@@ -553,6 +593,55 @@ impl ResolverRead {
         Ok(res)
     }
 
+    /// Watch the cluster for published paths matching `globs`. The
+    /// paths that already match are reported as added when the watch
+    /// starts, and then the paths published and unpublished since, as
+    /// they happen. Structural paths are never reported, even if
+    /// `globs` isn't published only.
+    ///
+    /// This is meant to replace polling `check_changed` or
+    /// `list_matching` to discover new paths. Each server watched
+    /// holds a connection open, and reconnects are handled
+    /// internally, reporting only what changed while disconnected. It
+    /// is assumed that every server in the cluster uses the same
+    /// `reader_ttl`. The stream ends if the watch is denied, it never
+    /// returns an error.
+    pub fn watch(&self, globs: GlobSet) -> mpsc::UnboundedReceiver<Changed> {
+        let (tx, rx) = mpsc::unbounded();
+        let (default, desired_auth, noise_key) = {
+            let inner = self.0 .0.lock();
+            (inner.default.clone(), inner.desired_auth.clone(), inner.noise_key.clone())
+        };
+        let ttl = self.1;
+        task::spawn(async move {
+            let (ref_tx, mut ref_rx) = mpsc::unbounded();
+            let mut watching: HashSet<Arc<Referral>> = HashSet::new();
+            let mut running = stream::FuturesUnordered::new();
+            let mut referral = Some(default.clone());
+            loop {
+                if let Some(r) = referral.take().filter(|r| watching.insert(r.clone())) {
+                    let noise_key = if r == default { noise_key.clone() } else { None };
+                    running.push(task::spawn(resolver_single::watch(
+                        r,
+                        desired_auth.clone(),
+                        noise_key,
+                        globs.clone(),
+                        ttl,
+                        ref_tx.clone(),
+                        tx.clone(),
+                    )));
+                }
+                select_biased! {
+                    r = ref_rx.select_next_some() => referral = Some(Arc::new(r)),
+                    r = running.next() => if r.is_none() {
+                        break
+                    },
+                }
+            }
+        });
+        rx
+    }
+
     pub async fn table(&self, path: Path) -> Result<Table> {
         let mut to = RAWTOREADPOOL.take();
         to.push(ToRead::Table(path));
@@ -566,6 +655,59 @@ impl ResolverRead {
             }
         }
     }
+
+    async fn admin(&self, req: Admin) -> Result<FromRead> {
+        let mut to = RAWTOREADPOOL.take();
+        to.push(ToRead::Admin(req));
+        let mut result = self.send(&to).await?;
+        if result.len() != 1 {
+            bail!("expected 1 result from admin got {}", result.len());
+        }
+        match result.pop().unwrap() {
+            FromRead::Denied => bail!("admin permission denied"),
+            FromRead::Error(e) => bail!("admin request failed {}", e),
+            m => Ok(m),
+        }
+    }
+
+    /// List the clients of the resolver server, including publishers
+    /// that have disconnected but whose paths have not expired
+    /// yet. Admin requests are answered by whichever server of the
+    /// cluster they are sent to, so to see every client, ask every
+    /// server. Requires the admin permission on the server's root,
+    /// servers with anonymous auth have no permissions and deny every
+    /// admin request.
+    pub async fn sessions(&self) -> Result<Pooled<Vec<Session>>> {
+        match self.admin(Admin::ListSessions).await? {
+            FromRead::Sessions(sessions) => Ok(sessions),
+            m => bail!("unexpected result from sessions {:?}", m),
+        }
+    }
+
+    /// List the paths the publisher at `write_addr` has published on
+    /// the resolver server. Requires the admin permission on the
+    /// server's root.
+    pub async fn published(&self, write_addr: SocketAddr) -> Result<Pooled<Vec<Path>>> {
+        match self.admin(Admin::Published(write_addr)).await? {
+            FromRead::List(paths) => Ok(paths),
+            m => bail!("unexpected result from published {:?}", m),
+        }
+    }
+
+    /// Disconnect the publisher at `write_addr` and unpublish
+    /// everything it published, without waiting for `writer_ttl` to
+    /// expire. Returns the unpublished paths. If the publisher is
+    /// still alive it will reconnect and publish them again. Requires
+    /// the admin permission on the server's root.
+    pub async fn clear_publisher(
+        &self,
+        write_addr: SocketAddr,
+    ) -> Result<Pooled<Vec<Path>>> {
+        match self.admin(Admin::Clear(write_addr)).await? {
+            FromRead::List(paths) => Ok(paths),
+            m => bail!("unexpected result from clear_publisher {:?}", m),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1,34 +1,42 @@
 use crate::{
-    auth::{UserInfo, ANONYMOUS},
-    channel::Channel,
+    audit::Audit,
+    auth::{self, Permissions, Tokens, UserInfo, ANONYMOUS},
+    channel::{Channel, Cipher},
     chars::Chars,
-    config,
+    config, noise,
     os::{Krb5ServerCtx, ServerCtx},
     pack::Pack,
     pool::{Pool, Pooled},
     protocol::{
+        glob::GlobSet,
         publisher,
         resolver::{
-            ClientAuthRead, ClientAuthWrite, ClientHello, ClientHelloWrite, CtxId,
-            FromWrite, ReadyForOwnershipCheck, Secret, ServerAuthWrite, ServerHelloRead,
-            ServerHelloWrite, ToRead, ToWrite,
+            self, Admin, Changed, ClientAuthRead, ClientAuthWrite, ClientHello,
+            ClientHelloWrite, CtxId, FromRead, FromWrite, ReadyForOwnershipCheck, Secret,
+            ServerAuthWrite, ServerHelloRead, ServerHelloWrite, Session, ToRead, ToWrite,
         },
     },
-    secstore::SecStore,
+    quotas::{Key, Quotas},
+    secstore::{self, Mechanism, SecStore},
     shard_resolver_store::Store,
     utils,
 };
 use anyhow::Result;
 use bytes::{Buf, Bytes};
-use futures::{channel::oneshot, prelude::*, select_biased};
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+    select_biased,
+};
 use log::{debug, info, warn};
 use parking_lot::Mutex;
 use std::{
-    collections::{HashMap, HashSet},
-    mem,
+    cmp,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, mem,
     net::SocketAddr,
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 use tokio::{
     net::{TcpListener, TcpStream},
@@ -38,37 +46,150 @@ use tokio::{
 
 atomic_id!(CId);
 
-#[derive(Debug, Clone)]
-struct CTracker(Arc<Mutex<HashSet<CId>>>);
+struct Conn {
+    addr: SocketAddr,
+    keys: Vec<Key>,
+    // the user, and the write address of publishers, once the client
+    // has authenticated
+    session: Option<(Arc<UserInfo>, Option<SocketAddr>)>,
+}
+
+// tracks open connections, who they are, and the quota keys each one
+// is counted against
+#[derive(Clone)]
+struct CTracker {
+    open: Arc<Mutex<HashMap<CId, Conn>>>,
+    quotas: Quotas,
+}
 
 impl CTracker {
-    fn new() -> Self {
-        CTracker(Arc::new(Mutex::new(HashSet::new())))
+    fn new(quotas: Quotas) -> Self {
+        CTracker { open: Arc::new(Mutex::new(HashMap::new())), quotas }
     }
 
-    fn open(&self) -> CId {
+    fn open(&self, addr: SocketAddr) -> Result<CId> {
+        let key = Key::Addr(addr.ip());
+        self.quotas.connect(key)?;
         let id = CId::new();
-        self.0.lock().insert(id);
-        id
+        self.open.lock().insert(id, Conn { addr, keys: vec![key], session: None });
+        Ok(id)
+    }
+
+    // count the connection against the user once we know who it is
+    fn authenticated(
+        &self,
+        id: CId,
+        uifo: &Arc<UserInfo>,
+        write_addr: Option<SocketAddr>,
+    ) -> Result<()> {
+        let key = Key::user(uifo);
+        if let Some(key) = key {
+            self.quotas.connect(key)?;
+        }
+        match self.open.lock().get_mut(&id) {
+            Some(conn) => {
+                conn.keys.extend(key);
+                conn.session = Some((uifo.clone(), write_addr));
+            }
+            None => {
+                if let Some(key) = key {
+                    self.quotas.disconnect(key)
+                }
+            }
+        }
+        Ok(())
     }
 
     fn close(&self, id: CId) {
-        self.0.lock().remove(&id);
+        if let Some(conn) = self.open.lock().remove(&id) {
+            for key in conn.keys {
+                self.quotas.disconnect(key)
+            }
+        }
     }
 
     fn num_open(&self) -> usize {
-        self.0.lock().len()
+        self.open.lock().len()
     }
+
+    // the authenticated connections, and the publishers in `clinfos`
+    // that are no longer connected
+    fn sessions(&self, clinfos: &Clinfos) -> Pooled<Vec<Session>> {
+        let mut sessions = Pooled::orphan(Vec::new());
+        for conn in self.open.lock().values() {
+            if let Some((uifo, write_addr)) = &conn.session {
+                sessions.push(Session {
+                    addr: Some(conn.addr),
+                    write_addr: *write_addr,
+                    user: uifo.principal.name().cloned(),
+                })
+            }
+        }
+        let connected =
+            sessions.iter().filter_map(|s| s.write_addr).collect::<HashSet<_>>();
+        for (write_addr, cl) in clinfos.0.lock().iter() {
+            if let ClientInfo::Running(_, uifo) = cl {
+                if !connected.contains(write_addr) {
+                    sessions.push(Session {
+                        addr: None,
+                        write_addr: Some(*write_addr),
+                        user: uifo.principal.name().cloned(),
+                    })
+                }
+            }
+        }
+        sessions
+    }
+}
+
+const RATE_LIMITED: &str = "rate limit exceeded";
+
+// why a write client loop is asked to stop
+enum Stop {
+    // a new connection from the same publisher took over
+    Replaced,
+    // an admin asked to clear the publisher, reply when it's done
+    Clear(oneshot::Sender<()>),
 }
 
 enum ClientInfo {
-    Running(oneshot::Sender<()>),
+    Running(oneshot::Sender<Stop>, Arc<UserInfo>),
     CleaningUp(Vec<oneshot::Sender<()>>),
 }
 
 #[derive(Clone)]
 struct Clinfos(Arc<Mutex<HashMap<SocketAddr, ClientInfo>>>);
 
+// unpublish everything the publisher at `write_addr` published. It
+// can't reconnect until this is done.
+async fn clear_publisher(
+    clinfos: &Clinfos,
+    secstore: Option<&SecStore>,
+    store: &mut Store,
+    uifo: Arc<UserInfo>,
+    write_addr: SocketAddr,
+) -> Result<()> {
+    {
+        let mut inner = clinfos.0.lock();
+        let waiters = match inner.remove(&write_addr) {
+            None => Vec::new(),
+            Some(ClientInfo::CleaningUp(waiters)) => waiters,
+            Some(ClientInfo::Running(stop, _)) => {
+                let _ = stop.send(Stop::Replaced);
+                Vec::new()
+            }
+        };
+        inner.insert(write_addr, ClientInfo::CleaningUp(waiters));
+        if let Some(secstore) = secstore {
+            secstore.remove(&write_addr);
+        }
+    }
+    store.handle_clear(uifo, write_addr).await?;
+    // wakes up anyone waiting to reconnect
+    clinfos.0.lock().remove(&write_addr);
+    Ok(())
+}
+
 lazy_static! {
     static ref WRITE_BATCHES: Pool<Vec<ToWrite>> = Pool::new(5000, 100000);
     static ref READ_BATCHES: Pool<Vec<ToRead>> = Pool::new(5000, 100000);
@@ -78,13 +199,15 @@ async fn client_loop_write(
     cfg: Arc<config::Config>,
     clinfos: Clinfos,
     ctracker: CTracker,
+    quotas: Quotas,
     connection_id: CId,
     mut store: Store,
-    con: Channel<ServerCtx>,
+    con: Channel<Cipher<ServerCtx>>,
     secstore: Option<SecStore>,
     server_stop: oneshot::Receiver<()>,
-    rx_stop: oneshot::Receiver<()>,
+    rx_stop: oneshot::Receiver<Stop>,
     uifo: Arc<UserInfo>,
+    expires: u64,
     write_addr: SocketAddr,
 ) -> Result<()> {
     let mut con = Some(con);
@@ -94,7 +217,7 @@ async fn client_loop_write(
     let mut act = false;
     let mut timeout = time::interval_at(Instant::now() + cfg.writer_ttl, cfg.writer_ttl);
     async fn receive_batch(
-        con: &mut Option<Channel<ServerCtx>>,
+        con: &mut Option<Channel<Cipher<ServerCtx>>>,
         batch: &mut Vec<ToWrite>,
     ) -> Result<()> {
         match con {
@@ -105,31 +228,30 @@ async fn client_loop_write(
     'main: loop {
         select_biased! {
             _ = server_stop => break Ok(()),
-            _ = rx_stop => break Ok(()),
+            stop = rx_stop => match stop {
+                Err(_) | Ok(Stop::Replaced) => break Ok(()),
+                Ok(Stop::Clear(done)) => {
+                    drop(con);
+                    ctracker.close(connection_id);
+                    let secstore = secstore.as_ref();
+                    clear_publisher(&clinfos, secstore, &mut store, uifo, write_addr)
+                        .await?;
+                    let _ = done.send(());
+                    break Ok(())
+                }
+            },
             _ = timeout.tick().fuse() => {
-                if act {
+                if act && expires > secstore::now() {
                     act = false;
                 } else {
                     drop(con);
                     ctracker.close(connection_id);
-                    {
-                        let mut inner = clinfos.0.lock();
-                        match inner.remove(&write_addr) {
-                            None => (),
-                            Some(ClientInfo::CleaningUp(_)) => unreachable!(),
-                            Some(ClientInfo::Running(stop)) => {
-                                let _ = stop.send(());
-                            }
-                        }
-                        let state = ClientInfo::CleaningUp(Vec::new());
-                        inner.insert(write_addr, state);
-                        if let Some(secstore) = secstore {
-                            secstore.remove(&write_addr);
-                        }
+                    let secstore = secstore.as_ref();
+                    clear_publisher(&clinfos, secstore, &mut store, uifo, write_addr)
+                        .await?;
+                    if act {
+                        bail!("write client token expired");
                     }
-                    store.handle_clear(uifo.clone(), write_addr).await?;
-                    let mut inner = clinfos.0.lock();
-                    inner.remove(&write_addr);
                     bail!("write client timed out");
                 }
             },
@@ -145,6 +267,9 @@ async fn client_loop_write(
                     if batch.len() == 1 && batch[0] == ToWrite::Heartbeat {
                         continue 'main
                     }
+                    let allowed = quotas.requests(&uifo, write_addr.ip(), batch.len());
+                    let limited = batch.len() - allowed;
+                    batch.truncate(allowed);
                     let c = con.as_mut().unwrap();
                     while let Some((i, _)) =
                         batch.iter().enumerate().find(|(_, m)| *m == &ToWrite::Clear)
@@ -179,6 +304,7 @@ async fn client_loop_write(
                         Some(c),
                         uifo.clone(),
                         write_addr,
+                        false,
                         batch.drain(..)
                     ).await {
                         warn!("handle_write_batch failed {}", e);
@@ -186,30 +312,91 @@ async fn client_loop_write(
                         ctracker.close(connection_id);
                         continue 'main;
                     }
+                    if limited > 0 {
+                        let c = con.as_mut().unwrap();
+                        for _ in 0..limited {
+                            c.queue_send(&FromWrite::Error(RATE_LIMITED.into()))?
+                        }
+                        c.flush().await?
+                    }
                 }
             },
         }
     }
 }
 
+// connect to the publisher at `write_addr` and make sure it knows the
+// secret we just gave to the client that claims to own it.
+async fn check_ownership(
+    cfg: &Arc<config::Config>,
+    resolver_id: SocketAddr,
+    write_addr: SocketAddr,
+    secret: u128,
+) -> Result<()> {
+    info!("hello_write connecting to {:?} for listener ownership check", write_addr);
+    let mut con: Channel<ServerCtx> = Channel::new(
+        time::timeout(cfg.hello_timeout, TcpStream::connect(write_addr)).await??,
+    );
+    time::timeout(cfg.hello_timeout, con.send_one(&1u64)).await??;
+    // we will need to select a protocol version here when
+    // we have more than one.
+    let _version: u64 = time::timeout(cfg.hello_timeout, con.receive()).await??;
+    use publisher::Hello as PHello;
+    let m = PHello::ResolverAuthenticate(resolver_id, Bytes::new());
+    time::timeout(cfg.hello_timeout, con.send_one(&m)).await??;
+    match time::timeout(cfg.hello_timeout, con.receive()).await?? {
+        PHello::Anonymous | PHello::Token(_) | PHello::Noise(_) => {
+            bail!("listener ownership check unexpected response")
+        }
+        PHello::ResolverAuthenticate(_, mut tok) => {
+            if tok.len() < 8 {
+                bail!("listener ownership check buffer short");
+            }
+            let expected =
+                utils::make_sha3_token(Some(tok.get_u64()), &[&(!secret).to_be_bytes()]);
+            if *tok != expected[mem::size_of::<u64>()..] {
+                bail!("listener ownership check failed");
+            }
+            info!("hello_write listener ownership check succeeded");
+            Ok(())
+        }
+    }
+}
+
+// finish the noise handshake a token client started, and then
+// receive it's token over the encrypted channel
+async fn accept_token(
+    cfg: &Arc<config::Config>,
+    con: &mut Channel<Cipher<ServerCtx>>,
+    secstore: &SecStore,
+    handshake: &[u8],
+) -> Result<Bytes> {
+    let (ctx, reply) = secstore.respond_token(handshake)?;
+    time::timeout(cfg.hello_timeout, con.send_one(&reply)).await??;
+    con.set_ctx(Cipher::Noise(ctx)).await;
+    time::timeout(cfg.hello_timeout, con.receive()).await?
+}
+
 async fn hello_client_write(
     cfg: Arc<config::Config>,
     clinfos: Clinfos,
     ctracker: CTracker,
+    quotas: Quotas,
     connection_id: CId,
     listen_addr: SocketAddr,
     store: Store,
-    mut con: Channel<ServerCtx>,
+    mut con: Channel<Cipher<ServerCtx>>,
     server_stop: oneshot::Receiver<()>,
     secstore: Option<SecStore>,
     resolver_id: SocketAddr,
+    version: u64,
     hello: ClientHelloWrite,
 ) -> Result<()> {
     info!("hello_write starting negotiation");
     debug!("hello_write client_hello: {:?}", hello);
     async fn send(
         cfg: &Arc<config::Config>,
-        con: &mut Channel<ServerCtx>,
+        con: &mut Channel<Cipher<ServerCtx>>,
         msg: impl Pack,
     ) -> Result<()> {
         Ok(time::timeout(cfg.hello_timeout, con.send_one(&msg)).await??)
@@ -220,7 +407,7 @@ async fn hello_client_write(
             let mut inner = clinfos.0.lock();
             match inner.get_mut(&hello.write_addr) {
                 None => break true,
-                Some(ClientInfo::Running(_)) => break false,
+                Some(ClientInfo::Running(_, _)) => break false,
                 Some(ClientInfo::CleaningUp(waiters)) => {
                     let (tx, rx) = oneshot::channel();
                     waiters.push(tx);
@@ -230,7 +417,7 @@ async fn hello_client_write(
         };
         let _ = rx.await;
     };
-    let uifo = match hello.auth {
+    let (uifo, expires) = match hello.auth {
         ClientAuthWrite::Anonymous => {
             let h = ServerHelloWrite {
                 ttl: cfg.writer_ttl.as_secs(),
@@ -241,7 +428,7 @@ async fn hello_client_write(
             info!("hello_write accepting Anonymous authentication");
             debug!("hello_write sending hello {:?}", h);
             send(&cfg, &mut con, h).await?;
-            ANONYMOUS.clone()
+            (ANONYMOUS.clone(), u64::MAX)
         }
         ClientAuthWrite::Reuse => match secstore {
             None => bail!("authentication not supported"),
@@ -257,9 +444,9 @@ async fn hello_client_write(
                     info!("hello_write reusing krb5 context");
                     debug!("hello_write sending {:?}", h);
                     send(&cfg, &mut con, h).await?;
-                    con.set_ctx(ctx.clone()).await;
+                    con.set_ctx(Cipher::Krb5(ctx.clone())).await;
                     info!("hello_write all traffic now encrypted");
-                    secstore.ifo(Some(&ctx.client()?))?
+                    (secstore.ifo(Some(&ctx.client()?)).await?, u64::MAX)
                 }
             },
         },
@@ -280,66 +467,58 @@ async fn hello_client_write(
                 info!("hello_write created context for {:?}", hello.write_addr);
                 debug!("hello_write sending {:?}", h);
                 send(&cfg, &mut con, h).await?;
-                con.set_ctx(ctx.clone()).await;
+                con.set_ctx(Cipher::Krb5(ctx.clone())).await;
                 info!("hello_write all traffic now encrypted");
                 send(&cfg, &mut con, Secret(secret)).await?;
                 let _: ReadyForOwnershipCheck =
                     time::timeout(cfg.hello_timeout, con.receive()).await??;
-                info!(
-                    "hello_write connecting to {:?} for listener ownership check",
-                    hello.write_addr
-                );
-                let mut con: Channel<ServerCtx> = Channel::new(
-                    time::timeout(
-                        cfg.hello_timeout,
-                        TcpStream::connect(hello.write_addr),
-                    )
-                    .await??,
-                );
-                time::timeout(cfg.hello_timeout, con.send_one(&1u64)).await??;
-                // we will need to select a protocol version here when
-                // we have more than one.
-                let _version: u64 =
+                check_ownership(&cfg, resolver_id, hello.write_addr, secret).await?;
+                let client = ctx.client()?;
+                let uifo = secstore.ifo(Some(&client)).await?;
+                let spn = spn.unwrap_or(Chars::from(client));
+                let ctx = Some(ctx.clone());
+                secstore.store(hello.write_addr, spn, secret, ctx, u64::MAX, version);
+                (uifo, u64::MAX)
+            }
+        },
+        ClientAuthWrite::Token(handshake) => match secstore {
+            None => bail!("authentication not supported"),
+            Some(ref secstore) => {
+                let token = accept_token(&cfg, &mut con, secstore, &handshake).await?;
+                info!("hello_write all traffic now encrypted");
+                let (uifo, secret, expires) = secstore.check_token(&token)?;
+                let h = ServerHelloWrite {
+                    ttl: cfg.writer_ttl.as_secs(),
+                    ttl_expired,
+                    resolver_id,
+                    auth: ServerAuthWrite::Token,
+                };
+                info!("hello_write accepted token for {:?}", hello.write_addr);
+                debug!("hello_write sending {:?}", h);
+                send(&cfg, &mut con, h).await?;
+                send(&cfg, &mut con, Secret(secret)).await?;
+                let _: ReadyForOwnershipCheck =
                     time::timeout(cfg.hello_timeout, con.receive()).await??;
-                use publisher::Hello as PHello;
-                let m = PHello::ResolverAuthenticate(resolver_id, Bytes::new());
-                time::timeout(cfg.hello_timeout, con.send_one(&m)).await??;
-                match time::timeout(cfg.hello_timeout, con.receive()).await?? {
-                    PHello::Anonymous | PHello::Token(_) => {
-                        bail!("listener ownership check unexpected response")
-                    }
-                    PHello::ResolverAuthenticate(_, mut tok) => {
-                        if tok.len() < 8 {
-                            bail!("listener ownership check buffer short");
-                        }
-                        let expected = utils::make_sha3_token(
-                            Some(tok.get_u64()),
-                            &[&(!secret).to_be_bytes()],
-                        );
-                        if &*tok != &expected[mem::size_of::<u64>()..] {
-                            bail!("listener ownership check failed");
-                        }
-                        let client = ctx.client()?;
-                        let uifo = secstore.ifo(Some(&client))?;
-                        let spn = spn.unwrap_or(Chars::from(client));
-                        info!("hello_write listener ownership check succeeded");
-                        secstore.store(hello.write_addr, spn, secret, ctx.clone());
-                        uifo
-                    }
-                }
+                check_ownership(&cfg, resolver_id, hello.write_addr, secret).await?;
+                let spn = Chars::new();
+                secstore.store(hello.write_addr, spn, secret, None, expires, version);
+                (uifo, expires)
             }
         },
     };
+    ctracker.authenticated(connection_id, &uifo, Some(hello.write_addr))?;
     let (tx_stop, rx_stop) = oneshot::channel();
     {
         let mut inner = clinfos.0.lock();
         match inner.get_mut(&hello.write_addr) {
             None => {
-                inner.insert(hello.write_addr, ClientInfo::Running(tx_stop));
+                let cl = ClientInfo::Running(tx_stop, uifo.clone());
+                inner.insert(hello.write_addr, cl);
             }
-            Some(ClientInfo::Running(cl)) => {
+            Some(ClientInfo::Running(cl, u)) => {
                 let cl = mem::replace(cl, tx_stop);
-                let _ = cl.send(());
+                *u = uifo.clone();
+                let _ = cl.send(Stop::Replaced);
             }
             Some(ClientInfo::CleaningUp(_)) => bail!("unexpected cleaning up"),
         }
@@ -348,6 +527,7 @@ async fn hello_client_write(
         cfg,
         clinfos,
         ctracker,
+        quotas,
         connection_id,
         store.clone(),
         con,
@@ -355,27 +535,107 @@ async fn hello_client_write(
         server_stop,
         rx_stop,
         uifo,
+        expires,
         hello.write_addr,
     )
     .await?)
 }
 
+// answer an admin request from `uifo`, which is only allowed with
+// the admin permission on the root of this server. Without a secstore
+// there are no permissions, so anonymous servers deny everything.
+async fn handle_admin(
+    cfg: &config::Config,
+    clinfos: &Clinfos,
+    ctracker: &CTracker,
+    secstore: Option<&SecStore>,
+    audit: Option<&Audit>,
+    store: &Store,
+    uifo: &UserInfo,
+    addr: SocketAddr,
+    req: Admin,
+) -> Result<FromRead> {
+    let allowed = secstore
+        .map(|s| s.pmap().allowed(cfg.root(), Permissions::ADMIN, uifo))
+        .unwrap_or(false);
+    if let Some(audit) = audit {
+        // things would need to be massively screwed for this to fail
+        let now =
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let op = match req {
+            Admin::ListSessions => "AdminListSessions",
+            Admin::Published(_) => "AdminPublished",
+            Admin::Clear(_) => "AdminClear",
+        };
+        audit.log(now, uifo, addr, op, cfg.root(), allowed)
+    }
+    if !allowed {
+        return Ok(FromRead::Denied);
+    }
+    Ok(match req {
+        Admin::ListSessions => FromRead::Sessions(ctracker.sessions(clinfos)),
+        Admin::Published(write_addr) => {
+            let mut paths = store.published(write_addr).await?;
+            paths.sort();
+            FromRead::List(paths)
+        }
+        Admin::Clear(write_addr) => {
+            let mut paths = store.published(write_addr).await?;
+            paths.sort();
+            let done = {
+                let mut inner = clinfos.0.lock();
+                match inner.remove(&write_addr) {
+                    Some(ClientInfo::Running(stop, _)) => {
+                        // hold off reconnects until the loop has cleared
+                        inner.insert(write_addr, ClientInfo::CleaningUp(Vec::new()));
+                        let (tx, rx) = oneshot::channel();
+                        let _ = stop.send(Stop::Clear(tx));
+                        Some(rx)
+                    }
+                    Some(cl @ ClientInfo::CleaningUp(_)) => {
+                        inner.insert(write_addr, cl);
+                        None
+                    }
+                    None => None,
+                }
+            };
+            match done {
+                None => FromRead::Error(format!("no publisher at {}", write_addr).into()),
+                Some(done) => {
+                    info!("admin {:?} cleared publisher {}", uifo.principal, write_addr);
+                    let _ = done.await;
+                    FromRead::List(paths)
+                }
+            }
+        }
+    })
+}
+
 async fn client_loop_read(
     cfg: Arc<config::Config>,
+    clinfos: Clinfos,
+    ctracker: CTracker,
+    quotas: Quotas,
     mut store: Store,
-    mut con: Channel<ServerCtx>,
+    mut con: Channel<Cipher<ServerCtx>>,
     server_stop: oneshot::Receiver<()>,
+    secstore: Option<SecStore>,
+    audit: Option<Audit>,
     uifo: Arc<UserInfo>,
+    expires: u64,
+    addr: SocketAddr,
 ) -> Result<()> {
     let mut batch = READ_BATCHES.take();
     let mut server_stop = server_stop.fuse();
     let mut act = false;
     let mut timeout = time::interval_at(Instant::now() + cfg.reader_ttl, cfg.reader_ttl);
-    loop {
+    let globs = 'main: loop {
         select_biased! {
-            _ = server_stop => break Ok(()),
+            _ = server_stop => return Ok(()),
             _ = timeout.tick().fuse() => {
-                if act {
+                if expires <= secstore::now() {
+                    bail!("client token expired");
+                } else if act {
                     act = false;
                 } else {
                     bail!("client timed out");
@@ -384,35 +644,140 @@ async fn client_loop_read(
             m = con.receive_batch(&mut batch).fuse() => {
                 m?;
                 act = true;
+                let allowed = quotas.requests(&uifo, addr.ip(), batch.len());
+                let limited = batch.len() - allowed;
+                batch.truncate(allowed);
+                // admin and watch requests are handled here, in order
+                // with the rest
+                while let Some(i) = batch
+                    .iter()
+                    .position(|m| matches!(m, ToRead::Admin(_) | ToRead::Watch(_)))
+                {
+                    let rest = batch.split_off(i + 1);
+                    let m = batch.pop();
+                    store.handle_batch_read(
+                        &mut con,
+                        uifo.clone(),
+                        addr,
+                        batch.drain(..)
+                    ).await?;
+                    match m {
+                        Some(ToRead::Admin(req)) => {
+                            let m = handle_admin(
+                                &cfg,
+                                &clinfos,
+                                &ctracker,
+                                secstore.as_ref(),
+                                audit.as_ref(),
+                                &store,
+                                &uifo,
+                                addr,
+                                req
+                            ).await?;
+                            con.queue_send(&m)?;
+                            con.flush().await?;
+                        }
+                        // the connection belongs to the watch from now on,
+                        // anything after it is ignored
+                        Some(ToRead::Watch(globs)) => break 'main globs,
+                        _ => unreachable!(),
+                    }
+                    batch = Pooled::orphan(rest);
+                }
                 store.handle_batch_read(
                     &mut con,
                     uifo.clone(),
+                    addr,
                     batch.drain(..)
                 ).await?;
+                if limited > 0 {
+                    for _ in 0..limited {
+                        con.queue_send(&FromRead::Error(RATE_LIMITED.into()))?
+                    }
+                    con.flush().await?
+                }
             },
         }
+    };
+    client_loop_watch(cfg, store, con, server_stop, uifo, expires, addr, globs).await
+}
+
+// send the watcher the paths matching `globs`, and then the changes
+// to them as they happen
+async fn client_loop_watch(
+    cfg: Arc<config::Config>,
+    store: Store,
+    mut con: Channel<Cipher<ServerCtx>>,
+    mut server_stop: future::Fuse<oneshot::Receiver<()>>,
+    uifo: Arc<UserInfo>,
+    expires: u64,
+    addr: SocketAddr,
+    globs: GlobSet,
+) -> Result<()> {
+    let (m, mut changes) = store.watch(uifo, addr, globs).await?;
+    con.send_one(&m).await?;
+    if let FromRead::Denied = m {
+        return Ok(());
+    }
+    let (mut read, mut write) = con.split();
+    let ttl = cfg.reader_ttl / 2;
+    let mut heartbeat = time::interval_at(Instant::now() + ttl, ttl);
+    loop {
+        select_biased! {
+            _ = server_stop => break Ok(()),
+            m = read.receive::<ToRead>().fuse() => match m {
+                Err(_) => break Ok(()),
+                Ok(_) => bail!("requests are not allowed on a watch connection"),
+            },
+            c = changes.next() => match c {
+                None => bail!("watcher fell behind, or the store shut down"),
+                Some(c) => {
+                    write.queue_send(&FromRead::Changed(c))?;
+                    while let Ok(c) = changes.try_recv() {
+                        write.queue_send(&FromRead::Changed(c))?;
+                    }
+                    write.flush().await?;
+                }
+            },
+            _ = heartbeat.tick().fuse() => {
+                if expires <= secstore::now() {
+                    bail!("client token expired")
+                }
+                let c = Changed {
+                    added: Pooled::orphan(Vec::new()),
+                    removed: Pooled::orphan(Vec::new()),
+                };
+                write.send_one(&FromRead::Changed(c)).await?
+            }
+        }
     }
 }
 
 async fn hello_client_read(
     cfg: Arc<config::Config>,
+    clinfos: Clinfos,
+    ctracker: CTracker,
+    quotas: Quotas,
+    connection_id: CId,
     store: Store,
-    mut con: Channel<ServerCtx>,
+    mut con: Channel<Cipher<ServerCtx>>,
     server_stop: oneshot::Receiver<()>,
     secstore: Option<SecStore>,
+    audit: Option<Audit>,
     hello: ClientAuthRead,
+    addr: SocketAddr,
 ) -> Result<()> {
     async fn send(
         cfg: &Arc<config::Config>,
-        con: &mut Channel<ServerCtx>,
+        con: &mut Channel<Cipher<ServerCtx>>,
         hello: ServerHelloRead,
     ) -> Result<()> {
         Ok(time::timeout(cfg.hello_timeout, con.send_one(&hello)).await??)
     }
-    let uifo = match hello {
+    let (uifo, expires) = match hello {
         ClientAuthRead::Anonymous => {
             send(&cfg, &mut con, ServerHelloRead::Anonymous).await?;
-            ANONYMOUS.clone()
+            (ANONYMOUS.clone(), u64::MAX)
         }
         ClientAuthRead::Reuse(_) => bail!("read session reuse deprecated"),
         ClientAuthRead::Initiate(tok) => match secstore {
@@ -421,18 +786,43 @@ async fn hello_client_read(
                 let (ctx, _, tok) = secstore.create(&tok)?;
                 send(&cfg, &mut con, ServerHelloRead::Accepted(tok, CtxId::new()))
                     .await?;
-                con.set_ctx(ctx.clone()).await;
-                secstore.ifo(Some(&ctx.client()?))?
+                con.set_ctx(Cipher::Krb5(ctx.clone())).await;
+                (secstore.ifo(Some(&ctx.client()?)).await?, u64::MAX)
+            }
+        },
+        ClientAuthRead::Token(handshake) => match secstore {
+            None => bail!("authentication requested but not supported"),
+            Some(ref secstore) => {
+                let tok = accept_token(&cfg, &mut con, secstore, &handshake).await?;
+                let (uifo, _, expires) = secstore.check_token(&tok)?;
+                send(&cfg, &mut con, ServerHelloRead::Token).await?;
+                (uifo, expires)
             }
         },
     };
-    Ok(client_loop_read(cfg, store.clone(), con, server_stop, uifo).await?)
+    ctracker.authenticated(connection_id, &uifo, None)?;
+    Ok(client_loop_read(
+        cfg,
+        clinfos,
+        ctracker,
+        quotas,
+        store.clone(),
+        con,
+        server_stop,
+        secstore,
+        audit,
+        uifo,
+        expires,
+        addr,
+    )
+    .await?)
 }
 
 async fn hello_client(
     cfg: Arc<config::Config>,
     clinfos: Clinfos,
     ctracker: CTracker,
+    quotas: Quotas,
     connection_id: CId,
     delay_reads: Option<Instant>,
     listen_addr: SocketAddr,
@@ -440,13 +830,15 @@ async fn hello_client(
     s: TcpStream,
     server_stop: oneshot::Receiver<()>,
     secstore: Option<SecStore>,
+    audit: Option<Audit>,
     id: SocketAddr,
 ) -> Result<()> {
     s.set_nodelay(true)?;
+    let addr = s.peer_addr()?;
     let mut con = Channel::new(s);
-    time::timeout(cfg.hello_timeout, con.send_one(&1u64)).await??;
-    // we will use this to select a protocol version when there is more than one
-    let _version: u64 = time::timeout(cfg.hello_timeout, con.receive()).await??;
+    time::timeout(cfg.hello_timeout, con.send_one(&resolver::PROTOCOL_VERSION)).await??;
+    let version: u64 = time::timeout(cfg.hello_timeout, con.receive()).await??;
+    let version = cmp::min(version, resolver::PROTOCOL_VERSION);
     let hello: ClientHello = time::timeout(cfg.hello_timeout, con.receive()).await??;
     match hello {
         ClientHello::ReadOnly(hello) => {
@@ -455,13 +847,27 @@ async fn hello_client(
                     bail!("no read clients allowed yet");
                 }
             }
-            Ok(hello_client_read(cfg, store.clone(), con, server_stop, secstore, hello)
-                .await?)
+            Ok(hello_client_read(
+                cfg,
+                clinfos,
+                ctracker,
+                quotas,
+                connection_id,
+                store.clone(),
+                con,
+                server_stop,
+                secstore,
+                audit,
+                hello,
+                addr,
+            )
+            .await?)
         }
         ClientHello::WriteOnly(hello) => Ok(hello_client_write(
             cfg,
             clinfos,
             ctracker,
+            quotas,
             connection_id,
             listen_addr,
             store.clone(),
@@ -469,37 +875,193 @@ async fn hello_client(
             server_stop,
             secstore,
             id,
+            version,
             hello,
         )
         .await?),
     }
 }
 
+// Tell a client why we won't serve it instead of just hanging up. Token
+// clients get the same handshake as usual, so the reason is encrypted
+// like anything else.
+async fn refuse(
+    cfg: Arc<config::Config>,
+    secstore: Option<SecStore>,
+    s: TcpStream,
+    id: SocketAddr,
+    reason: Chars,
+) {
+    async fn run(
+        cfg: Arc<config::Config>,
+        secstore: Option<SecStore>,
+        s: TcpStream,
+        id: SocketAddr,
+        reason: Chars,
+    ) -> Result<()> {
+        let t = cfg.hello_timeout;
+        let mut con: Channel<Cipher<ServerCtx>> = Channel::new(s);
+        time::timeout(t, con.send_one(&resolver::PROTOCOL_VERSION)).await??;
+        let _: u64 = time::timeout(t, con.receive()).await??;
+        match time::timeout(t, con.receive()).await?? {
+            ClientHello::ReadOnly(auth) => {
+                if let (ClientAuthRead::Token(handshake), Some(secstore)) =
+                    (auth, &secstore)
+                {
+                    accept_token(&cfg, &mut con, secstore, &handshake).await?;
+                }
+                let m = ServerHelloRead::Error(reason);
+                time::timeout(t, con.send_one(&m)).await??
+            }
+            ClientHello::WriteOnly(hello) => {
+                if let (ClientAuthWrite::Token(handshake), Some(secstore)) =
+                    (hello.auth, &secstore)
+                {
+                    accept_token(&cfg, &mut con, secstore, &handshake).await?;
+                }
+                let m = ServerHelloWrite {
+                    ttl: cfg.writer_ttl.as_secs(),
+                    ttl_expired: false,
+                    auth: ServerAuthWrite::Error(reason),
+                    resolver_id: id,
+                };
+                time::timeout(t, con.send_one(&m)).await??
+            }
+        }
+        Ok(())
+    }
+    if let Err(e) = run(cfg, secstore, s, id, reason).await {
+        debug!("failed to refuse client {}", e)
+    }
+}
+
+// the tokens we accept and our noise private key
+fn load_tokens(auth: &config::Auth) -> Result<(Tokens, Bytes)> {
+    match auth {
+        config::Auth::Anonymous | config::Auth::Krb5(_) => {
+            bail!("token authentication not configured")
+        }
+        config::Auth::Token { key_file, tokens_file, public_key, private_key_file } => {
+            let tokens = Tokens::load(key_file.as_deref(), tokens_file.as_deref())?;
+            let private_key_file = private_key_file.as_ref().ok_or_else(|| {
+                anyhow!("token auth requires the resolver server's private_key_file")
+            })?;
+            Ok((tokens, noise::load_key(private_key_file, public_key)?))
+        }
+    }
+}
+
+type Reload = (config::Config, config::PMap, oneshot::Sender<Result<()>>);
+
+fn log_changes<K: Ord + fmt::Display, V>(
+    what: &str,
+    old: impl IntoIterator<Item = (K, V)>,
+    new: impl IntoIterator<Item = (K, V)>,
+    eq: impl Fn(&V, &V) -> bool,
+) {
+    let old = old.into_iter().collect::<BTreeMap<_, _>>();
+    let new = new.into_iter().collect::<BTreeMap<_, _>>();
+    for (k, v) in new.iter() {
+        match old.get(k) {
+            None => info!("reload: added {} {}", what, k),
+            Some(o) if !eq(o, v) => info!("reload: changed {} {}", what, k),
+            Some(_) => (),
+        }
+    }
+    for k in old.keys().filter(|k| !new.contains_key(k)) {
+        info!("reload: removed {} {}", what, k)
+    }
+}
+
+fn reload(
+    cfg: &mut Arc<config::Config>,
+    permissions: &mut config::PMap,
+    secstore: Option<&SecStore>,
+    quotas: &Quotas,
+    published: &Store,
+    new_cfg: config::Config,
+    new_permissions: config::PMap,
+) -> Result<()> {
+    if new_cfg.addrs != cfg.addrs || new_cfg.root() != cfg.root() {
+        bail!("changing the resolver addresses or root requires a restart")
+    }
+    // the token files are read again, so keys and tokens can be
+    // added or revoked without a restart
+    let tokens = match (&cfg.auth, &new_cfg.auth) {
+        (config::Auth::Anonymous, config::Auth::Anonymous) => None,
+        (config::Auth::Krb5(s0), config::Auth::Krb5(s1)) if s0 == s1 => None,
+        (config::Auth::Token { .. }, config::Auth::Token { .. }) => {
+            Some(load_tokens(&new_cfg.auth)?)
+        }
+        (_, _) => bail!("changing the resolver auth requires a restart"),
+    };
+    if new_cfg.group_mapper != cfg.group_mapper {
+        bail!("changing the group mapper requires a restart")
+    }
+    if new_cfg.audit != cfg.audit {
+        bail!("changing the audit log requires a restart")
+    }
+    if let Some(secstore) = secstore {
+        secstore.reload(new_permissions.clone(), &new_cfg)?;
+        log_changes("permissions for", &permissions.0, &new_permissions.0, |o, n| o == n);
+        if let Some((tokens, key)) = tokens {
+            secstore.reload_tokens(tokens, key)?;
+            info!("reload: reloaded tokens");
+        }
+    }
+    log_changes("referral to child", &cfg.children, &new_cfg.children, |o, n| {
+        o == n && o.ttl == n.ttl
+    });
+    published.set_children(new_cfg.children.clone());
+    if new_cfg.limits != cfg.limits {
+        info!("reload: changed limits");
+        quotas.set_limits(new_cfg.limits.clone());
+    }
+    *permissions = new_permissions;
+    *cfg = Arc::new(new_cfg);
+    Ok(())
+}
+
 async fn server_loop(
     cfg: config::Config,
-    permissions: config::PMap,
+    mut permissions: config::PMap,
     delay_reads: bool,
     id: usize,
     stop: oneshot::Receiver<()>,
+    reload_rx: mpsc::UnboundedReceiver<Reload>,
     ready: oneshot::Sender<SocketAddr>,
 ) -> Result<SocketAddr> {
     let delay_reads =
         if delay_reads { Some(Instant::now() + cfg.writer_ttl) } else { None };
-    let cfg = Arc::new(cfg);
-    let ctracker = CTracker::new();
+    let mut cfg = Arc::new(cfg);
+    let quotas = Quotas::new(cfg.limits.clone());
+    let ctracker = CTracker::new(quotas.clone());
     let clinfos = Clinfos(Arc::new(Mutex::new(HashMap::new())));
     let id = cfg.addrs[id];
     let secstore = match &cfg.auth {
         config::Auth::Anonymous => None,
         config::Auth::Krb5(spns) => {
-            Some(SecStore::new(spns[&id].clone(), permissions, &cfg)?)
+            let mechanism = Mechanism::Krb5(spns[&id].clone());
+            Some(SecStore::new(mechanism, permissions.clone(), &cfg)?)
+        }
+        config::Auth::Token { .. } => {
+            let (tokens, key) = load_tokens(&cfg.auth)?;
+            Some(SecStore::new(Mechanism::Token(tokens, key), permissions.clone(), &cfg)?)
         }
     };
-    let published =
-        Store::new(cfg.parent.clone(), cfg.children.clone(), secstore.clone(), id);
+    let audit = cfg.audit.as_ref().map(|a| Audit::new(a, &cfg, id)).transpose()?;
+    let published = Store::new(
+        cfg.parent.clone(),
+        cfg.children.clone(),
+        secstore.clone(),
+        audit.clone(),
+        quotas.clone(),
+        id,
+    );
     let listener = TcpListener::bind(id).await?;
     let local_addr = listener.local_addr()?;
     let mut stop = stop.fuse();
+    let mut reload_rx = reload_rx.fuse();
     let mut client_stops: Vec<oneshot::Sender<()>> = Vec::new();
     let max_connections = cfg.max_connections;
     let _ = ready.send(local_addr);
@@ -511,23 +1073,50 @@ async fn server_loop(
                 }
                 return Ok(local_addr)
             },
+            r = reload_rx.next() => if let Some((new_cfg, new_permissions, reply)) = r {
+                let r = reload(
+                    &mut cfg,
+                    &mut permissions,
+                    secstore.as_ref(),
+                    &quotas,
+                    &published,
+                    new_cfg,
+                    new_permissions
+                );
+                if r.is_ok() {
+                    info!("reload: complete")
+                }
+                let _ = reply.send(r);
+            },
             cl = listener.accept().fuse() => match cl {
                 Err(e) => warn!("accept failed: {}", e),
-                Ok((client, _)) => {
+                Ok((client, addr)) => {
+                    let connection_id = match ctracker.open(addr) {
+                        Ok(id) => id,
+                        Err(e) => {
+                            warn!("refusing connection from {}: {}", addr, e);
+                            let reason = Chars::from(e.to_string());
+                            let secstore = secstore.clone();
+                            task::spawn(refuse(cfg.clone(), secstore, client, id, reason));
+                            continue
+                        }
+                    };
                     let (tx, rx) = oneshot::channel();
                     client_stops.push(tx);
-                    let connection_id = ctracker.open();
                     task::spawn({
                         let clinfos = clinfos.clone();
                         let ctracker = ctracker.clone();
+                        let quotas = quotas.clone();
                         let published = published.clone();
                         let secstore = secstore.clone();
+                        let audit = audit.clone();
                         let cfg = cfg.clone();
                         async move {
                             let r = hello_client(
                                 cfg,
                                 clinfos,
                                 ctracker.clone(),
+                                quotas,
                                 connection_id,
                                 delay_reads,
                                 local_addr,
@@ -535,6 +1124,7 @@ async fn server_loop(
                                 client,
                                 rx,
                                 secstore,
+                                audit,
                                 id
                             ).await;
                             ctracker.close(connection_id);
@@ -554,6 +1144,7 @@ async fn server_loop(
 #[derive(Debug)]
 pub struct Server {
     stop: Option<oneshot::Sender<()>>,
+    reload: mpsc::UnboundedSender<Reload>,
     local_addr: SocketAddr,
 }
 
@@ -574,15 +1165,64 @@ impl Server {
     ) -> Result<Server> {
         let (send_stop, recv_stop) = oneshot::channel();
         let (send_ready, recv_ready) = oneshot::channel();
-        let tsk = server_loop(cfg, permissions, delay_reads, id, recv_stop, send_ready);
+        let (reload, recv_reload) = mpsc::unbounded();
+        let tsk = server_loop(
+            cfg,
+            permissions,
+            delay_reads,
+            id,
+            recv_stop,
+            recv_reload,
+            send_ready,
+        );
         let local_addr = select_biased! {
             a = task::spawn(tsk).fuse() => a??,
             a = recv_ready.fuse() => a?,
         };
-        Ok(Server { stop: Some(send_stop), local_addr })
+        Ok(Server { stop: Some(send_stop), reload, local_addr })
+    }
+
+    /// Replace the permissions and the child referrals of a running
+    /// server without dropping any registrations. The new
+    /// permissions are checked before they replace the old ones, and
+    /// if anything is wrong the old config is kept and an error is
+    /// returned. With token auth the key and tokens files are read
+    /// again. Changing the addresses, root, or auth mechanism of the
+    /// server requires a restart.
+    pub async fn reload(
+        &self,
+        cfg: config::Config,
+        permissions: config::PMap,
+    ) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.reload
+            .unbounded_send((cfg, permissions, tx))
+            .map_err(|_| anyhow!("server is stopped"))?;
+        rx.await?
     }
 
     pub fn local_addr(&self) -> &SocketAddr {
         &self.local_addr
     }
 }
+
+/// Create a token signed with `key` that authenticates `user` as a
+/// member of `groups` to a resolver server configured with token
+/// auth and the same key. `expires` is in seconds since the unix
+/// epoch.
+pub fn make_token(
+    key: &[u8],
+    user: &str,
+    groups: &[&str],
+    expires: u64,
+) -> Result<String> {
+    auth::make_token(key, user, groups, expires)
+}
+
+/// Create a Noise key pair for resolver servers configured with
+/// token auth, returning the private and public keys in hex. The
+/// private key goes in the servers' `private_key_file`, and the
+/// public key in every client's config.
+pub fn make_noise_key() -> Result<(String, String)> {
+    noise::generate_key()
+}
@@ -1,14 +1,20 @@
 use crate::{
-    channel::Channel,
+    channel::{Channel, Cipher},
     chars::Chars,
+    noise::Initiator,
     os::{self, ClientCtx, Krb5Ctx},
     path::Path,
     pool::{Pool, Pooled},
-    protocol::resolver::{
-        ClientAuthRead, ClientAuthWrite, ClientHello, ClientHelloWrite, FromRead,
-        FromWrite, ReadyForOwnershipCheck, Referral, Secret, ServerAuthWrite,
-        ServerHelloRead, ServerHelloWrite, ToRead, ToWrite,
+    protocol::{
+        glob::GlobSet,
+        resolver::{
+            Changed, ClientAuthRead, ClientAuthWrite, ClientHello, ClientHelloWrite,
+            FromRead, FromWrite, ReadyForOwnershipCheck, Referral, Secret,
+            ServerAuthWrite, ServerHelloRead, ServerHelloWrite, ToRead, ToWrite,
+            PROTOCOL_VERSION,
+        },
     },
+    resolver_store::PATH_POOL,
     utils,
 };
 use anyhow::{anyhow, Error, Result};
@@ -24,7 +30,11 @@ use log::{debug, info, warn};
 use parking_lot::RwLock;
 use rand::{seq::SliceRandom, thread_rng, Rng};
 use std::{
-    cmp::max, collections::HashMap, fmt::Debug, net::SocketAddr, sync::Arc,
+    cmp::max,
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    net::SocketAddr,
+    sync::Arc,
     time::Duration,
 };
 use tokio::{
@@ -50,6 +60,11 @@ lazy_static! {
 pub enum Auth {
     Anonymous,
     Krb5 { upn: Option<String>, spn: Option<String> },
+    /// A bearer token issued by the resolver server's administrator.
+    /// Tokens are only sent to the resolver servers in the config,
+    /// over a connection only the holder of their private Noise key
+    /// can read, see `config::Auth::Token`.
+    Token(String),
 }
 
 fn create_ctx(upn: Option<&str>, target_spn: &str) -> Result<(ClientCtx, Bytes)> {
@@ -71,10 +86,23 @@ macro_rules! cwt {
     };
 }
 
+// finish the noise handshake started in the hello, and then send the
+// token over the encrypted channel
+async fn send_token(
+    con: &mut Channel<Cipher<ClientCtx>>,
+    init: Initiator,
+    tok: &str,
+) -> Result<()> {
+    let reply: Bytes = con.receive().await?;
+    con.set_ctx(Cipher::Noise(init.finish(&reply)?)).await;
+    con.send_one(&utils::bytes(tok.as_bytes())).await
+}
+
 async fn connect_read(
     resolver: &Referral,
     desired_auth: &Auth,
-) -> Result<Channel<ClientCtx>> {
+    noise_key: Option<&Bytes>,
+) -> Result<Channel<Cipher<ClientCtx>>> {
     let mut addrs = resolver.addrs.clone();
     addrs.as_mut_slice().shuffle(&mut thread_rng());
     let mut n = 0;
@@ -94,6 +122,7 @@ async fn connect_read(
         let mut con = Channel::new(con);
         cwt!("send version", con.send_one(&1u64));
         let _ver: u64 = cwt!("recv version", con.receive());
+        let mut noise = None;
         let (auth, ctx) = match desired_auth {
             Auth::Anonymous => (ClientAuthRead::Anonymous, None),
             Auth::Krb5 { .. } if resolver.krb5_spns.is_empty() => {
@@ -109,13 +138,31 @@ async fn connect_read(
                     try_cf!("create ctx", continue, create_ctx(upn, target_spn));
                 (ClientAuthRead::Initiate(tok), Some(ctx))
             }
+            Auth::Token(_) => match noise_key {
+                None => bail!(
+                    "the resolver server's noise key is unknown, not sending the token"
+                ),
+                Some(key) => {
+                    let (init, msg) =
+                        try_cf!("start noise", continue, Initiator::with_key(key));
+                    noise = Some(init);
+                    (ClientAuthRead::Token(msg), None)
+                }
+            },
         };
         cwt!("hello", con.send_one(&ClientHello::ReadOnly(auth)));
+        if let (Some(init), Auth::Token(tok)) = (noise, desired_auth) {
+            try_cf!("token", continue, send_token(&mut con, init, tok).await);
+        }
         let r: ServerHelloRead = cwt!("hello reply", con.receive());
         if let Some(ref ctx) = ctx {
-            con.set_ctx(ctx.clone()).await
+            con.set_ctx(Cipher::Krb5(ctx.clone())).await
         }
         match (desired_auth, r) {
+            (_, ServerHelloRead::Error(e)) => {
+                info!("resolver server {} refused the connection: {}", addr, e);
+                continue;
+            }
             (Auth::Anonymous, ServerHelloRead::Anonymous) => (),
             (Auth::Anonymous, _) => {
                 info!("server requires authentication");
@@ -126,6 +173,14 @@ async fn connect_read(
                 continue;
             }
             (Auth::Krb5 { .. }, ServerHelloRead::Reused) => (),
+            (Auth::Krb5 { .. }, ServerHelloRead::Token)
+            | (Auth::Token(_), ServerHelloRead::Anonymous)
+            | (Auth::Token(_), ServerHelloRead::Reused)
+            | (Auth::Token(_), ServerHelloRead::Accepted(_, _)) => {
+                info!("resolver server did not accept the requested authentication");
+                continue;
+            }
+            (Auth::Token(_), ServerHelloRead::Token) => (),
             (Auth::Krb5 { .. }, ServerHelloRead::Accepted(tok, _)) => {
                 let ctx = ctx.ok_or_else(|| anyhow!("bug accepted but no ctx"))?;
                 try_cf!("resolver tok", continue, ctx.step(Some(&tok)));
@@ -142,8 +197,9 @@ async fn connection_read(
     mut receiver: mpsc::UnboundedReceiver<ReadBatch>,
     resolver: Arc<Referral>,
     desired_auth: Auth,
+    noise_key: Option<Bytes>,
 ) {
-    let mut con: Option<Channel<ClientCtx>> = None;
+    let mut con: Option<Channel<Cipher<ClientCtx>>> = None;
     'main: loop {
         match receiver.next().await {
             None => break,
@@ -160,7 +216,13 @@ async fn connection_read(
                     tries += 1;
                     let c = match con {
                         Some(ref mut c) => c,
-                        None => match connect_read(&resolver, &desired_auth).await {
+                        None => match connect_read(
+                            &resolver,
+                            &desired_auth,
+                            noise_key.as_ref(),
+                        )
+                        .await
+                        {
                             Ok(c) => {
                                 con = Some(c);
                                 con.as_mut().unwrap()
@@ -234,10 +296,14 @@ async fn connection_read(
 pub(crate) struct ResolverRead(mpsc::UnboundedSender<ReadBatch>);
 
 impl ResolverRead {
-    pub(crate) fn new(resolver: Arc<Referral>, desired_auth: Auth) -> ResolverRead {
+    pub(crate) fn new(
+        resolver: Arc<Referral>,
+        desired_auth: Auth,
+        noise_key: Option<Bytes>,
+    ) -> ResolverRead {
         let (to_tx, to_rx) = mpsc::unbounded();
         task::spawn(async move {
-            connection_read(to_rx, resolver, desired_auth).await;
+            connection_read(to_rx, resolver, desired_auth, noise_key).await;
             info!("read task shutting down")
         });
         ResolverRead(to_tx)
@@ -253,6 +319,95 @@ impl ResolverRead {
     }
 }
 
+/// Watch `resolver` for published paths matching `globs`,
+/// reconnecting as needed. Referrals to other clusters are sent to
+/// `referrals`, and the added and removed paths to `changes`. The
+/// server sends something at least every `ttl / 2`, so a connection
+/// quiet for `ttl` is assumed dead. Stops when `changes` is closed,
+/// or the server denies the watch.
+pub(crate) async fn watch(
+    resolver: Arc<Referral>,
+    desired_auth: Auth,
+    noise_key: Option<Bytes>,
+    globs: GlobSet,
+    ttl: Duration,
+    referrals: mpsc::UnboundedSender<Referral>,
+    changes: mpsc::UnboundedSender<Changed>,
+) {
+    // everything we've reported, so after a reconnect only what
+    // changed in the meantime is sent
+    let mut known: HashSet<Path> = HashSet::new();
+    let mut tries: usize = 0;
+    while !changes.is_closed() {
+        if tries > 0 {
+            let wait = thread_rng().gen_range(1..12);
+            time::sleep(Duration::from_secs(wait)).await
+        }
+        tries += 1;
+        let mut con =
+            match connect_read(&resolver, &desired_auth, noise_key.as_ref()).await {
+                Ok(con) => con,
+                Err(e) => {
+                    warn!("watch connect failed: {}", e);
+                    continue;
+                }
+            };
+        cwt!("send watch", con.send_one(&ToRead::Watch(globs.clone())));
+        match cwt!("watch reply", con.receive::<FromRead>()) {
+            FromRead::ListMatching(mut lm) => {
+                for r in lm.referrals.drain(..) {
+                    let _ = referrals.unbounded_send(r);
+                }
+                let now: HashSet<Path> =
+                    lm.matched.iter().flat_map(|b| b.iter().cloned()).collect();
+                let mut c =
+                    Changed { added: PATH_POOL.take(), removed: PATH_POOL.take() };
+                c.added.extend(now.difference(&known).cloned());
+                c.removed.extend(known.difference(&now).cloned());
+                known = now;
+                if !(c.added.is_empty() && c.removed.is_empty()) {
+                    let _ = changes.unbounded_send(c);
+                }
+            }
+            FromRead::Denied => {
+                warn!("watch {:?} denied", globs);
+                break;
+            }
+            m => {
+                warn!("unexpected watch reply {:?}", m);
+                continue;
+            }
+        }
+        tries = 0;
+        loop {
+            match time::timeout(ttl, con.receive::<FromRead>()).await {
+                Err(_) => {
+                    warn!("watch connection timed out");
+                    break;
+                }
+                Ok(Err(e)) => {
+                    warn!("watch connection failed {}", e);
+                    break;
+                }
+                Ok(Ok(FromRead::Changed(mut c))) => {
+                    c.added.retain(|p| known.insert(p.clone()));
+                    c.removed.retain(|p| known.remove(p));
+                    if !(c.added.is_empty() && c.removed.is_empty()) {
+                        let _ = changes.unbounded_send(c);
+                    }
+                    if changes.is_closed() {
+                        break;
+                    }
+                }
+                Ok(Ok(m)) => {
+                    warn!("unexpected watch message {:?}", m);
+                    break;
+                }
+            }
+        }
+    }
+}
+
 macro_rules! wt {
     ($e:expr) => {
         time::timeout(HELLO_TO, $e).await
@@ -267,15 +422,17 @@ async fn connect_write(
     secrets: &Arc<RwLock<HashMap<SocketAddr, u128, FxBuildHasher>>>,
     security_context: &mut Option<ClientCtx>,
     desired_auth: &Auth,
+    noise_key: Option<&Bytes>,
     degraded: &mut bool,
-) -> Result<(u64, Channel<ClientCtx>)> {
+) -> Result<(u64, Channel<Cipher<ClientCtx>>)> {
     info!("write_con connecting to resolver {:?}", resolver_addr);
     let con = wt!(TcpStream::connect(&resolver_addr))??;
     con.set_nodelay(true)?;
     let mut con = Channel::new(con);
-    wt!(con.send_one(&1u64))??;
+    wt!(con.send_one(&PROTOCOL_VERSION))??;
     let _version: u64 = wt!(con.receive())??;
     let sec = Duration::from_secs(1);
+    let mut noise = None;
     let (auth, ctx) = match desired_auth {
         Auth::Anonymous => (ClientAuthWrite::Anonymous, None),
         Auth::Krb5 { .. } if resolver.krb5_spns.is_empty() => {
@@ -296,13 +453,30 @@ async fn connect_write(
                 (ClientAuthWrite::Initiate { spn, token }, Some(ctx))
             }
         },
+        Auth::Token(_) => match noise_key {
+            None => {
+                bail!("the resolver server's noise key is unknown, not sending the token")
+            }
+            Some(key) => {
+                let (init, msg) = Initiator::with_key(key)?;
+                noise = Some(init);
+                (ClientAuthWrite::Token(msg), None)
+            }
+        },
     };
     let h = ClientHello::WriteOnly(ClientHelloWrite { write_addr, auth });
     debug!("write_con connection established hello {:?}", h);
     wt!(con.send_one(&h))??;
+    if let (Some(init), Auth::Token(tok)) = (noise, desired_auth) {
+        wt!(send_token(&mut con, init, tok))??;
+        info!("write_con all traffic now encrypted");
+    }
     let r: ServerHelloWrite = wt!(con.receive())??;
     debug!("write_con resolver hello {:?}", r);
     match (desired_auth, r.auth) {
+        (_, ServerAuthWrite::Error(e)) => {
+            bail!("resolver server refused the connection: {}", e);
+        }
         (Auth::Anonymous, ServerAuthWrite::Anonymous) => {
             *security_context = None;
         }
@@ -314,7 +488,7 @@ async fn connect_write(
         }
         (Auth::Krb5 { .. }, ServerAuthWrite::Reused) => {
             let ctx = ctx.ok_or_else(|| anyhow!("bug, reused but no ctx"))?;
-            con.set_ctx(ctx.clone()).await;
+            con.set_ctx(Cipher::Krb5(ctx.clone())).await;
             info!("write_con all traffic now encrypted");
         }
         (Auth::Krb5 { .. }, ServerAuthWrite::Accepted(tok)) => {
@@ -322,7 +496,7 @@ async fn connect_write(
             info!("write_con processing resolver mutual authentication");
             ctx.step(Some(&tok))?;
             info!("write_con mutual authentication succeeded");
-            con.set_ctx(ctx.clone()).await;
+            con.set_ctx(Cipher::Krb5(ctx.clone())).await;
             info!("write_con all traffic now encrypted");
             *security_context = Some(ctx.clone());
             let secret: Secret = wt!(con.receive())??;
@@ -333,6 +507,22 @@ async fn connect_write(
             }
             wt!(con.send_one(&ReadyForOwnershipCheck))??;
         }
+        (Auth::Token(_), ServerAuthWrite::Token) => {
+            *security_context = None;
+            let secret: Secret = wt!(con.receive())??;
+            {
+                let mut secrets = secrets.write();
+                secrets.insert(resolver_addr, secret.0);
+                secrets.insert(r.resolver_id, secret.0);
+            }
+            wt!(con.send_one(&ReadyForOwnershipCheck))??;
+        }
+        (Auth::Krb5 { .. }, ServerAuthWrite::Token)
+        | (Auth::Token(_), ServerAuthWrite::Anonymous)
+        | (Auth::Token(_), ServerAuthWrite::Reused)
+        | (Auth::Token(_), ServerAuthWrite::Accepted(_)) => {
+            bail!("resolver server did not accept the requested authentication");
+        }
     }
     if !r.ttl_expired && !*degraded {
         info!("connected to resolver {:?} for write", resolver_addr);
@@ -393,11 +583,12 @@ async fn connection_write(
     write_addr: SocketAddr,
     published: Arc<RwLock<HashMap<Path, ToWrite>>>,
     desired_auth: Auth,
+    noise_key: Option<Bytes>,
     secrets: Arc<RwLock<HashMap<SocketAddr, u128, FxBuildHasher>>>,
 ) {
     let mut receiver = receiver.fuse();
     let mut degraded = false;
-    let mut con: Option<Channel<ClientCtx>> = None;
+    let mut con: Option<Channel<Cipher<ClientCtx>>> = None;
     let mut ctx: Option<ClientCtx> = None;
     let hb = Duration::from_secs(TTL / 2);
     let linger = Duration::from_secs(TTL / 10);
@@ -440,7 +631,8 @@ async fn connection_write(
                             None => {
                                 let r = connect_write(
                                     &resolver, resolver_addr, write_addr, &published,
-                                    &secrets, &mut ctx, &desired_auth, &mut degraded
+                                    &secrets, &mut ctx, &desired_auth, noise_key.as_ref(),
+                                    &mut degraded
                                 ).await;
                                 match r {
                                     Ok((ttl, c)) => {
@@ -484,7 +676,8 @@ async fn connection_write(
                             None => {
                                 let r = connect_write(
                                     &resolver, resolver_addr, write_addr, &published,
-                                    &secrets, &mut ctx, &desired_auth, &mut degraded
+                                    &secrets, &mut ctx, &desired_auth, noise_key.as_ref(),
+                                    &mut degraded
                                 ).await;
                                 match r {
                                     Ok((ttl, c)) => {
@@ -564,6 +757,7 @@ async fn write_mgr(
     mut receiver: mpsc::UnboundedReceiver<WriteBatch>,
     resolver: Arc<Referral>,
     desired_auth: Auth,
+    noise_key: Option<Bytes>,
     secrets: Arc<RwLock<HashMap<SocketAddr, u128, FxBuildHasher>>>,
     write_addr: SocketAddr,
 ) -> Result<()> {
@@ -577,6 +771,7 @@ async fn write_mgr(
             let resolver = resolver.clone();
             let published = published.clone();
             let desired_auth = desired_auth.clone();
+            let noise_key = noise_key.clone();
             let secrets = secrets.clone();
             senders.push(sender);
             task::spawn(async move {
@@ -587,6 +782,7 @@ async fn write_mgr(
                     write_addr,
                     published,
                     desired_auth,
+                    noise_key,
                     secrets,
                 )
                 .await;
@@ -637,12 +833,15 @@ impl ResolverWrite {
     pub(crate) fn new(
         resolver: Arc<Referral>,
         desired_auth: Auth,
+        noise_key: Option<Bytes>,
         write_addr: SocketAddr,
         secrets: Arc<RwLock<HashMap<SocketAddr, u128, FxBuildHasher>>>,
     ) -> ResolverWrite {
         let (to_tx, to_rx) = mpsc::unbounded();
         task::spawn(async move {
-            let r = write_mgr(to_rx, resolver, desired_auth, secrets, write_addr).await;
+            let r =
+                write_mgr(to_rx, resolver, desired_auth, noise_key, secrets, write_addr)
+                    .await;
             info!("write manager exited {:?}", r);
         });
         ResolverWrite(to_tx)
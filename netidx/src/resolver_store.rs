@@ -8,10 +8,10 @@ use crate::{
         glob::{GlobSet, Scope},
         resolver::Referral,
     },
-    secstore::SecStoreInner,
+    secstore::{PublisherSec, SecStoreInner},
     utils::{self, Addr},
 };
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use fxhash::{FxBuildHasher, FxHashMap};
 use immutable_chunkmap::set::Set;
 use log::debug;
@@ -25,6 +25,7 @@ use std::{
     },
     convert::AsRef,
     iter::{self, FromIterator},
+    mem,
     net::SocketAddr,
 };
 
@@ -112,6 +113,7 @@ pub(crate) struct Store {
     parent: Option<Referral>,
     children: BTreeMap<Path, Referral>,
     addrs: HCAddrs,
+    changes: HashMap<Path, bool>,
 }
 
 impl Store {
@@ -129,6 +131,7 @@ impl Store {
             parent,
             children,
             addrs: HCAddrs::new(),
+            changes: HashMap::new(),
         };
         let children = t.children.keys().cloned().collect::<Vec<_>>();
         for child in children {
@@ -142,6 +145,25 @@ impl Store {
         t
     }
 
+    /// Replace the set of child referrals
+    pub(crate) fn set_children(&mut self, children: BTreeMap<Path, Referral>) {
+        let old = mem::replace(&mut self.children, children);
+        for child in old.keys() {
+            if !self.children.contains_key(child) {
+                self.remove_parents(child.as_ref());
+            }
+        }
+        let added = self
+            .children
+            .keys()
+            .filter(|c| !old.contains_key(*c))
+            .cloned()
+            .collect::<Vec<_>>();
+        for child in added {
+            self.add_parents(child.append("z").as_ref());
+        }
+    }
+
     fn remove_parents(&mut self, mut p: &str) {
         let mut save = false;
         loop {
@@ -297,24 +319,30 @@ impl Store {
         }
     }
 
+    /// Returns true if `path` was not already published by `addr`
     pub(crate) fn publish(
         &mut self,
         path: Path,
         addr: SocketAddr,
         default: bool,
         flags: Option<u16>,
-    ) {
-        self.by_addr.entry(addr).or_insert_with(HashSet::new).insert(path.clone());
+    ) -> bool {
+        let new =
+            self.by_addr.entry(addr).or_insert_with(HashSet::new).insert(path.clone());
         let addrs = self.by_path.entry(path.clone()).or_insert_with(Set::new);
         let len = addrs.len();
         *addrs = self.addrs.add_address(addrs, Addr(addr));
+        let grew = addrs.len() > len;
         if let Some(flags) = flags {
             self.by_path_flags.insert(path.clone(), flags);
         }
         if default {
             self.defaults.insert(path.clone());
         }
-        if addrs.len() > len {
+        if len == 0 {
+            self.changed(&path, true);
+        }
+        if grew {
             self.add_column(&path);
             self.add_parents(path.as_ref());
             let n = Path::levels(path.as_ref());
@@ -323,17 +351,16 @@ impl Store {
                 .or_insert_with(BTreeMap::new)
                 .insert(path.clone(), Z64(1));
         }
+        new
     }
 
-    pub(crate) fn unpublish(&mut self, path: Path, addr: SocketAddr) {
-        let client_gone = self
+    /// Returns true if `path` was published by `addr`
+    pub(crate) fn unpublish(&mut self, path: Path, addr: SocketAddr) -> bool {
+        let (removed, client_gone) = self
             .by_addr
             .get_mut(&addr)
-            .map(|s| {
-                s.remove(&path);
-                s.is_empty()
-            })
-            .unwrap_or(true);
+            .map(|s| (s.remove(&path), s.is_empty()))
+            .unwrap_or((false, true));
         if client_gone {
             self.by_addr.remove(&addr);
         }
@@ -351,6 +378,7 @@ impl Store {
                         }
                     }
                     None => {
+                        self.changed(&path, false);
                         self.by_path.remove(&path);
                         self.by_path_flags.remove(&path);
                         self.defaults.remove(&path);
@@ -363,6 +391,25 @@ impl Store {
                 }
             }
         }
+        removed
+    }
+
+    // a path published and then unpublished (or the reverse) is no
+    // change at all
+    fn changed(&mut self, path: &Path, added: bool) {
+        if self.changes.remove(path).is_none() {
+            self.changes.insert(path.clone(), added);
+        }
+    }
+
+    /// Take the paths that gained their first publisher (true), or
+    /// lost their last one (false), since the last call.
+    pub(crate) fn take_changes(&mut self) -> HashMap<Path, bool> {
+        mem::take(&mut self.changes)
+    }
+
+    pub(crate) fn is_published(&self, path: &Path, addr: &SocketAddr) -> bool {
+        self.by_addr.get(addr).map(|paths| paths.contains(path)).unwrap_or(false)
     }
 
     pub(crate) fn published_for_addr(&self, addr: &SocketAddr) -> HashSet<Path> {
@@ -372,10 +419,14 @@ impl Store {
         }
     }
 
-    pub(crate) fn clear(&mut self, addr: &SocketAddr) {
-        for path in self.published_for_addr(addr).drain() {
-            self.unpublish(path, *addr);
+    /// Unpublish everything published by `addr`, returning the
+    /// paths that were removed
+    pub(crate) fn clear(&mut self, addr: &SocketAddr) -> HashSet<Path> {
+        let paths = self.published_for_addr(addr);
+        for path in paths.iter() {
+            self.unpublish(path.clone(), *addr);
         }
+        paths
     }
 
     fn get_flags(&self, path: &str) -> u16 {
@@ -434,22 +485,19 @@ impl Store {
         sec: &SecStoreInner,
         now: u64,
         perm: Permissions,
+        principal: &[u8],
         path: &Path,
-    ) -> (
-        u16,
-        Pooled<FxHashMap<SocketAddr, Chars>>,
-        Pooled<Vec<(SocketAddr, Bytes)>>,
-    ) {
+    ) -> (u16, Pooled<FxHashMap<SocketAddr, Chars>>, Pooled<Vec<(SocketAddr, Bytes)>>)
+    {
         let mut krb5_spns = SPN_POOL.take();
         let mut sign_addr = |addr: &SocketAddr| match sec.get(addr) {
             None => (*addr, Bytes::new()),
-            Some((spn, secret, _)) => {
-                if !krb5_spns.contains_key(addr) {
+            Some(PublisherSec { spn, secret, ctx, version, .. }) => {
+                if ctx.is_some() && !krb5_spns.contains_key(addr) {
                     krb5_spns.insert(*addr, spn.clone());
                 }
-                (
-                    *addr,
-                    utils::make_sha3_token(
+                if *version < 2 {
+                    let token = utils::make_sha3_token(
                         None,
                         &[
                             &secret.to_be_bytes(),
@@ -457,8 +505,26 @@ impl Store {
                             &(perm.bits() as u32).to_be_bytes(),
                             path.as_bytes(),
                         ],
-                    ),
-                )
+                    );
+                    return (*addr, token);
+                }
+                // the packed principal follows the signature, so the
+                // publisher knows who the subscriber is
+                let token = utils::make_sha3_token(
+                    None,
+                    &[
+                        &secret.to_be_bytes(),
+                        &now.to_be_bytes(),
+                        &(perm.bits() as u32).to_be_bytes(),
+                        &(principal.len() as u32).to_be_bytes(),
+                        principal,
+                        path.as_bytes(),
+                    ],
+                );
+                let mut buf = BytesMut::with_capacity(token.len() + principal.len());
+                buf.extend_from_slice(&token);
+                buf.extend_from_slice(principal);
+                (*addr, buf.freeze())
             }
         };
         let (flags, mut addrs) = self.resolve_default(path);
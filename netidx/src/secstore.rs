@@ -1,52 +1,84 @@
 use crate::{
-    auth::{PMap, UserDb, UserInfo},
+    auth::{self, PMap, Tokens, UserDb, UserInfo, ANONYMOUS},
     chars::Chars,
     config,
-    os::{self, Krb5Ctx, Mapper, ServerCtx},
+    noise::{self, NoiseCtx},
+    os::{self, Krb5Ctx, ServerCtx},
 };
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use fxhash::FxBuildHasher;
 use parking_lot::RwLock;
 use rand::Rng;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::SystemTime};
+use tokio::task;
+
+/// How clients prove who they are
+pub(crate) enum Mechanism {
+    /// Kerberos, with our spn
+    Krb5(String),
+    /// Bearer tokens, with our noise private key
+    Token(Tokens, Bytes),
+}
+
+/// An authenticated publisher
+pub(crate) struct PublisherSec {
+    pub(crate) spn: Chars,
+    pub(crate) secret: u128,
+    /// publishers authenticated by token have a secret but no context
+    pub(crate) ctx: Option<ServerCtx>,
+    /// when the publisher's token expires, in seconds since the unix
+    /// epoch
+    pub(crate) expires: u64,
+    /// the protocol version the publisher negotiated, it decides the
+    /// format of the tokens we sign for it
+    pub(crate) version: u64,
+}
 
 pub(crate) struct SecStoreInner {
-    ctxts: HashMap<SocketAddr, (Chars, u128, ServerCtx), FxBuildHasher>,
+    ctxts: HashMap<SocketAddr, PublisherSec, FxBuildHasher>,
     userdb: UserDb,
 }
 
 impl SecStoreInner {
-    pub(crate) fn get(&self, id: &SocketAddr) -> Option<&(Chars, u128, ServerCtx)> {
-        self.ctxts.get(id).and_then(|r| match r.2.ttl() {
-            Ok(ttl) if ttl.as_secs() > 0 => Some(r),
-            _ => None,
+    /// The publisher at `id`, unless it's authentication has expired
+    pub(crate) fn get(&self, id: &SocketAddr) -> Option<&PublisherSec> {
+        self.ctxts.get(id).and_then(|p| match &p.ctx {
+            None if p.expires > now() => Some(p),
+            None => None,
+            Some(ctx) => match ctx.ttl() {
+                Ok(ttl) if ttl.as_secs() > 0 => Some(p),
+                _ => None,
+            },
         })
     }
+}
 
-    fn ifo(&mut self, user: Option<&str>) -> Result<Arc<UserInfo>> {
-        self.userdb.ifo(user)
-    }
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
 
 #[derive(Clone)]
 pub(crate) struct SecStore {
-    spn: Arc<String>,
-    pmap: Arc<PMap>,
+    mechanism: Arc<RwLock<Mechanism>>,
+    pmap: Arc<RwLock<Arc<PMap>>>,
     pub(crate) store: Arc<RwLock<SecStoreInner>>,
 }
 
 impl SecStore {
     pub(crate) fn new(
-        spn: String,
+        mechanism: Mechanism,
         pmap: config::PMap,
         cfg: &Arc<config::Config>,
     ) -> Result<Self> {
-        let mut userdb = UserDb::new(Mapper::new()?);
+        let mut userdb = UserDb::new(auth::mapper(&cfg.group_mapper)?);
         let pmap = PMap::from_file(pmap, &mut userdb, cfg.root(), &cfg.children)?;
         Ok(SecStore {
-            spn: Arc::new(spn),
-            pmap: Arc::new(pmap),
+            mechanism: Arc::new(RwLock::new(mechanism)),
+            pmap: Arc::new(RwLock::new(Arc::new(pmap))),
             store: Arc::new(RwLock::new(SecStoreInner {
                 ctxts: HashMap::with_hasher(FxBuildHasher::default()),
                 userdb,
@@ -54,17 +86,46 @@ impl SecStore {
         })
     }
 
-    pub(crate) fn pmap(&self) -> &PMap {
-        &*self.pmap
+    pub(crate) fn pmap(&self) -> Arc<PMap> {
+        self.pmap.read().clone()
+    }
+
+    /// Validate the new permissions against `cfg`, and if they are
+    /// valid replace the current permissions with them.
+    pub(crate) fn reload(&self, pmap: config::PMap, cfg: &config::Config) -> Result<()> {
+        let pmap = {
+            let mut inner = self.store.write();
+            PMap::from_file(pmap, &mut inner.userdb, cfg.root(), &cfg.children)?
+        };
+        *self.pmap.write() = Arc::new(pmap);
+        Ok(())
+    }
+
+    /// Replace the tokens we accept and our noise key. Connected
+    /// token clients are not checked again, but the new tokens apply
+    /// from now on.
+    pub(crate) fn reload_tokens(&self, tokens: Tokens, key: Bytes) -> Result<()> {
+        let mut mechanism = self.mechanism.write();
+        match &*mechanism {
+            Mechanism::Token(_, _) => {
+                *mechanism = Mechanism::Token(tokens, key);
+                Ok(())
+            }
+            Mechanism::Krb5(_) => bail!("token authentication not supported"),
+        }
     }
 
     pub(crate) fn get(&self, id: &SocketAddr) -> Option<ServerCtx> {
         let inner = self.store.read();
-        inner.get(id).map(|(_, _, c)| c.clone())
+        inner.get(id).and_then(|p| p.ctx.clone())
     }
 
     pub(crate) fn create(&self, tok: &[u8]) -> Result<(ServerCtx, u128, Bytes)> {
-        let ctx = os::create_server_ctx(Some(self.spn.as_str()))?;
+        let spn = match &*self.mechanism.read() {
+            Mechanism::Krb5(spn) => spn.clone(),
+            Mechanism::Token(_, _) => bail!("krb5 authentication not supported"),
+        };
+        let ctx = os::create_server_ctx(Some(spn.as_str()))?;
         let secret = rand::thread_rng().gen::<u128>();
         let tok = ctx.step(Some(tok))?.map(|b| Bytes::copy_from_slice(&*b)).ok_or_else(
             || anyhow!("step didn't generate a mutual authentication token"),
@@ -77,10 +138,12 @@ impl SecStore {
         addr: SocketAddr,
         spn: Chars,
         secret: u128,
-        ctx: ServerCtx,
+        ctx: Option<ServerCtx>,
+        expires: u64,
+        version: u64,
     ) {
         let mut inner = self.store.write();
-        inner.ctxts.insert(addr, (spn, secret, ctx));
+        inner.ctxts.insert(addr, PublisherSec { spn, secret, ctx, expires, version });
     }
 
     pub(crate) fn remove(&self, addr: &SocketAddr) {
@@ -88,8 +151,38 @@ impl SecStore {
         inner.ctxts.remove(addr);
     }
 
-    pub(crate) fn ifo(&self, user: Option<&str>) -> Result<Arc<UserInfo>> {
-        let mut inner = self.store.write();
-        Ok(inner.ifo(user)?)
+    /// The user info for `user`. Looking up groups may block, so it
+    /// runs on the blocking pool without holding the store lock.
+    pub(crate) async fn ifo(&self, user: Option<&str>) -> Result<Arc<UserInfo>> {
+        match user {
+            None => Ok(ANONYMOUS.clone()),
+            Some(user) => {
+                let mapper = self.store.read().userdb.mapper();
+                let name = String::from(user);
+                let groups =
+                    task::spawn_blocking(move || mapper.lock().groups(&name)).await??;
+                Ok(self.store.write().userdb.with_groups(user, groups))
+            }
+        }
+    }
+
+    /// Answer the noise handshake a token client started with our
+    /// static key.
+    pub(crate) fn respond_token(&self, handshake: &[u8]) -> Result<(NoiseCtx, Bytes)> {
+        match &*self.mechanism.read() {
+            Mechanism::Token(_, key) => noise::respond_with_key(handshake, key),
+            Mechanism::Krb5(_) => bail!("token authentication not supported"),
+        }
+    }
+
+    /// Check a bearer token, and return the user it authenticates, a
+    /// new secret to share with them, and when the token expires.
+    pub(crate) fn check_token(&self, tok: &[u8]) -> Result<(Arc<UserInfo>, u128, u64)> {
+        let (user, groups, expires) = match &*self.mechanism.read() {
+            Mechanism::Token(tokens, _) => tokens.check(tok, now())?,
+            Mechanism::Krb5(_) => bail!("token authentication not supported"),
+        };
+        let ifo = self.store.write().userdb.with_groups(&user, groups);
+        Ok((ifo, rand::thread_rng().gen::<u128>(), expires))
     }
 }
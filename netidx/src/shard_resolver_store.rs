@@ -1,17 +1,19 @@
 use crate::{
-    auth::{Permissions, UserInfo},
-    channel::Channel,
+    audit::Audit,
+    auth::{PMap, Permissions, UserInfo},
+    channel::{Channel, Cipher},
     os::ServerCtx,
-    pack::Z64,
+    pack::{Pack, Z64},
     path::Path,
     pool::{Pool, Pooled},
     protocol::{
-        glob::Scope,
+        glob::{GlobSet, Scope},
         resolver::{
-            FromRead, FromWrite, GetChangeNr, ListMatching, Referral, Resolved, Table,
-            ToRead, ToWrite,
+            Changed, FromRead, FromWrite, GetChangeNr, ListMatching, Referral, Resolved,
+            Table, ToRead, ToWrite,
         },
     },
+    quotas::Quotas,
     resolver_store::{
         self, COLS_POOL, MAX_READ_BATCH, MAX_WRITE_BATCH, PATH_POOL, REF_POOL,
     },
@@ -20,7 +22,7 @@ use crate::{
 use anyhow::Result;
 use futures::{
     channel::{
-        mpsc::{unbounded, UnboundedSender},
+        mpsc::{channel, unbounded, Receiver, Sender, UnboundedSender},
         oneshot::{self, Canceled},
     },
     future::join_all,
@@ -30,6 +32,7 @@ use futures::{
 use fxhash::FxBuildHasher;
 use log::info;
 use std::{
+    cell::Cell,
     collections::{BTreeMap, HashMap, HashSet, VecDeque},
     hash::{BuildHasher, Hash, Hasher},
     iter,
@@ -40,6 +43,10 @@ use std::{
 };
 use tokio::task;
 
+// the batches of changes waiting for a watcher, beyond this it is
+// disconnected, and has to list everything again when it reconnects.
+const WATCH_QUEUE: usize = 1000;
+
 type ReadB = Vec<(u64, ToRead)>;
 type ReadR = VecDeque<(u64, FromRead)>;
 type WriteB = Vec<(u64, ToWrite)>;
@@ -59,38 +66,73 @@ lazy_static! {
 
 struct ReadRequest {
     uifo: Arc<UserInfo>,
+    addr: SocketAddr,
     batch: Pooled<ReadB>,
 }
 
 struct WriteRequest {
     uifo: Arc<UserInfo>,
     write_addr: SocketAddr,
+    // the batch is clearing the publisher, audit it as such
+    clear: bool,
     batch: Pooled<WriteB>,
 }
 
+struct WatchRequest {
+    uifo: Arc<UserInfo>,
+    addr: SocketAddr,
+    globs: GlobSet,
+    changes: Sender<Changed>,
+}
+
+// whether `uifo` may list everything `set` can match
+fn list_matching_allowed(pmap: Option<&PMap>, set: &GlobSet, uifo: &UserInfo) -> bool {
+    pmap.map(|pmap| {
+        set.iter()
+            .all(|g| pmap.allowed_in_scope(g.base(), g.scope(), Permissions::LIST, uifo))
+    })
+    .unwrap_or(true)
+}
+
+fn shard_of(build_hasher: &FxBuildHasher, shard_mask: usize, path: &Path) -> usize {
+    let mut hasher = build_hasher.build_hasher();
+    path.hash(&mut hasher);
+    hasher.finish() as usize & shard_mask
+}
+
 #[derive(Clone)]
 struct Shard {
     read: UnboundedSender<(ReadRequest, oneshot::Sender<Pooled<ReadR>>)>,
     write: UnboundedSender<(WriteRequest, oneshot::Sender<Pooled<WriteR>>)>,
     internal: UnboundedSender<(SocketAddr, oneshot::Sender<HashSet<Path>>)>,
+    published: UnboundedSender<(SocketAddr, Vec<Path>, oneshot::Sender<VecDeque<bool>>)>,
+    watch: UnboundedSender<(WatchRequest, oneshot::Sender<FromRead>)>,
+    children: UnboundedSender<BTreeMap<Path, Referral>>,
 }
 
 impl Shard {
     fn new(
         shard: usize,
+        shard_mask: usize,
         parent: Option<Referral>,
         children: BTreeMap<Path, Referral>,
         secstore: Option<SecStore>,
+        audit: Option<Audit>,
+        quotas: Quotas,
         resolver: SocketAddr,
     ) -> Self {
         let (read, read_rx) = unbounded();
         let (write, write_rx) = unbounded();
         let (internal, mut internal_rx) = unbounded();
+        let (published, mut published_rx) = unbounded();
+        let (watch, mut watch_rx) = unbounded();
+        let (children_tx, mut children_rx) = unbounded();
         let mut read_rx = read_rx.fuse();
         let mut write_rx = write_rx.fuse();
-        let t = Shard { read, write, internal };
+        let t = Shard { read, write, internal, published, watch, children: children_tx };
         task::spawn(async move {
             let mut store = resolver_store::Store::new(parent, children);
+            let mut watchers = Vec::new();
             loop {
                 select! {
                     batch = read_rx.next() => match batch {
@@ -100,6 +142,7 @@ impl Shard {
                                 shard,
                                 &mut store,
                                 secstore.as_ref(),
+                                audit.as_ref(),
                                 resolver,
                                 req
                             );
@@ -110,10 +153,15 @@ impl Shard {
                         None => break,
                         Some((req, reply)) => {
                             let r = Shard::process_write_batch(
+                                shard,
+                                shard_mask,
                                 &mut store,
                                 secstore.as_ref(),
+                                audit.as_ref(),
+                                &quotas,
                                 req
                             );
+                            Shard::notify(&mut store, &mut watchers);
                             let _ = reply.send(r);
                         }
                     },
@@ -122,6 +170,32 @@ impl Shard {
                         Some((addr, reply)) => {
                             let _ = reply.send(store.published_for_addr(&addr));
                         }
+                    },
+                    req = published_rx.next() => match req {
+                        None => break,
+                        Some((addr, paths, reply)) => {
+                            let _ = reply.send(
+                                paths.iter().map(|p| store.is_published(p, &addr)).collect()
+                            );
+                        }
+                    },
+                    req = watch_rx.next() => match req {
+                        None => break,
+                        Some((req, reply)) => {
+                            let r = Shard::process_watch(
+                                shard,
+                                &mut store,
+                                secstore.as_ref(),
+                                audit.as_ref(),
+                                &mut watchers,
+                                req
+                            );
+                            let _ = reply.send(r);
+                        }
+                    },
+                    children = children_rx.next() => match children {
+                        None => break,
+                        Some(children) => store.set_children(children),
                     }
                 }
             }
@@ -134,6 +208,7 @@ impl Shard {
         shard: usize,
         store: &mut resolver_store::Store,
         secstore: Option<&SecStore>,
+        audit: Option<&Audit>,
         resolver: SocketAddr,
         mut req: ReadRequest,
     ) -> Pooled<ReadR> {
@@ -143,13 +218,29 @@ impl Shard {
         let mut resp = FROM_READ_POOL.take();
         let sec = secstore.map(|s| s.store.read());
         let uifo = req.uifo;
+        let addr = req.addr;
+        // requests sent to every shard are only audited by shard 0
+        let audit = |all: bool, op: &'static str, path: &str, allowed: bool| {
+            if let Some(audit) = audit {
+                if !all || shard == 0 {
+                    audit.log(now, &uifo, addr, op, path, allowed)
+                }
+            }
+        };
+        let pmap = secstore.map(|s| s.pmap());
+        let mut principal = Vec::new();
+        if secstore.is_some() {
+            // encoding into a Vec can't fail
+            let _: Result<(), _> = uifo.principal.encode(&mut principal);
+        }
         resp.extend(req.batch.drain(..).map(|(id, m)| match m {
             ToRead::Resolve(path) => {
                 if let Some(r) = store.check_referral(&path) {
                     (id, FromRead::Referral(r))
                 } else {
-                    match secstore {
+                    match &pmap {
                         None => {
+                            audit(false, "Resolve", &path, true);
                             let (flags, addrs) = store.resolve(&path);
                             let a = Resolved {
                                 krb5_spns: Pooled::orphan(HashMap::with_hasher(
@@ -163,15 +254,18 @@ impl Shard {
                             };
                             (id, FromRead::Resolved(a))
                         }
-                        Some(ref secstore) => {
-                            let perm = secstore.pmap().permissions(&*path, &*uifo);
-                            if !perm.contains(Permissions::SUBSCRIBE) {
+                        Some(pmap) => {
+                            let perm = pmap.permissions(&*path, &*uifo);
+                            let allowed = perm.contains(Permissions::SUBSCRIBE);
+                            audit(false, "Resolve", &path, allowed);
+                            if !allowed {
                                 (id, FromRead::Denied)
                             } else {
                                 let (flags, krb5_spns, addrs) = store.resolve_and_sign(
                                     &**sec.as_ref().unwrap(),
                                     now,
                                     perm,
+                                    &principal,
                                     &path,
                                 );
                                 let a = Resolved {
@@ -192,9 +286,11 @@ impl Shard {
                 if let Some(r) = store.check_referral(&path) {
                     (id, FromRead::Referral(r))
                 } else {
-                    let allowed = secstore
-                        .map(|s| s.pmap().allowed(&*path, Permissions::LIST, &*uifo))
+                    let allowed = pmap
+                        .as_ref()
+                        .map(|p| p.allowed(&*path, Permissions::LIST, &*uifo))
                         .unwrap_or(true);
+                    audit(true, "List", &path, allowed);
                     if allowed {
                         (id, FromRead::List(store.list(&path)))
                     } else {
@@ -203,19 +299,10 @@ impl Shard {
                 }
             }
             ToRead::ListMatching(set) => {
-                let allowed = secstore
-                    .map(|s| {
-                        let pmap = s.pmap();
-                        set.iter().all(|g| {
-                            pmap.allowed_in_scope(
-                                g.base(),
-                                g.scope(),
-                                Permissions::LIST,
-                                &*uifo,
-                            )
-                        })
-                    })
-                    .unwrap_or(true);
+                let allowed = list_matching_allowed(pmap.as_deref(), &set, &uifo);
+                for g in set.iter() {
+                    audit(true, "ListMatching", g.glob().glob(), allowed);
+                }
                 if !allowed {
                     (id, FromRead::Denied)
                 } else {
@@ -236,9 +323,11 @@ impl Shard {
                 }
             }
             ToRead::GetChangeNr(path) => {
-                let allowed = secstore
-                    .map(|s| s.pmap().allowed(&*path, Permissions::LIST, &*uifo))
+                let allowed = pmap
+                    .as_ref()
+                    .map(|p| p.allowed(&*path, Permissions::LIST, &*uifo))
                     .unwrap_or(true);
+                audit(true, "GetChangeNr", &path, allowed);
                 if !allowed {
                     (id, FromRead::Denied)
                 } else {
@@ -255,9 +344,11 @@ impl Shard {
                 if let Some(r) = store.check_referral(&path) {
                     (id, FromRead::Referral(r))
                 } else {
-                    let allowed = secstore
-                        .map(|s| s.pmap().allowed(&*path, Permissions::LIST, &*uifo))
+                    let allowed = pmap
+                        .as_ref()
+                        .map(|p| p.allowed(&*path, Permissions::LIST, &*uifo))
                         .unwrap_or(true);
+                    audit(true, "Table", &path, allowed);
                     if !allowed {
                         (id, FromRead::Denied)
                     } else {
@@ -267,18 +358,113 @@ impl Shard {
                     }
                 }
             }
+            ToRead::Admin(_) | ToRead::Watch(_) => unreachable!(),
         }));
         resp
     }
 
+    // list what matches the watch now, and start sending changes to
+    // it, in one step so nothing is missed
+    fn process_watch(
+        shard: usize,
+        store: &mut resolver_store::Store,
+        secstore: Option<&SecStore>,
+        audit: Option<&Audit>,
+        watchers: &mut Vec<(GlobSet, Sender<Changed>)>,
+        req: WatchRequest,
+    ) -> FromRead {
+        let pmap = secstore.map(|s| s.pmap());
+        let allowed = list_matching_allowed(pmap.as_deref(), &req.globs, &req.uifo);
+        if let Some(audit) = audit.filter(|_| shard == 0) {
+            // things would need to be massively screwed for this to fail
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            for g in req.globs.iter() {
+                audit.log(now, &req.uifo, req.addr, "Watch", g.glob().glob(), allowed)
+            }
+        }
+        if !allowed {
+            return FromRead::Denied;
+        }
+        let mut referrals = REF_POOL.take();
+        if shard == 0 {
+            for glob in req.globs.iter() {
+                store.referrals_in_scope(&mut referrals, glob.base(), glob.scope())
+            }
+        }
+        let mut matched = PATH_BPOOL.take();
+        matched.push(store.list_matching(&req.globs));
+        watchers.push((req.globs, req.changes));
+        FromRead::ListMatching(ListMatching { referrals, matched })
+    }
+
+    // tell the watchers about paths that were published or
+    // unpublished, and forget the ones that went away. A watcher that
+    // falls too far behind is cut off in every shard.
+    fn notify(
+        store: &mut resolver_store::Store,
+        watchers: &mut Vec<(GlobSet, Sender<Changed>)>,
+    ) {
+        let changes = store.take_changes();
+        watchers.retain_mut(|(globs, tx)| {
+            let mut c = Changed { added: PATH_POOL.take(), removed: PATH_POOL.take() };
+            for (path, added) in changes.iter().filter(|(p, _)| globs.is_match(p)) {
+                if *added {
+                    c.added.push(path.clone())
+                } else {
+                    c.removed.push(path.clone())
+                }
+            }
+            if c.added.is_empty() && c.removed.is_empty() {
+                !tx.is_closed()
+            } else {
+                match tx.try_send(c) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        if e.is_full() {
+                            tx.close_channel();
+                        }
+                        false
+                    }
+                }
+            }
+        })
+    }
+
     fn process_write_batch(
+        shard: usize,
+        shard_mask: usize,
         store: &mut resolver_store::Store,
         secstore: Option<&SecStore>,
+        audit: Option<&Audit>,
+        quotas: &Quotas,
         mut req: WriteRequest,
     ) -> Pooled<WriteR> {
+        let now =
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
         let uifo = &*req.uifo;
         let write_addr = req.write_addr;
+        let clear = req.clear;
+        let pmap = secstore.map(|s| s.pmap());
+        // default publishers are sent to every shard, and are only
+        // audited by shard 0
+        let audit = |default: bool, op: &'static str, path: &str, allowed: bool| {
+            if let Some(audit) = audit {
+                if !default || shard == 0 {
+                    audit.log(now, uifo, write_addr, op, path, allowed)
+                }
+            }
+        };
+        // default publishers are stored in every shard, but only
+        // counted against quotas by the shard their path belongs to
+        let build_hasher = FxBuildHasher::default();
+        let counted = |path: &Path| shard_of(&build_hasher, shard_mask, path) == shard;
+        let added = Cell::new(0);
+        let removed = Cell::new(0);
         let publish = |s: &mut resolver_store::Store,
+                       op: &'static str,
                        path: Path,
                        default: bool,
                        flags: Option<u16>|
@@ -293,41 +479,69 @@ impl Shard {
                 } else {
                     Permissions::PUBLISH
                 };
-                if secstore.map(|s| s.pmap().allowed(&*path, perm, uifo)).unwrap_or(true)
-                {
-                    s.publish(path, write_addr, default, flags);
+                let allowed =
+                    pmap.as_ref().map(|p| p.allowed(&path, perm, uifo)).unwrap_or(true);
+                audit(default, op, &path, allowed);
+                if allowed {
+                    let counted = counted(&path);
+                    if s.publish(path, write_addr, default, flags) && counted {
+                        added.set(added.get() + 1);
+                    }
                     FromWrite::Published
                 } else {
                     FromWrite::Denied
                 }
             }
         };
+        let unpublish = |s: &mut resolver_store::Store,
+                         op: &'static str,
+                         path: Path,
+                         default: bool|
+         -> FromWrite {
+            if !Path::is_absolute(&*path) {
+                FromWrite::Error("absolute paths required".into())
+            } else if let Some(r) = s.check_referral(&path) {
+                FromWrite::Referral(r)
+            } else {
+                audit(default, op, &path, true);
+                let counted = counted(&path);
+                if s.unpublish(path, write_addr) && counted {
+                    removed.set(removed.get() + 1);
+                }
+                FromWrite::Unpublished
+            }
+        };
         let mut resp = FROM_WRITE_POOL.take();
         resp.extend(req.batch.drain(..).map(|(id, m)| match m {
             ToWrite::Heartbeat => unreachable!(),
             ToWrite::Clear => {
-                store.clear(&write_addr);
+                let paths = store.clear(&write_addr);
+                // each path is audited once, by the shard it belongs to
+                for path in paths.iter().filter(|p| counted(p)) {
+                    audit(false, "Clear", path, true);
+                    removed.set(removed.get() + 1);
+                }
                 (id, FromWrite::Unpublished)
             }
-            ToWrite::Publish(path) => (id, publish(store, path, false, None)),
-            ToWrite::PublishDefault(path) => (id, publish(store, path, true, None)),
+            ToWrite::Publish(path) => (id, publish(store, "Publish", path, false, None)),
+            ToWrite::PublishDefault(path) => {
+                (id, publish(store, "PublishDefault", path, true, None))
+            }
             ToWrite::PublishWithFlags(path, flags) => {
-                (id, publish(store, path, false, Some(flags)))
+                (id, publish(store, "PublishWithFlags", path, false, Some(flags)))
             }
             ToWrite::PublishDefaultWithFlags(path, flags) => {
-                (id, publish(store, path, true, Some(flags)))
+                (id, publish(store, "PublishDefaultWithFlags", path, true, Some(flags)))
             }
-            ToWrite::Unpublish(path) | ToWrite::UnpublishDefault(path) => {
-                if !Path::is_absolute(&*path) {
-                    (id, FromWrite::Error("absolute paths required".into()))
-                } else if let Some(r) = store.check_referral(&path) {
-                    (id, FromWrite::Referral(r))
-                } else {
-                    store.unpublish(path, write_addr);
-                    (id, FromWrite::Unpublished)
-                }
+            ToWrite::Unpublish(path) if clear => {
+                (id, unpublish(store, "Clear", path, false))
+            }
+            ToWrite::Unpublish(path) => (id, unpublish(store, "Unpublish", path, false)),
+            ToWrite::UnpublishDefault(path) => {
+                (id, unpublish(store, "UnpublishDefault", path, true))
             }
         }));
+        quotas.published(uifo, write_addr.ip(), added.get(), removed.get());
         resp
     }
 }
@@ -349,6 +563,7 @@ pub(crate) struct Store {
     shards: Vec<Shard>,
     build_hasher: FxBuildHasher,
     shard_mask: usize,
+    quotas: Quotas,
 }
 
 impl Store {
@@ -356,6 +571,8 @@ impl Store {
         parent: Option<Referral>,
         children: BTreeMap<Path, Referral>,
         secstore: Option<SecStore>,
+        audit: Option<Audit>,
+        quotas: Quotas,
         resolver: SocketAddr,
     ) -> Self {
         let shards = std::cmp::max(1, num_cpus::get().next_power_of_two());
@@ -365,20 +582,27 @@ impl Store {
             .map(|i| {
                 Shard::new(
                     i,
+                    shard_mask,
                     parent.clone(),
                     children.clone(),
                     secstore.clone(),
+                    audit.clone(),
+                    quotas.clone(),
                     resolver,
                 )
             })
             .collect();
-        Store { shards, shard_mask, build_hasher: FxBuildHasher::default() }
+        Store { shards, shard_mask, build_hasher: FxBuildHasher::default(), quotas }
+    }
+
+    pub(crate) fn set_children(&self, children: BTreeMap<Path, Referral>) {
+        for shard in self.shards.iter() {
+            let _ = shard.children.unbounded_send(children.clone());
+        }
     }
 
     fn shard(&self, path: &Path) -> usize {
-        let mut hasher = self.build_hasher.build_hasher();
-        path.hash(&mut hasher);
-        hasher.finish() as usize & self.shard_mask
+        shard_of(&self.build_hasher, self.shard_mask, path)
     }
 
     fn read_shard_batch(&self) -> Pooled<Vec<Pooled<ReadB>>> {
@@ -395,8 +619,9 @@ impl Store {
 
     pub(crate) async fn handle_batch_read(
         &mut self,
-        con: &mut Channel<ServerCtx>,
+        con: &mut Channel<Cipher<ServerCtx>>,
         uifo: Arc<UserInfo>,
+        addr: SocketAddr,
         mut msgs: impl Iterator<Item = ToRead>,
     ) -> Result<()> {
         let mut finished = false;
@@ -439,6 +664,8 @@ impl Store {
                         }
                         c += 100000;
                     }
+                    // handled by the server, never sent to the store
+                    Some(ToRead::Admin(_)) | Some(ToRead::Watch(_)) => unreachable!(),
                 }
                 n += 1;
             }
@@ -449,7 +676,7 @@ impl Store {
             let mut replies =
                 join_all(by_shard.drain(..).enumerate().map(|(i, batch)| {
                     let (tx, rx) = oneshot::channel();
-                    let req = ReadRequest { uifo: uifo.clone(), batch };
+                    let req = ReadRequest { uifo: uifo.clone(), addr, batch };
                     let _ = self.shards[i].read.unbounded_send((req, tx));
                     rx
                 }))
@@ -472,7 +699,9 @@ impl Store {
                     con.queue_send(&r)?;
                 } else {
                     match replies[0].pop_front().unwrap() {
-                        (_, FromRead::Resolved(_)) => unreachable!(),
+                        (_, FromRead::Resolved(_))
+                        | (_, FromRead::Sessions(_))
+                        | (_, FromRead::Changed(_)) => unreachable!(),
                         (_, m @ FromRead::Referral(_)) => {
                             same!(con, replies, &m, "desynced referral");
                         }
@@ -573,14 +802,74 @@ impl Store {
         }
     }
 
+    // Find the publishes in `batch` that would exceed the quota of
+    // the user or the publisher's address. Paths that are already
+    // published don't count, but finding them costs a trip to the
+    // shards, so that is only done when the quota might be
+    // exceeded. Publishes are refused here rather than in the shards
+    // because default publishers go to every shard, and they must
+    // all agree.
+    async fn over_quota(
+        &self,
+        uifo: &UserInfo,
+        write_addr: SocketAddr,
+        batch: &[ToWrite],
+    ) -> Result<VecDeque<u64>> {
+        fn publish_path(m: &ToWrite) -> Option<&Path> {
+            match m {
+                ToWrite::Publish(p)
+                | ToWrite::PublishDefault(p)
+                | ToWrite::PublishWithFlags(p, _)
+                | ToWrite::PublishDefaultWithFlags(p, _) => Some(p),
+                ToWrite::Heartbeat
+                | ToWrite::Clear
+                | ToWrite::Unpublish(_)
+                | ToWrite::UnpublishDefault(_) => None,
+            }
+        }
+        let mut over_quota = VecDeque::new();
+        let mut allowance = self.quotas.publish_allowance(uifo, write_addr.ip());
+        if batch.iter().filter_map(publish_path).count() <= allowance {
+            return Ok(over_quota);
+        }
+        let mut by_shard = vec![Vec::new(); self.shards.len()];
+        for path in batch.iter().filter_map(publish_path) {
+            by_shard[self.shard(path)].push(path.clone());
+        }
+        let mut published =
+            join_all(by_shard.into_iter().enumerate().map(|(i, paths)| {
+                let (tx, rx) = oneshot::channel();
+                let _ = self.shards[i].published.unbounded_send((write_addr, paths, tx));
+                rx
+            }))
+            .await
+            .into_iter()
+            .collect::<result::Result<Vec<VecDeque<bool>>, Canceled>>()?;
+        for (i, m) in batch.iter().enumerate() {
+            if let Some(path) = publish_path(m) {
+                let s = self.shard(path);
+                if !published[s].pop_front().unwrap() {
+                    if allowance == 0 {
+                        over_quota.push_back(i as u64);
+                    } else {
+                        allowance -= 1;
+                    }
+                }
+            }
+        }
+        Ok(over_quota)
+    }
+
     pub(crate) async fn handle_batch_write(
         &mut self,
-        mut con: Option<&mut Channel<ServerCtx>>,
+        mut con: Option<&mut Channel<Cipher<ServerCtx>>>,
         uifo: Arc<UserInfo>,
         write_addr: SocketAddr,
+        clear: bool,
         mut msgs: impl Iterator<Item = ToWrite>,
     ) -> Result<()> {
         let mut finished = false;
+        let mut batch = Vec::new();
         loop {
             let mut n = 0;
             let mut by_shard = self.write_shard_batch();
@@ -591,34 +880,51 @@ impl Store {
                         break;
                     }
                     Some(ToWrite::Heartbeat) => continue,
-                    Some(ToWrite::Clear) => {
+                    Some(m) => batch.push(m),
+                }
+            }
+            if batch.is_empty() {
+                assert!(finished);
+                break Ok(());
+            }
+            let mut over_quota = self.over_quota(&uifo, write_addr, &batch).await?;
+            let mut skip = over_quota.iter().copied().peekable();
+            for m in batch.drain(..) {
+                if skip.peek() == Some(&n) {
+                    skip.next();
+                    n += 1;
+                    continue;
+                }
+                match m {
+                    ToWrite::Heartbeat => unreachable!(),
+                    ToWrite::Clear => {
                         for b in by_shard.iter_mut() {
                             b.push((n, ToWrite::Clear));
                         }
                     }
-                    Some(ToWrite::Publish(path)) => {
+                    ToWrite::Publish(path) => {
                         let s = self.shard(&path);
                         by_shard[s].push((n, ToWrite::Publish(path)));
                     }
-                    Some(ToWrite::Unpublish(path)) => {
+                    ToWrite::Unpublish(path) => {
                         let s = self.shard(&path);
                         by_shard[s].push((n, ToWrite::Unpublish(path)));
                     }
-                    Some(ToWrite::UnpublishDefault(path)) => {
+                    ToWrite::UnpublishDefault(path) => {
                         for b in by_shard.iter_mut() {
                             b.push((n, ToWrite::UnpublishDefault(path.clone())));
                         }
                     }
-                    Some(ToWrite::PublishDefault(path)) => {
+                    ToWrite::PublishDefault(path) => {
                         for b in by_shard.iter_mut() {
                             b.push((n, ToWrite::PublishDefault(path.clone())));
                         }
                     }
-                    Some(ToWrite::PublishWithFlags(path, flags)) => {
+                    ToWrite::PublishWithFlags(path, flags) => {
                         let s = self.shard(&path);
                         by_shard[s].push((n, ToWrite::PublishWithFlags(path, flags)));
                     }
-                    Some(ToWrite::PublishDefaultWithFlags(path, flags)) => {
+                    ToWrite::PublishDefaultWithFlags(path, flags) => {
                         for b in by_shard.iter_mut() {
                             b.push((
                                 n,
@@ -629,14 +935,11 @@ impl Store {
                 }
                 n += 1;
             }
-            if by_shard.iter().all(|v| v.is_empty()) {
-                assert!(finished);
-                break Ok(());
-            }
             let mut replies =
                 join_all(by_shard.drain(..).enumerate().map(|(i, batch)| {
                     let (tx, rx) = oneshot::channel();
-                    let req = WriteRequest { uifo: uifo.clone(), write_addr, batch };
+                    let req =
+                        WriteRequest { uifo: uifo.clone(), write_addr, clear, batch };
                     let _ = self.shards[i].write.unbounded_send((req, tx));
                     rx
                 }))
@@ -645,7 +948,10 @@ impl Store {
                 .collect::<result::Result<Vec<Pooled<WriteR>>, Canceled>>()?;
             if let Some(ref mut c) = con {
                 for i in 0..n {
-                    if replies.len() == 1
+                    if over_quota.front() == Some(&i) {
+                        over_quota.pop_front();
+                        c.queue_send(&FromWrite::Error("publish quota exceeded".into()))?;
+                    } else if replies.len() == 1
                         || !replies
                             .iter()
                             .all(|v| v.front().map(|v| i == v.0).unwrap_or(false))
@@ -689,28 +995,87 @@ impl Store {
         }
     }
 
-    pub(crate) async fn handle_clear(
-        &mut self,
+    /// Start watching for published paths matching `globs`. Returns
+    /// the reply to the watch request, either the paths that match
+    /// now or `Denied`, and the changes from then on.
+    pub(crate) async fn watch(
+        &self,
         uifo: Arc<UserInfo>,
+        addr: SocketAddr,
+        globs: GlobSet,
+    ) -> Result<(FromRead, Receiver<Changed>)> {
+        // structural paths come and go without notice, so don't list
+        // them either
+        let globs = if globs.published_only() {
+            globs
+        } else {
+            GlobSet::new(true, globs.iter().cloned())?
+        };
+        let (tx, rx) = channel(WATCH_QUEUE);
+        let replies = join_all(self.shards.iter().map(|shard| {
+            let (reply, rx) = oneshot::channel();
+            let req = WatchRequest {
+                uifo: uifo.clone(),
+                addr,
+                globs: globs.clone(),
+                changes: tx.clone(),
+            };
+            let _ = shard.watch.unbounded_send((req, reply));
+            rx
+        }))
+        .await;
+        let mut referrals = REF_POOL.take();
+        let mut matched = PATH_BPOOL.take();
+        for r in replies {
+            match r? {
+                FromRead::ListMatching(mut lm) => {
+                    referrals.extend(lm.referrals.drain(..));
+                    matched.extend(lm.matched.drain(..));
+                }
+                m => return Ok((m, rx)),
+            }
+        }
+        Ok((FromRead::ListMatching(ListMatching { referrals, matched }), rx))
+    }
+
+    /// The paths published by the publisher at `write_addr`, in no
+    /// particular order
+    pub(crate) async fn published(
+        &self,
         write_addr: SocketAddr,
-    ) -> Result<()> {
-        use rand::{prelude::*, thread_rng};
-        let mut published_paths = join_all(self.shards.iter_mut().map(|shard| {
+    ) -> Result<Pooled<Vec<Path>>> {
+        let mut paths = PATH_POOL.take();
+        for set in join_all(self.shards.iter().map(|shard| {
             let (tx, rx) = oneshot::channel();
             let _ = shard.internal.unbounded_send((write_addr, tx));
             rx
         }))
         .await
-        .into_iter()
-        .flat_map(|s| s.unwrap().into_iter().map(ToWrite::Unpublish))
-        .collect::<Vec<_>>();
+        {
+            paths.extend(set?);
+        }
+        Ok(paths)
+    }
+
+    pub(crate) async fn handle_clear(
+        &mut self,
+        uifo: Arc<UserInfo>,
+        write_addr: SocketAddr,
+    ) -> Result<()> {
+        use rand::{prelude::*, thread_rng};
+        let mut published_paths = self
+            .published(write_addr)
+            .await?
+            .drain(..)
+            .map(ToWrite::Unpublish)
+            .collect::<Vec<_>>();
         published_paths.shuffle(&mut thread_rng());
         let iter = published_paths.into_iter();
         // clear the vast majority of published paths using resources fairly
-        self.handle_batch_write(None, uifo.clone(), write_addr, iter).await?;
+        self.handle_batch_write(None, uifo.clone(), write_addr, true, iter).await?;
         // clear out anything left over that was sent to all shards,
         // e.g. default publishers.
-        self.handle_batch_write(None, uifo, write_addr, iter::once(ToWrite::Clear))
+        self.handle_batch_write(None, uifo, write_addr, true, iter::once(ToWrite::Clear))
             .await?;
         Ok(())
     }
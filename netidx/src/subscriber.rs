@@ -1,11 +1,12 @@
 pub use crate::protocol::value::{FromValue, Typ, Value};
 use crate::{
     batch_channel::{self, BatchReceiver, BatchSender},
-    channel::{Channel, ReadChannel, WriteChannel},
+    channel::{Channel, Cipher, ReadChannel, WriteChannel},
     chars::Chars,
-    config::Config,
+    config::{Config, Encryption},
+    noise,
     os::{self, ClientCtx, Krb5Ctx},
-    pack::{Pack, PackError},
+    pack::{check_len, Pack, PackError},
     path::Path,
     pool::{Pool, Pooled},
     protocol::{
@@ -226,6 +227,7 @@ impl Pack for Event {
     }
 
     fn decode(buf: &mut impl Buf) -> result::Result<Self, PackError> {
+        check_len(buf, 1)?;
         if buf.chunk()[0] == 0x40 {
             buf.advance(1);
             Ok(Event::Unsubscribed)
@@ -558,6 +560,7 @@ struct SubscriberInner {
     durable_alive: HashMap<Path, DvalWeak>,
     trigger_resub: UnboundedSender<()>,
     desired_auth: Auth,
+    encryption: Encryption,
 }
 
 impl SubscriberInner {
@@ -622,11 +625,13 @@ impl Subscriber {
     /// create a new subscriber with the specified config and desired auth
     pub fn new(resolver: Config, desired_auth: Auth) -> Result<Subscriber> {
         let (tx, rx) = mpsc::unbounded();
+        let encryption = resolver.encryption;
         let resolver = ResolverRead::new(resolver, desired_auth.clone());
         let t = Subscriber(Arc::new(Mutex::new(SubscriberInner {
             id: SubscriberId::new(),
             resolver,
             desired_auth,
+            encryption,
             connections: HashMap::with_hasher(FxBuildHasher::default()),
             recently_failed: HashMap::with_hasher(FxBuildHasher::default()),
             subscribed: HashMap::new(),
@@ -986,6 +991,7 @@ impl Subscriber {
                     let mut t = self.0.lock();
                     let deadline = timeout.map(|t| now + t);
                     let desired_auth = t.desired_auth.clone();
+                    let encryption = t.encryption;
                     for (p, resolved) in to_resolve.into_iter().zip(res.drain(..)) {
                         if resolved.addrs.len() == 0 {
                             pending.insert(p, St::Error(anyhow!("path not found")));
@@ -1008,6 +1014,7 @@ impl Subscriber {
                                         target_spn,
                                         rx,
                                         desired_auth,
+                                        encryption,
                                     )
                                     .await;
                                     if let Some(subscriber) = subscriber.upgrade() {
@@ -1260,8 +1267,9 @@ fn unsubscribe(
 }
 
 async fn hello_publisher(
-    con: &mut Channel<ClientCtx>,
+    con: &mut Channel<Cipher<ClientCtx>>,
     auth: &Auth,
+    encryption: Encryption,
     target_spn: &Chars,
 ) -> Result<()> {
     use protocol::publisher::Hello;
@@ -1269,14 +1277,28 @@ async fn hello_publisher(
     con.send_one(&1u64).await?;
     let _ver: u64 = con.receive().await?;
     match auth {
-        Auth::Anonymous => {
-            con.send_one(&Hello::Anonymous).await?;
-            let reply: Hello = con.receive().await?;
-            match reply {
-                Hello::Anonymous => (),
-                _ => bail!("unexpected response from publisher"),
+        // token users are identified to publishers by the resolver
+        Auth::Anonymous | Auth::Token(_) => match encryption {
+            Encryption::Cleartext => {
+                con.send_one(&Hello::Anonymous).await?;
+                let reply: Hello = con.receive().await?;
+                match reply {
+                    Hello::Anonymous => (),
+                    _ => bail!("unexpected response from publisher"),
+                }
             }
-        }
+            Encryption::Noise => {
+                let (init, msg) = noise::Initiator::new()?;
+                con.send_one(&Hello::Noise(msg)).await?;
+                match con.receive().await? {
+                    Hello::Noise(reply) => {
+                        let ctx = init.finish(&reply)?;
+                        con.set_ctx(Cipher::Noise(ctx)).await;
+                    }
+                    _ => bail!("unexpected response from publisher"),
+                }
+            }
+        },
         Auth::Krb5 { upn, .. } => {
             let p = upn.as_ref().map(|p| p.as_str());
             let ctx = os::create_client_ctx(p, target_spn)?;
@@ -1287,14 +1309,16 @@ async fn hello_publisher(
             con.send_one(&Hello::Token(tok)).await?;
             match con.receive().await? {
                 Hello::Anonymous => bail!("publisher failed mutual authentication"),
-                Hello::ResolverAuthenticate(_, _) => bail!("protocol error"),
+                Hello::ResolverAuthenticate(_, _) | Hello::Noise(_) => {
+                    bail!("protocol error")
+                }
                 Hello::Token(tok) => {
                     if ctx.step(Some(&*tok))?.is_some() {
                         bail!("unexpected second token from step");
                     }
                 }
             }
-            con.set_ctx(ctx.clone()).await;
+            con.set_ctx(Cipher::Krb5(ctx.clone())).await;
         }
     }
     Ok(())
@@ -1337,7 +1361,7 @@ async fn process_batch(
     subscriptions: &mut HashMap<Id, Sub, FxBuildHasher>,
     pending: &mut HashMap<Path, SubscribeValRequest>,
     pending_writes: &mut HashMap<Id, VecDeque<oneshot::Sender<Value>>, FxBuildHasher>,
-    con: &mut WriteChannel<ClientCtx>,
+    con: &mut WriteChannel<Cipher<ClientCtx>>,
     subscriber: &Subscriber,
     conid: ConId,
 ) -> Result<()> {
@@ -1422,7 +1446,7 @@ async fn process_batch(
     Ok(())
 }
 
-fn try_flush(con: &mut WriteChannel<ClientCtx>) -> Result<()> {
+fn try_flush(con: &mut WriteChannel<Cipher<ClientCtx>>) -> Result<()> {
     if con.bytes_queued() > 0 {
         con.try_flush()?;
         Ok(())
@@ -1432,7 +1456,7 @@ fn try_flush(con: &mut WriteChannel<ClientCtx>) -> Result<()> {
 }
 
 fn decode_task(
-    mut con: ReadChannel<ClientCtx>,
+    mut con: ReadChannel<Cipher<ClientCtx>>,
     stop: oneshot::Receiver<()>,
 ) -> Receiver<Result<(Pooled<Vec<From>>, bool)>> {
     let (mut send, recv) = mpsc::channel(3);
@@ -1469,6 +1493,7 @@ async fn connection(
     target_spn: Chars,
     from_sub: BatchReceiver<ToCon>,
     auth: Auth,
+    encryption: Encryption,
 ) -> Result<()> {
     let mut pending: HashMap<Path, SubscribeValRequest> = HashMap::new();
     let mut subscriptions: HashMap<Id, Sub, FxBuildHasher> =
@@ -1478,7 +1503,7 @@ async fn connection(
     let conid = ConId::new();
     soc.set_nodelay(true)?;
     let mut con = Channel::new(soc);
-    hello_publisher(&mut con, &auth, &target_spn).await?;
+    hello_publisher(&mut con, &auth, encryption, &target_spn).await?;
     let (read_con, mut write_con) = con.split();
     let (tx_stop, rx_stop) = oneshot::channel();
     let mut pending_writes: HashMap<Id, VecDeque<oneshot::Sender<Value>>, FxBuildHasher> =
@@ -1,17 +1,40 @@
 use crate::config;
 
+// token auth with a new noise key, `name` keeps the key files of
+// tests running at the same time apart
+fn token_cfg(
+    name: &str,
+    key_file: Option<String>,
+    tokens_file: Option<String>,
+) -> config::Auth {
+    let (private, public_key) = crate::resolver_server::make_noise_key().unwrap();
+    let private_key_file =
+        std::env::temp_dir().join(format!("netidx-test-{}.noise", name));
+    std::fs::write(&private_key_file, private).unwrap();
+    let private_key_file = Some(private_key_file.to_string_lossy().into_owned());
+    config::Auth::Token { key_file, tokens_file, public_key, private_key_file }
+}
+
 mod resolver {
     use super::*;
     use crate::{
+        channel::Channel,
         chars::Chars,
+        noise,
+        os::ClientCtx,
         path::Path,
-        protocol::glob::{Glob, GlobSet},
+        protocol::{
+            glob::{Glob, GlobSet},
+            resolver::{ClientAuthRead, ClientHello, ServerHelloRead},
+        },
         publisher::PublishFlags,
         resolver::{Auth, ChangeTracker, ResolverRead, ResolverWrite},
-        resolver_server::Server,
+        resolver_server::{make_noise_key, make_token, Server},
     };
+    use bytes::Bytes;
+    use futures::StreamExt;
     use std::{iter, net::SocketAddr, time::Duration};
-    use tokio::{runtime::Runtime, time};
+    use tokio::{io::AsyncReadExt, net::TcpStream, runtime::Runtime, time};
 
     fn p(p: &'static str) -> Path {
         Path::from(p)
@@ -81,6 +104,282 @@ mod resolver {
         });
     }
 
+    #[test]
+    fn reload() {
+        Runtime::new().unwrap().block_on(async {
+            let cfg =
+                config::Config::load("../cfg/simple.json").expect("load simple config");
+            let root = config::Config::load("../cfg/complex-root.json").expect("root");
+            let server = Server::new(cfg.clone(), config::PMap::default(), false, 0)
+                .await
+                .expect("start server");
+            let mut rcfg = cfg.clone();
+            rcfg.addrs[0] = *server.local_addr();
+            let r = ResolverRead::new(rcfg, Auth::Anonymous);
+            let l = r.list(p("/")).await.unwrap();
+            assert_eq!(&**l, &[]);
+            let mut with_children = cfg.clone();
+            with_children.children = root.children.clone();
+            server.reload(with_children, config::PMap::default()).await.unwrap();
+            let l = r.list(p("/")).await.unwrap();
+            assert_eq!(&**l, &[p("/app")]);
+            server.reload(cfg.clone(), config::PMap::default()).await.unwrap();
+            let l = r.list(p("/")).await.unwrap();
+            assert_eq!(&**l, &[]);
+            assert!(server.reload(root, config::PMap::default()).await.is_err());
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn audit() {
+        Runtime::new().unwrap().block_on(async {
+            let file = std::env::temp_dir().join("netidx-test-audit.log");
+            let _ = std::fs::remove_file(&file);
+            let mut cfg =
+                config::Config::load("../cfg/simple.json").expect("load simple config");
+            cfg.audit = Some(config::Audit {
+                file: Some(file.to_string_lossy().into_owned()),
+                publish: None,
+                filter: vec![String::from("/app")],
+                denied_only: false,
+            });
+            let server = Server::new(cfg.clone(), config::PMap::default(), false, 0)
+                .await
+                .expect("start server");
+            cfg.addrs[0] = *server.local_addr();
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(cfg.clone(), Auth::Anonymous, paddr);
+            let r = ResolverRead::new(cfg, Auth::Anonymous);
+            w.publish(vec![p("/foo/bar"), p("/app/v0")]).await.unwrap();
+            r.resolve(vec![p("/foo/bar"), p("/app/v0")]).await.unwrap();
+            r.list(p("/app")).await.unwrap();
+            w.clear().await.unwrap();
+            time::sleep(Duration::from_millis(100)).await;
+            let log = std::fs::read_to_string(&file).unwrap();
+            std::fs::remove_file(&file).unwrap();
+            let mut recs = log
+                .lines()
+                .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+                .map(|v| {
+                    assert_eq!(v["user"], serde_json::Value::Null);
+                    assert_eq!(v["allowed"], true);
+                    (String::from(v["op"].as_str().unwrap()), v["path"].clone())
+                })
+                .collect::<Vec<_>>();
+            // the write client republishes when it connects
+            recs.dedup();
+            assert_eq!(
+                recs,
+                vec![
+                    (String::from("Publish"), "/app/v0".into()),
+                    (String::from("Resolve"), "/app/v0".into()),
+                    (String::from("List"), "/app".into()),
+                    (String::from("Clear"), "/app/v0".into()),
+                ]
+            );
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn limits() {
+        Runtime::new().unwrap().block_on(async {
+            let mut cfg =
+                config::Config::load("../cfg/simple.json").expect("load simple config");
+            cfg.limits.per_addr = config::Limit {
+                max_connections: Some(3),
+                requests_per_second: Some(100),
+                max_published: Some(3),
+            };
+            let server = Server::new(cfg.clone(), config::PMap::default(), false, 0)
+                .await
+                .expect("start server");
+            cfg.addrs[0] = *server.local_addr();
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(cfg.clone(), Auth::Anonymous, paddr);
+            let r = ResolverRead::new(cfg.clone(), Auth::Anonymous);
+            w.publish(vec![p("/app/v0"), p("/app/v1"), p("/app/v2")]).await.unwrap();
+            // publishing a path again doesn't count
+            w.publish(vec![p("/app/v0")]).await.unwrap();
+            assert!(w.publish(vec![p("/app/v3")]).await.is_err());
+            w.unpublish(vec![p("/app/v2")]).await.unwrap();
+            w.publish(vec![p("/app/v3")]).await.unwrap();
+            // more than one second's worth of requests
+            assert!(r.resolve((0..200).map(|_| p("/app/v0"))).await.is_err());
+            time::sleep(Duration::from_secs(1)).await;
+            r.resolve(vec![p("/app/v0"), p("/app/v3")]).await.unwrap();
+            // the read and write clients hold the other two connections
+            let mut buf = [0u8; 8];
+            let mut c0 = TcpStream::connect(cfg.addrs[0]).await.unwrap();
+            assert!(c0.read(&mut buf).await.unwrap() > 0);
+            // the next one is told why it's refused
+            let c1 = TcpStream::connect(cfg.addrs[0]).await.unwrap();
+            let mut c1: Channel<ClientCtx> = Channel::new(c1);
+            let _: u64 = c1.receive().await.unwrap();
+            c1.send_one(&1u64).await.unwrap();
+            c1.send_one(&ClientHello::ReadOnly(ClientAuthRead::Anonymous)).await.unwrap();
+            match c1.receive().await.unwrap() {
+                ServerHelloRead::Error(e) => assert!(e.contains("too many connections")),
+                m => panic!("expected an error, got {:?}", m),
+            }
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn admin() {
+        Runtime::new().unwrap().block_on(async {
+            let key_file = std::env::temp_dir().join("netidx-test-admin.key");
+            std::fs::write(&key_file, "not a very secret key\n").unwrap();
+            let mut cfg =
+                config::Config::load("../cfg/simple.json").expect("load simple config");
+            cfg.auth =
+                token_cfg("admin", Some(key_file.to_string_lossy().into_owned()), None);
+            let pmap =
+                config::PMap::parse(r#"{"/": {"admins": "a"}, "/app": {"": "p"}}"#)
+                    .unwrap();
+            let server = Server::new(cfg.clone(), pmap, false, 0).await.unwrap();
+            std::fs::remove_file(&key_file).unwrap();
+            cfg.addrs[0] = *server.local_addr();
+            let key = b"not a very secret key";
+            let token = |user, group| {
+                Auth::Token(make_token(key, user, &[group], u64::MAX).unwrap())
+            };
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(cfg.clone(), Auth::Anonymous, paddr);
+            let paths = vec![p("/app/v0"), p("/app/v1")];
+            w.publish(paths.clone()).await.unwrap();
+            let admin = ResolverRead::new(cfg.clone(), token("root", "admins"));
+            let ops = ResolverRead::new(cfg.clone(), token("bob", "ops"));
+            assert!(ops.sessions().await.is_err());
+            assert!(ops.clear_publisher(paddr).await.is_err());
+            let sessions = admin.sessions().await.unwrap();
+            assert!(sessions.iter().any(|s| s.addr.is_some()
+                && s.write_addr == Some(paddr)
+                && s.user.is_none()));
+            assert!(sessions
+                .iter()
+                .any(|s| s.write_addr.is_none() && s.user.as_deref() == Some("root")));
+            assert_eq!(&**admin.published(paddr).await.unwrap(), &paths[..]);
+            // the publisher dies, but its paths linger until writer_ttl
+            drop(w);
+            time::sleep(Duration::from_millis(100)).await;
+            let sessions = admin.sessions().await.unwrap();
+            assert!(sessions
+                .iter()
+                .any(|s| s.addr.is_none() && s.write_addr == Some(paddr)));
+            assert_eq!(&**admin.clear_publisher(paddr).await.unwrap(), &paths[..]);
+            assert!(admin.published(paddr).await.unwrap().is_empty());
+            let sessions = admin.sessions().await.unwrap();
+            assert!(sessions.iter().all(|s| s.write_addr != Some(paddr)));
+            assert!(admin.clear_publisher(paddr).await.is_err());
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn token_reload() {
+        Runtime::new().unwrap().block_on(async {
+            let tokens_file = std::env::temp_dir().join("netidx-test-reload.json");
+            std::fs::write(&tokens_file, r#"{"first": {"user": "svc"}}"#).unwrap();
+            let mut cfg =
+                config::Config::load("../cfg/simple.json").expect("load simple config");
+            cfg.auth = token_cfg(
+                "reload",
+                None,
+                Some(tokens_file.to_string_lossy().into_owned()),
+            );
+            let pmap = || config::PMap::parse(r#"{"/": {"svc": "a"}}"#).unwrap();
+            let server = Server::new(cfg.clone(), pmap(), false, 0).await.unwrap();
+            let mut rcfg = cfg.clone();
+            rcfg.addrs[0] = *server.local_addr();
+            let first = ResolverRead::new(rcfg.clone(), Auth::Token("first".into()));
+            first.sessions().await.unwrap();
+            std::fs::write(&tokens_file, r#"{"second": {"user": "svc"}}"#).unwrap();
+            server.reload(cfg.clone(), pmap()).await.unwrap();
+            let second = ResolverRead::new(rcfg, Auth::Token("second".into()));
+            second.sessions().await.unwrap();
+            // a broken tokens file keeps the old tokens
+            std::fs::write(&tokens_file, "not json").unwrap();
+            assert!(server.reload(cfg, pmap()).await.is_err());
+            second.sessions().await.unwrap();
+            std::fs::remove_file(&tokens_file).unwrap();
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn noise_key() {
+        Runtime::new().unwrap().block_on(async {
+            let tokens_file = std::env::temp_dir().join("netidx-test-noise-key.json");
+            std::fs::write(&tokens_file, r#"{"secret": {"user": "svc"}}"#).unwrap();
+            let tokens = Some(tokens_file.to_string_lossy().into_owned());
+            let mut cfg =
+                config::Config::load("../cfg/simple.json").expect("load simple config");
+            let pmap = || config::PMap::parse(r#"{"/": {"svc": "a"}}"#).unwrap();
+            let auth = token_cfg("noise-key", None, tokens);
+            let (_, other) = make_noise_key().unwrap();
+            // the server refuses to start without the matching private key
+            cfg.auth = auth.clone();
+            if let config::Auth::Token { public_key, .. } = &mut cfg.auth {
+                *public_key = other.clone();
+            }
+            assert!(Server::new(cfg.clone(), pmap(), false, 0).await.is_err());
+            if let config::Auth::Token { private_key_file, .. } = &mut cfg.auth {
+                *private_key_file = None;
+            }
+            assert!(Server::new(cfg.clone(), pmap(), false, 0).await.is_err());
+            cfg.auth = auth;
+            let server = Server::new(cfg.clone(), pmap(), false, 0).await.unwrap();
+            std::fs::remove_file(&tokens_file).unwrap();
+            cfg.addrs[0] = *server.local_addr();
+            ResolverRead::new(cfg.clone(), Auth::Token("secret".into()))
+                .sessions()
+                .await
+                .unwrap();
+            // a client expecting another key can't complete the
+            // handshake, so the token is never sent
+            let (_, msg) =
+                noise::Initiator::with_key(&noise::parse_key(&other).unwrap()).unwrap();
+            let c = TcpStream::connect(cfg.addrs[0]).await.unwrap();
+            let mut c: Channel<ClientCtx> = Channel::new(c);
+            let _: u64 = c.receive().await.unwrap();
+            c.send_one(&1u64).await.unwrap();
+            c.send_one(&ClientHello::ReadOnly(ClientAuthRead::Token(msg))).await.unwrap();
+            assert!(c.receive::<Bytes>().await.is_err());
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn watch() {
+        Runtime::new().unwrap().block_on(async {
+            let mut cfg =
+                config::Config::load("../cfg/simple.json").expect("load simple config");
+            let server = Server::new(cfg.clone(), config::PMap::default(), false, 0)
+                .await
+                .expect("start server");
+            cfg.addrs[0] = *server.local_addr();
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(cfg.clone(), Auth::Anonymous, paddr);
+            let r = ResolverRead::new(cfg, Auth::Anonymous);
+            w.publish(vec![p("/app/v0")]).await.unwrap();
+            let glob = Glob::new(Chars::from("/app/*")).unwrap();
+            let mut changes = r.watch(GlobSet::new(false, iter::once(glob)).unwrap());
+            let to = Duration::from_secs(10);
+            let c = time::timeout(to, changes.next()).await.unwrap().unwrap();
+            assert_eq!((&**c.added, &**c.removed), (&[p("/app/v0")][..], &[][..]));
+            w.publish(vec![p("/app/v1"), p("/foo/bar")]).await.unwrap();
+            let c = time::timeout(to, changes.next()).await.unwrap().unwrap();
+            assert_eq!((&**c.added, &**c.removed), (&[p("/app/v1")][..], &[][..]));
+            w.unpublish(vec![p("/app/v0"), p("/foo/bar")]).await.unwrap();
+            let c = time::timeout(to, changes.next()).await.unwrap().unwrap();
+            assert_eq!((&**c.added, &**c.removed), (&[][..], &[p("/app/v0")][..]));
+            drop(server)
+        });
+    }
+
     struct Ctx {
         _root: (Server, Server),
         _huge0: (Server, Server),
@@ -312,9 +611,11 @@ mod resolver {
 mod publisher {
     use super::*;
     use crate::{
+        auth::Principal,
+        chars::Chars,
         publisher::{BindCfg, Event as PEvent, PublishFlags, Publisher, Val},
         resolver::Auth,
-        resolver_server::Server,
+        resolver_server::{make_token, Server},
         subscriber::{Event, Subscriber, UpdatesFlags, Value},
     };
     use futures::{channel::mpsc, channel::oneshot, prelude::*, select_biased};
@@ -369,7 +670,7 @@ mod publisher {
         loop {
             select_biased! {
                 e = rx_ev.select_next_some() => match e {
-                    PEvent::Subscribe(_, _) | PEvent::Unsubscribe(_, _) => (),
+                    PEvent::Subscribe(_, _, _) | PEvent::Unsubscribe(_, _) => (),
                     PEvent::Destroyed(id) => {
                         assert!(id == dfp.unwrap().id());
                         dfp = None;
@@ -463,6 +764,100 @@ mod publisher {
             drop(server);
         });
     }
+
+    #[test]
+    fn noise_encryption() {
+        Runtime::new().unwrap().block_on(async {
+            let mut cfg =
+                config::Config::load("../cfg/simple.json").expect("load simple config");
+            let server = Server::new(cfg.clone(), config::PMap::default(), false, 0)
+                .await
+                .expect("start server");
+            cfg.addrs[0] = *server.local_addr();
+            let clear = cfg.clone();
+            cfg.encryption = config::Encryption::Noise;
+            let publisher = Publisher::new(
+                cfg.clone(),
+                Auth::Anonymous,
+                "127.0.0.1/32".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+            // bigger than one noise message
+            let big = Chars::from("x".repeat(200_000));
+            let _vp = publisher.publish("/app/v0".into(), Value::U64(42)).unwrap();
+            let _bp =
+                publisher.publish("/app/big".into(), Value::String(big.clone())).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, Auth::Anonymous).unwrap();
+            let vs = subscriber.subscribe_one("/app/v0".into(), None).await.unwrap();
+            assert_eq!(vs.last(), Event::Update(Value::U64(42)));
+            let bs = subscriber.subscribe_one("/app/big".into(), None).await.unwrap();
+            assert_eq!(bs.last(), Event::Update(Value::String(big)));
+            let clear = Subscriber::new(clear, Auth::Anonymous).unwrap();
+            let timeout = Some(Duration::from_secs(1));
+            assert!(clear.subscribe_one("/app/v0".into(), timeout).await.is_err());
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn token_auth() {
+        Runtime::new().unwrap().block_on(async {
+            let key_file = std::env::temp_dir().join("netidx-test-token.key");
+            std::fs::write(&key_file, "not a very secret key\n").unwrap();
+            let mut cfg =
+                config::Config::load("../cfg/simple.json").expect("load simple config");
+            cfg.auth =
+                token_cfg("token", Some(key_file.to_string_lossy().into_owned()), None);
+            let pmap = config::PMap::parse(r#"{"/app": {"ops": "spw"}}"#).unwrap();
+            let server = Server::new(cfg.clone(), pmap, false, 0).await.unwrap();
+            std::fs::remove_file(&key_file).unwrap();
+            cfg.addrs[0] = *server.local_addr();
+            let key = b"not a very secret key";
+            let token =
+                |user| Auth::Token(make_token(key, user, &["ops"], u64::MAX).unwrap());
+            let publisher = Publisher::new(
+                cfg.clone(),
+                token("alice"),
+                "127.0.0.1/32".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+            let vp = publisher.publish("/app/v0".into(), Value::U64(42)).unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            publisher.writes(vp.id(), tx);
+            let (tx_ev, mut rx_ev) = mpsc::unbounded();
+            publisher.events(tx_ev);
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg.clone(), token("bob")).unwrap();
+            let vs = subscriber.subscribe_one("/app/v0".into(), None).await.unwrap();
+            assert_eq!(vs.last(), Event::Update(Value::U64(42)));
+            let bob = Principal::User {
+                name: Chars::from("bob"),
+                groups: vec![Chars::from("ops")],
+            };
+            // the publisher knows who subscribed
+            match rx_ev.next().await.unwrap() {
+                PEvent::Subscribe(id, _, principal) => {
+                    assert_eq!(id, vp.id());
+                    assert_eq!(*principal, bob);
+                }
+                e => panic!("unexpected event {:?}", e),
+            }
+            // and who wrote
+            vs.write(Value::U64(43));
+            let mut batch = rx.next().await.unwrap();
+            let req = batch.pop().unwrap();
+            assert_eq!(req.value, Value::U64(43));
+            assert_eq!(*req.principal, bob);
+            assert_eq!(publisher.principal(&req.client).as_deref(), Some(&bob));
+            let anon = Subscriber::new(cfg, Auth::Anonymous).unwrap();
+            let timeout = Some(Duration::from_secs(1));
+            assert!(anon.subscribe_one("/app/v0".into(), timeout).await.is_err());
+            drop(server);
+        });
+    }
 }
 
 mod resolver_store {
@@ -605,3 +1000,149 @@ mod resolver_store {
         assert_eq!(cols.len(), 0);
     }
 }
+
+mod auth {
+    use crate::{
+        auth::{self, CachedMapper, PMap, Permissions, Tokens, UserDb, ANONYMOUS},
+        config,
+        os::GroupMapper,
+        protocol::glob::Scope,
+    };
+    use anyhow::Result;
+    use std::{collections::BTreeMap, fs, thread, time::Duration};
+
+    #[test]
+    fn test_pmap_globs() {
+        let file = config::PMap::parse(
+            r#"{
+                "/app": {"": "s"},
+                "/app/*/control": {"": "sw"},
+                "/app/*/secret": {"": "!s"},
+                "/app/special/control": {"": "!w"}
+            }"#,
+        )
+        .unwrap();
+        let mut db = UserDb::new(auth::mapper(&config::GroupMapper::Id).unwrap());
+        let pmap = PMap::from_file(file, &mut db, "/", &BTreeMap::new()).unwrap();
+        let s = Permissions::SUBSCRIBE;
+        let sw = Permissions::SUBSCRIBE | Permissions::WRITE;
+        let none = Permissions::empty();
+        let anon = &*ANONYMOUS;
+        assert_eq!(pmap.permissions("/app/foo", anon), s);
+        assert_eq!(pmap.permissions("/app/foo/control", anon), sw);
+        assert_eq!(pmap.permissions("/app/foo/control/x", anon), sw);
+        // * does not match the path separator
+        assert_eq!(pmap.permissions("/app/foo/bar/control", anon), s);
+        assert_eq!(pmap.permissions("/app/foo/secret/x", anon), none);
+        // a path entry overrides a glob at the same level
+        assert_eq!(pmap.permissions("/app/special/control", anon), s);
+        assert!(!pmap.allowed_in_scope("/app", &Scope::Subtree, s, anon));
+        assert!(pmap.allowed_in_scope("/app/foo/control", &Scope::Subtree, s, anon));
+    }
+
+    struct CountingMapper(usize);
+
+    impl GroupMapper for CountingMapper {
+        fn groups(&mut self, _user: &str) -> Result<Vec<String>> {
+            self.0 += 1;
+            Ok(vec![format!("g{}", self.0)])
+        }
+    }
+
+    #[test]
+    fn test_group_mappers() {
+        let file = std::env::temp_dir().join("netidx-test-groups.json");
+        fs::write(&file, r#"{"eric@EXAMPLE.COM": ["ops", "dev"]}"#).unwrap();
+        let cfg = config::GroupMapper::File(file.to_string_lossy().into_owned());
+        let mut db = UserDb::new(auth::mapper(&cfg).unwrap());
+        fs::remove_file(&file).unwrap();
+        let eric = db.ifo(Some("eric@EXAMPLE.COM")).unwrap();
+        assert_eq!(eric.groups.len(), 2);
+        assert_eq!(db.ifo(Some("nobody@EXAMPLE.COM")).unwrap().groups.len(), 0);
+        let file = std::env::temp_dir().join("netidx-test-groups.yaml");
+        fs::write(&file, "eric@EXAMPLE.COM:\n  - ops\n  - dev\n").unwrap();
+        let cfg = config::GroupMapper::File(file.to_string_lossy().into_owned());
+        let mut db = UserDb::new(auth::mapper(&cfg).unwrap());
+        fs::remove_file(&file).unwrap();
+        assert_eq!(db.ifo(Some("eric@EXAMPLE.COM")).unwrap().groups.len(), 2);
+        let mut cached =
+            CachedMapper::new(Duration::from_millis(100), Box::new(CountingMapper(0)));
+        assert_eq!(cached.groups("eric").unwrap(), vec!["g1"]);
+        assert_eq!(cached.groups("eric").unwrap(), vec!["g1"]);
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(cached.groups("eric").unwrap(), vec!["g2"]);
+    }
+
+    #[test]
+    fn test_tokens() {
+        let key_file = std::env::temp_dir().join("netidx-test-tokens.key");
+        let tokens_file = std::env::temp_dir().join("netidx-test-tokens.json");
+        fs::write(&key_file, "sekrit\n").unwrap();
+        fs::write(
+            &tokens_file,
+            r#"{
+                "letmein": {"user": "svc", "groups": ["ops"]},
+                "temporary": {"user": "tmp", "expires": 100}
+            }"#,
+        )
+        .unwrap();
+        let tokens = Tokens::load(
+            Some(&*key_file.to_string_lossy()),
+            Some(&*tokens_file.to_string_lossy()),
+        )
+        .unwrap();
+        fs::remove_file(&key_file).unwrap();
+        fs::remove_file(&tokens_file).unwrap();
+        let tok = auth::make_token(b"sekrit", "eric", &["ops", "dev"], 100).unwrap();
+        let (user, groups, expires) = tokens.check(tok.as_bytes(), 99).unwrap();
+        assert_eq!(user, "eric");
+        assert_eq!(groups, vec!["ops", "dev"]);
+        assert_eq!(expires, 100);
+        assert!(tokens.check(tok.as_bytes(), 100).is_err());
+        let forged = tok.replace("eric", "root");
+        assert!(tokens.check(forged.as_bytes(), 99).is_err());
+        let other = auth::make_token(b"other", "eric", &[], 100).unwrap();
+        assert!(tokens.check(other.as_bytes(), 99).is_err());
+        assert_eq!(
+            tokens.check(b"letmein", 99).unwrap(),
+            ("svc".into(), vec!["ops".into()], u64::MAX)
+        );
+        assert!(tokens.check(b"letmeout", 99).is_err());
+        assert_eq!(tokens.check(b"temporary", 99).unwrap(), ("tmp".into(), vec![], 100));
+        assert!(tokens.check(b"temporary", 100).is_err());
+        assert!(auth::make_token(b"sekrit", "a:b", &[], 100).is_err());
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    fn test_getgrouplist_matches_id() {
+        let mut id = auth::mapper(&config::GroupMapper::Id).unwrap();
+        let mut gl = auth::mapper(&config::GroupMapper::GetGroupList).unwrap();
+        assert_eq!(id.groups("root").unwrap(), gl.groups("root").unwrap());
+    }
+}
+
+mod quotas {
+    use crate::{
+        auth::ANONYMOUS,
+        config,
+        quotas::{Key, Quotas},
+    };
+    use std::net::IpAddr;
+
+    #[test]
+    fn reconnect() {
+        let mut limits = config::Limits::default();
+        limits.per_addr.requests_per_second = Some(10);
+        let quotas = Quotas::new(limits);
+        let addr: IpAddr = "127.0.0.1".parse().unwrap();
+        // reconnecting doesn't refill the bucket
+        let mut allowed = 0;
+        for _ in 0..10 {
+            quotas.connect(Key::Addr(addr)).unwrap();
+            allowed += quotas.requests(&ANONYMOUS, addr, 10);
+            quotas.disconnect(Key::Addr(addr));
+        }
+        assert_eq!(allowed, 10);
+    }
+}